@@ -133,7 +133,7 @@ else
     # System mode: Use systemd
     echo "Creating systemd service..."
     
-    sudo tee /etc/systemd/system/pkagent.service > /dev/null <<EOF
+    sudo tee /etc/systemd/system/publikey-agent.service > /dev/null <<EOF
 [Unit]
 Description=PubliKey Agent
 After=network.target
@@ -145,10 +145,10 @@ EOF
 
     # Create systemd timer
     echo "Creating systemd timer..."
-    sudo tee /etc/systemd/system/pkagent.timer > /dev/null <<EOF
+    sudo tee /etc/systemd/system/publikey-agent.timer > /dev/null <<EOF
 [Unit]
 Description=Run PubliKey Agent every minute
-Requires=pkagent.service
+Requires=publikey-agent.service
 
 [Timer]
 OnCalendar=*:*:00
@@ -159,11 +159,11 @@ WantedBy=timers.target
 EOF
 
     # Enable and start timer
-    echo "Enabling and starting pkagent timer..."
+    echo "Enabling and starting publikey-agent timer..."
     sudo systemctl daemon-reload
-    sudo systemctl enable pkagent.timer
-    sudo systemctl start pkagent.timer
+    sudo systemctl enable publikey-agent.timer
+    sudo systemctl start publikey-agent.timer
 
     echo "Installation complete! PubliKey Agent will run every minute in system mode."
-    echo "Check status with: sudo systemctl status pkagent.timer"
+    echo "Check status with: sudo systemctl status publikey-agent.timer"
 fi
\ No newline at end of file
@@ -1,34 +1,149 @@
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use reqwest::header::HeaderValue;
+use secrecy::{ExposeSecret, SecretString};
+use secrecy::zeroize::Zeroizing;
+use serde::de::{DeserializeOwned, Error as _};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use anyhow::{Result, anyhow};
-use tracing::{info, warn, error, instrument};
+use tracing::{debug, info, warn, error, instrument};
 
+use crate::assignment_signature::AssignmentsPubkey;
+use crate::proxy::ProxySetting;
+use crate::report::ReportSections;
+use crate::sse;
+use crate::ssh_keys::{AckOutcome, AssignmentState};
 use crate::system::SystemInfo;
+use crate::token::{self, TokenCommand, TokenType};
 use crate::users::UserInfo;
 
 #[derive(Serialize, Debug)]
 pub struct AgentReport {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    pub capabilities: Vec<String>,
     pub hostname: String,
     #[serde(rename = "systemInfo")]
     pub system_info: SystemInfo,
     #[serde(rename = "agentVersion")]
     pub agent_version: String,
     pub users: Vec<UserInfo>,
+    /// Constant across retries so the server can drop duplicate submissions
+    #[serde(rename = "idempotencyKey")]
+    pub idempotency_key: String,
+    /// Set when the user list may be incomplete, so the server must not treat missing users as deleted
+    #[serde(rename = "usersPartial", skip_serializing_if = "std::ops::Not::not")]
+    pub users_partial: bool,
+    #[serde(flatten)]
+    pub sections: ReportSections,
+}
+
+/// Generate a fresh idempotency key for a report attempt series
+pub fn new_idempotency_key() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Header naming the run a request belongs to, so server logs can be matched to the agent's
+pub const RUN_ID_HEADER: &str = "X-PubliKey-Run-Id";
+
+/// Generate the ID of a run
+pub fn new_run_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Header carrying the HMAC of a request signed with `--signing-key`
+pub const SIGNATURE_HEADER: &str = "X-PubliKey-Signature";
+
+/// Header carrying the time (seconds since the epoch) a request was signed at
+pub const TIMESTAMP_HEADER: &str = "X-PubliKey-Timestamp";
+
+/// Header carrying the server's base64 ed25519 signature over the key assignments response body
+pub const ASSIGNMENTS_SIGNATURE_HEADER: &str = "X-PubliKey-Assignments-Signature";
+
+/// Shared secret (`--signing-key`) requests are HMAC-signed with, so a captured bearer token
+/// cannot be replayed without it. The server checks the timestamp against its own clock, with a
+/// few minutes of slack.
+#[derive(Clone)]
+pub struct SigningKey(Zeroizing<Vec<u8>>);
+
+impl SigningKey {
+    /// Read the secret from a file; surrounding whitespace (a trailing newline) is not part of it
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let content = Zeroizing::new(
+            std::fs::read(path).map_err(|e| anyhow!("Failed to read signing key {}: {}", path.display(), e))?,
+        );
+        let key = content.trim_ascii();
+        if key.is_empty() {
+            return Err(anyhow!("Signing key {} is empty", path.display()));
+        }
+        Ok(Self(Zeroizing::new(key.to_vec())))
+    }
+
+    /// `sha256=<hex>` HMAC-SHA256 of `METHOD\npath\ntimestamp\n` followed by the body
+    pub fn sign(&self, method: &str, path: &str, timestamp: u64, body: &[u8]) -> String {
+        let mut message = format!("{}\n{}\n{}\n", method, path, timestamp).into_bytes();
+        message.extend_from_slice(body);
+        crate::webhook::sign(&self.0, &message)
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKey([REDACTED])")
+    }
+}
+
+/// Path (and query, if any) of a URL as covered by the request signature
+fn signed_path(url: &reqwest::Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct AgentReportResponse {
+    // Declared so --strict-api accepts them; the agent goes by the HTTP status instead
+    #[allow(dead_code)]
     pub success: bool,
     #[serde(rename = "hostId")]
     pub host_id: Option<String>,
     pub message: Option<String>,
     #[serde(rename = "usersProcessed")]
     pub users_processed: Option<u32>,
+    /// Set by servers that report whether this report created the host
+    #[serde(rename = "hostCreated")]
+    pub host_created: Option<bool>,
+    /// Capabilities the server will use; absent when the server does not negotiate
+    pub capabilities: Option<Vec<String>>,
+    #[serde(rename = "serverVersion")]
+    pub server_version: Option<String>,
+    #[allow(dead_code)]
     pub timestamp: Option<String>,
     pub error: Option<String>,
+    /// Fields this agent does not know about
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+impl AgentReportResponse {
+    /// Whether this was the first report from this host, i.e. the server just created it.
+    ///
+    /// Uses `hostCreated` when the server sends it, otherwise compares the host ID with the one
+    /// from the previous successful report.
+    pub fn is_new_host(&self, previous_host_id: Option<&str>) -> bool {
+        self.host_created
+            .unwrap_or_else(|| self.host_id.is_some() && self.host_id.as_deref() != previous_host_id)
+    }
+}
+
+/// First delay when polling for the key assignments of a newly created host
+pub const ASSIGNMENT_POLL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct KeyAssignment {
     pub username: String,
     pub fingerprint: String,
@@ -41,22 +156,153 @@ pub struct KeyAssignment {
     pub use_primary_key: Option<bool>,
     #[serde(rename = "assignmentId")]
     pub assignment_id: String,
+    /// Target account's uid; survives renames, so it takes precedence over the username
+    pub uid: Option<u32>,
+    #[serde(rename = "userEmail")]
+    pub user_email: Option<String>,
+    /// Seconds since the Unix epoch after which the key must be removed
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+    /// authorized_keys options written in front of the key (`command="...",no-pty`)
+    pub options: Option<String>,
+    /// Fields this agent does not know about
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+/// How one assignment was handled, keyed by its `assignmentId`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AssignmentAck {
+    #[serde(rename = "assignmentId")]
+    pub assignment_id: String,
+    pub outcome: AckOutcome,
+    /// Detailed state behind `outcome`
+    pub status: AssignmentState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// `--ping`: that this host is alive and which agent it runs, without the full report
+#[derive(Serialize, Debug)]
+pub struct Heartbeat {
+    pub hostname: String,
+    #[serde(rename = "agentVersion")]
+    pub agent_version: String,
+    #[serde(rename = "machineId")]
+    pub machine_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AssignmentAcks<'a> {
+    pub hostname: &'a str,
+    #[serde(rename = "runId")]
+    pub run_id: &'a str,
+    pub acknowledgements: &'a [AssignmentAck],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct KeyAssignmentsResponse {
     pub success: bool,
     #[serde(rename = "hostId")]
     pub host_id: Option<String>,
     pub hostname: Option<String>,
     pub assignments: Option<Vec<KeyAssignment>>,
+    #[serde(rename = "managedUsers")]
+    pub managed_users: Option<Vec<String>>,
+    #[serde(rename = "excludedUsers")]
+    pub excluded_users: Option<Vec<String>>,
+    /// Percentage of the fleet that should apply key removals this run
+    #[serde(rename = "rolloutPercent")]
+    pub rollout_percent: Option<u8>,
     pub timestamp: Option<String>,
     pub error: Option<String>,
+    /// Fields this agent does not know about
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// Validators of a key assignments response. Sent back on the next fetch, they let the server
+/// answer 304 instead of sending an unchanged list again.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(rename = "lastModified", skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn of(response: &reqwest::Response) -> Self {
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        Self { etag: header(reqwest::header::ETAG), last_modified: header(reqwest::header::LAST_MODIFIED) }
+    }
+}
+
+/// Outcome of a conditional key assignments fetch
+#[derive(Debug)]
+pub enum AssignmentsFetch {
+    Changed(Box<KeyAssignmentsResponse>, Validators),
+    /// 304: the response the validators came from is still current
+    NotModified,
+}
+
+/// Server responses that capture fields the agent does not know about
+pub trait UnknownFields {
+    /// Paths of unknown fields, prefixed with `path`
+    fn unknown_fields(&self, path: &str) -> Vec<String>;
+}
+
+fn extra_field_paths(extra: &HashMap<String, serde_json::Value>, path: &str) -> Vec<String> {
+    let mut fields: Vec<String> = extra
+        .keys()
+        .map(|key| if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) })
+        .collect();
+    fields.sort();
+    fields
+}
+
+impl UnknownFields for AgentReportResponse {
+    fn unknown_fields(&self, path: &str) -> Vec<String> {
+        extra_field_paths(&self.extra, path)
+    }
+}
+
+impl UnknownFields for KeyAssignment {
+    fn unknown_fields(&self, path: &str) -> Vec<String> {
+        extra_field_paths(&self.extra, path)
+    }
+}
+
+impl UnknownFields for KeyAssignmentsResponse {
+    fn unknown_fields(&self, path: &str) -> Vec<String> {
+        let mut fields = extra_field_paths(&self.extra, path);
+        let prefix = if path.is_empty() { "assignments".to_string() } else { format!("{}.assignments", path) };
+        for (index, assignment) in self.assignments.iter().flatten().enumerate() {
+            fields.extend(assignment.unknown_fields(&format!("{}[{}]", prefix, index)));
+        }
+        fields
+    }
+}
+
+/// Deserialize a server response, reporting the path of the offending field on failure.
+///
+/// Unknown fields are an error in strict mode and logged at debug level otherwise.
+pub fn parse_response<T: DeserializeOwned + UnknownFields>(text: &str, strict: bool) -> serde_json::Result<T> {
+    let parsed: T = serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(text))
+        .map_err(|e| serde_json::Error::custom(format!("at {}: {}", e.path(), e.inner())))?;
+
+    let unknown = parsed.unknown_fields("");
+    if !unknown.is_empty() {
+        if strict {
+            return Err(serde_json::Error::custom(format!("unknown field(s) in server response: {}", unknown.join(", "))));
+        }
+        debug!("Ignoring unknown field(s) in server response: {}", unknown.join(", "));
+    }
+    Ok(parsed)
 }
 
 #[derive(Deserialize, Debug)]
 pub struct VersionErrorResponse {
-    pub error: String,
     pub message: String,
     #[serde(rename = "minimumVersion")]
     pub minimum_version: String,
@@ -64,77 +310,709 @@ pub struct VersionErrorResponse {
     pub current_version: String,
 }
 
+/// Everything an API request can fail with. Callers match on the variant (to pick the exit code,
+/// decide whether to retry or spool) instead of parsing the message.
+#[derive(Debug)]
+pub enum ApiError {
+    /// 426: the server requires a newer agent; the versions are those the server named, if any
+    VersionTooOld { minimum: Option<String>, current: Option<String> },
+    /// 401 or 403: the token is wrong, expired or revoked
+    Unauthorized {
+        status: StatusCode,
+        body: String,
+        /// Seconds since the exp claim of a `--token-type jwt` token, when it has passed
+        jwt_expired_ago: Option<u64>,
+    },
+    /// Any other error status, with the server's error message or else the raw body
+    Http {
+        status: StatusCode,
+        body: String,
+        /// How long a 429 or 503 response asked the agent to wait before trying again
+        retry_after: Option<Duration>,
+    },
+    /// The request got no (complete) response: unreachable, timed out or cut off
+    Network {
+        /// What was being done, e.g. "Key assignments request"
+        what: &'static str,
+        /// `error` described with its causes and the proxy in use
+        detail: String,
+        error: reqwest::Error,
+    },
+    /// A success response whose body does not parse
+    Decode { what: &'static str, error: serde_json::Error },
+    /// The endpoint answered, but not the way the PubliKey API does
+    Protocol(String),
+    /// Key assignments without a valid signature by `--assignments-pubkey`
+    BadSignature(String),
+}
+
+impl ApiError {
+    /// Whether the request ran past `--timeout` or `--connect-timeout`
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Network { error, .. } if error.is_timeout())
+    }
+
+    /// Whether trying again may help: no response at all, 408, 429 or a server error. Anything
+    /// else the server said (a refused token, a malformed report, an unknown route) stands.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Self::Network { .. } => true,
+            Self::Http { status, .. } => {
+                *status == StatusCode::REQUEST_TIMEOUT || *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            Self::VersionTooOld { .. }
+            | Self::Unauthorized { .. }
+            | Self::Decode { .. }
+            | Self::Protocol(_)
+            | Self::BadSignature(_) => false,
+        }
+    }
+
+    /// How long the server asked the agent to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Http { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VersionTooOld { minimum: Some(minimum), current: Some(current) } => write!(
+                f,
+                "Agent version {} is too old. Minimum required version: {}. Please update the agent.",
+                current, minimum
+            ),
+            Self::VersionTooOld { .. } => f.write_str("Agent version too old. Please update the agent."),
+            Self::Unauthorized { status, body, jwt_expired_ago } => {
+                write!(f, "HTTP error ({}): {}", status, body)?;
+                match jwt_expired_ago {
+                    Some(ago) => write!(f, " (the JWT token expired {}s ago; mint a new one)", ago),
+                    None => Ok(()),
+                }
+            }
+            Self::Http { status, body, .. } => write!(f, "HTTP error ({}): {}", status, body),
+            Self::Network { what, detail, .. } => write!(f, "{} failed: {}", what, detail),
+            Self::Decode { what, error } => write!(f, "Failed to parse {}: {}", what, error),
+            Self::Protocol(message) => f.write_str(message),
+            Self::BadSignature(reason) => write!(f, "Refusing unverified key assignments: {}", reason),
+        }
+    }
+}
+
+// The messages already carry their causes, so `source` stays empty rather than repeat them in
+// `{:#}` output
+impl std::error::Error for ApiError {}
+
+/// The API error behind an error, if it came from one
+pub fn api_error(error: &anyhow::Error) -> Option<&ApiError> {
+    error.chain().find_map(|cause| cause.downcast_ref::<ApiError>())
+}
+
+/// A health check the server answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// A success status with a JSON object
+    pub healthy: bool,
+    /// From sending the request to the end of the response
+    pub latency: Duration,
+}
+
+/// An API route the agent calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Health,
+    Report,
+    Heartbeat,
+    Keys,
+    Acks,
+    /// Server-Sent Events announcing key assignment changes
+    Stream,
+}
+
+/// Path of each endpoint below the server's base URL. Overridable from the config file for
+/// gateways that rewrite the API's routes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointPaths {
+    pub health: String,
+    pub report: String,
+    pub heartbeat: String,
+    pub keys: String,
+    pub acks: String,
+    pub stream: String,
+}
+
+impl Default for EndpointPaths {
+    fn default() -> Self {
+        Self {
+            health: "/api/health".to_string(),
+            report: "/api/agent/report".to_string(),
+            heartbeat: "/api/agent/heartbeat".to_string(),
+            keys: "/api/host/keys".to_string(),
+            acks: "/api/host/keys/ack".to_string(),
+            stream: "/api/host/keys/stream".to_string(),
+        }
+    }
+}
+
+impl EndpointPaths {
+    pub fn get(&self, endpoint: Endpoint) -> &str {
+        match endpoint {
+            Endpoint::Health => &self.health,
+            Endpoint::Report => &self.report,
+            Endpoint::Heartbeat => &self.heartbeat,
+            Endpoint::Keys => &self.keys,
+            Endpoint::Acks => &self.acks,
+            Endpoint::Stream => &self.stream,
+        }
+    }
+
+    /// Reject paths that cannot be appended to the base URL as they are
+    pub fn validate(&self) -> Result<()> {
+        for (name, path) in [("health_path", &self.health), ("report_path", &self.report), ("heartbeat_path", &self.heartbeat), ("keys_path", &self.keys), ("ack_path", &self.acks), ("stream_path", &self.stream)] {
+            if !path.starts_with('/') {
+                return Err(anyhow!("{} must start with /, got {:?}", name, path));
+            }
+            if let Some(c) = path.chars().find(|c| c.is_whitespace() || c.is_control() || matches!(c, '?' | '#')) {
+                return Err(anyhow!("{} must be a plain path, but contains {:?}: {:?}", name, c, path));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct ApiClient {
     client: Client,
-    base_url: String,
-    token: String,
+    /// `--endpoint`s without trailing slashes, the primary first; each may carry a base path of its own
+    endpoints: Vec<String>,
+    /// Index into `endpoints` of the one requests go to
+    active: AtomicUsize,
+    paths: EndpointPaths,
+    /// Replaced when `token_command` hands out a new token
+    token: std::sync::RwLock<SecretString>,
+    /// Run again once when the server refuses the token, in case it rotated
+    token_command: Option<TokenCommand>,
+    token_type: TokenType,
+    strict_api: bool,
+    /// Cap on how long a Retry-After header may delay the next attempt
+    max_retry_after: Duration,
+    /// Sent with every request as [`RUN_ID_HEADER`]
+    run_id: String,
+    signing_key: Option<SigningKey>,
+    /// Key assignments are only accepted when signed by this key
+    assignments_pubkey: Option<AssignmentsPubkey>,
+    transport: Transport,
+}
+
+/// Default `--timeout`: the longest a whole API request may take
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default `--connect-timeout`
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest a key change stream stays open. Far beyond `--timeout`, since the stream is meant to
+/// idle; it is simply reopened afterwards.
+const STREAM_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How the API client reaches the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transport {
+    pub proxy: ProxySetting,
+    /// Longest a whole request, response body included, may take
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            proxy: ProxySetting::default(),
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+/// First and longest wait before reopening a key change stream that failed or closed
+const STREAM_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const STREAM_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// An open key change stream
+pub struct KeyStream {
+    response: reqwest::Response,
+    parser: sse::Parser,
+    pending: VecDeque<sse::Event>,
+}
+
+impl KeyStream {
+    /// Next event, or `None` once the server closes the stream
+    async fn next_event(&mut self) -> Result<Option<sse::Event>, ApiError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            let chunk = self.response.chunk().await.map_err(|error| ApiError::Network {
+                what: "Key change stream",
+                detail: error.to_string(),
+                error,
+            })?;
+            match chunk {
+                Some(bytes) => self.pending.extend(self.parser.push(&bytes)),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// The key change stream kept open across waits, with its reconnect backoff
+pub struct KeyWatch {
+    stream: Option<KeyStream>,
+    backoff: Duration,
+}
+
+impl Default for KeyWatch {
+    fn default() -> Self {
+        Self { stream: None, backoff: STREAM_BACKOFF_INITIAL }
+    }
+}
+
+/// Attempts made to deliver a report or fetch key assignments before giving up
+pub const MAX_RETRIES: u32 = 3;
+
+/// Longest backoff [`ApiClient::with_retry`] sleeps through over `max_retries` attempts
+pub fn retry_budget(max_retries: u32) -> Duration {
+    (1..max_retries).map(|attempt| retry_delay(attempt, 1.0)).sum()
+}
+
+/// Backoff after failed attempt `attempt` (1-based): exponential, plus up to half as much again
+/// scaled by `jitter` (0 to 1) so hosts that failed together do not all retry together
+fn retry_delay(attempt: u32, jitter: f64) -> Duration {
+    let base = Duration::from_secs(2u64.pow(attempt - 1));
+    base + base.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Default `--max-retry-after`: the longest Retry-After the agent waits out before its next attempt
+pub const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Retry-After of a 429 or 503 response; other statuses use the header for something else
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    if !matches!(response.status(), StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) {
+        return None;
+    }
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, std::time::SystemTime::now())
+}
+
+/// Parse a Retry-After value, delta-seconds or an HTTP-date, into a wait from `now`
+fn parse_retry_after(value: &str, now: std::time::SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means "now"
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+/// Random fraction in [0, 1), from the randomly keyed std hasher
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Start of the error raised when `--endpoint` points somewhere other than the API server's base URL
+pub const NOT_API_ENDPOINT: &str = "the endpoint does not look like the PubliKey API";
+
+/// Likely intended endpoint: without a trailing /api, otherwise just scheme, host and port
+fn suggested_endpoint(endpoint: &str) -> Option<String> {
+    let endpoint = endpoint.trim_end_matches('/');
+    if let Some(stripped) = endpoint.strip_suffix("/api") {
+        return Some(stripped.to_string());
+    }
+    reqwest::Url::parse(endpoint).ok().map(|url| url.origin().ascii_serialization())
+}
+
+fn endpoint_format_error(problem: &str, endpoint: &str) -> ApiError {
+    let hint = match suggested_endpoint(endpoint) {
+        Some(guess) if guess != endpoint.trim_end_matches('/') => format!("; did you mean {} ?", guess),
+        _ => String::new(),
+    };
+    ApiError::Protocol(format!(
+        "{}: {}. --endpoint must be the server's base URL without /api, e.g. https://publikey.example.com:3000{}",
+        NOT_API_ENDPOINT,
+        problem,
+        hint
+    ))
+}
+
+/// `endpoint` without trailing slashes, refused if it already ends in /api
+fn base_url(endpoint: &str) -> Result<String> {
+    let base_url = endpoint.trim_end_matches('/').to_string();
+    if base_url.ends_with("/api") {
+        return Err(endpoint_format_error("it already ends in /api, which the agent appends itself", endpoint).into());
+    }
+    Ok(base_url)
+}
+
+/// Whether a response is an HTML page rather than an API answer
+fn is_html(content_type: Option<&str>, body: &str) -> bool {
+    let start = body.trim_start().get(..9).unwrap_or_default().to_ascii_lowercase();
+    content_type.is_some_and(|ct| ct.to_ascii_lowercase().starts_with("text/html"))
+        || start.starts_with("<!doctype")
+        || start.starts_with("<html")
 }
 
 impl ApiClient {
-    pub fn new(endpoint: String, token: String) -> Result<Self> {
-        let base_url = if endpoint.ends_with('/') {
-            format!("{}api", endpoint)
-        } else {
-            format!("{}/api", endpoint)
-        };
+    pub fn new(endpoint: String, token: SecretString) -> Result<Self> {
+        let base_url = base_url(&endpoint)?;
+        let token = normalize_token(token.expose_secret())?;
 
-        let client = Client::builder()
-            .user_agent(format!("kmagent/{}", env!("CARGO_PKG_VERSION")))
+        let transport = Transport::default();
+        let client = Self::client_builder(&transport)?
             .build()
             .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
 
         Ok(Self {
             client,
-            base_url,
-            token,
+            endpoints: vec![base_url],
+            active: AtomicUsize::new(0),
+            paths: EndpointPaths::default(),
+            token: std::sync::RwLock::new(token),
+            token_command: None,
+            token_type: TokenType::Bearer,
+            strict_api: false,
+            max_retry_after: DEFAULT_MAX_RETRY_AFTER,
+            run_id: new_run_id(),
+            signing_key: None,
+            assignments_pubkey: None,
+            transport,
         })
     }
 
+    /// Create a client whose TLS connections use the given rustls configuration (e.g. certificate pinning)
+    pub fn with_tls_config(endpoint: String, token: SecretString, tls: rustls::ClientConfig) -> Result<Self> {
+        Self::with_transport(endpoint, token, tls, Transport::default())
+    }
+
+    /// Like [`ApiClient::with_tls_config`], with the proxy and timeouts of `transport`
+    pub fn with_transport(endpoint: String, token: SecretString, tls: rustls::ClientConfig, transport: Transport) -> Result<Self> {
+        let mut api_client = Self::new(endpoint, token)?;
+        api_client.client = Self::client_builder(&transport)?
+            .use_preconfigured_tls(tls)
+            .build()
+            .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
+        api_client.transport = transport;
+        Ok(api_client)
+    }
+
+    /// Call the endpoints at these paths instead of the defaults
+    pub fn with_endpoint_paths(mut self, paths: EndpointPaths) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// Full URL of an endpoint; the one place base URL and path are joined
+    pub fn url_for(&self, endpoint: Endpoint) -> String {
+        format!("{}/{}", self.endpoint(), self.paths.get(endpoint).trim_start_matches('/'))
+    }
+
+    /// Fall back to these endpoints, in order, when the primary does not answer
+    pub fn with_fallback_endpoints(mut self, endpoints: &[String]) -> Result<Self> {
+        for endpoint in endpoints {
+            self.endpoints.push(base_url(endpoint)?);
+        }
+        Ok(self)
+    }
+
+    /// Base URL requests currently go to
+    pub fn endpoint(&self) -> &str {
+        &self.endpoints[self.active.load(Ordering::Relaxed)]
+    }
+
+    /// Send requests to `endpoint` from now on, if it is one of the configured ones
+    pub fn use_endpoint(&self, endpoint: &str) -> bool {
+        match self.endpoints.iter().position(|candidate| candidate == endpoint) {
+            Some(index) => {
+                self.active.store(index, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run `request` against each endpoint in order until one answers, and stay on that one so
+    /// the rest of the run sees a single server. Only a missing answer moves on: no response, or
+    /// one that is not from the PubliKey API. When no endpoint answers, the client is back on the
+    /// primary and its error is returned.
+    pub async fn with_failover<T, F, Fut>(&self, what: &str, request: F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut primary_error = None;
+        for index in 0..self.endpoints.len() {
+            self.active.store(index, Ordering::Relaxed);
+            match request().await {
+                Err(e @ (ApiError::Network { .. } | ApiError::Protocol(_))) if self.endpoints.len() > 1 => {
+                    warn!("{} via {} failed: {}", what, self.endpoint(), e);
+                    primary_error.get_or_insert(e);
+                }
+                result => {
+                    if index > 0 {
+                        warn!("{} answered by fallback endpoint {}", what, self.endpoint());
+                    }
+                    return result;
+                }
+            }
+        }
+        self.active.store(0, Ordering::Relaxed);
+        Err(primary_error.expect("at least one endpoint"))
+    }
+
+    /// Reject server responses containing fields this agent does not know about
+    pub fn with_strict_api(mut self, strict: bool) -> Self {
+        self.strict_api = strict;
+        self
+    }
+
+    /// Wait out a server's Retry-After only up to this long
+    pub fn with_max_retry_after(mut self, max: Duration) -> Self {
+        self.max_retry_after = max;
+        self
+    }
+
+    /// Send this run ID instead of the one generated for the client
+    pub fn with_run_id(mut self, run_id: String) -> Self {
+        self.run_id = run_id;
+        self
+    }
+
+    /// HMAC-sign every request with this shared secret, if any
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Reject key assignments not signed by this key, if any
+    pub fn with_assignments_pubkey(mut self, key: Option<AssignmentsPubkey>) -> Self {
+        self.assignments_pubkey = key;
+        self
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Record how the token was issued; for JWTs the exp claim is read so errors can point at expiry
+    pub fn with_token_type(mut self, token_type: TokenType) -> Self {
+        if token_type == TokenType::Jwt && let Err(e) = token::jwt_expiry(self.token().expose_secret()) {
+            warn!("--token-type jwt given, but {}", e);
+        }
+        self.token_type = token_type;
+        self
+    }
+
+    /// Run this command again to get a new token when the server refuses the current one
+    pub fn with_token_command(mut self, command: Option<TokenCommand>) -> Self {
+        self.token_command = command;
+        self
+    }
+
+    fn token(&self) -> SecretString {
+        self.token.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Identifies where key assignments come from: the keys URLs of all endpoints (fallbacks serve
+    /// the same assignments), the token (or the command
+    /// printing it, as its tokens may rotate) and the key they are verified with, hashed so the
+    /// token is not written to disk. Cached assignments from another source (including ones
+    /// fetched before `--assignments-pubkey` was set) are never used.
+    pub fn assignments_source(&self) -> String {
+        let pubkey = self.assignments_pubkey.as_ref().map(AssignmentsPubkey::fingerprint).unwrap_or_default();
+        let credential = match &self.token_command {
+            Some(command) => Zeroizing::new(format!("command:{}", command.command())),
+            None => Zeroizing::new(self.token().expose_secret().to_string()),
+        };
+        let keys_path = self.paths.get(Endpoint::Keys).trim_start_matches('/');
+        let keys_urls: Vec<String> = self.endpoints.iter().map(|endpoint| format!("{}/{}", endpoint, keys_path)).collect();
+        let source = Zeroizing::new(format!("{}\n{}\n{}", keys_urls.join(","), credential.as_str(), pubkey));
+        let digest = Sha256::digest(source.as_bytes());
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Expiry of a JWT token, seconds since the epoch
+    pub fn token_expiry(&self) -> Option<u64> {
+        match self.token_type {
+            TokenType::Bearer => None,
+            TokenType::Jwt => token::jwt_expiry(self.token().expose_secret()).ok().flatten(),
+        }
+    }
+
+    /// Error for a non-success response, carrying its status and Retry-After
+    fn http_error(&self, status: StatusCode, retry_after: Option<Duration>, body: String) -> ApiError {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                ApiError::Unauthorized { status, body, jwt_expired_ago: self.jwt_expired_ago(status) }
+            }
+            _ => ApiError::Http { status, body, retry_after },
+        }
+    }
+
+    /// For a 401: how long ago a JWT passed its exp claim, the likely cause
+    fn jwt_expired_ago(&self, status: StatusCode) -> Option<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        match self.token_expiry() {
+            Some(exp) if status == StatusCode::UNAUTHORIZED && exp <= now => Some(now - exp),
+            _ => None,
+        }
+    }
+
+    fn client_builder(transport: &Transport) -> Result<reqwest::ClientBuilder> {
+        let builder = Client::builder()
+            .user_agent(format!("kmagent/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(transport.timeout)
+            .connect_timeout(transport.connect_timeout);
+        transport.proxy.apply(builder)
+    }
+
+    /// Error for a request that got no (complete) response, a timeout told apart from other failures
+    fn request_failed(&self, what: &'static str, error: reqwest::Error) -> ApiError {
+        ApiError::Network { what, detail: self.transport.proxy.describe_error(&error), error }
+    }
+
+    /// Start a request, tagged with the run ID
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.client.request(method, url).header(RUN_ID_HEADER, &self.run_id)
+    }
+
+    /// Send a request, signed with `--signing-key` if there is one
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, reqwest::Error> {
+        let mut request = request.build()?;
+        if let Some(key) = &self.signing_key {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let body = request.body().and_then(reqwest::Body::as_bytes).unwrap_or_default();
+            let signature = key.sign(request.method().as_str(), &signed_path(request.url()), timestamp, body);
+            let headers = request.headers_mut();
+            headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+            headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).expect("hex signature is a valid header value"));
+        }
+        self.client.execute(request).await
+    }
+
+    /// Authorization header value used by all authenticated endpoints, marked sensitive so it never shows up in Debug output
+    fn auth_header(&self) -> HeaderValue {
+        let value = Zeroizing::new(format!("Bearer {}", self.token().expose_secret()));
+        let mut header = HeaderValue::from_str(&value).expect("token validated as printable ASCII");
+        header.set_sensitive(true);
+        header
+    }
+
+    /// Scrub the token from text that may be echoed back by the server
+    fn redact(&self, text: String) -> String {
+        let token = self.token();
+        let token = token.expose_secret();
+        if text.contains(token) {
+            text.replace(token, "[REDACTED]")
+        } else {
+            text
+        }
+    }
+
+    /// Check the server answers, with the token so a refused one shows up here as
+    /// [`ApiError::Unauthorized`] rather than later in the report
     #[instrument(skip(self))]
-    pub async fn health_check(&self) -> Result<bool> {
-        let url = format!("{}/health", self.base_url);
+    pub async fn health_check(&self) -> Result<Health, ApiError> {
+        let url = self.url_for(Endpoint::Health);
         
         info!("Checking API health at: {}", url);
         
-        let response = self.client
-            .get(&url)
-            .send()
+        let started = std::time::Instant::now();
+        let request = self.request(reqwest::Method::GET, &url)
+            .header("Authorization", self.auth_header());
+        let response = self.send(request)
             .await
-            .map_err(|e| anyhow!("Health check request failed: {}", e))?;
+            .map_err(|e| self.request_failed("Health check request", e))?;
 
         let status = response.status();
-        if status.is_success() {
-            info!("Health check passed");
-            Ok(true)
-        } else {
-            warn!("Health check failed with status: {}", status);
-            Ok(false)
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = self.redact(response.text().await.unwrap_or_default());
+        let latency = started.elapsed();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            warn!("Health check refused the token with status: {}", status);
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|value| Some(value.get("error")?.as_str()?.to_string()))
+                .unwrap_or(body);
+            return Err(self.http_error(status, None, message));
         }
+        let healthy = if status.is_success() {
+            if is_html(content_type.as_deref(), &body) {
+                return Err(endpoint_format_error(&format!("{} returned an HTML page (the web UI?)", url), self.endpoint()));
+            }
+            if serde_json::from_str::<serde_json::Value>(&body).is_ok_and(|value| value.is_object()) {
+                info!("Health check passed in {} ms", latency.as_millis());
+                true
+            } else {
+                warn!("Health check at {} did not return a JSON object; this may not be the PubliKey API", url);
+                false
+            }
+        } else {
+            warn!("Health check failed with status: {} after {} ms", status, latency.as_millis());
+            false
+        };
+        Ok(Health { healthy, latency })
     }
 
     #[instrument(skip(self, report))]
-    pub async fn report_agent_data(&self, report: &AgentReport) -> Result<AgentReportResponse> {
-        let url = format!("{}/agent/report", self.base_url);
+    pub async fn report_agent_data(&self, report: &AgentReport) -> Result<AgentReportResponse, ApiError> {
+        let url = self.url_for(Endpoint::Report);
         
         info!("Reporting agent data to: {}", url);
         info!("Report contains {} users", report.users.len());
         
-        let response = self.client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
+        self.post_report(&url, report, &report.idempotency_key).await
+    }
+
+    /// Deliver a report previously stored in the offline spool
+    #[instrument(skip(self, report))]
+    pub async fn report_spooled(&self, report: &serde_json::Value, idempotency_key: &str) -> Result<AgentReportResponse, ApiError> {
+        let url = self.url_for(Endpoint::Report);
+        info!("Flushing spooled report to: {}", url);
+        self.post_report(&url, report, idempotency_key).await
+    }
+
+    async fn post_report<T: Serialize + ?Sized>(&self, url: &str, report: &T, idempotency_key: &str) -> Result<AgentReportResponse, ApiError> {
+        let request = self.request(reqwest::Method::POST, url)
+            .header("Authorization", self.auth_header())
             .header("Content-Type", "application/json")
-            .json(report)
-            .send()
+            .header("Idempotency-Key", idempotency_key)
+            .json(report);
+        let response = self.send(request)
             .await
-            .map_err(|e| anyhow!("Agent report request failed: {}", e))?;
+            .map_err(|e| self.request_failed("Agent report request", e))?;
 
         let status = response.status();
+        let retry_after = retry_after(&response);
         let response_text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+            .map_err(|e| self.request_failed("Reading the response", e))?;
+        let response_text = self.redact(response_text);
 
         if status.is_success() {
-            let parsed_response: AgentReportResponse = serde_json::from_str(&response_text)
-                .map_err(|e| anyhow!("Failed to parse successful response: {}", e))?;
+            let parsed_response: AgentReportResponse = parse_response(&response_text, self.strict_api)
+                .map_err(|error| ApiError::Decode { what: "report response", error })?;
             
             info!("Agent report successful: {}", parsed_response.message.as_deref().unwrap_or("No message"));
             if let Some(users_processed) = parsed_response.users_processed {
@@ -148,93 +1026,1222 @@ impl ApiClient {
                 error!("Agent version too old: {}", version_error.message);
                 error!("Current version: {}, Minimum required: {}", 
                        version_error.current_version, version_error.minimum_version);
-                return Err(anyhow!("Agent version {} is too old. Minimum required version: {}. Please update the agent.",
-                                 version_error.current_version, version_error.minimum_version));
+                Err(ApiError::VersionTooOld {
+                    minimum: Some(version_error.minimum_version),
+                    current: Some(version_error.current_version),
+                })
             } else {
                 error!("Agent version check failed with HTTP 426 but could not parse response");
-                return Err(anyhow!("Agent version too old. Please update the agent."));
+                Err(ApiError::VersionTooOld { minimum: None, current: None })
             }
         } else {
             // Try to parse as error response first
-            if let Ok(error_response) = serde_json::from_str::<AgentReportResponse>(&response_text) {
-                if let Some(error_msg) = &error_response.error {
-                    error!("API error ({}): {}", status, error_msg);
-                    return Err(anyhow!("API request failed: {}", error_msg));
-                }
+            if let Ok(error_response) = serde_json::from_str::<AgentReportResponse>(&response_text)
+                && let Some(error_msg) = &error_response.error
+            {
+                error!("API error ({}): {}", status, error_msg);
+                return Err(self.http_error(status, retry_after, error_msg.clone()));
             }
             
             error!("HTTP error ({}): {}", status, response_text);
-            Err(anyhow!("HTTP error ({}): {}", status, response_text))
+            Err(self.http_error(status, retry_after, response_text))
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_key_assignments(&self) -> Result<KeyAssignmentsResponse, ApiError> {
+        match self.get_key_assignments_if_changed(&Validators::default()).await? {
+            AssignmentsFetch::Changed(response, _) => Ok(*response),
+            AssignmentsFetch::NotModified => Err(ApiError::Protocol("Key assignments request answered 304 Not Modified to an unconditional request".to_string())),
         }
     }
 
+    /// Fetch key assignments unless they are unchanged since the response `validators` came from
     #[instrument(skip(self))]
-    pub async fn get_key_assignments(&self) -> Result<KeyAssignmentsResponse> {
-        let url = format!("{}/host/keys", self.base_url);
+    pub async fn get_key_assignments_if_changed(&self, validators: &Validators) -> Result<AssignmentsFetch, ApiError> {
+        let url = self.url_for(Endpoint::Keys);
         
         info!("Fetching key assignments from: {}", url);
         
-        let response = self.client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()
+        let mut request = self.request(reqwest::Method::GET, &url)
+            .header("Authorization", self.auth_header());
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = self.send(request)
             .await
-            .map_err(|e| anyhow!("Key assignments request failed: {}", e))?;
+            .map_err(|e| self.request_failed("Key assignments request", e))?;
 
         let status = response.status();
-        let response_text = response.text().await
-            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+        if status == StatusCode::NOT_MODIFIED {
+            info!("Key assignments unchanged since the last fetch");
+            return Ok(AssignmentsFetch::NotModified);
+        }
+        let retry_after = retry_after(&response);
+        let new_validators = Validators::of(&response);
+        let signature = response.headers()
+            .get(ASSIGNMENTS_SIGNATURE_HEADER)
+            .map(|value| value.to_str().unwrap_or_default().to_string());
+        let body = response.bytes().await
+            .map_err(|e| self.request_failed("Reading the response", e))?;
+        let response_text = self.redact(String::from_utf8_lossy(&body).into_owned());
 
         if status.is_success() {
-            let parsed_response: KeyAssignmentsResponse = serde_json::from_str(&response_text)
-                .map_err(|e| anyhow!("Failed to parse key assignments response: {}", e))?;
+            // Checked against the bytes as received, before anything is parsed
+            if let Some(pubkey) = &self.assignments_pubkey {
+                let signature = signature.ok_or_else(|| {
+                    ApiError::BadSignature(format!("the response carries no {} header", ASSIGNMENTS_SIGNATURE_HEADER))
+                })?;
+                pubkey.verify(&body, &signature).map_err(ApiError::BadSignature)?;
+                debug!("Key assignments signature verified");
+            }
+            let parsed_response: KeyAssignmentsResponse = parse_response(&response_text, self.strict_api)
+                .map_err(|error| ApiError::Decode { what: "key assignments response", error })?;
             
             let assignment_count = parsed_response.assignments.as_ref().map(|a| a.len()).unwrap_or(0);
             info!("Retrieved {} key assignments", assignment_count);
             
-            Ok(parsed_response)
+            Ok(AssignmentsFetch::Changed(Box::new(parsed_response), new_validators))
         } else {
             // Try to parse as error response first
-            if let Ok(error_response) = serde_json::from_str::<KeyAssignmentsResponse>(&response_text) {
-                if let Some(error_msg) = &error_response.error {
-                    error!("API error ({}): {}", status, error_msg);
-                    return Err(anyhow!("API request failed: {}", error_msg));
-                }
+            if let Ok(error_response) = serde_json::from_str::<KeyAssignmentsResponse>(&response_text)
+                && let Some(error_msg) = &error_response.error
+            {
+                error!("API error ({}): {}", status, error_msg);
+                return Err(self.http_error(status, retry_after, error_msg.clone()));
             }
             
             error!("HTTP error ({}): {}", status, response_text);
-            Err(anyhow!("HTTP error ({}): {}", status, response_text))
+            Err(self.http_error(status, retry_after, response_text))
+        }
+    }
+
+    /// Tell the server how each assignment of the last sync was handled
+    #[instrument(skip(self, acknowledgements))]
+    pub async fn acknowledge_assignments(&self, hostname: &str, acknowledgements: &[AssignmentAck]) -> Result<(), ApiError> {
+        let url = self.url_for(Endpoint::Acks);
+
+        info!("Acknowledging {} key assignments at: {}", acknowledgements.len(), url);
+
+        let request = self.request(reqwest::Method::POST, &url)
+            .header("Authorization", self.auth_header())
+            .json(&AssignmentAcks { hostname, run_id: &self.run_id, acknowledgements });
+        let response = self.send(request)
+            .await
+            .map_err(|e| self.request_failed("Assignment acknowledgement request", e))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = retry_after(&response);
+        let response_text = self.redact(response.text().await.unwrap_or_default());
+        error!("HTTP error ({}): {}", status, response_text);
+        Err(self.http_error(status, retry_after, response_text))
+    }
+
+    #[instrument(skip(self, heartbeat))]
+    pub async fn send_heartbeat(&self, heartbeat: &Heartbeat) -> Result<(), ApiError> {
+        let url = self.url_for(Endpoint::Heartbeat);
+
+        info!("Sending heartbeat to: {}", url);
+
+        let request = self.request(reqwest::Method::POST, &url)
+            .header("Authorization", self.auth_header())
+            .json(heartbeat);
+        let response = self.send(request)
+            .await
+            .map_err(|e| self.request_failed("Heartbeat request", e))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = retry_after(&response);
+        let response_text = self.redact(response.text().await.unwrap_or_default());
+        error!("HTTP error ({}): {}", status, response_text);
+        Err(self.http_error(status, retry_after, response_text))
+    }
+
+    /// Open the key change stream
+    pub async fn open_key_stream(&self) -> Result<KeyStream, ApiError> {
+        let url = self.url_for(Endpoint::Stream);
+        info!("Opening key change stream: {}", url);
+        let request = self
+            .request(reqwest::Method::GET, &url)
+            .header("Authorization", self.auth_header())
+            .header("Accept", "text/event-stream")
+            .timeout(STREAM_MAX_AGE);
+        let response = self
+            .send(request)
+            .await
+            .map_err(|e| self.request_failed("Key change stream request", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = retry_after(&response);
+            let response_text = self.redact(response.text().await.unwrap_or_default());
+            return Err(self.http_error(status, retry_after, response_text));
+        }
+        let content_type = response.headers().get(reqwest::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        if !content_type.starts_with("text/event-stream") {
+            return Err(ApiError::Protocol(format!("Key change stream answered with {:?} instead of text/event-stream", content_type)));
+        }
+        Ok(KeyStream { response, parser: sse::Parser::default(), pending: VecDeque::new() })
+    }
+
+    /// Wait up to `max_wait` for the server to announce a key assignment change; false when the
+    /// time ran out first. A stream that cannot be opened, fails or closes is reopened with
+    /// exponential backoff, so a server without the stream just makes this a sleep.
+    #[instrument(skip(self, watch))]
+    pub async fn wait_for_key_change(&self, watch: &mut KeyWatch, max_wait: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            let failure = match &mut watch.stream {
+                Some(stream) => match tokio::time::timeout(remaining, stream.next_event()).await {
+                    Err(_) => return false,
+                    Ok(Ok(Some(event))) => {
+                        watch.backoff = STREAM_BACKOFF_INITIAL;
+                        if event.is_change() {
+                            info!("Server announced a key change ({})", event.event);
+                            return true;
+                        }
+                        continue;
+                    }
+                    Ok(Ok(None)) => "the server closed it".to_string(),
+                    Ok(Err(e)) => e.to_string(),
+                },
+                None => match tokio::time::timeout(remaining, self.open_key_stream()).await {
+                    Err(_) => return false,
+                    Ok(Ok(stream)) => {
+                        watch.stream = Some(stream);
+                        continue;
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                },
+            };
+            watch.stream = None;
+            warn!("Key change stream unavailable ({}), reopening in {:?}", failure, watch.backoff);
+            tokio::time::sleep(watch.backoff.min(remaining)).await;
+            watch.backoff = (watch.backoff * 2).min(STREAM_BACKOFF_MAX);
+        }
+    }
+
+    /// Re-fetch key assignments with exponential backoff until some arrive, giving up after `window`
+    #[instrument(skip(self))]
+    pub async fn wait_for_assignments(&self, window: Duration, initial_delay: Duration) -> Option<KeyAssignmentsResponse> {
+        let poll = async {
+            let mut delay = initial_delay;
+            loop {
+                tokio::time::sleep(delay).await;
+                match self.get_key_assignments().await {
+                    Ok(response) if response.assignments.as_ref().is_some_and(|a| !a.is_empty()) => return response,
+                    Ok(_) => info!("No key assignments yet, retrying in {:?}", delay * 2),
+                    Err(e) => warn!("Polling key assignments failed: {}", e),
+                }
+                delay *= 2;
+            }
+        };
+
+        match tokio::time::timeout(window, poll).await {
+            Ok(response) => Some(response),
+            Err(_) => {
+                warn!("No key assignments arrived within {:?}", window);
+                None
+            }
         }
     }
 
     #[instrument(skip(self, report))]
-    pub async fn report_with_retry(&self, report: &AgentReport, max_retries: u32) -> Result<AgentReportResponse> {
-        let mut last_error = None;
-        
-        for attempt in 1..=max_retries {
-            match self.report_agent_data(report).await {
+    pub async fn report_with_retry(&self, report: &AgentReport, max_retries: u32) -> Result<AgentReportResponse, ApiError> {
+        self.with_retry("Report", max_retries, || self.report_agent_data(report)).await
+    }
+
+    #[instrument(skip(self, heartbeat))]
+    pub async fn heartbeat_with_retry(&self, heartbeat: &Heartbeat, max_retries: u32) -> Result<(), ApiError> {
+        self.with_retry("Heartbeat", max_retries, || self.send_heartbeat(heartbeat)).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_key_assignments_with_retry(&self, max_retries: u32, validators: &Validators) -> Result<AssignmentsFetch, ApiError> {
+        self.with_retry("Key assignments", max_retries, || self.get_key_assignments_if_changed(validators)).await
+    }
+
+    /// Run `request` up to `max_retries` times (at least once) with exponential backoff, as long
+    /// as it fails in a way [`ApiError::is_retriable`] says another attempt may fix. A server's
+    /// Retry-After, up to `max_retry_after`, lengthens the wait. A refused token is fetched anew
+    /// from `--token-command`, if there is one, and tried once more right away.
+    async fn with_retry<T, F, Fut>(&self, what: &str, max_retries: u32, request: F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut attempt = 1;
+        let mut token_refreshed = false;
+        loop {
+            let e = match request().await {
                 Ok(response) => return Ok(response),
-                Err(e) => {
-                    let error_msg = e.to_string();
-                    
-                    // Don't retry on version errors (HTTP 426) - these won't resolve with retries
-                    if error_msg.contains("Agent version") && error_msg.contains("too old") {
-                        error!("Version error detected - not retrying: {}", error_msg);
-                        return Err(e);
-                    }
-                    
-                    warn!("Report attempt {} failed: {}", attempt, e);
-                    last_error = Some(e);
-                    
-                    if attempt < max_retries {
-                        let delay = std::time::Duration::from_secs(2u64.pow(attempt - 1));
-                        info!("Retrying in {:?}...", delay);
-                        tokio::time::sleep(delay).await;
+                Err(e) => e,
+            };
+            if matches!(e, ApiError::Unauthorized { .. }) && !token_refreshed && let Some(command) = &self.token_command {
+                token_refreshed = true;
+                warn!("{} refused the token; running --token-command again in case it rotated", what);
+                match command.run().await.map_err(anyhow::Error::from).and_then(|token| normalize_token(token.expose_secret())) {
+                    Ok(token) => {
+                        *self.token.write().unwrap_or_else(|e| e.into_inner()) = token;
+                        continue;
                     }
+                    Err(refresh_error) => warn!("Could not refresh the token: {:#}", refresh_error),
                 }
             }
+            if !e.is_retriable() {
+                error!("{} failed, not retrying: {}", what, e);
+                return Err(e);
+            }
+            warn!("{} attempt {} failed: {}", what, attempt, e);
+            if attempt >= max_retries {
+                return Err(e);
+            }
+
+            let mut delay = retry_delay(attempt, random_fraction());
+            if let Some(retry_after) = e.retry_after() {
+                info!("Server asked to retry after {:?} (waiting at most {:?})", retry_after, self.max_retry_after);
+                delay = delay.max(retry_after.min(self.max_retry_after));
+            }
+            info!("Retrying in {:?}...", delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
         }
-        
-        Err(last_error.unwrap_or_else(|| anyhow!("All retry attempts failed")))
     }
-}
\ No newline at end of file
+}
+
+/// Normalize an API token: trim whitespace, strip an accidental "Bearer " prefix
+/// and make sure it can be sent in an HTTP header.
+fn normalize_token(token: &str) -> Result<SecretString> {
+    let mut token = token.trim();
+
+    let (scheme, rest) = token.split_once(char::is_whitespace).unwrap_or((token, ""));
+    if scheme.eq_ignore_ascii_case("bearer") {
+        info!("Stripping \"Bearer \" prefix from API token");
+        token = rest.trim_start();
+    }
+
+    if token.is_empty() {
+        return Err(anyhow!("API token is empty"));
+    }
+
+    if let Some(c) = token.chars().find(|c| !c.is_ascii_graphic()) {
+        return Err(anyhow!(
+            "API token contains invalid character {:?}; tokens must be printable ASCII without spaces",
+            c
+        ));
+    }
+
+    Ok(SecretString::from(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AssignmentsSigner, CapturedLogs, MockResponse, mock_server};
+
+    #[test]
+    fn test_normalize_plain_token() {
+        assert_eq!(normalize_token("pk_live_abc123").unwrap().expose_secret(), "pk_live_abc123");
+        assert_eq!(normalize_token("  pk_live_abc123\n").unwrap().expose_secret(), "pk_live_abc123");
+    }
+
+    #[test]
+    fn test_normalize_bearer_prefixed_token() {
+        assert_eq!(normalize_token("Bearer pk_live_abc123").unwrap().expose_secret(), "pk_live_abc123");
+        assert_eq!(normalize_token("bearer pk_live_abc123").unwrap().expose_secret(), "pk_live_abc123");
+        assert_eq!(normalize_token("BEARER   pk_live_abc123").unwrap().expose_secret(), "pk_live_abc123");
+        assert!(normalize_token("Bearer ").is_err());
+    }
+
+    #[test]
+    fn test_normalize_non_ascii_token() {
+        assert!(normalize_token("pk_live_äbc").is_err());
+        assert!(normalize_token("pk_live abc").is_err());
+        assert!(normalize_token("").is_err());
+    }
+
+    #[test]
+    fn test_auth_header_uses_normalized_token() {
+        let client = ApiClient::new("http://localhost:3000".to_string(), "Bearer pk_test".into()).unwrap();
+        let header = client.auth_header();
+        assert_eq!(header, "Bearer pk_test");
+        assert!(header.is_sensitive());
+        assert!(!format!("{:?}", header).contains("pk_test"));
+    }
+
+    const ASSIGNMENT: &str = r#"{"username":"alice","fingerprint":"SHA256:x","publicKey":"ssh-ed25519 AAAA","keyType":"ssh-ed25519","assignmentId":"a1"}"#;
+
+    #[test]
+    fn test_parse_response_unknown_fields() {
+        let extra = format!(
+            r#"{{"success":true,"rollout":"canary","assignments":[{},{}]}}"#,
+            ASSIGNMENT,
+            ASSIGNMENT.replace(r#""assignmentId":"a1""#, r#""assignmentId":"a2","priority":3"#)
+        );
+
+        // Lenient mode ignores (and logs) unknown fields
+        let response: KeyAssignmentsResponse = parse_response(&extra, false).unwrap();
+        assert_eq!(response.assignments.as_ref().unwrap().len(), 2);
+        assert_eq!(response.unknown_fields(""), vec!["rollout", "assignments[1].priority"]);
+
+        // Strict mode names every offending field
+        let err = parse_response::<KeyAssignmentsResponse>(&extra, true).unwrap_err().to_string();
+        assert!(err.contains("rollout"), "{}", err);
+        assert!(err.contains("assignments[1].priority"), "{}", err);
+
+        let report = r#"{"success":true,"hostId":"h1","shard":7}"#;
+        assert!(parse_response::<AgentReportResponse>(report, false).is_ok());
+        assert!(parse_response::<AgentReportResponse>(report, true).unwrap_err().to_string().contains("shard"));
+
+        let exact = format!(r#"{{"success":true,"assignments":[{}]}}"#, ASSIGNMENT);
+        assert!(parse_response::<KeyAssignmentsResponse>(&exact, true).is_ok());
+
+        // Key options are a known field
+        let with_options = format!(
+            r#"{{"success":true,"assignments":[{}]}}"#,
+            ASSIGNMENT.replace(r#""assignmentId":"a1""#, r#""assignmentId":"a1","options":"no-pty""#)
+        );
+        let response = parse_response::<KeyAssignmentsResponse>(&with_options, true).unwrap();
+        assert_eq!(response.assignments.unwrap()[0].options.as_deref(), Some("no-pty"));
+    }
+
+    #[test]
+    fn test_parse_response_missing_fields() {
+        let missing = format!(
+            r#"{{"success":true,"assignments":[{},{}]}}"#,
+            ASSIGNMENT,
+            ASSIGNMENT.replace(r#","assignmentId":"a1""#, "")
+        );
+        for strict in [false, true] {
+            let err = parse_response::<KeyAssignmentsResponse>(&missing, strict).unwrap_err().to_string();
+            assert!(err.contains("assignments[1]"), "{}", err);
+            assert!(err.contains("assignmentId"), "{}", err);
+        }
+
+        // Optional fields may be absent in both modes
+        for strict in [false, true] {
+            let response: AgentReportResponse = parse_response(r#"{"success":true}"#, strict).unwrap();
+            assert!(response.host_id.is_none());
+        }
+
+        let wrong_type = r#"{"success":true,"assignments":[],"managedUsers":"alice"}"#;
+        let err = parse_response::<KeyAssignmentsResponse>(wrong_type, false).unwrap_err().to_string();
+        assert!(err.contains("managedUsers"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_strict_api_client_rejects_unknown_fields() {
+        let (endpoint, _) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[],"v2":true}"#)]).await;
+        let lenient = ApiClient::new(endpoint.clone(), "pk_test".into()).unwrap();
+        assert!(lenient.get_key_assignments().await.is_ok());
+
+        let strict = ApiClient::new(endpoint, "pk_test".into()).unwrap().with_strict_api(true);
+        let err = strict.get_key_assignments().await.unwrap_err().to_string();
+        assert!(err.contains("v2"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_mentions_expired_jwt() {
+        use base64::Engine;
+        let encode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(part);
+        let expired = format!("{}.{}.", encode(r#"{"alg":"none"}"#), encode(r#"{"exp":1000}"#));
+        let unauthorized = || MockResponse::new(401, r#"{"success":false,"error":"invalid token"}"#);
+
+        let (endpoint, _) = mock_server(vec![unauthorized(), unauthorized()]).await;
+        let client = ApiClient::new(endpoint, expired.clone().into()).unwrap().with_token_type(TokenType::Jwt);
+        assert_eq!(client.token_expiry(), Some(1000));
+        let err = client.report_agent_data(&minimal_report()).await.unwrap_err().to_string();
+        assert!(err.contains("JWT token expired"), "{}", err);
+        let err = client.get_key_assignments().await.unwrap_err().to_string();
+        assert!(err.contains("JWT token expired"), "{}", err);
+
+        // The same token sent as a plain bearer token gets no expiry hint
+        let (endpoint, _) = mock_server(vec![unauthorized()]).await;
+        let client = ApiClient::new(endpoint, expired.into()).unwrap();
+        let err = client.report_agent_data(&minimal_report()).await.unwrap_err().to_string();
+        assert!(!err.contains("expired"), "{}", err);
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        assert_eq!(retry_budget(1), Duration::ZERO);
+        // 1s and 2s, each with up to 50% jitter
+        assert_eq!(retry_budget(MAX_RETRIES), Duration::from_millis(4500));
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_server_times_out() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let transport = Transport { timeout: Duration::from_millis(200), ..Transport::default() };
+        let client = ApiClient::with_transport(endpoint, "pk_test".into(), tls, transport).unwrap();
+
+        let started = std::time::Instant::now();
+        let error = client.health_check().await.unwrap_err();
+        assert!(error.is_timeout(), "{}", error);
+        let error = client.get_key_assignments().await.unwrap_err();
+        assert!(error.is_timeout(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_delays_next_attempt() {
+        let rate_limited = MockResponse {
+            headers: vec![("Retry-After".to_string(), "2".to_string())],
+            ..MockResponse::new(429, "")
+        };
+        let (endpoint, requests) = mock_server(vec![
+            rate_limited.clone(),
+            rate_limited.clone(),
+            MockResponse::new(200, r#"{"success":true,"hostId":"h1"}"#),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        client.report_with_retry(&minimal_report(), 3).await.unwrap();
+
+        // The first backoff alone would be at most 1.5s
+        let gaps: Vec<Duration> = requests.lock().unwrap().windows(2).map(|pair| pair[1].received - pair[0].received).collect();
+        assert_eq!(gaps.len(), 2);
+        assert!(gaps.iter().all(|gap| *gap >= Duration::from_secs(2)), "{:?}", gaps);
+
+        // Capped by --max-retry-after
+        let (endpoint, requests) = mock_server(vec![MockResponse {
+            headers: vec![("Retry-After".to_string(), "120".to_string())],
+            ..MockResponse::new(503, "")
+        }])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap().with_max_retry_after(Duration::ZERO);
+        client.get_key_assignments_with_retry(2, &Validators::default()).await.unwrap_err();
+        let requests = requests.lock().unwrap();
+        assert!(requests[1].received - requests[0].received < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_retry_delay_jitter() {
+        assert_eq!(retry_delay(1, 0.0), Duration::from_secs(1));
+        assert_eq!(retry_delay(3, 0.5), Duration::from_secs(5));
+        assert_eq!(retry_delay(2, 7.0), Duration::from_secs(3));
+        for _ in 0..100 {
+            let fraction = random_fraction();
+            assert!((0.0..1.0).contains(&fraction), "{}", fraction);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors_never_contain_token() {
+        const TOKEN: &str = "pk_live_s3cr3t_token";
+        let echoed = format!("invalid credentials: Bearer {}", TOKEN);
+        let responses = vec![
+            MockResponse::new(500, &echoed),
+            MockResponse::new(401, &serde_json::json!({"success": false, "error": echoed}).to_string()),
+            MockResponse::new(
+                426,
+                r#"{"message":"too old","minimumVersion":"9.0.0","currentVersion":"0.1.0"}"#,
+            ),
+            MockResponse::new(426, &echoed),
+            MockResponse::new(200, &echoed),
+        ];
+
+        let mut errors: Vec<anyhow::Error> = Vec::new();
+        for response in responses {
+            let (endpoint, _) = mock_server(vec![response]).await;
+            let client = ApiClient::new(endpoint, TOKEN.into()).unwrap();
+            errors.push(client.report_agent_data(&minimal_report()).await.unwrap_err().into());
+            errors.push(client.get_key_assignments().await.unwrap_err().into());
+        }
+
+        // Connection failures
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let client = ApiClient::new(closed, TOKEN.into()).unwrap();
+        errors.push(client.health_check().await.unwrap_err().into());
+        errors.push(client.report_agent_data(&minimal_report()).await.unwrap_err().into());
+        errors.push(client.get_key_assignments().await.unwrap_err().into());
+
+        // Token validation
+        errors.push(ApiClient::new("http://localhost".to_string(), format!("{} x", TOKEN).into()).err().unwrap());
+        errors.push(ApiClient::new("http://localhost".to_string(), format!("{}ä", TOKEN).into()).err().unwrap());
+
+        assert_eq!(errors.len(), 15);
+        for error in &errors {
+            for formatted in [format!("{}", error), format!("{:#}", error), format!("{:?}", error)] {
+                assert!(!formatted.contains(TOKEN), "token leaked: {}", formatted);
+            }
+        }
+        assert!(!format!("{:?}", SecretString::from(TOKEN)).contains(TOKEN));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_detects_web_ui() {
+        let html = MockResponse {
+            headers: vec![("content-type".to_string(), "text/html; charset=utf-8".to_string())],
+            ..MockResponse::new(200, "<!DOCTYPE html><html><body>PubliKey</body></html>")
+        };
+        let (server, _) = mock_server(vec![html]).await;
+        let endpoint = format!("{}/dashboard", server);
+        let err = ApiClient::new(endpoint, "pk_test".into()).unwrap().health_check().await.unwrap_err().to_string();
+        assert!(err.starts_with(NOT_API_ENDPOINT), "{}", err);
+        assert!(err.contains(&format!("did you mean {} ?", server)), "{}", err);
+
+        // Sniffed from the body when the content type is missing
+        let (server, _) = mock_server(vec![MockResponse::new(200, "\n<html><head></head></html>")]).await;
+        assert!(ApiClient::new(server, "pk_test".into()).unwrap().health_check().await.is_err());
+
+        // A 200 that is not a JSON object is not healthy, a JSON object is
+        let (server, _) = mock_server(vec![MockResponse::new(200, "OK")]).await;
+        assert!(!ApiClient::new(server, "pk_test".into()).unwrap().health_check().await.unwrap().healthy);
+        let (server, _) = mock_server(vec![MockResponse::new(200, r#"{"status":"ok"}"#)]).await;
+        assert!(ApiClient::new(server, "pk_test".into()).unwrap().health_check().await.unwrap().healthy);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_authenticates() {
+        let (server, requests) = mock_server(vec![MockResponse::new(200, r#"{"status":"ok"}"#)]).await;
+        let health = ApiClient::new(server, "pk_test".into()).unwrap().health_check().await.unwrap();
+        assert!(health.healthy);
+        assert!(health.latency > Duration::ZERO);
+        assert_eq!(requests.lock().unwrap()[0].header("authorization"), Some("Bearer pk_test"));
+
+        for status in [401, 403] {
+            let (server, _) = mock_server(vec![MockResponse::new(status, r#"{"error":"Invalid API token"}"#)]).await;
+            let error = ApiClient::new(server, "pk_test".into()).unwrap().health_check().await.unwrap_err();
+            assert!(matches!(error, ApiError::Unauthorized { .. }), "{:?}", error);
+            assert!(error.to_string().contains("Invalid API token"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let (endpoint, requests) = mock_server(vec![
+            MockResponse::new(204, ""),
+            MockResponse::new(503, "maintenance"),
+            MockResponse::new(200, "{}"),
+            MockResponse::new(401, r#"{"error":"Invalid API token"}"#),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        let heartbeat = Heartbeat {
+            hostname: "web-1".to_string(),
+            agent_version: "0.4.0".to_string(),
+            machine_id: "0123456789abcdef".to_string(),
+        };
+        client.send_heartbeat(&heartbeat).await.unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "POST /api/agent/heartbeat HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer pk_test"));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body, serde_json::json!({"hostname": "web-1", "agentVersion": "0.4.0", "machineId": "0123456789abcdef"}));
+
+        // An overloaded server is retried, a refused token is not
+        client.heartbeat_with_retry(&heartbeat, 2).await.unwrap();
+        let error = client.heartbeat_with_retry(&heartbeat, 3).await.unwrap_err();
+        assert!(matches!(error, ApiError::Unauthorized { .. }), "{:?}", error);
+        assert_eq!(requests.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_acknowledge_assignments() {
+        let (endpoint, requests) = mock_server(vec![MockResponse::new(204, ""), MockResponse::new(404, "not found")]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap().with_run_id("run-1".to_string());
+        let acknowledgements = vec![AssignmentAck {
+            assignment_id: "a1".to_string(),
+            outcome: AckOutcome::Skipped,
+            status: AssignmentState::UserNotFound,
+            detail: Some("no local account mallory".to_string()),
+        }];
+        client.acknowledge_assignments("web-1", &acknowledgements).await.unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "POST /api/host/keys/ack HTTP/1.1");
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "hostname": "web-1",
+                "runId": "run-1",
+                "acknowledgements": [{
+                    "assignmentId": "a1",
+                    "outcome": "skipped",
+                    "status": "user-not-found",
+                    "detail": "no local account mallory",
+                }],
+            })
+        );
+
+        assert!(client.acknowledge_assignments("web-1", &acknowledgements).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_every_request_carries_the_run_id() {
+        let (endpoint, requests) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        assert_ne!(client.run_id(), ApiClient::new("http://localhost".to_string(), "pk_test".into()).unwrap().run_id());
+        client.health_check().await.unwrap();
+        client.report_agent_data(&minimal_report()).await.unwrap();
+        client.get_key_assignments().await.unwrap();
+        client.acknowledge_assignments("web-1", &[]).await.unwrap();
+        client.open_key_stream().await.err().unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 5);
+        assert!(requests.iter().all(|request| request.header(RUN_ID_HEADER) == Some(client.run_id())));
+    }
+
+    #[tokio::test]
+    async fn test_failover_sticks_to_the_endpoint_that_answered() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let down = format!("http://127.0.0.1:{}", port);
+        let (fallback, requests) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let client = ApiClient::new(down.clone(), "pk_test".into())
+            .unwrap()
+            .with_fallback_endpoints(std::slice::from_ref(&fallback))
+            .unwrap();
+
+        assert!(client.with_failover("Health check", || client.health_check()).await.unwrap().healthy);
+        assert_eq!(client.endpoint(), fallback);
+        // The report and the assignments go to the same server
+        client.report_agent_data(&minimal_report()).await.unwrap();
+        client.get_key_assignments().await.unwrap();
+        let paths: Vec<_> = requests.lock().unwrap().iter().map(|r| r.request_line.clone()).collect();
+        assert_eq!(paths, ["GET /api/health HTTP/1.1", "POST /api/agent/report HTTP/1.1", "GET /api/host/keys HTTP/1.1"]);
+
+        // An answering primary is used even when it is unhealthy
+        let (primary, _) = mock_server(vec![MockResponse::new(503, "maintenance")]).await;
+        let client = ApiClient::new(primary.clone(), "pk_test".into()).unwrap().with_fallback_endpoints(&[fallback]).unwrap();
+        assert!(!client.with_failover("Health check", || client.health_check()).await.unwrap().healthy);
+        assert_eq!(client.endpoint(), primary);
+
+        // Nobody answers: back on the primary with its error
+        let client = ApiClient::new(down.clone(), "pk_test".into()).unwrap().with_fallback_endpoints(&[down.replace("127.0.0.1", "localhost")]).unwrap();
+        let error = client.with_failover("Health check", || client.health_check()).await.unwrap_err();
+        assert!(matches!(error, ApiError::Network { .. }));
+        assert_eq!(client.endpoint(), down);
+        assert!(client.use_endpoint(&down.replace("127.0.0.1", "localhost")));
+        assert!(!client.use_endpoint("https://elsewhere.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_token_never_logged() {
+        const TOKEN: &str = "pk_live_n3verL0gMe";
+        let (logs, _guard) = CapturedLogs::start();
+        // A server that echoes what it was sent back in its errors
+        let (endpoint, _) = mock_server(vec![
+            MockResponse::new(200, r#"{"success":true}"#),
+            MockResponse::new(401, &format!(r#"{{"success":false,"error":"unknown token {}"}}"#, TOKEN)),
+            MockResponse::new(500, &format!("upstream rejected Authorization: Bearer {}", TOKEN)),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, TOKEN.into()).unwrap().with_token_type(TokenType::Jwt);
+        client.health_check().await.unwrap();
+        let errors = [
+            client.report_agent_data(&minimal_report()).await.unwrap_err(),
+            client.get_key_assignments().await.unwrap_err(),
+        ];
+        for error in &errors {
+            error!("{} / {:?}", error, error);
+        }
+        let cli = <crate::cli::Cli as clap::Parser>::try_parse_from(["pkagent", "--token", TOKEN]).unwrap();
+        info!("{:?}", cli);
+
+        let logs = logs.contents();
+        assert!(logs.contains("Checking API health"), "{}", logs);
+        assert!(logs.contains("[REDACTED]"), "{}", logs);
+        assert!(!logs.contains(TOKEN), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_refused_token_refetched_from_command_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_file = dir.path().join("token");
+        std::fs::write(&token_file, "pk_old\n").unwrap();
+        let command = TokenCommand::new(format!("cat {}", token_file.display()), Duration::from_secs(5));
+        let unauthorized = || MockResponse::new(401, r#"{"success":false,"error":"invalid token"}"#);
+
+        let (endpoint, requests) = mock_server(vec![unauthorized(), MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let client = ApiClient::new(endpoint, command.run().await.unwrap()).unwrap().with_token_command(Some(command.clone()));
+        // Rotated since the run started
+        std::fs::write(&token_file, "pk_new\n").unwrap();
+        client.get_key_assignments_with_retry(1, &Validators::default()).await.unwrap();
+        let headers: Vec<_> = requests.lock().unwrap().iter().map(|r| r.header("authorization").unwrap().to_string()).collect();
+        assert_eq!(headers, ["Bearer pk_old", "Bearer pk_new"]);
+
+        // Refused again: the command is not run a second time
+        let (endpoint, requests) = mock_server(vec![unauthorized()]).await;
+        let client = ApiClient::new(endpoint, "pk_old".into()).unwrap().with_token_command(Some(command));
+        let error = client.get_key_assignments_with_retry(3, &Validators::default()).await.unwrap_err();
+        assert!(matches!(error, ApiError::Unauthorized { .. }));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_assignments_verified_against_pubkey() {
+        let body = r#"{"success":true,"assignments":[]}"#;
+        let signer = AssignmentsSigner::generate();
+        let signed = |signature: String| MockResponse {
+            status: 200,
+            headers: vec![(ASSIGNMENTS_SIGNATURE_HEADER.to_string(), signature)],
+            body: body.to_string(),
+        };
+        let fetch = |response: MockResponse, pubkey: Option<AssignmentsPubkey>| async move {
+            let (endpoint, _) = mock_server(vec![response]).await;
+            ApiClient::new(endpoint, "pk_test".into()).unwrap().with_assignments_pubkey(pubkey).get_key_assignments().await
+        };
+
+        assert!(fetch(signed(signer.sign(body)), Some(signer.public_key())).await.is_ok());
+        // Unsigned responses are fine only while no key is configured
+        assert!(fetch(MockResponse::new(200, body), None).await.is_ok());
+
+        let missing = fetch(MockResponse::new(200, body), Some(signer.public_key())).await.unwrap_err();
+        assert!(matches!(&missing, ApiError::BadSignature(reason) if reason.contains(ASSIGNMENTS_SIGNATURE_HEADER)), "{}", missing);
+        let other_key = fetch(signed(AssignmentsSigner::generate().sign(body)), Some(signer.public_key())).await.unwrap_err();
+        assert!(matches!(other_key, ApiError::BadSignature(_)));
+        assert!(!other_key.is_retriable());
+        let tampered = fetch(signed(signer.sign(r#"{"success":true}"#)), Some(signer.public_key())).await.unwrap_err();
+        assert!(matches!(tampered, ApiError::BadSignature(_)));
+
+        // Assignments cached before the key was configured were never verified
+        let client = ApiClient::new("http://localhost".to_string(), "pk_test".into()).unwrap();
+        let unverified = client.assignments_source();
+        assert_ne!(client.with_assignments_pubkey(Some(signer.public_key())).assignments_source(), unverified);
+    }
+
+    #[test]
+    fn test_signing_key_known_vectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+        std::fs::write(&path, "s3cret\n").unwrap();
+        let key = SigningKey::load(&path).unwrap();
+        assert_eq!(
+            key.sign("POST", "/api/agent/report", 1700000000, br#"{"hostname":"web-1"}"#),
+            "sha256=f91825e20b866b03941c494d373c5e50b5146cd0575d1575e4bce4ef013df06b"
+        );
+        assert_eq!(
+            key.sign("GET", "/api/host/keys", 1700000000, b""),
+            "sha256=ba934fc218776fe6f7f63e5d1d2c3bdbbe2b8f22433d109bbed829c10b7b9336"
+        );
+        assert!(!format!("{:?}", key).contains("s3cret"));
+
+        std::fs::write(&path, " \n").unwrap();
+        assert!(SigningKey::load(&path).unwrap_err().to_string().contains("is empty"));
+    }
+
+    #[tokio::test]
+    async fn test_signed_requests_carry_verifiable_signature() {
+        let (endpoint, requests) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+        std::fs::write(&path, "s3cret").unwrap();
+        let key = SigningKey::load(&path).unwrap();
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap().with_signing_key(Some(key.clone()));
+        client.report_agent_data(&minimal_report()).await.unwrap();
+        client.get_key_assignments().await.unwrap();
+
+        for request in requests.lock().unwrap().iter() {
+            let mut parts = request.request_line.split(' ');
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+            let timestamp: u64 = request.header(TIMESTAMP_HEADER).unwrap().parse().unwrap();
+            assert_eq!(request.header(SIGNATURE_HEADER), Some(key.sign(method, path, timestamp, request.body.as_bytes()).as_str()));
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // Unsigned without a key
+        let (endpoint, requests) = mock_server(vec![MockResponse::new(200, r#"{"success":true}"#)]).await;
+        ApiClient::new(endpoint, "pk_test".into()).unwrap().health_check().await.unwrap();
+        assert!(requests.lock().unwrap()[0].header(SIGNATURE_HEADER).is_none());
+    }
+
+    #[test]
+    fn test_double_api_endpoint_rejected() {
+        for endpoint in ["https://pk.example.com:3000/api", "https://pk.example.com:3000/api/"] {
+            let err = ApiClient::new(endpoint.to_string(), "pk_test".into()).err().unwrap().to_string();
+            assert!(err.starts_with(NOT_API_ENDPOINT), "{}", err);
+            assert!(err.contains("did you mean https://pk.example.com:3000 ?"), "{}", err);
+        }
+        assert!(ApiClient::new("https://pk.example.com:3000/apis".to_string(), "pk_test".into()).is_ok());
+    }
+
+    #[test]
+    fn test_url_for() {
+        let client = |endpoint: &str, paths: EndpointPaths| {
+            ApiClient::new(endpoint.to_string(), "pk_test".into()).unwrap().with_endpoint_paths(paths)
+        };
+
+        let default = client("https://pk.example.com:3000", EndpointPaths::default());
+        assert_eq!(default.url_for(Endpoint::Health), "https://pk.example.com:3000/api/health");
+        assert_eq!(default.url_for(Endpoint::Report), "https://pk.example.com:3000/api/agent/report");
+        assert_eq!(default.url_for(Endpoint::Keys), "https://pk.example.com:3000/api/host/keys");
+
+        // Trailing slashes and a base path on the endpoint
+        let prefixed = client("https://gw.example.com/tenant-a//", EndpointPaths::default());
+        assert_eq!(prefixed.url_for(Endpoint::Keys), "https://gw.example.com/tenant-a/api/host/keys");
+
+        let rewritten = EndpointPaths {
+            report: "/v2/publikey/report".to_string(),
+            keys: "//v2/publikey/keys".to_string(),
+            ..EndpointPaths::default()
+        };
+        let gateway = client("https://gw.example.com/", rewritten.clone());
+        assert_eq!(gateway.url_for(Endpoint::Health), "https://gw.example.com/api/health");
+        assert_eq!(gateway.url_for(Endpoint::Report), "https://gw.example.com/v2/publikey/report");
+        assert_eq!(gateway.url_for(Endpoint::Keys), "https://gw.example.com/v2/publikey/keys");
+
+        let both = client("https://gw.example.com/base", rewritten);
+        assert_eq!(both.url_for(Endpoint::Report), "https://gw.example.com/base/v2/publikey/report");
+    }
+
+    #[test]
+    fn test_endpoint_paths_validation() {
+        assert!(EndpointPaths::default().validate().is_ok());
+        for (health, problem) in [("health", "start with /"), ("/health?x=1", "plain path"), ("/he alth", "plain path"), ("", "start with /")] {
+            let paths = EndpointPaths { health: health.to_string(), ..EndpointPaths::default() };
+            let err = paths.validate().unwrap_err().to_string();
+            assert!(err.starts_with("health_path") && err.contains(problem), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_is_new_host() {
+        let response = |host_id: Option<&str>, host_created: Option<bool>| AgentReportResponse {
+            host_id: host_id.map(str::to_string),
+            host_created,
+            ..serde_json::from_str(r#"{"success":true}"#).unwrap()
+        };
+
+        assert!(response(Some("h1"), None).is_new_host(None));
+        assert!(response(Some("h2"), None).is_new_host(Some("h1")));
+        assert!(!response(Some("h1"), None).is_new_host(Some("h1")));
+        assert!(!response(None, None).is_new_host(None));
+        // The server's own flag wins over the inferred one
+        assert!(response(Some("h1"), Some(true)).is_new_host(Some("h1")));
+        assert!(!response(Some("h1"), Some(false)).is_new_host(None));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_assignments_polls_until_populated() {
+        let (endpoint, requests) = mock_server(vec![
+            MockResponse::new(200, r#"{"success":true,"assignments":[]}"#),
+            MockResponse::new(200, &format!(r#"{{"success":true,"assignments":[{}]}}"#, ASSIGNMENT)),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+
+        let response = client
+            .wait_for_assignments(Duration::from_secs(5), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(response.assignments.unwrap().len(), 1);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_assignments_bounded_by_window() {
+        let (endpoint, _) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+
+        let start = std::time::Instant::now();
+        let response = client
+            .wait_for_assignments(Duration::from_millis(300), Duration::from_millis(10))
+            .await;
+        assert!(response.is_none());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    fn event_stream(body: &str) -> MockResponse {
+        MockResponse {
+            headers: vec![("content-type".to_string(), "text/event-stream".to_string())],
+            ..MockResponse::new(200, body)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_change_stream_reconnects_until_change() {
+        // A keepalive, then the server closes; the reopened stream announces a change
+        let (endpoint, requests) =
+            mock_server(vec![event_stream("event: ping\ndata:\n\n"), event_stream(": hi\nevent: keys-changed\ndata: {}\n\n")]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        let mut watch = KeyWatch::default();
+
+        assert!(client.wait_for_key_change(&mut watch, Duration::from_secs(10)).await);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].request_line.starts_with("GET /api/host/keys/stream "));
+        assert_eq!(requests[0].header("accept"), Some("text/event-stream"));
+    }
+
+    #[tokio::test]
+    async fn test_key_change_stream_unavailable_waits_out_the_interval() {
+        let (endpoint, requests) = mock_server(vec![MockResponse::new(404, "")]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+
+        let start = std::time::Instant::now();
+        assert!(!client.wait_for_key_change(&mut KeyWatch::default(), Duration::from_millis(1500)).await);
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+        // Backoff: opened at 0s and after 1s, not in a tight loop
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (endpoint, _) = mock_server(vec![MockResponse::new(200, "{}")]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        let error = client.open_key_stream().await.err().unwrap();
+        assert!(error.to_string().contains("instead of text/event-stream"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_constant_across_retries() {
+        let (endpoint, requests) = mock_server(vec![
+            MockResponse::new(500, ""),
+            MockResponse::new(200, r#"{"success":true,"hostId":"h1"}"#),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        let mut report = minimal_report();
+        report.idempotency_key = new_idempotency_key();
+
+        client.report_with_retry(&report, 3).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let keys: Vec<_> = requests.iter().map(|r| r.header("Idempotency-Key").unwrap()).collect();
+        assert_eq!(keys[0], report.idempotency_key);
+        assert_eq!(keys[0], keys[1]);
+        assert!(requests.iter().all(|r| r.body.contains(&format!("\"idempotencyKey\":\"{}\"", report.idempotency_key))));
+    }
+
+    #[tokio::test]
+    async fn test_retry_only_retriable_failures() {
+        // A refused token or a malformed report fails on the first attempt
+        for status in [400, 401, 403, 404, 426] {
+            let (endpoint, requests) = mock_server(vec![MockResponse::new(status, "")]).await;
+            let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+            let error = client.report_with_retry(&minimal_report(), 3).await.unwrap_err();
+            assert!(!error.is_retriable(), "{}: {}", status, error);
+            assert_eq!(requests.lock().unwrap().len(), 1, "{}", status);
+        }
+
+        // Rate limiting and server errors are retried, for key assignments too
+        let (endpoint, requests) = mock_server(vec![
+            MockResponse::new(429, ""),
+            MockResponse::new(503, ""),
+            MockResponse::new(200, r#"{"success":true,"assignments":[]}"#),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        client.get_key_assignments_with_retry(3, &Validators::default()).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        let (endpoint, _) = mock_server(vec![MockResponse::new(502, "")]).await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+        let error = client.get_key_assignments().await.unwrap_err();
+        assert!(matches!(error, ApiError::Http { status: StatusCode::BAD_GATEWAY, .. }), "{}", error);
+        assert!(error.is_retriable());
+    }
+
+    #[tokio::test]
+    async fn test_responses_map_to_api_error_variants() {
+        let (endpoint, _) = mock_server(vec![
+            MockResponse::new(426, r#"{"error":"outdated","message":"too old","minimumVersion":"9.0.0","currentVersion":"0.1.0"}"#),
+            MockResponse::new(401, r#"{"success":false,"error":"invalid token"}"#),
+            MockResponse::new(404, "not here"),
+            MockResponse::new(200, r#"{"success":"yes"}"#),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint, "pk_test".into()).unwrap();
+
+        let error = client.report_agent_data(&minimal_report()).await.unwrap_err();
+        assert!(
+            matches!(&error, ApiError::VersionTooOld { minimum: Some(minimum), current: Some(current) } if minimum == "9.0.0" && current == "0.1.0"),
+            "{:?}",
+            error
+        );
+        let error = client.get_key_assignments().await.unwrap_err();
+        assert!(matches!(&error, ApiError::Unauthorized { status: StatusCode::UNAUTHORIZED, body, .. } if body == "invalid token"), "{:?}", error);
+        let error = client.get_key_assignments().await.unwrap_err();
+        assert!(matches!(&error, ApiError::Http { status: StatusCode::NOT_FOUND, body, .. } if body == "not here"), "{:?}", error);
+        let error = client.get_key_assignments().await.unwrap_err();
+        assert!(matches!(error, ApiError::Decode { what: "key assignments response", .. }), "{:?}", error);
+
+        // The anyhow boundary keeps the variant reachable
+        let error = anyhow::Error::from(error).context("sync");
+        assert!(matches!(api_error(&error), Some(ApiError::Decode { .. })));
+    }
+
+    #[test]
+    fn test_new_idempotency_keys_are_unique() {
+        assert_ne!(new_idempotency_key(), new_idempotency_key());
+    }
+
+    fn minimal_report() -> AgentReport {
+        AgentReport {
+            schema_version: crate::report::REPORT_SCHEMA_VERSION,
+            capabilities: Vec::new(),
+            hostname: "host".to_string(),
+            system_info: SystemInfo {
+                os: "Linux".to_string(),
+                arch: "x86_64".to_string(),
+                platform: "linux".to_string(),
+                kernel: "6.1.0".to_string(),
+                distribution: "Debian GNU/Linux".to_string(),
+                version: "12".to_string(),
+                immutable_os: false,
+            },
+            agent_version: "0.4.0".to_string(),
+            users: Vec::new(),
+            idempotency_key: "key-1".to_string(),
+            users_partial: false,
+            sections: ReportSections::default(),
+        }
+    }
+
+    #[test]
+    fn test_minimal_report_snapshot() {
+        let value = serde_json::to_value(minimal_report()).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "schemaVersion": 1,
+                "capabilities": [],
+                "hostname": "host",
+                "systemInfo": {
+                    "os": "Linux",
+                    "arch": "x86_64",
+                    "platform": "linux",
+                    "kernel": "6.1.0",
+                    "distribution": "Debian GNU/Linux",
+                    "version": "12"
+                },
+                "agentVersion": "0.4.0",
+                "users": [],
+                "idempotencyKey": "key-1"
+            })
+        );
+    }
+
+    #[test]
+    fn test_full_report_snapshot() {
+        use crate::report::*;
+        use crate::users::{HomeEncryption, UserInfo};
+
+        let mut report = minimal_report();
+        report.capabilities = ["network", "ssh", "storage", "timings", "keyInventory"].map(String::from).to_vec();
+        report.users = vec![UserInfo {
+            username: "alice".to_string(),
+            uid: 1000,
+            shell: Some("/bin/bash".to_string()),
+            home_dir: Some("/home/alice".to_string()),
+            disabled: Some(false),
+            home_encryption: Some(HomeEncryption::Plain),
+            home_mounted: Some(true),
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        }];
+        report.sections = ReportSections {
+            network: Some(NetworkSection {
+                interfaces: vec![NetworkInterface {
+                    name: "eth0".to_string(),
+                    mac_address: "00:11:22:33:44:55".to_string(),
+                }],
+            }),
+            ssh: Some(SshSection {
+                authorized_keys_patterns: vec![".ssh/authorized_keys".to_string()],
+                permit_root_login: "no".to_string(),
+                root_login_disabled: true,
+                login_blockers: Vec::new(),
+                umask: None,
+            }),
+            storage: Some(StorageSection {
+                mounts: vec![StorageMount {
+                    mount_point: "/".to_string(),
+                    total_bytes: 100,
+                    available_bytes: 40,
+                }],
+            }),
+            timings: Some(TimingsSection { collection_ms: 12 }),
+            key_inventory: Some(vec![KeyInventoryEntry {
+                username: "alice".to_string(),
+                path: "/home/alice/.ssh/authorized_keys".to_string(),
+                fingerprints: vec!["SHA256:abc".to_string()],
+                externally_managed: Vec::new(),
+                certificates: Vec::new(),
+            }]),
+            ssh_dirs: None,
+        };
+
+        let value = serde_json::to_value(report).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "schemaVersion": 1,
+                "capabilities": ["network", "ssh", "storage", "timings", "keyInventory"],
+                "hostname": "host",
+                "systemInfo": {
+                    "os": "Linux",
+                    "arch": "x86_64",
+                    "platform": "linux",
+                    "kernel": "6.1.0",
+                    "distribution": "Debian GNU/Linux",
+                    "version": "12"
+                },
+                "agentVersion": "0.4.0",
+                "users": [{
+                    "username": "alice",
+                    "uid": 1000,
+                    "shell": "/bin/bash",
+                    "home_dir": "/home/alice",
+                    "disabled": false,
+                    "home_encryption": "plain",
+                    "home_mounted": true
+                }],
+                "idempotencyKey": "key-1",
+                "network": {"interfaces": [{"name": "eth0", "macAddress": "00:11:22:33:44:55"}]},
+                "ssh": {
+                    "authorizedKeysPatterns": [".ssh/authorized_keys"],
+                    "permitRootLogin": "no",
+                    "rootLoginDisabled": true
+                },
+                "storage": {"mounts": [{"mountPoint": "/", "totalBytes": 100, "availableBytes": 40}]},
+                "timings": {"collectionMs": 12},
+                "keyInventory": [{
+                    "username": "alice",
+                    "path": "/home/alice/.ssh/authorized_keys",
+                    "fingerprints": ["SHA256:abc"]
+                }]
+            })
+        );
+    }
+}
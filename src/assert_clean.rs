@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::ssh_keys::KeySyncStats;
+
+/// A condition `--assert-clean` can require of the computed sync plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[allow(clippy::enum_variant_names)]
+pub enum CleanCondition {
+    /// No keys would be added or removed
+    NoDrift,
+    /// No keys in scope lack a matching assignment
+    NoUnmanaged,
+    /// No ownership or mode problems on authorized_keys files
+    NoPermissionWarnings,
+}
+
+impl CleanCondition {
+    pub const ALL: &[&str] = &["no-drift", "no-unmanaged", "no-permission-warnings"];
+}
+
+impl fmt::Display for CleanCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CleanCondition::NoDrift => "no-drift",
+            CleanCondition::NoUnmanaged => "no-unmanaged",
+            CleanCondition::NoPermissionWarnings => "no-permission-warnings",
+        })
+    }
+}
+
+/// A failed condition for one user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub condition: CleanCondition,
+    pub username: String,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed for {}: {}", self.condition, self.username, self.detail)
+    }
+}
+
+/// Check the selected conditions against a sync plan
+pub fn check(stats: &KeySyncStats, conditions: &[CleanCondition]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for &condition in conditions {
+        match condition {
+            CleanCondition::NoDrift => {
+                for change in &stats.changes {
+                    violations.push(Violation {
+                        condition,
+                        username: change.username.clone(),
+                        detail: format!(
+                            "{} keys to add, {} to remove, {} to update in {}",
+                            change.added.len(),
+                            change.removed.len(),
+                            change.updated.len(),
+                            change.path.display()
+                        ),
+                    });
+                }
+            }
+            CleanCondition::NoUnmanaged => {
+                for unmanaged in &stats.unmanaged_keys {
+                    violations.push(Violation {
+                        condition,
+                        username: unmanaged.username.clone(),
+                        detail: format!(
+                            "unmanaged keys in {}: {}",
+                            unmanaged.path.display(),
+                            unmanaged.fingerprints.join(", ")
+                        ),
+                    });
+                }
+            }
+            CleanCondition::NoPermissionWarnings => {
+                for warning in &stats.permission_warnings {
+                    violations.push(Violation {
+                        condition,
+                        username: warning.username.clone(),
+                        detail: warning.detail.clone(),
+                    });
+                }
+            }
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::ssh_keys::{FileKeyChanges, PermissionWarning, UnmanagedKeys};
+
+    fn dirty_stats() -> KeySyncStats {
+        KeySyncStats {
+            changes: vec![FileKeyChanges {
+                username: "alice".to_string(),
+                path: PathBuf::from("/home/alice/.ssh/authorized_keys"),
+                added: vec!["SHA256:new".to_string()],
+                removed: Vec::new(),
+                updated: Vec::new(),
+            }],
+            unmanaged_keys: vec![UnmanagedKeys {
+                username: "bob".to_string(),
+                path: PathBuf::from("/home/bob/.ssh/authorized_keys"),
+                fingerprints: vec!["SHA256:stray".to_string()],
+            }],
+            permission_warnings: vec![PermissionWarning {
+                username: "carol".to_string(),
+                path: PathBuf::from("/home/carol/.ssh/authorized_keys"),
+                detail: "/home/carol/.ssh/authorized_keys is group or world writable (mode 666)".to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_each_condition_reports_its_user() {
+        let stats = dirty_stats();
+        for (condition, username) in [
+            (CleanCondition::NoDrift, "alice"),
+            (CleanCondition::NoUnmanaged, "bob"),
+            (CleanCondition::NoPermissionWarnings, "carol"),
+        ] {
+            let violations = check(&stats, &[condition]);
+            assert_eq!(violations.len(), 1, "{}", condition);
+            assert_eq!(violations[0].condition, condition);
+            assert_eq!(violations[0].username, username);
+            assert!(violations[0].to_string().starts_with(&format!("{} failed for {}", condition, username)));
+        }
+    }
+
+    #[test]
+    fn test_clean_plan_passes() {
+        let all = [CleanCondition::NoDrift, CleanCondition::NoUnmanaged, CleanCondition::NoPermissionWarnings];
+        assert!(check(&KeySyncStats::default(), &all).is_empty());
+        assert_eq!(check(&dirty_stats(), &all).len(), 3);
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::api::{ApiClient, ApiError, AssignmentsFetch, KeyAssignmentsResponse, Validators};
+use crate::state::StateDir;
+
+/// State file holding the last key assignments fetched from the server. It names users and their
+/// keys, so it is written 0600 like all state.
+pub const CACHE_FILE: &str = "assignments.json";
+
+/// Default for --cached-assignments-max-age
+pub const DEFAULT_MAX_AGE: &str = "168h";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedAssignments<R> {
+    /// Seconds since the epoch
+    #[serde(rename = "fetchedAt")]
+    fetched_at: u64,
+    /// [`ApiClient::assignments_source`] of the client that fetched it
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    validators: Validators,
+    response: R,
+}
+
+/// Remember a successfully fetched response
+pub fn store(state: &StateDir, source: &str, response: &KeyAssignmentsResponse, validators: &Validators, now: u64) -> Result<()> {
+    state.store(CACHE_FILE, &CachedAssignments { fetched_at: now, source: source.to_string(), validators: validators.clone(), response })
+}
+
+/// The cached response and its age, unless there is none, it came from another endpoint or token,
+/// or it is older than `max_age`
+pub fn load(state: &StateDir, source: &str, now: u64, max_age: Duration) -> Result<(KeyAssignmentsResponse, Duration)> {
+    let cached: CachedAssignments<KeyAssignmentsResponse> = state.load(CACHE_FILE).ok_or_else(|| anyhow!("no cached key assignments"))?;
+    if cached.source != source {
+        return Err(anyhow!("cached key assignments came from another endpoint or token"));
+    }
+    let age = Duration::from_secs(now.saturating_sub(cached.fetched_at));
+    if age > max_age {
+        return Err(anyhow!(
+            "cached key assignments are {}h old, past the maximum of {}h",
+            age.as_secs() / 3600,
+            max_age.as_secs() / 3600
+        ));
+    }
+    Ok((cached.response, age))
+}
+
+/// Fetch key assignments, sending the validators of the cached copy so an unchanged list is not
+/// downloaded again, and cache what comes back. A 304 returns the cached copy.
+pub async fn fetch(client: &ApiClient, state: &StateDir, max_retries: u32) -> Result<KeyAssignmentsResponse, ApiError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let source = client.assignments_source();
+    let cached = state
+        .load::<CachedAssignments<KeyAssignmentsResponse>>(CACHE_FILE)
+        .filter(|cached| cached.source == source);
+    let validators = cached.as_ref().map(|cached| cached.validators.clone()).unwrap_or_default();
+
+    let (response, validators) = match client.get_key_assignments_with_retry(max_retries, &validators).await? {
+        AssignmentsFetch::Changed(response, validators) => (*response, validators),
+        AssignmentsFetch::NotModified => {
+            let cached = cached.ok_or_else(|| {
+                ApiError::Protocol("Key assignments request answered 304 Not Modified, but there is no cached copy".to_string())
+            })?;
+            info!("Reusing cached key assignments");
+            (cached.response, cached.validators)
+        }
+    };
+    // Stored again after a 304 too: the copy is current as of now
+    if let Err(e) = store(state, &source, &response, &validators, now) {
+        warn!("Failed to cache key assignments: {}", e);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, mock_server};
+    use std::os::unix::fs::PermissionsExt;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn test_round_trip_and_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let max_age = Duration::from_secs(24 * HOUR);
+        assert!(load(&state, "source", 1_000_000, max_age).is_err());
+
+        let response: KeyAssignmentsResponse = serde_json::from_value(serde_json::json!({
+            "success": true,
+            "assignments": [{
+                "username": "alice",
+                "fingerprint": "SHA256:abc",
+                "publicKey": "ssh-ed25519 AAAA",
+                "keyType": "ssh-ed25519",
+                "assignmentId": "a1",
+            }],
+            "managedUsers": ["alice"],
+        }))
+        .unwrap();
+        store(&state, "source", &response, &Validators::default(), 1_000_000).unwrap();
+        let mode = std::fs::metadata(state.file(CACHE_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (cached, age) = load(&state, "source", 1_000_000 + HOUR, max_age).unwrap();
+        assert_eq!(age, Duration::from_secs(HOUR));
+        assert_eq!(cached.assignments.unwrap()[0].assignment_id, "a1");
+        assert_eq!(cached.managed_users, Some(vec!["alice".to_string()]));
+
+        let err = load(&state, "source", 1_000_000 + 25 * HOUR, max_age).unwrap_err().to_string();
+        assert!(err.contains("25h old"), "{}", err);
+
+        // Never replayed for another endpoint or token
+        let err = load(&state, "other", 1_000_000 + HOUR, max_age).unwrap_err().to_string();
+        assert!(err.contains("another endpoint or token"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_assignments_are_not_downloaded_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let (endpoint, requests) = mock_server(vec![
+            MockResponse {
+                headers: vec![("ETag".to_string(), "\"v1\"".to_string())],
+                ..MockResponse::new(200, r#"{"success":true,"assignments":[{"username":"alice","fingerprint":"SHA256:abc","publicKey":"ssh-ed25519 AAAA","keyType":"ssh-ed25519","assignmentId":"a1"}]}"#)
+            },
+            MockResponse::new(304, ""),
+        ])
+        .await;
+        let client = ApiClient::new(endpoint.clone(), "pk_test".into()).unwrap();
+        assert_eq!(fetch(&client, &state, 1).await.unwrap().assignments.unwrap().len(), 1);
+
+        // The 304 hands back the cached list
+        let response = fetch(&client, &state, 1).await.unwrap();
+        assert_eq!(response.assignments.unwrap()[0].assignment_id, "a1");
+
+        // Another token starts over
+        let other = ApiClient::new(endpoint, "pk_other".into()).unwrap();
+        assert!(matches!(fetch(&other, &state, 1).await, Err(ApiError::Protocol(_))));
+
+        let requests = requests.lock().unwrap();
+        let sent: Vec<_> = requests.iter().map(|request| request.header("if-none-match")).collect();
+        assert_eq!(sent, vec![None, Some("\"v1\""), None]);
+    }
+}
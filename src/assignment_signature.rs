@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use ring::signature::{ED25519, UnparsedPublicKey};
+
+/// DER prefix of an ed25519 SubjectPublicKeyInfo; the raw 32-byte key follows it
+const ED25519_SPKI_PREFIX: &[u8] = &[0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// The server's ed25519 public key (`--assignments-pubkey`). Key assignments must carry a valid
+/// signature by the matching private key, so neither a MITM on the API connection nor a
+/// compromised web server can push keys without also holding that key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssignmentsPubkey([u8; 32]);
+
+impl AssignmentsPubkey {
+    /// Read the key from a file, as a PEM `PUBLIC KEY` or the base64 of the raw 32 bytes
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read assignments public key {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid assignments public key {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let der = match text.strip_prefix("-----BEGIN PUBLIC KEY-----") {
+            Some(rest) => {
+                let body = rest
+                    .strip_suffix("-----END PUBLIC KEY-----")
+                    .ok_or_else(|| anyhow!("unterminated PEM block"))?;
+                let body: String = body.split_whitespace().collect();
+                let der = decode(&body)?;
+                der.strip_prefix(ED25519_SPKI_PREFIX)
+                    .ok_or_else(|| anyhow!("not an ed25519 public key"))?
+                    .to_vec()
+            }
+            None => decode(text)?,
+        };
+        let key = der
+            .try_into()
+            .map_err(|der: Vec<u8>| anyhow!("expected a 32-byte ed25519 key, got {} bytes", der.len()))?;
+        Ok(Self(key))
+    }
+
+    /// Check the base64 detached `signature` over the exact bytes of the response body
+    pub fn verify(&self, body: &[u8], signature: &str) -> Result<(), String> {
+        let signature = decode(signature.trim()).map_err(|e| e.to_string())?;
+        UnparsedPublicKey::new(&ED25519, &self.0)
+            .verify(body, &signature)
+            .map_err(|_| "signature does not match the configured public key".to_string())
+    }
+
+    /// Hex of the key, to tell apart caches written under different keys
+    pub fn fingerprint(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+fn decode(text: &str) -> Result<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD.decode(text).map_err(|e| anyhow!("invalid base64: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::AssignmentsSigner;
+
+    #[test]
+    fn test_parse_raw_and_pem_keys() {
+        // RFC 8032 test 1 public key
+        let raw = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+        let key = AssignmentsPubkey::parse(&format!("{}\n", raw)).unwrap();
+        let pem = "-----BEGIN PUBLIC KEY-----\nMCowBQYDK2VwAyEA11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=\n-----END PUBLIC KEY-----\n";
+        assert_eq!(AssignmentsPubkey::parse(pem).unwrap(), key);
+        assert_eq!(key.fingerprint(), "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+
+        assert!(AssignmentsPubkey::parse("c2hvcnQ=").unwrap_err().to_string().contains("32-byte"));
+        assert!(AssignmentsPubkey::parse("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_verify_detached_signature() {
+        let signer = AssignmentsSigner::generate();
+        let body = r#"{"success":true,"assignments":[]}"#;
+        let signature = signer.sign(body);
+        let public = signer.public_key();
+        assert!(public.verify(body.as_bytes(), &signature).is_ok());
+        assert!(public.verify(br#"{"success":true,"assignments":[{}]}"#, &signature).is_err());
+        assert!(AssignmentsSigner::generate().public_key().verify(body.as_bytes(), &signature).is_err());
+        assert!(public.verify(body.as_bytes(), "garbage").is_err());
+    }
+}
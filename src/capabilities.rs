@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::api::AgentReportResponse;
+use crate::report::REPORT_CAPABILITIES;
+use crate::state::StateDir;
+
+/// State file caching the capability set the server agreed to
+const NEGOTIATED_FILE: &str = "capabilities.json";
+
+/// Capabilities the server can turn off, with the setting used when nothing was negotiated
+pub const NEGOTIABLE: &[(&str, bool)] = &[
+    // Send the keyInventory report section
+    ("keyInventory", true),
+    // Deliver reports spooled by earlier runs after a successful report
+    ("spoolReplay", true),
+    // Acknowledge each assignment's outcome after a sync; servers without the endpoint never ask
+    ("assignmentAcks", false),
+];
+
+/// Everything this agent build supports, sent as the report's `capabilities`
+pub fn supported() -> Vec<String> {
+    let mut capabilities: Vec<String> = REPORT_CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    for (name, _) in NEGOTIABLE {
+        if !capabilities.iter().any(|c| c == name) {
+            capabilities.push(name.to_string());
+        }
+    }
+    capabilities
+}
+
+/// Capability set the server echoed back, and the server version it came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Negotiated {
+    #[serde(rename = "serverVersion")]
+    pub server_version: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+/// Which negotiable code paths are enabled
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    negotiated: Option<Negotiated>,
+}
+
+impl Capabilities {
+    /// Capabilities cached by an earlier run
+    pub fn load(state: &StateDir) -> Self {
+        Self { negotiated: state.load(NEGOTIATED_FILE) }
+    }
+
+    /// Whether the code path behind `name` should run
+    pub fn enabled(&self, name: &str) -> bool {
+        match &self.negotiated {
+            Some(negotiated) => negotiated.capabilities.iter().any(|c| c == name),
+            // Conservative default: what agents did before negotiation existed, off for anything else
+            None => NEGOTIABLE.iter().any(|&(known, default)| known == name && default),
+        }
+    }
+
+    /// Apply a report response; returns whether the set changed.
+    ///
+    /// An echoed list replaces the cached one. Without one the cache stays valid only while the
+    /// server version is unchanged, since an upgraded or downgraded server may support other things.
+    pub fn update(&mut self, response: &AgentReportResponse) -> bool {
+        let next = match (&response.capabilities, &self.negotiated) {
+            (Some(capabilities), _) => Some(Negotiated {
+                server_version: response.server_version.clone(),
+                capabilities: capabilities.clone(),
+            }),
+            (None, Some(cached)) if response.server_version.is_some() && cached.server_version != response.server_version => {
+                info!(
+                    "Server version changed ({} -> {}), dropping negotiated capabilities",
+                    cached.server_version.as_deref().unwrap_or("unknown"),
+                    response.server_version.as_deref().unwrap_or("unknown")
+                );
+                None
+            }
+            (None, cached) => cached.clone(),
+        };
+
+        let changed = next != self.negotiated;
+        if changed {
+            debug!("Negotiated capabilities: {:?}", next);
+        }
+        self.negotiated = next;
+        changed
+    }
+
+    /// Cache the negotiated set for later runs
+    pub fn save(&self, state: &StateDir) -> anyhow::Result<()> {
+        match &self.negotiated {
+            Some(negotiated) => state.store(NEGOTIATED_FILE, negotiated),
+            None => state.remove(NEGOTIATED_FILE).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(capabilities: Option<&[&str]>, server_version: Option<&str>) -> AgentReportResponse {
+        let mut json = serde_json::json!({"success": true});
+        if let Some(capabilities) = capabilities {
+            json["capabilities"] = serde_json::json!(capabilities);
+        }
+        if let Some(server_version) = server_version {
+            json["serverVersion"] = serde_json::json!(server_version);
+        }
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_defaults_without_negotiation() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut capabilities = Capabilities::load(&state);
+        assert!(capabilities.enabled("keyInventory"));
+        assert!(capabilities.enabled("spoolReplay"));
+        assert!(!capabilities.enabled("deltaReports"));
+        assert!(!capabilities.enabled("assignmentAcks"));
+
+        // A server that does not negotiate leaves the defaults in place
+        assert!(!capabilities.update(&response(None, Some("1.0.0"))));
+        assert!(capabilities.enabled("spoolReplay"));
+    }
+
+    #[test]
+    fn test_echoed_set_is_used_and_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut capabilities = Capabilities::load(&state);
+
+        assert!(capabilities.update(&response(Some(&["ssh", "keyInventory", "deltaReports"]), Some("1.0.0"))));
+        assert!(!capabilities.enabled("spoolReplay"));
+        assert!(capabilities.enabled("deltaReports"));
+        capabilities.save(&state).unwrap();
+
+        // Later runs start from the cache, and keep it while the server version is unchanged
+        let mut capabilities = Capabilities::load(&state);
+        assert!(!capabilities.enabled("spoolReplay"));
+        assert!(!capabilities.update(&response(None, Some("1.0.0"))));
+        assert!(!capabilities.update(&response(None, None)));
+        assert!(!capabilities.enabled("spoolReplay"));
+
+        // An empty echo turns everything negotiable off
+        capabilities.update(&response(Some(&[]), Some("1.0.0")));
+        assert!(!capabilities.enabled("keyInventory"));
+    }
+
+    #[test]
+    fn test_server_version_change_resets_to_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let mut capabilities = Capabilities::load(&state);
+        capabilities.update(&response(Some(&["keyInventory"]), Some("1.0.0")));
+        capabilities.save(&state).unwrap();
+
+        let mut capabilities = Capabilities::load(&state);
+        assert!(capabilities.update(&response(None, Some("2.0.0"))));
+        assert!(capabilities.enabled("spoolReplay"));
+        capabilities.save(&state).unwrap();
+        assert!(!state.file(NEGOTIATED_FILE).exists());
+    }
+
+    #[test]
+    fn test_supported_includes_report_sections() {
+        let supported = supported();
+        assert!(supported.iter().any(|c| c == "ssh"));
+        assert_eq!(supported.iter().filter(|c| *c == "keyInventory").count(), 1);
+        assert!(supported.iter().any(|c| c == "spoolReplay"));
+        assert!(supported.iter().any(|c| c == "assignmentAcks"));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::ssh_keys::KeySyncStats;
+
+/// Why `--check` lists an authorized_keys file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DriftStatus {
+    /// The managed keys differ from the assignments
+    Drifted,
+    /// The file has no PubliKey marker, so its keys were not compared
+    Unmanaged,
+}
+
+/// How one authorized_keys file differs from its user's key assignments
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileDrift {
+    pub username: String,
+    pub path: PathBuf,
+    pub status: DriftStatus,
+    /// Assigned keys absent from the file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    /// Keys in the file that no assignment accounts for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unexpected: Vec<String>,
+    /// Assigned keys present with other options than assigned
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub options_differ: Vec<String>,
+}
+
+impl FileDrift {
+    fn new(username: &str, path: &Path, status: DriftStatus) -> Self {
+        Self {
+            username: username.to_string(),
+            path: path.to_path_buf(),
+            status,
+            missing: Vec::new(),
+            unexpected: Vec::new(),
+            options_differ: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for FileDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.status == DriftStatus::Unmanaged {
+            return write!(f, "{}: {} is unmanaged (no PubliKey marker); not compared", self.username, self.path.display());
+        }
+        write!(f, "{}: {}", self.username, self.path.display())?;
+        for (label, fingerprints) in [("missing", &self.missing), ("unexpected", &self.unexpected), ("options differ", &self.options_differ)] {
+            if !fingerprints.is_empty() {
+                write!(f, "\n    {}: {}", label, fingerprints.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files whose keys do not match the assignments exactly, by user and path, from a dry-run plan.
+/// Empty when the host is compliant.
+pub fn compare(stats: &KeySyncStats) -> Vec<FileDrift> {
+    let unmarked: HashSet<&PathBuf> = stats.unmarked_files.iter().map(|file| &file.path).collect();
+    let mut drift: BTreeMap<(String, PathBuf), FileDrift> = BTreeMap::new();
+    for file in &stats.unmarked_files {
+        drift.insert((file.username.clone(), file.path.clone()), FileDrift::new(&file.username, &file.path, DriftStatus::Unmanaged));
+    }
+    let mut record = |username: &str, path: &PathBuf, missing: &[String], unexpected: &[String], options_differ: &[String]| {
+        if unmarked.contains(path) {
+            return;
+        }
+        let file = drift
+            .entry((username.to_string(), path.clone()))
+            .or_insert_with(|| FileDrift::new(username, path, DriftStatus::Drifted));
+        file.missing.extend_from_slice(missing);
+        file.unexpected.extend_from_slice(unexpected);
+        file.options_differ.extend_from_slice(options_differ);
+    };
+    for change in &stats.changes {
+        record(&change.username, &change.path, &change.added, &change.removed, &change.updated);
+    }
+    // Keys the sync leaves alone (outside the managed block, other tools' keys) still grant access
+    for keys in stats.unmanaged_keys.iter().chain(&stats.external_keys) {
+        record(&keys.username, &keys.path, &[], &keys.fingerprints, &[]);
+    }
+    drift
+        .into_values()
+        .map(|mut file| {
+            file.unexpected.sort();
+            file.unexpected.dedup();
+            file
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_keys::{FileKeyChanges, UnmanagedKeys, UnmarkedFile};
+
+    fn path(user: &str) -> PathBuf {
+        PathBuf::from(format!("/home/{}/.ssh/authorized_keys", user))
+    }
+
+    #[test]
+    fn test_compliant_plan_has_no_drift() {
+        assert!(compare(&KeySyncStats { files_examined: 3, ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn test_compare_lists_missing_unexpected_and_unmanaged() {
+        let stats = KeySyncStats {
+            changes: vec![FileKeyChanges {
+                username: "alice".to_string(),
+                path: path("alice"),
+                added: vec!["SHA256:assigned".to_string()],
+                removed: vec!["SHA256:revoked".to_string()],
+                updated: vec!["SHA256:restricted".to_string()],
+            }],
+            unmanaged_keys: vec![
+                // A revoked key is also unassigned; it is listed once
+                UnmanagedKeys { username: "alice".to_string(), path: path("alice"), fingerprints: vec!["SHA256:revoked".to_string()] },
+                UnmanagedKeys { username: "bob".to_string(), path: path("bob"), fingerprints: vec!["SHA256:stray".to_string()] },
+                UnmanagedKeys { username: "carol".to_string(), path: path("carol"), fingerprints: vec!["SHA256:own".to_string()] },
+            ],
+            external_keys: vec![UnmanagedKeys {
+                username: "bob".to_string(),
+                path: path("bob"),
+                fingerprints: vec!["SHA256:cloud-init".to_string()],
+            }],
+            unmarked_files: vec![UnmarkedFile { username: "carol".to_string(), path: path("carol") }],
+            ..Default::default()
+        };
+        let drift = compare(&stats);
+        assert_eq!(drift.len(), 3);
+
+        assert_eq!(drift[0].username, "alice");
+        assert_eq!(drift[0].status, DriftStatus::Drifted);
+        assert_eq!(drift[0].missing, ["SHA256:assigned"]);
+        assert_eq!(drift[0].unexpected, ["SHA256:revoked"]);
+        assert_eq!(drift[0].options_differ, ["SHA256:restricted"]);
+
+        assert_eq!(drift[1].username, "bob");
+        assert!(drift[1].missing.is_empty());
+        assert_eq!(drift[1].unexpected, ["SHA256:cloud-init", "SHA256:stray"]);
+
+        assert_eq!(drift[2].status, DriftStatus::Unmanaged);
+        assert!(drift[2].unexpected.is_empty());
+        assert_eq!(drift[2].to_string(), "carol: /home/carol/.ssh/authorized_keys is unmanaged (no PubliKey marker); not compared");
+
+        assert_eq!(
+            serde_json::to_value(&drift[0]).unwrap(),
+            serde_json::json!({
+                "username": "alice",
+                "path": "/home/alice/.ssh/authorized_keys",
+                "status": "drifted",
+                "missing": ["SHA256:assigned"],
+                "unexpected": ["SHA256:revoked"],
+                "optionsDiffer": ["SHA256:restricted"],
+            })
+        );
+        assert_eq!(
+            drift[0].to_string(),
+            "alice: /home/alice/.ssh/authorized_keys\n    missing: SHA256:assigned\n    unexpected: SHA256:revoked\n    options differ: SHA256:restricted"
+        );
+    }
+}
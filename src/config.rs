@@ -0,0 +1,226 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, anyhow};
+use secrecy::SecretString;
+use serde::Deserialize;
+
+/// Config file read when --config is not given; a missing file there is not an error
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/publikey/agent.toml";
+
+/// Config file the packages install at [`DEFAULT_CONFIG_PATH`]: every setting commented out, so
+/// it loads as empty until the admin uncomments one
+pub const TEMPLATE: &str = "\
+# PubliKey Agent configuration. Flags and PUBLIKEY_* environment variables take precedence;
+# the endpoint and token are usually kept in /etc/publikey/agent.env.
+
+# endpoint = \"https://publikey.example.com\"
+# token = \"pk_...\"
+# exclude_users = [\"backup\", \"nobody\"]
+# include_users = [\"alice\", \"bob\"]
+# user_mode = false
+# dry_run = false
+# assignments_pubkey = \"/etc/publikey/assignments.pub\"
+
+# Endpoint paths, for gateways that rewrite the API's routes
+# health_path = \"/api/health\"
+# report_path = \"/api/agent/report\"
+# heartbeat_path = \"/api/agent/heartbeat\"
+# keys_path = \"/api/host/keys\"
+# ack_path = \"/api/host/keys/ack\"
+# stream_path = \"/api/host/keys/stream\"
+";
+
+/// Settings that can live in the TOML config file instead of on the command line.
+/// Flags and environment variables take precedence over every field.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Comma-separated for fallback endpoints, like `--endpoint`
+    pub endpoint: Option<String>,
+    pub token: Option<SecretString>,
+    pub exclude_users: Option<Vec<String>>,
+    pub include_users: Option<Vec<String>>,
+    pub user_mode: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// Pins the server's key assignments signing key, like `--assignments-pubkey`
+    pub assignments_pubkey: Option<PathBuf>,
+    /// Endpoint path overrides for gateways that rewrite the API's routes
+    pub health_path: Option<String>,
+    pub report_path: Option<String>,
+    pub heartbeat_path: Option<String>,
+    pub keys_path: Option<String>,
+    pub ack_path: Option<String>,
+    pub stream_path: Option<String>,
+}
+
+/// A config file that does not parse, located precisely enough to fix by hand
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// 1-based line and column of the offending value, when known
+    pub position: Option<(usize, usize)>,
+    /// Dotted path of the field being read, empty for syntax errors outside any field
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config file {}", self.path.display())?;
+        if let Some((line, column)) = self.position {
+            write!(f, " at line {}, column {}", line, column)?;
+        }
+        if !self.field.is_empty() && self.field != "." {
+            write!(f, " (field `{}`)", self.field)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Load the config file. `explicit` is whether the path came from --config; only then is a
+/// missing file an error.
+pub fn load(path: &Path, explicit: bool) -> Result<Option<AgentConfig>> {
+    // Stat first: the mode is settled before the contents are parsed, and a token is only
+    // rejected once parsing shows there is one
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read config file {}", path.display())),
+    };
+    let world_readable = metadata.permissions().mode() & 0o004 != 0;
+
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        // The system-wide file is usually root-only; user-mode runs simply go without it
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !explicit => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read config file {}", path.display())),
+    };
+    let config = parse(path, &text)?;
+    if world_readable && config.token.is_some() {
+        return Err(anyhow!(
+            "Refusing to read the token from world-readable config file {} (chmod o-r it, or use PUBLIKEY_TOKEN)",
+            path.display()
+        ));
+    }
+    Ok(Some(config))
+}
+
+fn parse(path: &Path, text: &str) -> Result<AgentConfig, ConfigError> {
+    serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(|e| {
+        let field = e.path().to_string();
+        let inner = e.into_inner();
+        // serde quotes the offending value, which here would be (part of) the token
+        let message = match field.as_str() {
+            "token" => "expected the token as a string (value not shown)".to_string(),
+            _ => inner.message().to_string(),
+        };
+        ConfigError {
+            path: path.to_path_buf(),
+            position: inner.span().map(|span| line_column(text, span.start)),
+            field,
+            message,
+        }
+    })
+}
+
+/// 1-based line and column of byte `offset` in `text`
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    fn write_config(dir: &Path, content: &str, mode: u32) -> PathBuf {
+        let path = dir.join("agent.toml");
+        fs::write(&path, content).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            dir.path(),
+            "endpoint = \"https://publikey.example.com\"\ntoken = \"pk_file\"\nexclude_users = [\"backup\", \"nobody\"]\nuser_mode = true\n",
+            0o600,
+        );
+        let config = load(&path, true).unwrap().unwrap();
+        assert_eq!(config.endpoint.as_deref(), Some("https://publikey.example.com"));
+        assert_eq!(config.token.unwrap().expose_secret(), "pk_file");
+        assert_eq!(config.exclude_users, Some(vec!["backup".to_string(), "nobody".to_string()]));
+        assert_eq!(config.user_mode, Some(true));
+        assert_eq!(config.dry_run, None);
+    }
+
+    #[test]
+    fn test_template_loads_empty_and_every_setting_parses() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = load(&write_config(dir.path(), TEMPLATE, 0o600), true).unwrap().unwrap();
+        assert!(config.endpoint.is_none() && config.token.is_none() && config.health_path.is_none());
+
+        // Uncommented, the examples are all known fields of the right type
+        let uncommented: String = TEMPLATE
+            .lines()
+            .filter_map(|line| line.strip_prefix("# ").filter(|setting| setting.contains(" = ")))
+            .map(|setting| format!("{}\n", setting))
+            .collect();
+        let config = load(&write_config(dir.path(), &uncommented, 0o600), true).unwrap().unwrap();
+        assert_eq!(config.endpoint.as_deref(), Some("https://publikey.example.com"));
+        assert_eq!(config.stream_path.as_deref(), Some("/api/host/keys/stream"));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.toml");
+        assert!(load(&path, false).unwrap().is_none());
+        assert!(load(&path, true).is_err());
+    }
+
+    #[test]
+    fn test_world_readable_token_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(dir.path(), "token = \"pk_file\"\n", 0o644);
+        let err = load(&path, true).unwrap_err().to_string();
+        assert!(err.contains("world-readable"), "{}", err);
+        assert!(!err.contains("pk_file"));
+
+        // Without a token the file may be readable by all
+        let path = write_config(dir.path(), "endpoint = \"https://publikey.example.com\"\n", 0o644);
+        assert!(load(&path, true).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_errors_name_line_and_field() {
+        let path = Path::new("/etc/publikey/agent.toml");
+        let err = parse(path, "endpoint = \"https://pk\"\nuser_mode = \"yes\"\n").unwrap_err();
+        assert_eq!(err.position, Some((2, 13)));
+        assert_eq!(err.field, "user_mode");
+        let message = err.to_string();
+        assert!(message.starts_with("Invalid config file /etc/publikey/agent.toml at line 2, column 13 (field `user_mode`): "), "{}", message);
+
+        let err = parse(path, "endpoint = \"https://pk\"\nendpont = \"typo\"\n").unwrap_err();
+        assert_eq!(err.position.map(|(line, _)| line), Some(2));
+        assert!(err.message.contains("endpont"), "{}", err.message);
+
+        let err = parse(path, "endpoint = \n").unwrap_err();
+        assert_eq!(err.position.map(|(line, _)| line), Some(1));
+
+        // A mistyped token is located, not quoted
+        let err = parse(path, "token = 918273645\n").unwrap_err();
+        assert_eq!(err.field, "token");
+        assert!(!err.to_string().contains("918273645"), "{}", err);
+    }
+}
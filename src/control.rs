@@ -0,0 +1,302 @@
+//! Local control API for --watch: a tiny HTTP server on a unix socket that lets provisioning hooks
+//! ask for an immediate run, read the last run's result and have the config file re-read.
+
+use std::convert::Infallible;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::UnixListener;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::api;
+use crate::cli::Cli;
+use crate::output::{self, RunSummary};
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/pkagent/control.sock";
+
+/// Produces the reloaded settings for `POST /reload`
+pub type Reload = Box<dyn Fn() -> Result<Cli> + Send + Sync>;
+
+#[derive(Default)]
+struct Shared {
+    /// `--output json` document of the last finished run
+    last_run: Option<serde_json::Value>,
+    /// Run in progress, or asked for and not started yet
+    active_run: Option<String>,
+    sync_requested: bool,
+    reloaded: Option<Cli>,
+}
+
+struct Inner {
+    shared: Mutex<Shared>,
+    wake: Notify,
+    reload: Reload,
+}
+
+/// The control socket of a --watch process. Stops serving and removes the socket when dropped.
+pub struct ControlSocket {
+    inner: Arc<Inner>,
+    path: PathBuf,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Listen on `path`, replacing a stale socket left by an earlier run of the same user. Refuses
+    /// a path owned by someone else, one that is not a socket, and one another process serves.
+    pub fn bind(path: &Path, reload: Reload) -> Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            let euid = nix::unistd::geteuid().as_raw();
+            if metadata.uid() != euid {
+                bail!("Control socket {} is owned by UID {}, not {}; refusing to use it", path.display(), metadata.uid(), euid);
+            }
+            if !metadata.file_type().is_socket() {
+                bail!("Control socket path {} exists and is not a socket; refusing to replace it", path.display());
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("Control socket {} is in use by another process", path.display());
+            }
+            fs::remove_file(path).with_context(|| format!("Failed to remove stale control socket {}", path.display()))?;
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let listener = UnixListener::bind(path).with_context(|| format!("Failed to listen on control socket {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict control socket {}", path.display()))?;
+        info!("Serving the control API on {}", path.display());
+
+        let inner = Arc::new(Inner { shared: Mutex::new(Shared::default()), wake: Notify::new(), reload });
+        let server = tokio::spawn(serve(listener, inner.clone()));
+        Ok(Self { inner, path: path.to_path_buf(), server })
+    }
+
+    /// Run ID for the run about to start: the one `POST /sync` handed out, or a new one
+    pub fn begin_run(&self) -> String {
+        let mut shared = self.inner.shared.lock().unwrap();
+        let run_id = match shared.active_run.clone() {
+            Some(run_id) if shared.sync_requested => run_id,
+            _ => api::new_run_id(),
+        };
+        shared.sync_requested = false;
+        shared.active_run = Some(run_id.clone());
+        run_id
+    }
+
+    /// Record a finished run for `GET /status`
+    pub fn finish_run(&self, result: &Result<RunSummary>) {
+        let mut shared = self.inner.shared.lock().unwrap();
+        shared.last_run = Some(output::json_result(result));
+        shared.active_run = None;
+    }
+
+    /// Wait for `POST /sync`
+    pub async fn sync_requested(&self) {
+        loop {
+            let notified = self.inner.wake.notified();
+            if self.inner.shared.lock().unwrap().sync_requested {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Settings from the last successful `POST /reload` not taken yet
+    pub fn take_reload(&self) -> Option<Cli> {
+        self.inner.shared.lock().unwrap().reloaded.take()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.server.abort();
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("Failed to remove control socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+async fn serve(listener: UnixListener, inner: Arc<Inner>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Control socket accept failed: {}", e);
+                continue;
+            }
+        };
+        let inner = inner.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = handle(&inner, &request);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            if let Err(e) = hyper::server::conn::Http::new().http1_only(true).serve_connection(stream, service).await {
+                debug!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn handle(inner: &Inner, request: &Request<Body>) -> Response<Body> {
+    let json = |status: StatusCode, value: serde_json::Value| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(value.to_string()))
+            .unwrap_or_default()
+    };
+    let allowed = match request.uri().path() {
+        "/status" => Method::GET,
+        "/sync" | "/reload" => Method::POST,
+        _ => return json(StatusCode::NOT_FOUND, serde_json::json!({ "error": "unknown path" })),
+    };
+    if request.method() != allowed {
+        return json(StatusCode::METHOD_NOT_ALLOWED, serde_json::json!({ "error": format!("use {}", allowed) }));
+    }
+
+    match request.uri().path() {
+        "/status" => match &inner.shared.lock().unwrap().last_run {
+            Some(last_run) => json(StatusCode::OK, last_run.clone()),
+            None => json(StatusCode::NOT_FOUND, serde_json::json!({ "error": "no run has finished yet" })),
+        },
+        "/sync" => {
+            let mut shared = inner.shared.lock().unwrap();
+            let deduplicated = shared.active_run.is_some();
+            let run_id = shared.active_run.get_or_insert_with(api::new_run_id).clone();
+            if !deduplicated {
+                shared.sync_requested = true;
+                inner.wake.notify_one();
+                info!("Run {} requested on the control socket", run_id);
+            }
+            json(StatusCode::ACCEPTED, serde_json::json!({ "runId": run_id, "deduplicated": deduplicated }))
+        }
+        _ => match (inner.reload)() {
+            Ok(cli) => {
+                inner.shared.lock().unwrap().reloaded = Some(cli);
+                info!("Configuration reloaded on the control socket; it applies from the next run");
+                json(StatusCode::OK, serde_json::json!({ "reloaded": true }))
+            }
+            Err(e) => {
+                warn!("Configuration reload failed: {:#}", e);
+                json(StatusCode::BAD_REQUEST, serde_json::json!({ "reloaded": false, "error": format!("{:#}", e) }))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::net::UnixStream;
+
+    fn reload_from(args: &'static [&'static str]) -> Reload {
+        Box::new(move || Ok(Cli::try_parse_from(args)?))
+    }
+
+    /// One request over a fresh connection, as a uds-capable client would send it
+    async fn request(path: &Path, method: Method, uri: &str) -> (StatusCode, serde_json::Value) {
+        let stream = UnixStream::connect(path).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::builder().method(method).uri(uri).header("Host", "localhost").body(Body::empty()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_status_sync_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/control.sock");
+        let control = ControlSocket::bind(&path, reload_from(&["pkagent", "--watch", "--poll-interval", "1m"])).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let (status, body) = request(&path, Method::GET, "/status").await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+        assert_eq!(request(&path, Method::GET, "/sync").await.0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(request(&path, Method::POST, "/nope").await.0, StatusCode::NOT_FOUND);
+
+        // A second request while the first is pending gets the same run
+        let (status, body) = request(&path, Method::POST, "/sync").await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["deduplicated"], false);
+        let run_id = body["runId"].as_str().unwrap().to_string();
+        let (_, body) = request(&path, Method::POST, "/sync").await;
+        assert_eq!((body["runId"].as_str(), body["deduplicated"].as_bool()), (Some(run_id.as_str()), Some(true)));
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), control.sync_requested()).await.unwrap();
+        assert_eq!(control.begin_run(), run_id);
+        // Also while it runs
+        let (_, body) = request(&path, Method::POST, "/sync").await;
+        assert_eq!(body["runId"].as_str(), Some(run_id.as_str()));
+        control.finish_run(&Ok(RunSummary { run_id: Some(run_id.clone()), ..RunSummary::message("Report completed successfully") }));
+
+        let (status, body) = request(&path, Method::GET, "/status").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["runId"].as_str(), Some(run_id.as_str()));
+        assert_eq!(body["failed"], false);
+        // Nothing was asked for after the run ended
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), control.sync_requested()).await.is_err());
+        assert_ne!(control.begin_run(), run_id);
+
+        let (status, body) = request(&path, Method::POST, "/reload").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(control.take_reload().unwrap().run_args().poll_interval, std::time::Duration::from_secs(60));
+        assert!(control.take_reload().is_none());
+
+        drop(control);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_failed_reload_keeps_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let control = ControlSocket::bind(&path, reload_from(&["pkagent", "--poll-interval", "soon"])).unwrap();
+        let (status, body) = request(&path, Method::POST, "/reload").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["reloaded"], false);
+        assert!(control.take_reload().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bind_refuses_foreign_paths() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let file = dir.path().join("file.sock");
+        fs::write(&file, "").unwrap();
+        let error = ControlSocket::bind(&file, reload_from(&["pkagent"])).err().unwrap();
+        assert!(error.to_string().contains("not a socket"), "{}", error);
+
+        // A socket another process serves stays; a stale one is replaced
+        let path = dir.path().join("control.sock");
+        let first = ControlSocket::bind(&path, reload_from(&["pkagent"])).unwrap();
+        let error = ControlSocket::bind(&path, reload_from(&["pkagent"])).err().unwrap();
+        assert!(error.to_string().contains("in use"), "{}", error);
+        drop(first);
+        assert!(!path.exists());
+        let stale = std::os::unix::net::UnixListener::bind(dir.path().join("stale.sock")).unwrap();
+        drop(stale);
+        ControlSocket::bind(&dir.path().join("stale.sock"), reload_from(&["pkagent"])).unwrap();
+
+        if nix::unistd::geteuid().is_root() {
+            let foreign = dir.path().join("foreign.sock");
+            drop(std::os::unix::net::UnixListener::bind(&foreign).unwrap());
+            nix::unistd::chown(&foreign, Some(nix::unistd::Uid::from_raw(4321)), None).unwrap();
+            let error = ControlSocket::bind(&foreign, reload_from(&["pkagent"])).err().unwrap();
+            assert!(error.to_string().contains("owned by UID 4321"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::path::Path;
+
+/// Lines of unchanged context around each change
+const CONTEXT: usize = 3;
+
+/// Past this many changed lines the diff stops looking for the smallest edit and shows the rest
+/// as replaced; keeps memory bounded on files with thousands of keys
+const MAX_EDITS: usize = 1000;
+
+/// Key blobs longer than this are cut down to their ends unless `--full-diff` is given
+const ELIDE_OVER: usize = 40;
+
+/// Characters kept at each end of an elided blob
+const ELIDE_KEEP: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// How `--diff` renders a file's planned rewrite
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffStyle {
+    /// ANSI colors, for a terminal
+    pub color: bool,
+    /// Show key blobs whole instead of eliding their middle
+    pub full: bool,
+}
+
+/// Unified diff of `old` (`None` for a file that does not exist yet) against `new`, headed by
+/// `path`. Empty when the contents match line for line.
+pub fn unified(path: &Path, old: Option<&str>, new: &str, style: DiffStyle) -> String {
+    let old_lines: Vec<&str> = old.unwrap_or_default().lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+    if ops.iter().all(|op| matches!(op, Op::Equal(_))) {
+        return String::new();
+    }
+
+    let paint = |code: &str, text: String| if style.color { format!("\x1b[{}m{}\x1b[0m", code, text) } else { text };
+    let mut out = Vec::new();
+    let old_name = if old.is_some() { path.display().to_string() } else { "/dev/null".to_string() };
+    out.push(paint("1", format!("--- {}", old_name)));
+    out.push(paint("1", format!("+++ {}", path.display())));
+
+    // Hunks: runs of changes with their context, merged when the context would overlap
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        if matches!(op, Op::Equal(_)) {
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    for (start, end) in hunks {
+        let old_before = ops[..start].iter().filter(|op| !matches!(op, Op::Insert(_))).count();
+        let new_before = ops[..start].iter().filter(|op| !matches!(op, Op::Delete(_))).count();
+        let old_count = ops[start..end].iter().filter(|op| !matches!(op, Op::Insert(_))).count();
+        let new_count = ops[start..end].iter().filter(|op| !matches!(op, Op::Delete(_))).count();
+        out.push(paint(
+            "36",
+            format!("@@ -{} +{} @@", range(old_before, old_count), range(new_before, new_count)),
+        ));
+        for op in &ops[start..end] {
+            let show = |line: &str| if style.full { line.to_string() } else { elide_blobs(line) };
+            out.push(match op {
+                Op::Equal(line) => format!(" {}", show(line)),
+                Op::Delete(line) => paint("31", format!("-{}", show(line))),
+                Op::Insert(line) => paint("32", format!("+{}", show(line))),
+            });
+        }
+    }
+    out.join("\n") + "\n"
+}
+
+/// `start,count` of a hunk side; an empty side names the line before it, as `diff -u` does
+fn range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
+
+/// Shorten long base64 words (key blobs) to their ends so lines fit on a screen
+fn elide_blobs(line: &str) -> String {
+    let is_blob = |word: &str| {
+        word.len() > ELIDE_OVER && word.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+    };
+    if !line.split_whitespace().any(is_blob) {
+        return line.to_string();
+    }
+    line.split(' ')
+        .map(|word| {
+            if is_blob(word) {
+                format!("{}…{}", &word[..ELIDE_KEEP], &word[word.len() - ELIDE_KEEP..])
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Shortest edit script from `a` to `b` (Myers), after taking off the lines both share at
+/// either end
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<Op<'a>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops: Vec<Op> = a[..prefix].iter().map(|line| Op::Equal(line)).collect();
+    ops.extend(myers(a_mid, b_mid).unwrap_or_else(|| {
+        a_mid.iter().map(|line| Op::Delete(line)).chain(b_mid.iter().map(|line| Op::Insert(line))).collect()
+    }));
+    ops.extend(a[a.len() - suffix..].iter().map(|line| Op::Equal(line)));
+    ops
+}
+
+/// `None` when the edit distance exceeds [`MAX_EDITS`]
+fn myers<'a>(a: &[&'a str], b: &[&'a str]) -> Option<Vec<Op<'a>>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    // Furthest reaching x per diagonal before each round, kept to the diagonals that round reads
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let at = |k: isize| (k + offset) as usize;
+    'search: for d in 0..=max as isize {
+        if d as usize > MAX_EDITS {
+            return None;
+        }
+        trace.push(v[at(-d - 1)..=at(d + 1)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) { v[at(k + 1)] } else { v[at(k - 1)] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| (k + d + 1) as usize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) { k + 1 } else { k - 1 };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal(a[x as usize - 1]));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(Op::Insert(b[y as usize - 1]));
+                y -= 1;
+            } else {
+                ops.push(Op::Delete(a[x as usize - 1]));
+                x -= 1;
+            }
+        }
+    }
+    ops.reverse();
+    Some(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e alice@laptop";
+
+    /// Apply an edit script to `a`, checking it is consistent with it
+    fn apply<'a>(a: &[&'a str], ops: &[Op<'a>]) -> Vec<&'a str> {
+        let mut old = a.iter();
+        let mut out = Vec::new();
+        for op in ops {
+            match op {
+                Op::Equal(line) => {
+                    assert_eq!(old.next(), Some(line));
+                    out.push(*line);
+                }
+                Op::Delete(line) => assert_eq!(old.next(), Some(line)),
+                Op::Insert(line) => out.push(*line),
+            }
+        }
+        assert_eq!(old.next(), None);
+        out
+    }
+
+    #[test]
+    fn test_edit_scripts_are_minimal_and_consistent() {
+        let cases: &[(&[&str], &[&str], usize)] = &[
+            (&[], &[], 0),
+            (&[], &["a"], 1),
+            (&["a", "b", "c"], &["a", "b", "c"], 0),
+            (&["a", "b", "c", "a", "b", "b", "a"], &["c", "b", "a", "b", "a", "c"], 5),
+            (&["x", "a", "y"], &["a"], 2),
+        ];
+        for &(a, b, edits) in cases {
+            let ops = diff_lines(a, b);
+            assert_eq!(apply(a, &ops), b, "{:?} -> {:?}", a, b);
+            assert_eq!(ops.iter().filter(|op| !matches!(op, Op::Equal(_))).count(), edits, "{:?} -> {:?}", a, b);
+        }
+
+        // Too far apart: shown as replaced, still correct
+        let a: Vec<String> = (0..=MAX_EDITS).map(|i| format!("old {}", i)).collect();
+        let b: Vec<String> = (0..=MAX_EDITS).map(|i| format!("new {}", i)).collect();
+        let (a, b): (Vec<&str>, Vec<&str>) = (a.iter().map(String::as_str).collect(), b.iter().map(String::as_str).collect());
+        assert_eq!(apply(&a, &diff_lines(&a, &b)), b);
+    }
+
+    #[test]
+    fn test_unified_hunks() {
+        let old = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        let new = "one\nTWO\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\neleven\n";
+        let diff = unified(Path::new("/home/alice/.ssh/authorized_keys"), Some(old), new, DiffStyle::default());
+        assert_eq!(
+            diff,
+            "--- /home/alice/.ssh/authorized_keys\n+++ /home/alice/.ssh/authorized_keys\n\
+             @@ -1,5 +1,5 @@\n one\n-two\n+TWO\n three\n four\n five\n\
+             @@ -8,3 +8,4 @@\n eight\n nine\n ten\n+eleven\n"
+        );
+        assert_eq!(unified(Path::new("/x"), Some(old), old, DiffStyle::default()), "");
+
+        let created = unified(Path::new("/x"), None, "a\n", DiffStyle::default());
+        assert_eq!(created, "--- /dev/null\n+++ /x\n@@ -0,0 +1 @@\n+a\n");
+        let colored = unified(Path::new("/x"), None, "a\n", DiffStyle { color: true, full: false });
+        assert!(colored.contains("\x1b[32m+a\x1b[0m"), "{:?}", colored);
+    }
+
+    #[test]
+    fn test_key_blobs_elided_unless_full() {
+        let diff = unified(Path::new("/x"), Some(""), KEY, DiffStyle::default());
+        assert!(diff.ends_with("\n+ssh-ed25519 AAAAC3NzaC1l…BLEkip0gGx9e alice@laptop\n"), "{}", diff);
+        assert_eq!(elide_blobs("# PubliKey managed - do not edit manually"), "# PubliKey managed - do not edit manually");
+
+        let full = unified(Path::new("/x"), Some(""), KEY, DiffStyle { color: false, full: true });
+        assert!(full.contains(KEY), "{}", full);
+    }
+}
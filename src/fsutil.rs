@@ -0,0 +1,295 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use tracing::warn;
+
+/// Filesystem operations used by `atomic_write`, mockable in tests
+pub trait FileOps {
+    /// Create `path`, which must not exist yet, with `mode` and write `bytes`
+    fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()>;
+    /// Flush file contents to disk
+    fn sync_file(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Flush a directory entry (e.g. a rename) to disk
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The real filesystem
+pub struct RealFs;
+
+impl FileOps for RealFs {
+    fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+        // The temp file sits in directories users can write to (~/.ssh): never reuse or follow
+        // whatever they planted there
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .custom_flags(nix::libc::O_NOFOLLOW)
+            .open(path)?;
+        // The mode passed to open() is subject to the umask
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.write_all(bytes)
+    }
+
+    fn sync_file(&self, path: &Path) -> io::Result<()> {
+        fs::File::open(path)?.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::File::open(dir)?.sync_all()
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+/// Temporary file written next to `path` before being renamed over it; the random part keeps
+/// others from guessing the name ahead of time
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    let mut temp_name = name.to_os_string();
+    temp_name.push(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    Ok(path.with_file_name(temp_name))
+}
+
+/// Replace `path` with `bytes` so that readers and crashes see either the old or the new content.
+///
+/// The temp file is fsynced before the rename and the directory after it. On failure the
+/// original file is left untouched and the temp file removed.
+pub fn atomic_write(path: &Path, bytes: &[u8], mode: u32) -> Result<()> {
+    atomic_write_with(&RealFs, path, bytes, mode)
+}
+
+pub fn atomic_write_with(ops: &dyn FileOps, path: &Path, bytes: &[u8], mode: u32) -> Result<()> {
+    let temp = temp_path(path)?;
+
+    let staged = ops
+        .write_new(&temp, bytes, mode)
+        .map_err(|e| anyhow!("Failed to write {}: {}", temp.display(), e))
+        .and_then(|_| {
+            ops.sync_file(&temp)
+                .map_err(|e| anyhow!("Failed to sync {}: {}", temp.display(), e))
+        })
+        .and_then(|_| {
+            ops.rename(&temp, path)
+                .map_err(|e| anyhow!("Failed to move {} into place: {}", path.display(), e))
+        });
+    if let Err(e) = staged {
+        if let Err(remove_err) = ops.remove(&temp)
+            && remove_err.kind() != io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove temporary file {}: {}", temp.display(), remove_err);
+        }
+        return Err(e);
+    }
+
+    // The new content is in place; a failed directory sync only weakens durability
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = ops.sync_dir(dir) {
+        warn!("Failed to sync directory {}: {}", dir.display(), e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records calls and delegates to the real filesystem, failing the named operation
+    struct RecordingFs {
+        calls: RefCell<Vec<String>>,
+        fail: Option<&'static str>,
+    }
+
+    impl RecordingFs {
+        fn new(fail: Option<&'static str>) -> Self {
+            Self { calls: RefCell::new(Vec::new()), fail }
+        }
+
+        fn call(&self, op: &'static str, path: &Path) -> io::Result<()> {
+            self.calls.borrow_mut().push(format!("{} {}", op, display_name(path)));
+            if self.fail == Some(op) {
+                return Err(io::Error::other("injected failure"));
+            }
+            Ok(())
+        }
+    }
+
+    /// File name with the random part of a temp file name replaced by `*`
+    fn display_name(path: &Path) -> String {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        match name.strip_suffix(".tmp").and_then(|stem| stem.rsplit_once('.')) {
+            Some((base, random)) if random.len() == 32 => format!("{}.*.tmp", base),
+            _ => name,
+        }
+    }
+
+    /// Files left in `dir` besides `keep`
+    fn leftovers(dir: &Path, keep: &str) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name != keep)
+            .collect()
+    }
+
+    impl FileOps for RecordingFs {
+        fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+            RealFs.write_new(path, bytes, mode)?;
+            self.call("write", path)
+        }
+
+        fn sync_file(&self, path: &Path) -> io::Result<()> {
+            self.call("fsync", path)?;
+            RealFs.sync_file(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.call("rename", from)?;
+            RealFs.rename(from, to)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            self.call("fsync-dir", dir)?;
+            RealFs.sync_dir(dir)
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.call("remove", path)?;
+            RealFs.remove(path)
+        }
+    }
+
+    #[test]
+    fn test_atomic_write_syscall_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("authorized_keys");
+        let ops = RecordingFs::new(None);
+
+        atomic_write_with(&ops, &target, b"ssh-ed25519 AAAA\n", 0o600).unwrap();
+
+        let dir_name = dir.path().file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(
+            *ops.calls.borrow(),
+            vec![
+                "write authorized_keys.*.tmp".to_string(),
+                "fsync authorized_keys.*.tmp".to_string(),
+                "rename authorized_keys.*.tmp".to_string(),
+                format!("fsync-dir {}", dir_name),
+            ]
+        );
+        assert_eq!(fs::read(&target).unwrap(), b"ssh-ed25519 AAAA\n");
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_failed_write_keeps_original_and_removes_temp() {
+        for failing in ["write", "fsync", "rename"] {
+            let dir = tempfile::tempdir().unwrap();
+            let target = dir.path().join("authorized_keys");
+            fs::write(&target, "original\n").unwrap();
+            let ops = RecordingFs::new(Some(failing));
+
+            assert!(atomic_write_with(&ops, &target, b"new\n", 0o600).is_err(), "{}", failing);
+
+            assert_eq!(fs::read_to_string(&target).unwrap(), "original\n", "{}", failing);
+            assert_eq!(leftovers(dir.path(), "authorized_keys"), Vec::<String>::new(), "{}", failing);
+            assert_eq!(ops.calls.borrow().last().unwrap(), "remove authorized_keys.*.tmp", "{}", failing);
+        }
+    }
+
+    #[test]
+    fn test_failed_directory_sync_is_not_fatal() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("state.json");
+        let ops = RecordingFs::new(Some("fsync-dir"));
+
+        atomic_write_with(&ops, &target, b"{}", 0o644).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_atomic_write_replaces_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("last_run.json");
+        fs::write(&target, "old").unwrap();
+
+        atomic_write(&target, b"new", 0o644).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(leftovers(dir.path(), "last_run.json"), Vec::<String>::new());
+    }
+
+    /// Plants a symlink to `victim` at the temp path just before it is created, as a user racing a
+    /// root run in their own ~/.ssh could
+    struct PlantingFs {
+        victim: PathBuf,
+    }
+
+    impl FileOps for PlantingFs {
+        fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+            std::os::unix::fs::symlink(&self.victim, path)?;
+            RealFs.write_new(path, bytes, mode)
+        }
+
+        fn sync_file(&self, path: &Path) -> io::Result<()> {
+            RealFs.sync_file(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            RealFs.rename(from, to)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            RealFs.sync_dir(dir)
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            RealFs.remove(path)
+        }
+    }
+
+    #[test]
+    fn test_planted_temp_symlink_is_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let victim = dir.path().join("shadow");
+        fs::write(&victim, "root:*:19000::::::\n").unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o640)).unwrap();
+        let ssh_dir = dir.path().join(".ssh");
+        fs::create_dir(&ssh_dir).unwrap();
+        let target = ssh_dir.join("authorized_keys");
+        fs::write(&target, "original\n").unwrap();
+
+        let ops = PlantingFs { victim: victim.clone() };
+        assert!(atomic_write_with(&ops, &target, b"ssh-ed25519 AAAA\n", 0o600).is_err());
+
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "root:*:19000::::::\n");
+        assert_eq!(fs::metadata(&victim).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "original\n");
+        // The planted link is cleaned up like any failed temp file
+        assert_eq!(leftovers(&ssh_dir, "authorized_keys"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_temp_names_are_unpredictable() {
+        let path = Path::new("/home/alice/.ssh/authorized_keys");
+        let (first, second) = (temp_path(path).unwrap(), temp_path(path).unwrap());
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        assert_eq!(display_name(&first), "authorized_keys.*.tmp");
+    }
+}
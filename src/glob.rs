@@ -0,0 +1,74 @@
+/// Match a path against a shell-style glob pattern.
+///
+/// `*` matches any run of characters except `/`, `**` also crosses `/`,
+/// and `?` matches a single character other than `/`.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    match_from(&pattern, &path)
+}
+
+fn match_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            (0..=path.len()).any(|i| match_from(rest, &path[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=path.len() {
+                if match_from(rest, &path[i..]) {
+                    return true;
+                }
+                if path.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => matches!(path.first(), Some(c) if *c != '/') && match_from(&pattern[1..], &path[1..]),
+        Some(c) => path.first() == Some(c) && match_from(&pattern[1..], &path[1..]),
+    }
+}
+
+/// Whether a path passes include/exclude glob filters (exclude wins over include)
+pub fn path_allowed(path: &str, include: &[String], exclude: &[String]) -> bool {
+    if exclude.iter().any(|pattern| glob_match(pattern, path)) {
+        return false;
+    }
+    include.is_empty() || include.iter().any(|pattern| glob_match(pattern, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/etc/ssh/keys/*", "/etc/ssh/keys/alice"));
+        assert!(!glob_match("/etc/ssh/keys/*", "/etc/ssh/keys/alice/authorized_keys"));
+        assert!(glob_match("/etc/ssh/keys/**", "/etc/ssh/keys/alice/authorized_keys"));
+        assert!(glob_match("/home/*/.ssh/authorized_keys", "/home/bob/.ssh/authorized_keys"));
+        assert!(glob_match("/home/?ob/.ssh/*", "/home/bob/.ssh/authorized_keys2"));
+        assert!(!glob_match("/home/?ob/.ssh/*", "/home/bob/.ssh"));
+        assert!(glob_match("**/authorized_keys", "/root/.ssh/authorized_keys"));
+        assert!(!glob_match("/root/.ssh/authorized_keys", "/root/.ssh/authorized_keys2"));
+    }
+
+    #[test]
+    fn test_path_allowed() {
+        let exclude = vec!["/etc/ssh/keys/*".to_string()];
+        let include = vec!["/home/**".to_string(), "/etc/ssh/keys/*".to_string()];
+
+        assert!(path_allowed("/home/alice/.ssh/authorized_keys", &[], &exclude));
+        assert!(!path_allowed("/etc/ssh/keys/alice", &[], &exclude));
+
+        assert!(path_allowed("/home/alice/.ssh/authorized_keys", &include, &[]));
+        assert!(!path_allowed("/root/.ssh/authorized_keys", &include, &[]));
+
+        // Both flags: exclude wins
+        assert!(!path_allowed("/etc/ssh/keys/alice", &include, &exclude));
+        assert!(path_allowed("/home/alice/.ssh/authorized_keys", &include, &exclude));
+    }
+}
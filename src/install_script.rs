@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use secrecy::SecretString;
+
+use crate::service::{self, Schedule, ServiceUnits};
+use crate::setup::{self, SetupAnswers};
+use crate::update::UpdateManager;
+
+/// Where the installer puts the binary
+const INSTALL_PATH: &str = "/usr/local/bin/pkagent";
+
+/// Literal left in the script by --token-placeholder, for provisioning tools to substitute
+pub const TOKEN_PLACEHOLDER: &str = "@PUBLIKEY_TOKEN@";
+
+/// Platforms the installer supports: `uname -s`, `uname -m`, and the release OS and arch names
+const PLATFORMS: &[(&str, &str, &str, &str)] = &[
+    ("Linux", "x86_64", "linux", "x86_64"),
+    ("Linux", "aarch64", "linux", "aarch64"),
+    ("Linux", "arm64", "linux", "aarch64"),
+    ("Linux", "armv7l", "linux", "arm"),
+];
+
+/// The installer; `{{NAME}}` markers are filled in by `render_template`
+const TEMPLATE: &str = r#"#!/bin/sh
+# PubliKey Agent installer (generated by pkagent {{AGENT_VERSION}} print-install-script)
+set -eu
+
+PUBLIKEY_ENDPOINT='{{ENDPOINT}}'
+{{TOKEN_ASSIGNMENT}}
+RELEASE_URL='{{RELEASE_URL}}'
+INSTALL_PATH='{{INSTALL_PATH}}'
+CONFIG_PATH='{{CONFIG_PATH}}'
+UNIT_DIR='{{UNIT_DIR}}'
+
+if [ "$(id -u)" -ne 0 ]; then
+    echo "This installer must run as root" >&2
+    exit 1
+fi
+
+case "$(uname -s)-$(uname -m)" in
+{{PLATFORM_CASES}}    *)
+        echo "Unsupported platform: $(uname -s) $(uname -m)" >&2
+        exit 1
+        ;;
+esac
+
+fetch() {
+    if command -v curl >/dev/null 2>&1; then
+        curl -fsSL -o "$2" "$1"
+    elif command -v wget >/dev/null 2>&1; then
+        wget -q -O "$2" "$1"
+    else
+        echo "curl or wget is required" >&2
+        exit 1
+    fi
+}
+
+TMP_DIR=$(mktemp -d)
+trap 'rm -rf "$TMP_DIR"' EXIT
+
+echo "Downloading $BINARY_NAME..."
+fetch "$RELEASE_URL/$BINARY_NAME" "$TMP_DIR/$BINARY_NAME"
+fetch "$RELEASE_URL/$BINARY_NAME.sha256" "$TMP_DIR/$BINARY_NAME.sha256"
+
+EXPECTED=$(cut -d ' ' -f 1 "$TMP_DIR/$BINARY_NAME.sha256")
+if command -v sha256sum >/dev/null 2>&1; then
+    ACTUAL=$(sha256sum "$TMP_DIR/$BINARY_NAME" | cut -d ' ' -f 1)
+else
+    ACTUAL=$(shasum -a 256 "$TMP_DIR/$BINARY_NAME" | cut -d ' ' -f 1)
+fi
+if [ "$EXPECTED" != "$ACTUAL" ]; then
+    echo "Checksum mismatch for $BINARY_NAME: expected $EXPECTED, got $ACTUAL" >&2
+    exit 1
+fi
+
+install -m 0755 "$TMP_DIR/$BINARY_NAME" "$INSTALL_PATH"
+echo "Installed $INSTALL_PATH"
+
+mkdir -p "$(dirname "$CONFIG_PATH")"
+(
+    umask 077
+    cat > "$CONFIG_PATH" <<EOF
+{{CONFIG}}EOF
+)
+echo "Wrote $CONFIG_PATH"
+
+cat > "$UNIT_DIR/{{SERVICE_NAME}}" <<'EOF'
+{{SERVICE_UNIT}}EOF
+cat > "$UNIT_DIR/{{TIMER_NAME}}" <<'EOF'
+{{TIMER_UNIT}}EOF
+
+systemctl daemon-reload
+systemctl {{ENABLE_TIMER}}
+echo "PubliKey Agent installed; check it with: systemctl status {{TIMER_NAME}}"
+"#;
+
+/// Replace every `{{NAME}}` in `template`; unknown or unfilled markers are an error
+fn render_template(template: &str, values: &[(&str, String)]) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or_else(|| anyhow!("Unterminated template marker"))? + start;
+        let name = &rest[start + 2..end];
+        let value = values
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| anyhow!("No value for template marker {{{{{}}}}}", name))?;
+        output.push_str(&rest[..start]);
+        output.push_str(value);
+        rest = &rest[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// `case` arms mapping `uname` output to release asset names
+fn platform_cases() -> String {
+    PLATFORMS
+        .iter()
+        .map(|(uname_s, uname_m, os, arch)| {
+            format!(
+                "    {}-{})\n        BINARY_NAME='{}'\n        ;;\n",
+                uname_s,
+                uname_m,
+                UpdateManager::binary_name(os, arch)
+            )
+        })
+        .collect()
+}
+
+/// Generate the installer for `endpoint`, downloading the release of `agent_version`.
+///
+/// The token never appears in the script: it is read from PUBLIKEY_TOKEN when the script runs, or,
+/// with `token_placeholder`, left as `TOKEN_PLACEHOLDER` for the operator to fill in. The systemd
+/// units and config come from the templates `pkagent install-service` installs.
+pub fn render(endpoint: &str, token_placeholder: bool, agent_version: &str) -> Result<String> {
+    if endpoint.is_empty() || endpoint.contains(|c: char| c == '\'' || c.is_whitespace()) {
+        return Err(anyhow!("Endpoint {:?} cannot be embedded in the install script", endpoint));
+    }
+
+    // Rendered with shell variables, expanded by the config heredoc at install time
+    let answers = SetupAnswers {
+        endpoint: "${PUBLIKEY_ENDPOINT}".to_string(),
+        token: SecretString::from("${PUBLIKEY_TOKEN}"),
+        user_mode: false,
+        schedule: setup::DEFAULT_SCHEDULE.to_string(),
+        config_path: PathBuf::from(setup::SYSTEM_CONFIG_PATH),
+        install_service: true,
+    };
+    let schedule = Schedule::Calendar(answers.schedule.clone());
+    let (service, timer) = ServiceUnits::system(Path::new(INSTALL_PATH), &answers.config_path, schedule).render();
+    let token_assignment = if token_placeholder {
+        format!("PUBLIKEY_TOKEN='{}'", TOKEN_PLACEHOLDER)
+    } else {
+        "PUBLIKEY_TOKEN=\"${PUBLIKEY_TOKEN:?set PUBLIKEY_TOKEN to the agent token}\"".to_string()
+    };
+
+    render_template(
+        TEMPLATE,
+        &[
+            ("AGENT_VERSION", agent_version.to_string()),
+            ("ENDPOINT", endpoint.to_string()),
+            ("TOKEN_ASSIGNMENT", token_assignment),
+            ("RELEASE_URL", UpdateManager::release_download_url(agent_version)),
+            ("INSTALL_PATH", INSTALL_PATH.to_string()),
+            ("CONFIG_PATH", setup::SYSTEM_CONFIG_PATH.to_string()),
+            ("PLATFORM_CASES", platform_cases()),
+            ("CONFIG", setup::render_config(&answers, "print-install-script")),
+            ("UNIT_DIR", service::SYSTEM_UNIT_DIR.to_string()),
+            ("SERVICE_NAME", service::SERVICE_UNIT.to_string()),
+            ("SERVICE_UNIT", service),
+            ("TIMER_NAME", service::TIMER_UNIT.to_string()),
+            ("TIMER_UNIT", timer),
+            ("ENABLE_TIMER", service::ENABLE_TIMER.join(" ")),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_render_template() {
+        let values = [("A", "1".to_string()), ("B", "two".to_string())];
+        assert_eq!(render_template("{{A}}-{{B}}-{{A}}", &values).unwrap(), "1-two-1");
+        assert_eq!(render_template("no markers", &values).unwrap(), "no markers");
+        assert!(render_template("{{C}}", &values).unwrap_err().to_string().contains("{{C}}"));
+        assert!(render_template("{{A", &values).is_err());
+        // Substituted values are not scanned again
+        assert_eq!(render_template("{{A}}", &[("A", "{{B}}".to_string())]).unwrap(), "{{B}}");
+    }
+
+    #[test]
+    fn test_script_content() {
+        let script = render("https://publikey.example.com", false, "0.4.0").unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("PUBLIKEY_ENDPOINT='https://publikey.example.com'\n"));
+        assert!(script.contains("RELEASE_URL='https://github.com/ruohki/agent/releases/download/v0.4.0'\n"));
+        assert!(script.contains("    Linux-x86_64)\n        BINARY_NAME='pkagent-linux-x86_64'\n"));
+        assert!(script.contains("    Linux-arm64)\n        BINARY_NAME='pkagent-linux-aarch64'\n"));
+        assert!(script.contains("PUBLIKEY_ENDPOINT=${PUBLIKEY_ENDPOINT}\nPUBLIKEY_TOKEN=${PUBLIKEY_TOKEN}\n"));
+        assert!(script.contains("ExecStart=/usr/local/bin/pkagent run\n"));
+        assert!(script.contains("EnvironmentFile=/etc/publikey/agent.env\n"));
+        assert!(script.contains("ProtectSystem=strict\n"));
+        assert!(script.contains("cat > \"$UNIT_DIR/publikey-agent.timer\" <<'EOF'\n"));
+        assert!(script.contains("systemctl enable --now publikey-agent.timer\n"));
+        assert!(!script.contains("{{"));
+        assert!(!script.contains(TOKEN_PLACEHOLDER));
+
+        let with_placeholder = render("https://publikey.example.com", true, "0.4.0").unwrap();
+        assert!(with_placeholder.contains("PUBLIKEY_TOKEN='@PUBLIKEY_TOKEN@'\n"));
+
+        assert!(render("https://pk.example.com/'; rm -rf /", false, "0.4.0").is_err());
+    }
+
+    #[test]
+    fn test_script_syntax() {
+        for token_placeholder in [false, true] {
+            let script = render("https://publikey.example.com", token_placeholder, "0.4.0").unwrap();
+            let mut sh = Command::new("sh").arg("-n").stdin(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+            sh.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+            let output = sh.wait_with_output().unwrap();
+            assert!(output.status.success(), "sh -n: {}", String::from_utf8_lossy(&output.stderr));
+        }
+    }
+}
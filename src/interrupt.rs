@@ -0,0 +1,106 @@
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Set once SIGTERM or SIGINT arrives. The sync checks it between users so a stop never leaves a
+/// file half-written; everything else stops at its next await.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+static INSTALLED: OnceLock<Interrupt> = OnceLock::new();
+
+impl Interrupt {
+    /// Catch SIGTERM and SIGINT for the rest of the process instead of dying on them
+    pub fn install() -> Result<Self> {
+        if let Some(interrupt) = INSTALLED.get() {
+            return Ok(interrupt.clone());
+        }
+        let interrupt = INSTALLED.get_or_init(Self::default).clone();
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut int = signal(SignalKind::interrupt())?;
+        let handler = interrupt.clone();
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = int.recv() => "SIGINT",
+            };
+            warn!("Received {}, stopping after the current file", name);
+            handler.trigger();
+        });
+        Ok(interrupt)
+    }
+
+    /// The process-wide interrupt once installed, otherwise one that never fires
+    pub fn current() -> Self {
+        INSTALLED.get().cloned().unwrap_or_default()
+    }
+
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once interrupted
+    pub async fn wait(&self) {
+        loop {
+            // Registered before the check so a trigger in between is not missed
+            let notified = self.notify.notified();
+            if self.is_set() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Error a run ends with when it was interrupted
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Interrupted by signal")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Whether `error` (or its cause) is an interruption
+pub fn is_interrupted(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Interrupted>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_wait_returns_once_triggered() {
+        let interrupt = Interrupt::default();
+        assert!(!interrupt.is_set());
+        let waiter = tokio::spawn({
+            let interrupt = interrupt.clone();
+            async move { interrupt.wait().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        interrupt.trigger();
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert!(interrupt.is_set());
+
+        // Already interrupted: returns immediately
+        tokio::time::timeout(Duration::from_secs(5), interrupt.wait()).await.unwrap();
+        assert!(is_interrupted(&anyhow::Error::new(Interrupted).context("sync")));
+    }
+}
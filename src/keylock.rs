@@ -0,0 +1,131 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use tracing::debug;
+
+/// Default wait for another tool to release an authorized_keys lock
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause between attempts while the lock is held elsewhere
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Lock file guarding `keys_file`: a sidecar `<file>.lock` in the same directory.
+///
+/// Other key management tools can cooperate by taking an exclusive `flock(2)` on this path
+/// for as long as they read and rewrite the authorized_keys file. The file itself cannot
+/// be locked because writes replace it by renaming a temporary file over it.
+pub fn lock_path(keys_file: &Path) -> PathBuf {
+    let mut name = keys_file.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    keys_file.with_file_name(name)
+}
+
+/// An exclusive lock on an authorized_keys file, released on drop
+#[derive(Debug)]
+pub struct KeyFileLock {
+    _lock: Flock<File>,
+}
+
+/// Lock `keys_file`, waiting up to `timeout` for another holder.
+///
+/// `None` when the file's directory does not exist yet, so there is nothing another tool could be
+/// writing. A holder that outlasts the timeout is reported as `ErrorKind::TimedOut`.
+pub fn lock(keys_file: &Path, timeout: Duration) -> io::Result<Option<KeyFileLock>> {
+    if !keys_file.parent().is_some_and(Path::is_dir) {
+        return Ok(None);
+    }
+    let path = lock_path(keys_file);
+    // Never follow a symlink planted in a user-writable directory; readable by all so tools
+    // running as the user can take the lock too
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o644)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(&path)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => return Ok(Some(KeyFileLock { _lock: lock })),
+            Err((returned, Errno::EWOULDBLOCK)) => {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} still held by another process after {:?}", path.display(), timeout),
+                    ));
+                }
+                debug!("Waiting for {}", path.display());
+                file = returned;
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+            Err((_, errno)) => return Err(io::Error::from(errno)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Hold the lock on `keys_file` from another thread for `hold`; returns once it is taken
+    fn hold_in_thread(keys_file: &Path, hold: Duration) -> std::thread::JoinHandle<()> {
+        let keys_file = keys_file.to_path_buf();
+        let (taken, wait) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let _lock = lock(&keys_file, Duration::ZERO).unwrap().unwrap();
+            taken.send(()).unwrap();
+            std::thread::sleep(hold);
+        });
+        wait.recv().unwrap();
+        handle
+    }
+
+    #[test]
+    fn test_lock_path() {
+        assert_eq!(lock_path(Path::new("/home/a/.ssh/authorized_keys")), Path::new("/home/a/.ssh/authorized_keys.lock"));
+    }
+
+    #[test]
+    fn test_waits_for_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys_file = dir.path().join("authorized_keys");
+        let holder = hold_in_thread(&keys_file, Duration::from_millis(200));
+
+        let start = Instant::now();
+        assert!(lock(&keys_file, Duration::from_secs(5)).unwrap().is_some());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys_file = dir.path().join("authorized_keys");
+        let holder = hold_in_thread(&keys_file, Duration::from_millis(500));
+
+        let err = lock(&keys_file, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        holder.join().unwrap();
+
+        // Released on drop
+        assert!(lock(&keys_file, Duration::ZERO).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_missing_directory_and_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(lock(&dir.path().join("missing/authorized_keys"), Duration::ZERO).unwrap().is_none());
+
+        let keys_file = dir.path().join("authorized_keys");
+        std::os::unix::fs::symlink(dir.path().join("elsewhere"), lock_path(&keys_file)).unwrap();
+        assert!(lock(&keys_file, Duration::ZERO).is_err());
+        assert!(!dir.path().join("elsewhere").exists());
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::fsutil;
+use crate::ssh_keys::is_safe_username;
+
+/// Subdirectory of the state directory used when --cache-dir is not given
+pub const DEFAULT_CACHE_SUBDIR: &str = "authorized-keys";
+
+/// Keys printed for a user by an earlier authorized-keys lookup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedKeys {
+    /// Seconds since the epoch
+    #[serde(rename = "fetchedAt")]
+    pub fetched_at: u64,
+    pub keys: Vec<String>,
+}
+
+/// Per-user cache of authorized-keys lookups, one JSON file per user
+pub struct KeysCache {
+    dir: PathBuf,
+}
+
+impl KeysCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, username: &str) -> Result<PathBuf> {
+        if !is_safe_username(username) {
+            return Err(anyhow!("refusing to cache keys for unsafe username {:?}", username));
+        }
+        Ok(self.dir.join(format!("{}.json", username)))
+    }
+
+    /// The cached entry for `username`. Entries that are unreadable, corrupt, or not private to the
+    /// agent's user (root:root 0600 for the system agent) are discarded.
+    pub fn load(&self, username: &str) -> Option<CachedKeys> {
+        let path = self.path(username).ok()?;
+        let metadata = fs::symlink_metadata(&path).ok()?;
+        if let Err(e) = check_private(&metadata) {
+            warn!("Discarding cache entry {}: {}", path.display(), e);
+            discard(&path);
+            return None;
+        }
+        let parsed = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| serde_json::from_slice(&data).map_err(anyhow::Error::from));
+        match parsed {
+            Ok(cached) => Some(cached),
+            Err(e) => {
+                warn!("Discarding corrupt cache entry {}: {}", path.display(), e);
+                discard(&path);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, username: &str, cached: &CachedKeys) -> Result<()> {
+        let path = self.path(username)?;
+        fs::create_dir_all(&self.dir).map_err(|e| anyhow!("Failed to create {}: {}", self.dir.display(), e))?;
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))
+            .map_err(|e| anyhow!("Failed to set permissions on {}: {}", self.dir.display(), e))?;
+        fsutil::atomic_write(&path, &serde_json::to_vec(cached)?, 0o600)
+    }
+}
+
+/// A regular file owned by this process's user and group, unreadable to anyone else
+fn check_private(metadata: &fs::Metadata) -> Result<()> {
+    if !metadata.is_file() {
+        return Err(anyhow!("not a regular file"));
+    }
+    let (uid, gid) = (nix::unistd::geteuid().as_raw(), nix::unistd::getegid().as_raw());
+    if metadata.uid() != uid || metadata.gid() != gid {
+        return Err(anyhow!("owned by {}:{}, expected {}:{}", metadata.uid(), metadata.gid(), uid, gid));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!("mode {:o} is not 0600", metadata.mode() & 0o777));
+    }
+    Ok(())
+}
+
+fn discard(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached() -> CachedKeys {
+        CachedKeys {
+            fetched_at: 1_000,
+            keys: vec!["ssh-ed25519 AAAA alice@laptop".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = KeysCache::new(dir.path().join("cache"));
+        assert_eq!(cache.load("alice"), None);
+
+        cache.store("alice", &cached()).unwrap();
+        assert_eq!(cache.load("alice"), Some(cached()));
+        let path = dir.path().join("cache/alice.json");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(dir.path().join("cache")).unwrap().permissions().mode() & 0o777, 0o700);
+
+        assert!(cache.store("../etc/passwd", &cached()).is_err());
+        assert_eq!(cache.load("../etc/passwd"), None);
+    }
+
+    #[test]
+    fn test_bad_entries_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = KeysCache::new(dir.path().to_path_buf());
+
+        fs::write(dir.path().join("alice.json"), "{\"fetchedAt\": 1").unwrap();
+        fs::set_permissions(dir.path().join("alice.json"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(cache.load("alice"), None);
+        assert!(!dir.path().join("alice.json").exists());
+
+        cache.store("bob", &cached()).unwrap();
+        fs::set_permissions(dir.path().join("bob.json"), fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(cache.load("bob"), None);
+        assert!(!dir.path().join("bob.json").exists());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use serde::Serialize;
+
+use crate::users::UserInfo;
+
+/// Files pam_nologin checks, in order
+const NOLOGIN_PATHS: &[&str] = &["/var/run/nologin", "/etc/nologin"];
+
+/// PAM stacks sshd uses directly or through includes
+const PAM_CONFIG_PATHS: &[&str] = &[
+    "/etc/pam.d/sshd",
+    "/etc/pam.d/common-account",
+    "/etc/pam.d/system-auth",
+    "/etc/pam.d/password-auth",
+];
+
+/// nologin messages are reported up to this many bytes
+const NOLOGIN_MESSAGE_LIMIT: usize = 1024;
+
+/// Something outside the authorized_keys files that refuses logins
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum LoginBlocker {
+    /// A nologin file exists, so pam_nologin refuses everyone but root
+    Nologin { message: String },
+    /// A pam_access rule denies logins from everywhere
+    AccessDenied { rule: String },
+    /// The shadow expiry date has passed
+    AccountExpired {
+        #[serde(rename = "expiredAt")]
+        expired_at: u64,
+    },
+}
+
+impl fmt::Display for LoginBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginBlocker::Nologin { message } if message.is_empty() => {
+                write!(f, "nologin file present, non-root logins are refused")
+            }
+            LoginBlocker::Nologin { message } => {
+                write!(f, "nologin file present, non-root logins are refused: {}", message)
+            }
+            LoginBlocker::AccessDenied { rule } => write!(f, "pam_access denies logins ({})", rule),
+            LoginBlocker::AccountExpired { .. } => write!(f, "account has expired"),
+        }
+    }
+}
+
+/// Raw inputs for login blocker detection
+#[derive(Debug, Default)]
+pub struct LoginSources {
+    pub nologin: Option<String>,
+    pub access_conf: Option<String>,
+    /// Contents of the PAM stacks, to tell whether pam_access is in use
+    pub pam_configs: Vec<String>,
+    pub shadow: Option<String>,
+    /// /proc/self/status, for the umask
+    pub proc_status: Option<String>,
+}
+
+impl LoginSources {
+    pub fn read() -> Self {
+        Self {
+            nologin: NOLOGIN_PATHS.iter().find_map(|path| fs::read_to_string(path).ok()),
+            access_conf: fs::read_to_string("/etc/security/access.conf").ok(),
+            pam_configs: PAM_CONFIG_PATHS.iter().filter_map(|path| fs::read_to_string(path).ok()).collect(),
+            shadow: fs::read_to_string("/etc/shadow").ok(),
+            proc_status: fs::read_to_string("/proc/self/status").ok(),
+        }
+    }
+}
+
+/// Login blockers found on this host
+#[derive(Debug, Default)]
+pub struct LoginFindings {
+    pub host: Vec<LoginBlocker>,
+    pub users: HashMap<String, Vec<LoginBlocker>>,
+    /// The agent's umask, e.g. "0022"
+    pub umask: Option<String>,
+}
+
+impl LoginFindings {
+    /// Blockers affecting `user`, including nologin for everyone but root
+    pub fn for_user(&self, user: &UserInfo) -> Vec<LoginBlocker> {
+        // Host-wide access rules are already evaluated per user
+        let host = self
+            .host
+            .iter()
+            .filter(|blocker| user.uid != 0 && matches!(blocker, LoginBlocker::Nologin { .. }))
+            .cloned();
+        host.chain(self.users.get(&user.username).into_iter().flatten().cloned()).collect()
+    }
+}
+
+/// Best-effort detection of login blockers; `now` is in seconds since the epoch
+pub fn detect(sources: &LoginSources, users: &[UserInfo], now: u64) -> LoginFindings {
+    let mut findings = LoginFindings {
+        umask: sources.proc_status.as_deref().and_then(parse_umask),
+        ..Default::default()
+    };
+
+    if let Some(content) = &sources.nologin {
+        let mut message = content.trim().to_string();
+        if message.len() > NOLOGIN_MESSAGE_LIMIT {
+            let mut end = NOLOGIN_MESSAGE_LIMIT;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        findings.host.push(LoginBlocker::Nologin { message });
+    }
+
+    let access_rules = match &sources.access_conf {
+        Some(content) if pam_access_enabled(&sources.pam_configs) => parse_access_conf(content),
+        _ => Vec::new(),
+    };
+    if let Some(rule) = access_rules.iter().find(|rule| rule.is_blanket_deny()) {
+        findings.host.push(LoginBlocker::AccessDenied { rule: rule.line.clone() });
+    }
+
+    let expiry = sources.shadow.as_deref().map(parse_shadow_expiry).unwrap_or_default();
+    for user in users {
+        let mut blockers = Vec::new();
+        if let Some(rule) = access_denied_by(&access_rules, &user.username) {
+            blockers.push(LoginBlocker::AccessDenied { rule: rule.line.clone() });
+        }
+        if let Some(&expires_at) = expiry.get(&user.username)
+            && expires_at <= now
+        {
+            blockers.push(LoginBlocker::AccountExpired { expired_at: expires_at });
+        }
+        if !blockers.is_empty() {
+            findings.users.insert(user.username.clone(), blockers);
+        }
+    }
+    findings
+}
+
+/// Umask from /proc/self/status (Linux 4.7+)
+fn parse_umask(status: &str) -> Option<String> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .map(|value| value.trim().to_string())
+}
+
+fn pam_access_enabled(pam_configs: &[String]) -> bool {
+    pam_configs.iter().flat_map(|content| content.lines()).any(|line| {
+        let line = line.trim();
+        !line.starts_with('#') && line.contains("pam_access.so")
+    })
+}
+
+/// Account expiry per user from /etc/shadow, in seconds since the epoch
+fn parse_shadow_expiry(content: &str) -> HashMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let days: u64 = fields.get(7)?.trim().parse().ok()?;
+            // 0 is ambiguous (no expiry or 1970-01-01); shadow(5) says not to use it
+            (days > 0).then(|| (fields[0].to_string(), days * 24 * 60 * 60))
+        })
+        .collect()
+}
+
+/// One `permission : users : origins` line of access.conf
+#[derive(Debug)]
+struct AccessRule {
+    line: String,
+    deny: bool,
+    users: Vec<String>,
+    origins: Vec<String>,
+}
+
+impl AccessRule {
+    fn is_blanket_deny(&self) -> bool {
+        self.deny && self.users.first().is_some_and(|u| u == "ALL") && self.origins == ["ALL"]
+    }
+}
+
+fn parse_access_conf(content: &str) -> Vec<AccessRule> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.splitn(3, ':').map(str::trim).collect();
+            let [permission, users, origins] = fields.as_slice() else {
+                return None;
+            };
+            let deny = match *permission {
+                "+" => false,
+                "-" => true,
+                _ => return None,
+            };
+            let tokens = |list: &str| list.split([' ', '\t', ',']).filter(|t| !t.is_empty()).map(str::to_string).collect();
+            Some(AccessRule {
+                line: line.to_string(),
+                deny,
+                users: tokens(users),
+                origins: tokens(origins),
+            })
+        })
+        .collect()
+}
+
+/// Whether a token list with optional `EXCEPT` matches `username`; `None` when that
+/// depends on group or netgroup membership we do not evaluate
+fn list_matches(tokens: &[String], username: &str) -> Option<bool> {
+    let (items, exceptions) = match tokens.iter().position(|t| t == "EXCEPT") {
+        Some(index) => (&tokens[..index], Some(&tokens[index + 1..])),
+        None => (tokens, None),
+    };
+
+    let mut matched = Some(false);
+    for token in items {
+        let token_match = if token == "ALL" {
+            Some(true)
+        } else if token.starts_with('(') || token.contains('@') {
+            None
+        } else {
+            Some(token == username)
+        };
+        match token_match {
+            Some(true) => {
+                matched = Some(true);
+                break;
+            }
+            None => matched = None,
+            Some(false) => {}
+        }
+    }
+
+    match (matched, exceptions) {
+        (Some(true), Some(exceptions)) => list_matches(exceptions, username).map(|excepted| !excepted),
+        _ => matched,
+    }
+}
+
+/// The rule denying `username` from everywhere, if pam_access would stop there.
+///
+/// Rules are first-match; a rule that may apply but limits origins makes the outcome
+/// connection-dependent, so evaluation stops without reporting anything.
+fn access_denied_by<'a>(rules: &'a [AccessRule], username: &str) -> Option<&'a AccessRule> {
+    for rule in rules {
+        match list_matches(&rule.users, username) {
+            Some(false) => continue,
+            Some(true) if rule.origins == ["ALL"] => return rule.deny.then_some(rule),
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCESS_CONF: &str = "\
+# Login access control table
++ : root : ALL
++ : ops deploy : 10.0.0.0/8
+- : ALL EXCEPT root alice : ALL
+";
+
+    const SHADOW: &str = "\
+root:*:19000:0:99999:7:::
+alice:$6$x:19000:0:99999:7::19500:
+bob:$6$y:19000:0:99999:7::30000:
+carol:!:19000:0:99999:7::0:
+";
+
+    fn user(username: &str, uid: u32) -> UserInfo {
+        UserInfo {
+            username: username.to_string(),
+            uid,
+            shell: Some("/bin/bash".to_string()),
+            home_dir: Some(format!("/home/{}", username)),
+            disabled: Some(false),
+            home_encryption: None,
+            home_mounted: None,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        }
+    }
+
+    fn sources() -> LoginSources {
+        LoginSources {
+            nologin: Some("System maintenance until 18:00\n".to_string()),
+            access_conf: Some(ACCESS_CONF.to_string()),
+            pam_configs: vec!["account  required  pam_access.so\n".to_string()],
+            shadow: Some(SHADOW.to_string()),
+            proc_status: Some("Name:\tpkagent\nUmask:\t0022\nState:\tR (running)\n".to_string()),
+        }
+    }
+
+    // 2024-01-01
+    const NOW: u64 = 19723 * 24 * 60 * 60;
+
+    #[test]
+    fn test_host_blockers() {
+        let findings = detect(&sources(), &[], NOW);
+        assert_eq!(findings.umask.as_deref(), Some("0022"));
+        assert_eq!(
+            findings.host,
+            vec![
+                LoginBlocker::Nologin { message: "System maintenance until 18:00".to_string() },
+                LoginBlocker::AccessDenied { rule: "- : ALL EXCEPT root alice : ALL".to_string() },
+            ]
+        );
+
+        // access.conf only matters when PAM loads pam_access
+        let findings = detect(&LoginSources { pam_configs: vec!["# account required pam_access.so\n".to_string()], ..sources() }, &[], NOW);
+        assert_eq!(findings.host.len(), 1);
+        assert!(detect(&LoginSources::default(), &[], NOW).host.is_empty());
+    }
+
+    #[test]
+    fn test_user_blockers() {
+        let users = [user("root", 0), user("alice", 1000), user("bob", 1001), user("carol", 1002), user("ops", 1003), user("dave", 1004)];
+        let findings = detect(&sources(), &users, NOW);
+
+        assert!(!findings.users.contains_key("root"));
+        assert_eq!(findings.users["alice"], vec![LoginBlocker::AccountExpired { expired_at: 19500 * 24 * 60 * 60 }]);
+        assert_eq!(findings.users["bob"], vec![LoginBlocker::AccessDenied { rule: "- : ALL EXCEPT root alice : ALL".to_string() }]);
+        assert!(findings.users["carol"].iter().all(|b| matches!(b, LoginBlocker::AccessDenied { .. })));
+        assert!(findings.users.contains_key("dave"));
+        // ops may log in from 10.0.0.0/8, so whether they are denied depends on the connection
+        assert!(!findings.users.contains_key("ops"));
+
+        // Group rules we cannot evaluate stop evaluation before the blanket deny
+        let rules = parse_access_conf("+ : (admins) : ALL\n- : ALL : ALL\n");
+        assert!(access_denied_by(&rules, "dave").is_none());
+
+        // root is exempt from nologin
+        assert!(findings.for_user(&users[0]).is_empty());
+        assert_eq!(findings.for_user(&users[1]).len(), 2);
+    }
+
+    #[test]
+    fn test_list_matches() {
+        let tokens = |list: &str| list.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(list_matches(&tokens("ALL"), "alice"), Some(true));
+        assert_eq!(list_matches(&tokens("bob alice"), "alice"), Some(true));
+        assert_eq!(list_matches(&tokens("bob"), "alice"), Some(false));
+        assert_eq!(list_matches(&tokens("ALL EXCEPT alice"), "alice"), Some(false));
+        assert_eq!(list_matches(&tokens("ALL EXCEPT (wheel)"), "alice"), None);
+        assert_eq!(list_matches(&tokens("@staff bob"), "alice"), None);
+        assert_eq!(list_matches(&tokens("@staff alice"), "alice"), Some(true));
+    }
+
+    #[test]
+    fn test_nologin_message_is_capped() {
+        let sources = LoginSources {
+            nologin: Some("é".repeat(NOLOGIN_MESSAGE_LIMIT)),
+            ..Default::default()
+        };
+        let findings = detect(&sources, &[], NOW);
+        let LoginBlocker::Nologin { message } = &findings.host[0] else {
+            panic!("expected nologin");
+        };
+        assert!(message.len() <= NOLOGIN_MESSAGE_LIMIT);
+    }
+}
@@ -1,183 +1,1003 @@
+#[macro_use]
+mod output;
+mod bundle;
+mod assert_clean;
+mod check;
+mod diff;
+mod fsutil;
 mod cli;
 mod system;
 mod users;
 mod api;
 mod ssh_keys;
 mod update;
+mod policy;
+mod setup;
+mod spool;
+mod state;
+mod glob;
+mod report;
+mod webhook;
+mod preflight;
+mod pin;
+mod rollout;
+mod sshd;
+mod login;
+mod capabilities;
+mod ssh_dir_scan;
+mod token;
+mod trust;
+mod client_cert;
+mod proxy;
+mod keylock;
+mod motd;
+mod config;
+mod package;
+mod install_script;
+mod optout;
+mod offline;
+mod assignment_cache;
+mod assignment_signature;
+mod keys_cache;
+mod service;
+mod notify;
+mod interrupt;
+mod sse;
+mod control;
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod sync_properties;
 
-use clap::Parser;
 use tracing::{info, error, warn, instrument};
 use anyhow::Result;
+use std::io::IsTerminal;
 
-use cli::Args;
+use cli::{Cli, Command, InstallServiceArgs, RunArgs, UpdateArgs};
+use output::{ExitCode, OutputFormat, ReportedHost, RunError, RunSummary};
 use api::{ApiClient, AgentReport};
-use ssh_keys::SshKeyManager;
-use update::UpdateManager;
+use ssh_keys::{DryRunScope, KeySyncStats, OrphanMode, SshKeyManager};
+use update::{UpdateManager, UpdateOutcome};
+use policy::KeyPolicy;
+use spool::Spool;
+use state::StateDir;
+use capabilities::Capabilities;
+use report::ReportSections;
+use webhook::WebhookNotifier;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init();
     
-    let args = Args::parse();
+    let cli = Cli::parse_with_config()?;
+    let args = cli.run_args();
     
-    println!("PubliKey Agent v{}", args.agent_version);
-    if let Some(ref endpoint) = args.endpoint {
-        println!("Endpoint: {}", endpoint);
+    // Keep the token out of core dumps
+    if !args.allow_core_dumps
+        && let Err(e) = disable_core_dumps()
+    {
+        warn!("Failed to disable core dumps: {}", e);
     }
-    if args.dry_run {
-        println!("DRY RUN MODE: No files will be modified");
+    
+    if let Some(Command::Setup(setup_args)) = &cli.command {
+        return setup::run_setup(setup_args).await;
     }
     
-    info!("Starting PubliKey Agent v{}", args.agent_version);
-    if let Some(ref endpoint) = args.endpoint {
-        info!("Endpoint: {}", endpoint);
+    if let Some(Command::InstallService(service_args)) = &cli.command {
+        return install_service(args, service_args);
     }
-    info!("Dry run mode: {}", args.dry_run);
     
-    // Validate that include and exclude users are not both specified
-    if !args.include_users.is_empty() && !args.exclude_users.is_empty() {
-        eprintln!("Error: Cannot specify both --include-users and --exclude-users. Use only one.");
-        std::process::exit(1);
+    if let Some(Command::ResetPin { .. }) = &cli.command {
+        let state = StateDir::open(args.state_dir())?;
+        if pin::reset_pin(&state)? {
+            println!("Certificate pin removed; the next run will pin the endpoint's current certificate");
+        } else {
+            println!("No certificate pin stored in {}", state.path().display());
+        }
+        return Ok(());
     }
     
-    // Handle update operations first
-    if args.check_update || args.update {
-        println!("Checking for updates...");
-        let update_manager = UpdateManager::new()?;
-        let update_installed = update_manager.check_and_update(&args.agent_version, args.dry_run, args.update).await?;
-        
-        // If we just installed an update, exit so user can restart with new version
-        if args.update && update_installed {
-            println!("Please restart the agent to use the new version.");
-            return Ok(());
+    if let Some(Command::ResetState { keep, .. }) = &cli.command {
+        let state = StateDir::open(args.state_dir())?;
+        let removed = state.reset(keep)?;
+        if removed.is_empty() {
+            println!("No agent state to remove in {}", state.path().display());
+        } else {
+            println!("Removed agent state from {}: {}", state.path().display(), removed.join(", "));
         }
-        
-        // If we just checked for updates, exit
-        if args.check_update && !args.update {
-            return Ok(());
+        return Ok(());
+    }
+    
+    if let Some(Command::RemoveMotd { .. }) = &cli.command {
+        let path = args.write_motd.as_ref().ok_or_else(|| anyhow::anyhow!("--write-motd names the snippet to remove"))?;
+        if motd::remove(path)? {
+            println!("Removed {}", path.display());
+        } else {
+            println!("No MOTD snippet at {}", path.display());
         }
-        
-        // If we were trying to update but no update was needed, exit
-        if args.update && !update_installed {
-            return Ok(());
+        return Ok(());
+    }
+    
+    if let Some(Command::SupportBundle { out, max_bytes, .. }) = &cli.command {
+        let sources = bundle::BundleSources {
+            agent_version: args.agent_version.clone(),
+            state_dir: args.state_dir(),
+            config_dump: format!("{:#?}\n", cli),
+            authorized_keys_patterns: SshKeyManager::new().authorized_keys_patterns().unwrap_or_default(),
+            token: args.token.clone(),
+        };
+        let manifest = bundle::write_bundle(&sources, out, *max_bytes)?;
+        println!(
+            "Support bundle written to {} ({} files included, {} redacted, {} left out)",
+            out.display(),
+            manifest.included.len(),
+            manifest.redacted.len(),
+            manifest.omitted.len()
+        );
+        return Ok(());
+    }
+    
+    if let Some(Command::PrintInstallScript { endpoint, token_placeholder }) = &cli.command {
+        print!("{}", install_script::render(endpoint, *token_placeholder, env!("CARGO_PKG_VERSION"))?);
+        return Ok(());
+    }
+    
+    if let Some(Command::AuthorizedKeys { username, cache_ttl, cache_dir, fail_open, .. }) = &cli.command {
+        let cache = keys_cache::KeysCache::new(cache_dir.clone().unwrap_or_else(|| args.state_dir().join(keys_cache::DEFAULT_CACHE_SUBDIR)));
+        for line in cached_authorized_keys(args, username, args.timeout, &cache, *cache_ttl, *fail_open).await? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    
+    if let Some(Command::PackageAssets { format, out }) = &cli.command {
+        for path in package::write_assets(*format, out)? {
+            println!("Wrote {}", path.display());
+        }
+        return Ok(());
+    }
+    
+    if let Some(Command::Version) = &cli.command {
+        println!("pkagent {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    
+    let (format, result, changed_exit_code) = match &cli.command {
+        Some(Command::CheckUpdate(update_args)) => {
+            output::set_format(update_args.output);
+            (update_args.output, run_update(update_args, false).await, None)
+        }
+        Some(Command::Update(update_args)) => {
+            output::set_format(update_args.output);
+            (update_args.output, run_update(update_args, true).await, None)
+        }
+        _ if args.ping => {
+            output::set_format(args.output);
+            (args.output, run_ping(args).await, None)
+        }
+        _ if args.watch => {
+            output::set_format(args.output);
+            (args.output, run_watch(args).await, args.changed_exit_code)
+        }
+        _ => {
+            output::set_format(args.output);
+            (args.output, run_cycle(args, &api::new_run_id()).await, args.changed_exit_code)
+        }
+    };
+    
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Ansible => println!("{}", output::ansible_result(&result)),
+        OutputFormat::Json => println!("{}", output::json_result(&result)),
+    }
+    
+    let code = output::exit_code(&result, changed_exit_code);
+    if code != 0 {
+        std::process::exit(code);
+    }
+    
+    Ok(())
+}
+
+/// API client for a run, pinned to the stored certificate with --pin-cert. Starts out on the
+/// endpoint the last run settled on.
+fn api_client(
+    args: &RunArgs,
+    state: &StateDir,
+    endpoints: &[String],
+    token: secrecy::SecretString,
+    trust_roots: &trust::TrustRoots,
+) -> Result<(ApiClient, Option<pin::CertPin>)> {
+    let endpoint = endpoints.first().ok_or_else(|| anyhow::anyhow!("--endpoint is required for normal operations"))?;
+    let cert_pin = if args.pin_cert {
+        Some(pin::CertPin::load(state, endpoint, trust_roots.store.clone())?)
+    } else {
+        None
+    };
+    let tls = api_tls_config(args, endpoint, trust_roots, cert_pin.as_ref())?;
+    let api_client = configured_api_client(args, endpoint, &endpoints[1..], token, tls)?;
+    if let Some(endpoint) = state::read_active_endpoint(state) {
+        api_client.use_endpoint(&endpoint);
+    }
+    Ok((api_client, cert_pin))
+}
+
+/// API client for `endpoint` and its fallbacks with the request options from the flags, shared by
+/// runs and the AuthorizedKeysCommand
+fn configured_api_client(
+    args: &RunArgs,
+    endpoint: &str,
+    fallback_endpoints: &[String],
+    token: secrecy::SecretString,
+    tls: rustls::ClientConfig,
+) -> Result<ApiClient> {
+    Ok(ApiClient::with_transport(endpoint.to_string(), token, tls, args.transport())?
+        .with_fallback_endpoints(fallback_endpoints)?
+        .with_endpoint_paths(args.endpoint_paths.clone())
+        .with_strict_api(args.strict_api)
+        .with_max_retry_after(args.max_retry_after)
+        .with_token_type(args.token_type)
+        .with_token_command(args.token_command())
+        .with_signing_key(args.signing_key()?)
+        .with_assignments_pubkey(args.assignments_pubkey()?))
+}
+
+/// TLS configuration for the API client: --insecure-skip-tls-verify, the certificate pin or the
+/// trust roots, presenting the --client-cert identity if any
+fn api_tls_config(
+    args: &RunArgs,
+    endpoint: &str,
+    trust_roots: &trust::TrustRoots,
+    cert_pin: Option<&pin::CertPin>,
+) -> Result<rustls::ClientConfig> {
+    let identity = args.client_identity()?;
+    if args.insecure_skip_tls_verify {
+        eprintln!("WARNING: --insecure-skip-tls-verify is set: the API server's TLS certificate is NOT verified. Never use this in production.");
+        warn!("TLS certificate verification disabled for {}", endpoint);
+        return Ok(trust::insecure_client_config(identity.as_ref()));
+    }
+    Ok(match cert_pin {
+        Some(cert_pin) => cert_pin.client_config(identity.as_ref()),
+        None => trust_roots.api_client_config(identity.as_ref()),
+    })
+}
+
+/// --watch: a full run every --poll-interval, and a key sync as soon as the server announces a
+/// change on its key change stream. Stops on SIGTERM/SIGINT.
+async fn run_watch(initial: &RunArgs) -> Result<RunSummary> {
+    let interrupt = interrupt::Interrupt::install()?;
+    let control = match &initial.control_socket {
+        Some(path) => Some(control::ControlSocket::bind(path, Box::new(Cli::reload))?),
+        None => None,
+    };
+    let mut reloaded: Option<std::sync::Arc<Cli>> = None;
+    let mut client = watch_client(initial).await?;
+    let mut watch = api::KeyWatch::default();
+    loop {
+        if let Some(cli) = control.as_ref().and_then(|control| control.take_reload()) {
+            say!("Configuration reloaded");
+            let cli = std::sync::Arc::new(cli);
+            client = watch_client(cli.run_args()).await.unwrap_or_else(|e| {
+                eprintln!("Error: {:#}", e);
+                None
+            });
+            watch = api::KeyWatch::default();
+            reloaded = Some(cli);
+        }
+        let current = reloaded.clone();
+        let args = current.as_deref().map_or(initial, Cli::run_args);
+        let state = StateDir::open_or_stateless(args.state_dir());
+
+        let run_id = control.as_ref().map_or_else(api::new_run_id, |control| control.begin_run());
+        let result = run_cycle(args, &run_id).await;
+        if let Err(e) = &result {
+            eprintln!("Error: {:#}", e);
+        }
+        if let Some(control) = &control {
+            control.finish_run(&result);
+        }
+        if interrupt.is_set() {
+            return result;
+        }
+        // Follow the run to whichever endpoint it settled on
+        if let Some(client) = &client
+            && let Some(endpoint) = state::read_active_endpoint(&state)
+        {
+            client.use_endpoint(&endpoint);
+        }
+        let deadline = tokio::time::Instant::now() + args.poll_interval;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let changed = tokio::select! {
+                changed = async {
+                    match &client {
+                        Some(client) => client.wait_for_key_change(&mut watch, remaining).await,
+                        None => {
+                            tokio::time::sleep(remaining).await;
+                            false
+                        }
+                    }
+                } => changed,
+                _ = async {
+                    match &control {
+                        Some(control) => control.sync_requested().await,
+                        None => std::future::pending().await,
+                    }
+                } => break,
+                _ = interrupt.wait() => return Ok(RunSummary::message("Stopped by signal")),
+            };
+            let Some(client) = client.as_ref().filter(|_| changed) else {
+                break;
+            };
+            say!("Server announced a key change, syncing keys...");
+            if let Err(e) = sync_announced_change(args, client, &state).await {
+                eprintln!("Error: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Client for the key change stream of --watch, when there is a server to ask
+async fn watch_client(args: &RunArgs) -> Result<Option<ApiClient>> {
+    if args.endpoint.is_empty() || args.assignments_file.is_some() {
+        return Ok(None);
+    }
+    let Some(token) = args.api_token().await? else {
+        return Ok(None);
+    };
+    let trust_roots = trust::load(args.ca_cert.as_deref())?;
+    let state = StateDir::open_or_stateless(args.state_dir());
+    Ok(Some(api_client(args, &state, &args.endpoint, token, &trust_roots)?.0))
+}
+
+/// Fetch the assignments after a change announcement and sync them, without a full report
+async fn sync_announced_change(args: &RunArgs, client: &ApiClient, state: &StateDir) -> Result<KeySyncStats> {
+    let key_policy = KeyPolicy::new(&args.allowed_key_types, args.min_rsa_bits)?;
+    let key_response = assignment_cache::fetch(client, state, api::MAX_RETRIES).await?;
+    sync_local(args, state, key_policy, &key_response)
+}
+
+/// A run bounded by --cycle-timeout (or the systemd watchdog interval), reported to systemd when
+/// it started us as a `Type=notify` service
+async fn run_cycle(args: &RunArgs, run_id: &str) -> Result<RunSummary> {
+    let notifier = notify::Notifier::from_env().unwrap_or_else(|e| {
+        warn!("Not notifying systemd: {:#}", e);
+        None
+    });
+    let interrupt = interrupt::Interrupt::install()?;
+    // The sync itself stops between users; this ends the run at its next await (API calls, retries)
+    let interruptible = async {
+        tokio::select! {
+            biased;
+            result = run(args, run_id) => result,
+            _ = interrupt.wait() => {
+                eprintln!("Error: {}", interrupt::Interrupted);
+                Err(anyhow::Error::new(interrupt::Interrupted))
+            }
+        }
+    };
+    let result = match args.cycle_timeout.or_else(notify::watchdog_interval) {
+        Some(limit) => tokio::time::timeout(limit, interruptible)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Run did not finish within {:?}; aborted", limit))),
+        None => interruptible.await,
+    };
+    if let Some(notifier) = notifier
+        && let Err(e) = notifier.cycle_finished(&result)
+    {
+        warn!("{:#}", e);
+    }
+    say!("Run ID: {}", run_id);
+    result.map(|summary| RunSummary { run_id: Some(run_id.to_string()), ..summary })
+}
+
+/// Write the systemd units (or print them with --dry-run) and optionally enable the timer
+fn install_service(args: &RunArgs, service_args: &InstallServiceArgs) -> Result<()> {
+    // Fallbacks go along as PUBLIKEY_ENDPOINT's comma-separated list
+    let endpoint = (!args.endpoint.is_empty()).then(|| args.endpoint.join(","));
+    let token = args.token.clone();
+    let (Some(endpoint), Some(token)) = (endpoint, token) else {
+        anyhow::bail!("install-service needs an endpoint and a token (--endpoint/--token, PUBLIKEY_ENDPOINT/PUBLIKEY_TOKEN or the config file)");
+    };
+    let schedule = match (service_args.interval, &service_args.on_calendar) {
+        (Some(interval), _) => service::Schedule::Interval(interval),
+        (None, Some(spec)) => service::Schedule::Calendar(spec.clone()),
+        (None, None) => service::Schedule::Calendar(setup::DEFAULT_SCHEDULE.to_string()),
+    };
+    let mut writable_paths = vec![args.state_dir()];
+    if let Some(motd) = args.write_motd.as_ref().and_then(|path| path.parent()) {
+        writable_paths.push(motd.to_path_buf());
+    }
+    let units = service::ServiceUnits {
+        binary: std::env::current_exe().map_err(|e| anyhow::anyhow!("Failed to get current executable path: {}", e))?,
+        env_file: service_args.env_file.clone(),
+        config: args.config.clone(),
+        schedule,
+        writable_paths,
+    };
+    
+    if args.dry_run.is_some() {
+        let (service, timer) = units.render();
+        let unit_dir = &service_args.unit_dir;
+        println!("# {}\n{}", unit_dir.join(service::SERVICE_UNIT).display(), service);
+        println!("# {}\n{}", unit_dir.join(service::TIMER_UNIT).display(), timer);
+        println!("# {} (mode 0600) would hold PUBLIKEY_ENDPOINT={} and the token", units.env_file.display(), endpoint);
+        return Ok(());
+    }
+    
+    if !service::systemd_running(std::path::Path::new("/")) {
+        anyhow::bail!(
+            "systemd is not running on this host (no /run/systemd/system); schedule `pkagent run` with cron or your init system instead, \
+             or use --dry-run to print the units"
+        );
+    }
+    service::install(&units, &service_args.unit_dir, &endpoint, &token)?;
+    println!("Installed {} and {} in {}", service::SERVICE_UNIT, service::TIMER_UNIT, service_args.unit_dir.display());
+    if service_args.enable {
+        service::enable(false)?;
+        println!("Enabled and started {}", service::TIMER_UNIT);
+    } else {
+        println!("Enable it with: systemctl daemon-reload && systemctl {}", service::ENABLE_TIMER.join(" "));
+    }
+    Ok(())
+}
+
+/// Check for a newer release and, with `install`, install it
+async fn run_update(args: &UpdateArgs, install: bool) -> Result<RunSummary> {
+    say!("PubliKey Agent v{}", args.agent_version);
+    say!("Checking for updates...");
+    let trust_roots = trust::load(args.ca_cert.as_deref())?;
+    let update_manager = UpdateManager::new(trust_roots.client_config(), args.proxy_setting())?.with_immutable_os(system::immutable_os());
+    let outcome = update_manager.check_and_update(&args.agent_version, args.dry_run, install).await?;
+    
+    if !install {
+        return Ok(RunSummary {
+            update_available: outcome == UpdateOutcome::Available,
+            ..RunSummary::message("Update check completed")
+        });
+    }
+    
+    // Exit so the user can restart with the new version
+    if outcome == UpdateOutcome::Installed {
+        say!("Please restart the agent to use the new version.");
+        return Ok(RunSummary {
+            changed: !args.dry_run,
+            msg: "Update installed".to_string(),
+            ..Default::default()
+        });
+    }
+    
+    Ok(RunSummary::message("No update needed"))
+}
+
+/// --ping: deliver a heartbeat and nothing else. No splay, preflight or health check, so a
+/// cron job every minute stays cheap; failing to deliver it is an error for monitoring to see.
+async fn run_ping(args: &RunArgs) -> Result<RunSummary> {
+    let heartbeat = async {
+        if args.endpoint.is_empty() {
+            anyhow::bail!("--endpoint is required for --ping");
+        }
+        let token = args.api_token().await?
+            .ok_or_else(|| anyhow::anyhow!("--token or --token-command is required for --ping"))?;
+        let trust_roots = trust::load(args.ca_cert.as_deref())?;
+        let state = StateDir::open_or_stateless(args.state_dir());
+        let (api_client, _) = api_client(args, &state, &args.endpoint, token, &trust_roots)?;
+        let heartbeat = api::Heartbeat {
+            hostname: system::collect_hostname()?,
+            agent_version: args.agent_version.clone(),
+            machine_id: rollout::machine_id(),
+        };
+        info!("Sending heartbeat for {} (agent v{})", heartbeat.hostname, heartbeat.agent_version);
+        api_client
+            .with_failover("Heartbeat", || api_client.heartbeat_with_retry(&heartbeat, api::MAX_RETRIES))
+            .await
+            .map_err(|e| anyhow::Error::from(e).context("Failed to deliver heartbeat"))?;
+        if args.endpoint.len() > 1
+            && let Err(e) = state::record_active_endpoint(&state, api_client.endpoint())
+        {
+            warn!("Failed to record the active endpoint: {}", e);
         }
+        say!("Heartbeat delivered to {}", api_client.endpoint());
+        Ok(RunSummary::message("Heartbeat delivered"))
+    };
+    heartbeat.await.inspect_err(|e| eprintln!("Error: {:#}", e))
+}
+
+async fn run(args: &RunArgs, run_id: &str) -> Result<RunSummary> {
+    say!("PubliKey Agent v{}", args.agent_version);
+    if !args.endpoint.is_empty() {
+        say!("Endpoint: {}", args.endpoint.join(", "));
+    }
+    if args.dry_run.is_some() {
+        say!("DRY RUN MODE: No files will be modified");
+    }
+    
+    info!("Starting PubliKey Agent v{} (run {})", args.agent_version, run_id);
+    if !args.endpoint.is_empty() {
+        info!("Endpoint: {}", args.endpoint.join(", "));
+    }
+    info!("Dry run mode: {:?}", args.dry_run);
+    
+    // Set up the webhook early so a missing secret file fails fast
+    let notifier = args
+        .notify_webhook
+        .as_ref()
+        .map(|url| {
+            WebhookNotifier::new(
+                url.clone(),
+                args.notify_webhook_secret_file.as_deref(),
+                args.notify_webhook_signature_header.clone(),
+            )
+        })
+        .transpose()
+        .inspect_err(|e| eprintln!("Error: {:#}", e))?;
+    
+    // Validate the local key policy before doing any work
+    let key_policy = KeyPolicy::new(&args.allowed_key_types, args.min_rsa_bits).inspect_err(|e| eprintln!("Error: {:#}", e))?;
+    
+    // Roots for verifying the API and update servers
+    let trust_roots = trust::load(args.ca_cert.as_deref())?;
+    
+    if let Some(path) = &args.assignments_file {
+        return run_offline(args, path, key_policy).inspect_err(|e| eprintln!("Error: {:#}", e));
     }
     
     // Validate required arguments for normal operations
-    let endpoint = args.endpoint.ok_or_else(|| anyhow::anyhow!("--endpoint is required for normal operations"))?;
-    let token = args.token.ok_or_else(|| anyhow::anyhow!("--token is required for normal operations"))?;
+    if args.endpoint.is_empty() {
+        anyhow::bail!("--endpoint is required for normal operations");
+    }
+    let token = args.api_token().await.inspect_err(|e| eprintln!("Error: {:#}", e))?
+        .ok_or_else(|| anyhow::anyhow!("--token or --token-command is required for normal operations"))?;
+    
+    if let Some(splay) = args.splay {
+        if args.dry_run.is_some() || std::io::stdin().is_terminal() {
+            info!("Skipping splay for a dry run or interactive session");
+        } else {
+            let delay = rollout::splay_delay(&rollout::machine_id(), splay);
+            info!("Waiting {:?} of the {:?} splay before contacting the server", delay, splay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+    
+    // Cheap reachability probe before the heavier HTTP calls; behind --proxy only the proxy is
+    // reachable directly
+    let state = StateDir::open_or_stateless(args.state_dir());
+    let probe_targets = match &args.proxy {
+        Some(proxy) => vec![proxy.redacted()],
+        None => args.endpoint.clone(),
+    };
+    if preflight::preflight(&probe_targets, args.offline_ok, preflight::PROBE_TIMEOUT, &state).await
+        == preflight::PreflightOutcome::SkipOffline
+    {
+        say!("Endpoint unreachable, skipping run (offline)");
+        return Ok(RunSummary::message("skipped: offline"));
+    }
     
-    let api_client = ApiClient::new(endpoint, token)?;
+    let (api_client, cert_pin) = api_client(args, &state, &args.endpoint, token, &trust_roots)?;
+    let api_client = api_client.with_run_id(run_id.to_string());
+    if let Some(exp) = api_client.token_expiry() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let budget = api::retry_budget(api::MAX_RETRIES) + args.wait_for_assignments.unwrap_or_default();
+        if let Some(warning) = token::expiry_warning(exp, now, budget) {
+            say!("Warning: {}", warning);
+            warn!("{}", warning);
+        }
+    }
     
-    // Initial health check
-    println!("Checking API health...");
-    match api_client.health_check().await {
-        Ok(true) => {
-            println!("API health check passed");
-            info!("API health check passed");
+    // Initial health check, which also settles the endpoint for the rest of the run
+    say!("Checking API health...");
+    let health = api_client.with_failover("Health check", || api_client.health_check()).await;
+    if args.endpoint.len() > 1 && health.is_ok() {
+        say!("Using endpoint {}", api_client.endpoint());
+        info!("Using endpoint {}", api_client.endpoint());
+        if let Err(e) = state::record_active_endpoint(&state, api_client.endpoint()) {
+            warn!("Failed to record the active endpoint: {}", e);
+        }
+    }
+    let health_summary = output::HealthSummary::of(&health);
+    match health {
+        Ok(api::Health { healthy: true, latency }) => {
+            say!("API health check passed ({} ms)", latency.as_millis());
+            info!("API health check passed ({} ms)", latency.as_millis());
         },
-        Ok(false) => {
-            println!("Warning: API health check failed, but continuing...");
-            warn!("API health check failed, but continuing...");
+        Ok(api::Health { healthy: false, latency }) => {
+            say!("Warning: API health check failed ({} ms), but continuing...", latency.as_millis());
+            warn!("API health check failed ({} ms), but continuing...", latency.as_millis());
         },
+        // Every later request would be refused the same way
+        Err(e @ api::ApiError::Unauthorized { .. }) => {
+            eprintln!("Error: Health check: the API token was refused: {}", e);
+            return Err(anyhow::Error::from(e).context("Health check: the API token was refused"));
+        }
+        Err(e @ api::ApiError::Protocol(_)) => return Err(e.into()),
+        // A server that accepts connections but does not answer would stall every later request too
+        Err(e) if e.is_timeout() => return Err(e.into()),
         Err(e) => {
-            println!("Warning: Health check error: {}, continuing anyway...", e);
+            say!("Warning: Health check error: {}, continuing anyway...", e);
             error!("Health check error: {}", e);
             warn!("Continuing despite health check failure...");
         }
     }
     
-    println!("Running report...");
+    if let Some(cert_pin) = &cert_pin {
+        cert_pin.ensure_unchanged()?;
+        if let Err(e) = cert_pin.persist_first_use() {
+            warn!("Failed to persist certificate pin: {}", e);
+        }
+    }
+    
+    say!("Running report...");
     info!("Running report");
-    match run_report_cycle(&api_client, &args.agent_version, args.dry_run, &args.exclude_users, &args.include_users, args.user_mode).await {
-        Ok(_) => {
-            println!("Report completed successfully");
-            info!("Report completed successfully");
+    let fallback_policy = key_policy.clone();
+    match run_report_cycle(&api_client, &state, args, key_policy, notifier.as_ref()).await {
+        Ok(ReportCycle { host, stats, errors }) => {
+            let msg = if args.report_only {
+                "Report completed successfully; key sync skipped (--report-only)"
+            } else if args.sync_only {
+                "Key sync completed; no report sent (--sync-only)"
+            } else {
+                "Report completed successfully"
+            };
+            say!("{}", msg);
+            info!("{}", msg);
+            if let Err(e) = state::record_last_run(&state, "success") {
+                warn!("Failed to record last run state: {}", e);
+            }
+            if let Some(path) = &args.write_motd
+                && args.dry_run.is_none()
+                && !args.report_only
+            {
+                let summary = motd::MotdSummary {
+                    agent_version: args.agent_version.clone(),
+                    endpoint: api_client.endpoint().to_string(),
+                    synced_at: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    users: stats.as_ref().map(|stats| stats.users_processed),
+                    errors: stats.as_ref().map_or(0, |stats| stats.errors),
+                };
+                // The banner is informational; failing to write it never fails the run
+                if let Err(e) = motd::write(path, &summary) {
+                    warn!("Failed to write MOTD snippet {}: {}", path.display(), e);
+                }
+            }
+            if let Some(conditions) = &args.assert_clean {
+                let Some(stats) = &stats else {
+                    eprintln!("assert-clean: no key sync plan was computed");
+                    return Err(anyhow::anyhow!("assert-clean failed: no key sync plan was computed"));
+                };
+                let violations = assert_clean::check(stats, conditions);
+                for violation in &violations {
+                    eprintln!("assert-clean: {}", violation);
+                }
+                if !violations.is_empty() {
+                    return Err(anyhow::anyhow!("assert-clean failed: {} violations", violations.len()));
+                }
+            }
+            let check = match &stats {
+                Some(stats) if args.check => {
+                    let drift = check::compare(stats);
+                    if drift.is_empty() {
+                        say!("Compliance check passed: authorized_keys match the key assignments");
+                    } else {
+                        say!("Compliance check failed: {} authorized_keys files differ from the key assignments", drift.len());
+                        for file in &drift {
+                            say!("  {}", file);
+                        }
+                    }
+                    Some(drift)
+                }
+                None if args.check && errors.is_empty() => {
+                    eprintln!("Error: --check: the server sent no key assignments to compare with");
+                    return Err(anyhow::anyhow!("--check: the server sent no key assignments to compare with"));
+                }
+                _ => None,
+            };
+            let msg = match &check {
+                Some(drift) if drift.is_empty() => "Compliance check passed".to_string(),
+                Some(drift) => format!("Compliance check failed: {} authorized_keys files differ from the key assignments", drift.len()),
+                None => msg.to_string(),
+            };
+            Ok(RunSummary {
+                changed: !args.check && stats.as_ref().is_some_and(output::stats_changed),
+                msg,
+                host,
+                stats,
+                errors,
+                health: Some(health_summary),
+                check,
+                ..Default::default()
+            })
         }
         Err(e) => {
+            if let Err(state_err) = state::record_last_run(&state, "failed") {
+                warn!("Failed to record last run state: {}", state_err);
+            }
             let error_msg = e.to_string();
-            if error_msg.contains("Agent version") && error_msg.contains("too old") {
+            if matches!(api::api_error(&e), Some(api::ApiError::VersionTooOld { .. })) {
                 eprintln!("❌ {}", error_msg);
                 eprintln!("Please download and install the latest version of the PubliKey agent.");
             } else {
                 eprintln!("Error: {}", error_msg);
             }
-            return Err(e);
+            if args.use_cached_on_failure && cache_fallback_allowed(&e) {
+                match cached_assignments(args, &api_client, &state).and_then(|key_response| sync_local(args, &state, fallback_policy, &key_response)) {
+                    Ok(stats) => {
+                        return Ok(RunSummary {
+                            changed: output::stats_changed(&stats),
+                            msg: "Report failed; applied cached key assignments".to_string(),
+                            stats: Some(stats),
+                            errors: vec![RunError::from_error(&e)],
+                            health: Some(health_summary),
+                            ..Default::default()
+                        });
+                    }
+                    Err(cache_err) => {
+                        say!("Not applying cached key assignments: {:#}", cache_err);
+                        warn!("Not applying cached key assignments: {:#}", cache_err);
+                    }
+                }
+            }
+            Err(e)
         }
     }
-    
-    Ok(())
 }
 
-#[instrument(skip(api_client, exclude_users, include_users))]
-async fn run_report_cycle(api_client: &ApiClient, agent_version: &str, dry_run: bool, exclude_users: &[String], include_users: &[String], user_mode: bool) -> Result<()> {
-    info!("Starting report cycle");
+/// Keys for sshd's AuthorizedKeysCommand, from the cache while it is fresh. With `fail_open` a
+/// stale entry stands in when the server cannot be asked.
+async fn cached_authorized_keys(
+    args: &RunArgs,
+    username: &str,
+    timeout: std::time::Duration,
+    cache: &keys_cache::KeysCache,
+    ttl: std::time::Duration,
+    fail_open: bool,
+) -> Result<Vec<String>> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let cached = cache.load(username);
+    if let Some(cached) = &cached
+        && cached.fetched_at <= now
+        && now - cached.fetched_at < ttl.as_secs()
+    {
+        info!("Serving keys for {} from the cache", username);
+        return Ok(cached.keys.clone());
+    }
     
-    // Collect system information
-    let hostname = system::collect_hostname()?;
-    let system_info = system::collect_system_info()?;
-    let users = users::collect_users(exclude_users, include_users, user_mode)?;
+    match authorized_keys(args, username, timeout).await {
+        Ok(keys) => {
+            if let Err(e) = cache.store(username, &keys_cache::CachedKeys { fetched_at: now, keys: keys.clone() }) {
+                warn!("Failed to cache keys for {}: {}", username, e);
+            }
+            Ok(keys)
+        }
+        Err(e) => match cached {
+            Some(cached) if fail_open => {
+                warn!("{:#}; serving keys for {} cached {}s ago (--fail-open)", e, username, now.saturating_sub(cached.fetched_at));
+                Ok(cached.keys)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Keys for sshd's AuthorizedKeysCommand, fetched from the server. Runs on every login, so it only
+/// fetches assignments: no health check, report, state or update.
+async fn authorized_keys(args: &RunArgs, username: &str, timeout: std::time::Duration) -> Result<Vec<String>> {
+    let endpoint = args.endpoint.first().cloned().ok_or_else(|| anyhow::anyhow!("--endpoint is required for authorized-keys"))?;
+    let token = args.api_token().await?.ok_or_else(|| anyhow::anyhow!("--token or --token-command is required for authorized-keys"))?;
+    let key_policy = KeyPolicy::new(&args.allowed_key_types, args.min_rsa_bits)?;
+    let trust_roots = trust::load(args.ca_cert.as_deref())?;
+    let tls = api_tls_config(args, &endpoint, &trust_roots, None)?;
+    let api_client = configured_api_client(args, &endpoint, &args.endpoint[1..], token, tls)?;
     
-    println!("Collected system data:");
-    println!("  Hostname: {}", hostname);
-    println!("  OS: {} {} ({})", system_info.distribution, system_info.version, system_info.arch);
-    println!("  Users: {} (filtered: UID 0 and >= 1000)", users.len());
+    let key_response = tokio::time::timeout(timeout, api_client.with_failover("Key assignments", || api_client.get_key_assignments()))
+        .await
+        .map_err(|_| anyhow::anyhow!("Key assignments request timed out after {:?}", timeout))??;
+    let Some(assignments) = &key_response.assignments else {
+        return Ok(Vec::new());
+    };
+    let all_users = users::collect_users(&[], &[], args.user_mode, args.home_override)?;
+    let user_filter = users::effective_user_filter(
+        &args.include_users,
+        &args.exclude_users,
+        key_response.managed_users.as_deref(),
+        key_response.excluded_users.as_deref(),
+        args.local_filters_override,
+    );
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok(SshKeyManager::new()
+        .with_key_policy(key_policy)
+        .with_user_filter(user_filter)
+        .authorized_keys_for(&all_users, username, assignments, now))
+}
+
+/// Apply assignments from an exported file without contacting the API
+fn run_offline(args: &RunArgs, path: &std::path::Path, key_policy: KeyPolicy) -> Result<RunSummary> {
+    let key_response = offline::load(path)?;
+    let count = key_response.assignments.as_ref().map_or(0, |a| a.len());
+    say!("Loaded {} SSH key assignments from {}", count, path.display());
+    info!("Loaded {} SSH key assignments from {}", count, path.display());
     
-    info!("Collected system data:");
-    info!("  Hostname: {}", hostname);
-    info!("  OS: {} {} ({})", system_info.distribution, system_info.version, system_info.arch);
-    info!("  Users: {} (filtered: UID 0 and >= 1000)", users.len());
+    let state = StateDir::open_or_stateless(args.state_dir());
+    let stats = sync_local(args, &state, key_policy, &key_response)?;
+    Ok(RunSummary {
+        changed: output::stats_changed(&stats),
+        msg: "Offline sync completed".to_string(),
+        stats: Some(stats),
+        ..Default::default()
+    })
+}
+
+/// Sync assignments that did not come from this run's report cycle
+fn sync_local(args: &RunArgs, state: &StateDir, key_policy: KeyPolicy, key_response: &api::KeyAssignmentsResponse) -> Result<KeySyncStats> {
+    let (all_users, _) = collect_local_users(args, state)?;
+    say!("Syncing SSH keys{}...", if args.dry_run.is_some() { " (DRY RUN)" } else { "" });
+    let ssh_manager = key_manager(args, key_policy, key_response, &all_users, sshd::effective_permit_root_login());
+    let assignments = key_response.assignments.as_deref().unwrap_or_default();
+    let stats = sync_assignments(args, &ssh_manager, &all_users, assignments)
+        .map_err(|e| e.context("SSH key sync failed"))?;
+    info!("SSH key sync stats: {:?}", stats);
+    Ok(stats)
+}
+
+/// Whether --use-cached-on-failure may stand in after this failure. A refused token, a
+/// too-old agent or an assignments list failing its signature check is not an outage, so it does not.
+fn cache_fallback_allowed(error: &anyhow::Error) -> bool {
+    !matches!(
+        api::api_error(error),
+        Some(api::ApiError::Unauthorized { .. } | api::ApiError::VersionTooOld { .. } | api::ApiError::BadSignature(_))
+    )
+}
+
+/// Key assignments cached by the last successful fetch, for --use-cached-on-failure
+fn cached_assignments(args: &RunArgs, api_client: &ApiClient, state: &StateDir) -> Result<api::KeyAssignmentsResponse> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (response, age) = assignment_cache::load(state, &api_client.assignments_source(), now, args.cached_assignments_max_age)?;
+    say!("Using cached key assignments fetched {}m ago", age.as_secs() / 60);
+    warn!("Using cached key assignments fetched {}s ago", age.as_secs());
+    Ok(response)
+}
+
+/// What a report cycle that got its report through produced
+struct ReportCycle {
+    /// The host as reported; `None` with --sync-only
+    host: Option<ReportedHost>,
+    stats: Option<KeySyncStats>,
+    /// Failures after the report that did not abort the cycle
+    errors: Vec<RunError>,
+}
+
+/// Hint for a --sync-only run the server refuses assignments to
+const SYNC_ONLY_UNKNOWN_HOST: &str = "The server has no report from this host yet; run a full report first (without --sync-only)";
+
+/// What the cycle found out about this host locally, for both the report and the key sync
+struct LocalHost {
+    hostname: String,
+    all_users: Vec<users::UserInfo>,
+    login_findings: login::LoginFindings,
+    permit_root_login: sshd::PermitRootLogin,
+    /// When collection began, for the report's timings
+    collection_start: std::time::Instant,
+}
+
+#[instrument(skip(api_client, state, args, key_policy, notifier), fields(run_id = %api_client.run_id()))]
+async fn run_report_cycle(api_client: &ApiClient, state: &StateDir, args: &RunArgs, key_policy: KeyPolicy, notifier: Option<&WebhookNotifier>) -> Result<ReportCycle> {
+    info!("Starting report cycle");
+    let dry_run = args.dry_run.is_some();
     
-    // Create report
-    let report = AgentReport {
+    let collection_start = std::time::Instant::now();
+    let hostname = system::collect_hostname()?;
+    let (all_users, login_findings) = collect_local_users(args, state)?;
+    let local = LocalHost {
         hostname,
-        system_info,
-        agent_version: agent_version.to_string(),
-        users: users.clone(),
+        all_users,
+        login_findings,
+        permit_root_login: sshd::effective_permit_root_login(),
+        collection_start,
     };
-    
-    // Send report with retry logic
-    println!("Sending report to server...");
-    let response = api_client.report_with_retry(&report, 3).await?;
-    
-    println!("Report sent successfully");
-    info!("Report sent successfully");
-    if let Some(host_id) = &response.host_id {
-        println!("Host ID: {}", host_id);
-        info!("Host ID: {}", host_id);
+    let mut capabilities = Capabilities::load(state);
+    let (host, new_host) = if args.sync_only {
+        say!("Skipping the inventory report (--sync-only)");
+        info!("Skipping the inventory report (--sync-only)");
+        (None, false)
+    } else {
+        let (host, new_host) = send_report(api_client, state, args, &local, &mut capabilities).await?;
+        (Some(host), new_host)
+    };
+    if args.report_only {
+        say!("Key sync skipped (--report-only)");
+        info!("Key sync skipped (--report-only)");
+        return Ok(ReportCycle { host, stats: None, errors: Vec::new() });
     }
+    let LocalHost { hostname, all_users, permit_root_login, .. } = local;
     
     // Fetch key assignments and deploy SSH keys
-    match api_client.get_key_assignments().await {
+    let mut sync_stats = None;
+    let mut errors = Vec::new();
+    let mut key_assignments = assignment_cache::fetch(api_client, state, api::MAX_RETRIES).await.map_err(anyhow::Error::from);
+    // A server that creates assignments after the first report may not have any yet
+    if let Some(window) = args.wait_for_assignments
+        && new_host
+        && key_assignments.as_ref().is_ok_and(|r| r.assignments.as_ref().is_none_or(|a| a.is_empty()))
+    {
+        say!("First report from this host; waiting up to {:?} for key assignments", window);
+        if let Some(key_response) = api_client.wait_for_assignments(window, api::ASSIGNMENT_POLL_INITIAL_DELAY).await {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            // Polled without validators, so the next fetch downloads the list again
+            if let Err(e) = assignment_cache::store(state, &api_client.assignments_source(), &key_response, &api::Validators::default(), now) {
+                warn!("Failed to cache key assignments: {}", e);
+            }
+            key_assignments = Ok(key_response);
+        }
+    }
+    let mut from_cache = false;
+    let key_assignments = match key_assignments {
+        Err(e) if args.use_cached_on_failure && cache_fallback_allowed(&e) => match cached_assignments(args, api_client, state) {
+            Ok(key_response) => {
+                eprintln!("Failed to fetch key assignments: {}", e);
+                errors.push(RunError::from_error(&e.context("Failed to fetch key assignments")));
+                from_cache = true;
+                Ok(key_response)
+            }
+            Err(cache_err) => {
+                say!("Not applying cached key assignments: {:#}", cache_err);
+                warn!("Not applying cached key assignments: {:#}", cache_err);
+                Err(e)
+            }
+        },
+        other => other,
+    };
+    match key_assignments {
         Ok(key_response) => {
             let assignment_count = key_response.assignments.as_ref().map(|a| a.len()).unwrap_or(0);
-            println!("Retrieved {} SSH key assignments", assignment_count);
+            say!("Retrieved {} SSH key assignments", assignment_count);
             info!("Retrieved {} SSH key assignments", assignment_count);
             
             if let Some(assignments) = &key_response.assignments {
                 let mode = if dry_run { " (DRY RUN)" } else { "" };
-                println!("Syncing SSH keys{}...", mode);
-                let ssh_manager = SshKeyManager::new();
                 
-                match ssh_manager.sync_ssh_keys(&users, assignments, dry_run, user_mode) {
+                say!("Syncing SSH keys{}...", mode);
+                let ssh_manager = key_manager(args, key_policy, &key_response, &all_users, permit_root_login);
+                match sync_assignments(args, &ssh_manager, &all_users, assignments) {
                     Ok(stats) => {
-                        let prefix = if dry_run { "Would have: " } else { "" };
-                        println!("SSH key sync completed{}:", mode);
-                        println!("  {} users processed", stats.users_processed);
-                        println!("  {}{} keys added", prefix, stats.keys_added);
-                        println!("  {}{} keys removed", prefix, stats.keys_removed);
-                        println!("  {}{} files updated", prefix, stats.files_updated);
-                        if stats.errors > 0 {
-                            println!("  {} errors occurred", stats.errors);
-                        }
-                        
                         info!("SSH key sync stats: {:?}", stats);
+                        
+                        if let Some(notifier) = notifier
+                            && WebhookNotifier::should_notify(&stats, dry_run)
+                        {
+                            notifier.notify(&hostname, api_client.run_id(), &stats).await;
+                        }
+                        if !dry_run && !from_cache && capabilities.enabled("assignmentAcks") {
+                            let acknowledgements = stats.acknowledgements();
+                            if let Err(e) = api_client.acknowledge_assignments(&hostname, &acknowledgements).await {
+                                warn!("Failed to acknowledge key assignments: {}", e);
+                            }
+                        }
+                        sync_stats = Some(stats);
                     }
                     Err(e) => {
                         eprintln!("SSH key sync failed: {}", e);
                         error!("SSH key sync failed: {}", e);
+                        errors.push(RunError { category: ExitCode::PartialSync, message: format!("SSH key sync failed: {}", e) });
                     }
                 }
             } else {
@@ -187,8 +1007,553 @@ async fn run_report_cycle(api_client: &ApiClient, agent_version: &str, dry_run:
         Err(e) => {
             eprintln!("Failed to fetch key assignments: {}", e);
             error!("Failed to fetch key assignments: {}", e);
+            let e = e.context("Failed to fetch key assignments");
+            // Servers may only hand out assignments to hosts they have a report from
+            let e = if args.sync_only
+                && let Some(api::ApiError::Http { status, .. }) = api::api_error(&e)
+                && matches!(*status, reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::CONFLICT)
+            {
+                eprintln!("{}", SYNC_ONLY_UNKNOWN_HOST);
+                e.context(SYNC_ONLY_UNKNOWN_HOST)
+            } else {
+                e
+            };
+            errors.push(RunError::from_error(&e));
         }
     }
     
-    Ok(())
+    Ok(ReportCycle { host, stats: sync_stats, errors })
+}
+
+/// Collect the rest of the inventory and send the report, unless it is unchanged since the last
+/// one. Returns the host as reported and whether the server just created it.
+async fn send_report(
+    api_client: &ApiClient,
+    state: &StateDir,
+    args: &RunArgs,
+    local: &LocalHost,
+    capabilities: &mut Capabilities,
+) -> Result<(ReportedHost, bool)> {
+    let user_mode = args.user_mode;
+    let LocalHost { hostname, all_users, login_findings, permit_root_login, collection_start } = local;
+    
+    // Collect system information
+    let system_info = system::collect_system_info()?;
+    let mut users = all_users.clone();
+    users::filter_users(&mut users, &args.include_users, &args.exclude_users);
+    
+    // Guard against reporting a passwd file caught mid-rewrite as mass deletions
+    let previous_user_count = state::read_user_count(state);
+    let users_partial = !user_mode && users::user_count_dropped(previous_user_count, all_users.len(), args.max_user_drop_percent);
+    if users_partial {
+        say!(
+            "Warning: user count dropped from {} to {}; marking the report as partial",
+            previous_user_count.unwrap_or(0),
+            all_users.len()
+        );
+        warn!("User count dropped beyond {}%, marking report as partial", args.max_user_drop_percent);
+    }
+    
+    say!("Collected system data:");
+    say!("  Hostname: {}", hostname);
+    say!("  OS: {} {} ({})", system_info.distribution, system_info.version, system_info.arch);
+    say!("  Users: {} (filtered: UID 0 and >= 1000)", users.len());
+    
+    info!("Collected system data:");
+    info!("  Hostname: {}", hostname);
+    info!("  OS: {} {} ({})", system_info.distribution, system_info.version, system_info.arch);
+    info!("  Users: {} (filtered: UID 0 and >= 1000)", users.len());
+    
+    // Collect optional report sections
+    let inventory_manager = SshKeyManager::new()
+        .with_path_filters(args.include_paths.clone(), args.exclude_paths.clone());
+    let inventory_manager = if args.ignore_key_file_suffixes.is_empty() {
+        inventory_manager
+    } else {
+        inventory_manager.with_artifact_suffixes(args.ignore_key_file_suffixes.clone())
+    };
+    let inventory_manager = if args.home_roots.is_empty() {
+        inventory_manager
+    } else {
+        inventory_manager.with_home_roots(args.home_roots.clone())
+    };
+    let inventory_manager = if args.external_key_markers.is_empty() {
+        inventory_manager
+    } else {
+        inventory_manager.with_external_key_markers(args.external_key_markers.clone())
+    };
+    let mut sections = ReportSections::default();
+    #[cfg(feature = "metrics")]
+    {
+        sections.network = Some(report::collect_network());
+        sections.storage = Some(report::collect_storage());
+    }
+    match inventory_manager.authorized_keys_patterns() {
+        Ok(patterns) => {
+            sections.ssh = Some(report::SshSection {
+                authorized_keys_patterns: patterns,
+                permit_root_login: permit_root_login.to_string(),
+                root_login_disabled: !permit_root_login.allows_key_login(false),
+                login_blockers: login_findings.host.clone(),
+                umask: login_findings.umask.clone(),
+            })
+        }
+        Err(e) => warn!("Failed to read AuthorizedKeysFile patterns: {}", e),
+    }
+    if capabilities.enabled("keyInventory") {
+        match inventory_manager.key_inventory(&users) {
+            Ok(inventory) => sections.key_inventory = Some(inventory),
+            Err(e) => warn!("Failed to collect key inventory: {}", e),
+        }
+    } else {
+        info!("Server did not negotiate keyInventory, leaving it out of the report");
+    }
+    if args.scan_ssh_dirs {
+        let honors_authorized_keys2 = sections
+            .ssh
+            .as_ref()
+            .is_some_and(|ssh| ssh.authorized_keys_patterns.iter().any(|p| p.ends_with("authorized_keys2")));
+        let ssh_dirs = ssh_dir_scan::scan_users(&users, &args.exclude_paths, honors_authorized_keys2);
+        for warning in ssh_dirs.iter().flat_map(|dir| dir.warnings()) {
+            say!("Warning: {}", warning);
+            warn!("{}", warning);
+        }
+        sections.ssh_dirs = Some(ssh_dirs);
+    }
+    sections.timings = Some(report::TimingsSection {
+        collection_ms: collection_start.elapsed().as_millis() as u64,
+    });
+    
+    // Create report
+    let report = AgentReport {
+        schema_version: report::REPORT_SCHEMA_VERSION,
+        capabilities: capabilities::supported(),
+        hostname: hostname.clone(),
+        system_info,
+        agent_version: args.agent_version.clone(),
+        users: users.clone(),
+        idempotency_key: api::new_idempotency_key(),
+        users_partial,
+        sections,
+    };
+    
+    // Skip a report identical to the last one delivered, unless that one is too old
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let fingerprint = report::fingerprint(&report, &api_client.url_for(api::Endpoint::Report));
+    let unchanged_for = report::unchanged_for(state::read_last_report(state).as_ref(), &fingerprint, now, args.max_report_interval);
+    if args.force_report {
+        info!("Report fingerprint {}; sending it because of --force-report", fingerprint);
+    } else if let Some(age) = unchanged_for {
+        info!(
+            "Report fingerprint {} matches the report sent {}s ago; not sending it (a full report goes out at least every {:?})",
+            fingerprint,
+            age.as_secs(),
+            args.max_report_interval
+        );
+    } else {
+        info!("Report fingerprint {} differs from the last report sent, or that one is too old; sending it", fingerprint);
+    }
+    
+    // Send report with retry logic, spooling it for a later run if delivery fails
+    let spool = match state.subdir("spool") {
+        Ok(dir) => Some(Spool::new(dir)),
+        Err(e) => {
+            warn!("Reports that fail to send cannot be spooled: {:#}", e);
+            None
+        }
+    };
+    let response = if let Some(age) = unchanged_for.filter(|_| !args.force_report) {
+        say!("Report unchanged since it was sent {}m ago, not sending it", age.as_secs() / 60);
+        None
+    } else {
+        say!("Sending report to server...");
+        match api_client.report_with_retry(&report, api::MAX_RETRIES).await {
+            Ok(response) => Some(response),
+            Err(e) => {
+                if args.token_type == token::TokenType::Jwt {
+                    // The JWT will not be valid by the time a later run could deliver the report
+                    say!("Not spooling the report: it was sent with a short-lived JWT");
+                } else if !matches!(e, api::ApiError::VersionTooOld { .. })
+                    && let Some(spool) = &spool
+                {
+                    match serde_json::to_value(&report).map_err(anyhow::Error::from).and_then(|value| spool.push(value, &report.idempotency_key)) {
+                        Ok(path) => say!("Report spooled for later delivery: {}", path.display()),
+                        Err(spool_err) => warn!("Failed to spool report: {}", spool_err),
+                    }
+                }
+                return Err(e.into());
+            }
+        }
+    };
+    
+    if let Err(e) = state::record_user_count(state, all_users.len()) {
+        warn!("Failed to persist user count: {}", e);
+    }
+    let mut new_host = false;
+    if let Some(response) = &response {
+        say!("Report sent successfully");
+        info!("Report sent successfully");
+        if let Err(e) = state::record_last_report(state, &fingerprint, now) {
+            warn!("Failed to persist report fingerprint: {}", e);
+        }
+        if capabilities.update(response)
+            && let Err(e) = capabilities.save(state)
+        {
+            warn!("Failed to persist negotiated capabilities: {}", e);
+        }
+        match &spool {
+            Some(spool) if capabilities.enabled("spoolReplay") => flush_spool(api_client, spool).await,
+            Some(_) => info!("Server did not negotiate spoolReplay, keeping spooled reports"),
+            None => {}
+        }
+        new_host = response.is_new_host(state::read_host_id(state).as_deref());
+        if let Some(host_id) = &response.host_id {
+            say!("Host ID: {}", host_id);
+            info!("Host ID: {}", host_id);
+            if let Err(e) = state::record_host_id(state, host_id) {
+                warn!("Failed to persist host ID: {}", e);
+            }
+        }
+    }
+    
+    let host = ReportedHost {
+        hostname: report.hostname.clone(),
+        system_info: report.system_info.clone(),
+        users: report.users.clone(),
+        // A skipped report leaves the ID the server gave the last one
+        host_id: match response {
+            Some(response) => response.host_id,
+            None => state::read_host_id(state),
+        },
+    };
+    Ok((host, new_host))
+}
+
+/// Local accounts with their sshd, login and opt-out findings applied
+fn collect_local_users(args: &RunArgs, state: &StateDir) -> Result<(Vec<users::UserInfo>, login::LoginFindings)> {
+    let mut all_users = users::collect_users(&[], &[], args.user_mode, args.home_override)?;
+    if let Some(path) = &args.user_email_map {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read user email map {}: {}", path.display(), e))?;
+        users::apply_email_map(&mut all_users, &users::parse_email_map(&content)?);
+    }
+    let pubkey_auth_disabled = sshd::pubkey_auth_disabled_users(all_users.iter().map(|user| user.username.as_str()));
+    for user in &mut all_users {
+        if pubkey_auth_disabled.contains(&user.username) {
+            user.pubkey_auth_disabled = Some(true);
+        }
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let login_findings = login::detect(&login::LoginSources::read(), &all_users, now);
+    for user in &mut all_users {
+        user.login_blockers = login_findings.for_user(user);
+    }
+    if !args.ignore_user_optout {
+        let max_duration = std::time::Duration::from_secs(args.max_opt_out_days * 24 * 60 * 60);
+        let mut seen = state.load(optout::SEEN_FILE).unwrap_or_default();
+        for warning in optout::mark_users(&mut all_users, &mut seen, now, max_duration) {
+            say!("Warning: {}", warning);
+            warn!("{}", warning);
+        }
+        if let Err(e) = state.store(optout::SEEN_FILE, &seen) {
+            warn!("Failed to persist opt-out state: {}", e);
+        }
+    }
+    Ok((all_users, login_findings))
+}
+
+/// Key manager configured from the flags and the server's (or assignments file's) user lists
+fn key_manager(
+    args: &RunArgs,
+    key_policy: KeyPolicy,
+    key_response: &api::KeyAssignmentsResponse,
+    all_users: &[users::UserInfo],
+    permit_root_login: sshd::PermitRootLogin,
+) -> SshKeyManager {
+    let pubkey_auth_disabled = all_users
+        .iter()
+        .filter(|user| user.pubkey_auth_disabled == Some(true))
+        .map(|user| user.username.clone())
+        .collect();
+    // Server-provided user lists override local flags unless told otherwise
+    let user_filter = users::effective_user_filter(
+        &args.include_users,
+        &args.exclude_users,
+        key_response.managed_users.as_deref(),
+        key_response.excluded_users.as_deref(),
+        args.local_filters_override,
+    );
+    info!("Effective user filter for key sync: {:?}", user_filter);
+
+    let ssh_manager = SshKeyManager::new()
+        .with_interrupt(interrupt::Interrupt::current())
+        .with_attempt_unmounted_homes(args.sync_unmounted_homes)
+        .with_key_policy(key_policy)
+        .with_content_preview(args.diff)
+        .with_path_filters(args.include_paths.clone(), args.exclude_paths.clone())
+        .with_user_filter(user_filter)
+        .with_pubkey_auth_disabled(pubkey_auth_disabled)
+        .with_defer_removals(rollout::defer_removals(
+            key_response.rollout_percent,
+            rollout::rollout_bucket(&rollout::machine_id()),
+            args.ignore_rollout,
+        ));
+    let ssh_manager = if args.sync_root_anyway {
+        ssh_manager
+    } else {
+        ssh_manager.with_root_login(permit_root_login)
+    };
+    let ssh_manager = if args.denied_key_dirs.is_empty() {
+        ssh_manager
+    } else {
+        ssh_manager.with_denied_key_dirs(args.denied_key_dirs.clone())
+    };
+    let ssh_manager = if args.ignore_key_file_suffixes.is_empty() {
+        ssh_manager
+    } else {
+        ssh_manager.with_artifact_suffixes(args.ignore_key_file_suffixes.clone())
+    };
+    let ssh_manager = if args.home_roots.is_empty() {
+        ssh_manager
+    } else {
+        ssh_manager.with_home_roots(args.home_roots.clone())
+    };
+    let ssh_manager = if args.external_key_markers.is_empty() {
+        ssh_manager
+    } else {
+        ssh_manager.with_external_key_markers(args.external_key_markers.clone())
+    };
+    let ssh_manager = ssh_manager
+        .with_remove_external_keys(args.remove_external_keys)
+        .with_adopt_existing_keys(args.adopt_existing_keys)
+        .with_exclusive(args.exclusive);
+    match args.key_lock_timeout {
+        Some(timeout) => ssh_manager.with_lock_timeout(timeout),
+        None => ssh_manager,
+    }
+}
+
+/// Apply assignments to the local users and print what happened
+fn sync_assignments(
+    args: &RunArgs,
+    ssh_manager: &SshKeyManager,
+    all_users: &[users::UserInfo],
+    assignments: &[api::KeyAssignment],
+) -> Result<KeySyncStats> {
+    let dry_run = args.dry_run.is_some();
+    let mut stats = ssh_manager.sync_ssh_keys(all_users, assignments, dry_run, args.user_mode)?;
+    if let Some(scope) = args.dry_run {
+        stats.limit_to(scope);
+    }
+    for user in all_users.iter().filter(|user| assignments.iter().any(|a| a.username == user.username)) {
+        stats.login_warnings.extend(user.login_blockers.iter().map(|blocker| ssh_keys::LoginWarning {
+            username: user.username.clone(),
+            detail: blocker.to_string(),
+        }));
+    }
+    if let Some(orphan_mode) = args.clean_orphans {
+        match ssh_manager.find_orphaned_files(all_users) {
+            Ok(mut orphans) => {
+                if orphan_mode == OrphanMode::Delete {
+                    let grace = std::time::Duration::from_secs(args.orphan_grace_days * 24 * 60 * 60);
+                    ssh_manager.delete_orphaned_files(&mut orphans, grace, dry_run);
+                }
+                stats.orphaned_files = orphans;
+            }
+            Err(e) => {
+                eprintln!("Orphan scan failed: {}", e);
+                error!("Orphan scan failed: {}", e);
+            }
+        }
+    }
+    let prefix = if dry_run { "Would have: " } else { "" };
+    say!("SSH key sync completed{}:", if dry_run { " (DRY RUN)" } else { "" });
+    say!("  {} of {} users processed", stats.users_processed, stats.users_considered);
+    if stats.users_skipped > 0 {
+        let reasons: Vec<String> = stats
+            .users_skipped_reasons
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect();
+        say!("  {} users skipped ({})", stats.users_skipped, reasons.join(", "));
+    }
+    if stats.dry_run_scope != Some(DryRunScope::Removals) {
+        say!("  {}{} keys added", prefix, stats.keys_added);
+    }
+    if stats.dry_run_scope != Some(DryRunScope::Additions) {
+        say!("  {}{} keys removed", prefix, stats.keys_removed);
+    }
+    if stats.keys_updated > 0 {
+        say!("  {}{} keys updated with new options", prefix, stats.keys_updated);
+    }
+    say!("  {}{} of {} files updated", prefix, stats.files_updated, stats.files_examined);
+    if stats.dry_run_scope.is_some() {
+        for change in &stats.changes {
+            for fingerprint in &change.added {
+                say!("  Would add {} to {} ({})", fingerprint, change.path.display(), change.username);
+            }
+            for fingerprint in &change.removed {
+                say!("  Would remove {} from {} ({})", fingerprint, change.path.display(), change.username);
+            }
+            for fingerprint in &change.updated {
+                say!("  Would update options of {} in {} ({})", fingerprint, change.path.display(), change.username);
+            }
+        }
+    }
+    if let Some(totals) = &stats.plan_totals {
+        say!(
+            "  Full plan: {} keys added, {} keys removed, {} files updated",
+            totals.keys_added, totals.keys_removed, totals.files_updated
+        );
+    }
+    if stats.removals_deferred > 0 {
+        say!("  {} key removals deferred (outside rollout canary)", stats.removals_deferred);
+    }
+    if stats.keys_already_present_unmanaged > 0 {
+        say!(
+            "  {} assigned keys already present outside the managed keys, not duplicated (--adopt-existing-keys manages them)",
+            stats.keys_already_present_unmanaged
+        );
+    }
+    if stats.permissions_fixed > 0 {
+        say!("  {}{} file and directory permissions restored", prefix, stats.permissions_fixed);
+        if dry_run {
+            for fix in &stats.permission_fixes {
+                say!("  Would fix {} ({})", fix.detail, fix.username);
+            }
+        }
+    }
+    if stats.keys_adopted > 0 {
+        say!("  {}{} existing keys adopted into the managed keys", prefix, stats.keys_adopted);
+    }
+    if stats.preserved_lines > 0 {
+        say!("  {} lines preserved as written (option-prefixed keys, unknown key types, comments)", stats.preserved_lines);
+    }
+    for external in &stats.external_keys {
+        let action = if args.remove_external_keys { "not preserved" } else { "preserved" };
+        say!("  {} externally managed keys {} for {} ({})", external.fingerprints.len(), action, external.username, external.path.display());
+    }
+    if stats.root_login_disabled {
+        say!("  root's keys skipped: sshd does not permit root key logins (use --sync-root-anyway to override)");
+    }
+    if stats.files_excluded > 0 {
+        say!("  {} files excluded by path filters", stats.files_excluded);
+    }
+    if stats.errors > 0 {
+        say!("  {} errors occurred", stats.errors);
+    }
+    for locked in &stats.lock_timeouts {
+        say!("  Skipped {}: {} is locked by another tool", locked.username, locked.path.display());
+    }
+    for orphan in &stats.orphaned_files {
+        let status = if orphan.deleted {
+            "deleted"
+        } else if orphan.block_removed {
+            "cleared of its managed block"
+        } else {
+            "found"
+        };
+        say!("  Orphaned file {}: {} ({} keys, user {})", status, orphan.path.display(), orphan.key_count, orphan.username);
+    }
+    for warning in &stats.login_warnings {
+        say!("  Warning: {} may still be unable to log in: {}", warning.username, warning.detail);
+    }
+    if stats.assignments_rejected > 0 {
+        say!("  {} assignments rejected: {}", stats.assignments_rejected, stats.rejected_assignment_ids.join(", "));
+    }
+    if args.diff {
+        // say! writes to stdout only in text mode
+        let color = if output::format() == OutputFormat::Text {
+            std::io::stdout().is_terminal()
+        } else {
+            std::io::stderr().is_terminal()
+        };
+        let style = diff::DiffStyle { color, full: args.full_diff };
+        for preview in &stats.previews {
+            let diff = diff::unified(&preview.path, preview.old.as_deref(), &preview.new, style);
+            if !diff.is_empty() {
+                say!("{}", diff.trim_end());
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Set RLIMIT_CORE to zero so a crash cannot write process memory to disk
+fn disable_core_dumps() -> Result<()> {
+    use nix::sys::resource::{Resource, setrlimit};
+    setrlimit(Resource::RLIMIT_CORE, 0, 0).map_err(|e| anyhow::anyhow!("setrlimit(RLIMIT_CORE) failed: {}", e))
+}
+
+/// Deliver reports spooled by earlier runs, oldest first, stopping at the first failure
+async fn flush_spool(api_client: &ApiClient, spool: &Spool) {
+    let pending = match spool.pending() {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!("Failed to read report spool: {}", e);
+            return;
+        }
+    };
+    
+    for item in pending {
+        let mut report = item.entry.report.clone();
+        if let Some(object) = report.as_object_mut() {
+            object.insert("spooledAt".to_string(), item.entry.spooled_at.into());
+            object.insert("sequence".to_string(), item.entry.sequence.into());
+            object.insert("timestampSuspect".to_string(), item.timestamp_suspect.into());
+        }
+        
+        match api_client.report_spooled(&report, &item.entry.idempotency_key).await {
+            Ok(_) => {
+                info!("Delivered spooled report #{}", item.entry.sequence);
+                if let Err(e) = spool.remove(&item) {
+                    warn!("{}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to deliver spooled report #{}: {}", item.entry.sequence, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_config_errors_reach_the_json_result() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let args = Cli::try_parse_from([
+            "pkagent",
+            "--endpoint",
+            "http://127.0.0.1:9",
+            "--token",
+            "pk_test",
+            "--allowed-key-types",
+            "ssh-ed25519,ssh-bogus",
+            "--output",
+            "json",
+            "--state-dir",
+            state_dir.path().to_str().unwrap(),
+        ])
+        .unwrap()
+        .legacy;
+        assert_eq!(args.output, OutputFormat::Json);
+
+        let result = run(&args, "run-1").await;
+        let value = output::json_result(&result);
+        assert_eq!(value["failed"], true);
+        assert!(value["msg"].as_str().unwrap().contains("ssh-bogus"), "{}", value);
+        assert_eq!(value["errors"][0]["category"], "failure");
+        assert_eq!(output::exit_code(&result, None), 1);
+    }
 }
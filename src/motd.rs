@@ -0,0 +1,177 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use anyhow::{Context, Result};
+
+use crate::fsutil::{self, FileOps, RealFs};
+
+/// What the login banner says about the last successful run
+#[derive(Debug, Clone)]
+pub struct MotdSummary {
+    pub agent_version: String,
+    pub endpoint: String,
+    /// Seconds since the epoch
+    pub synced_at: u64,
+    /// Users processed by the key sync; `None` when no sync ran
+    pub users: Option<u32>,
+    pub errors: u32,
+}
+
+impl MotdSummary {
+    /// The two lines shown at login
+    pub fn render(&self) -> String {
+        let mut first = format!(
+            "SSH keys managed by PubliKey agent v{} — last sync {}",
+            self.agent_version,
+            format_utc(self.synced_at)
+        );
+        if let Some(users) = self.users {
+            first.push_str(&format!(", {} users, {} errors", users, self.errors));
+        }
+        format!(
+            "{}\nManual edits to managed authorized_keys files are overwritten (server: {})\n",
+            first, self.endpoint
+        )
+    }
+}
+
+/// Whether `path` is run by update-motd (Debian/Ubuntu) rather than included as text
+fn is_update_motd_script(path: &Path) -> bool {
+    path.parent().and_then(Path::file_name).is_some_and(|dir| dir == "update-motd.d")
+}
+
+/// Write the snippet, replacing the previous one atomically. Files in update-motd.d become
+/// executable scripts printing the summary; anywhere else they hold the plain text.
+pub fn write(path: &Path, summary: &MotdSummary) -> Result<()> {
+    write_with(&RealFs, path, summary)
+}
+
+fn write_with(ops: &dyn FileOps, path: &Path, summary: &MotdSummary) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let text = summary.render();
+    if is_update_motd_script(path) {
+        let script = format!("#!/bin/sh\n# Written by pkagent --write-motd\ncat <<'EOF'\n{}EOF\n", text);
+        fsutil::atomic_write_with(ops, path, script.as_bytes(), 0o755)
+    } else {
+        fsutil::atomic_write_with(ops, path, text.as_bytes(), 0o644)
+    }
+}
+
+/// Remove the snippet; returns whether one existed
+pub fn remove(path: &Path) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// "2024-05-02 10:31 UTC"
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let minutes_of_day = secs % 86_400 / 60;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, minutes_of_day / 60, minutes_of_day % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn summary() -> MotdSummary {
+        MotdSummary {
+            agent_version: "0.4.0".to_string(),
+            endpoint: "https://publikey.example.com".to_string(),
+            synced_at: 1_714_645_860,
+            users: Some(14),
+            errors: 0,
+        }
+    }
+
+    #[test]
+    fn test_content() {
+        assert_eq!(
+            summary().render(),
+            "SSH keys managed by PubliKey agent v0.4.0 — last sync 2024-05-02 10:31 UTC, 14 users, 0 errors\n\
+             Manual edits to managed authorized_keys files are overwritten (server: https://publikey.example.com)\n"
+        );
+        let no_sync = MotdSummary { users: None, ..summary() };
+        assert!(no_sync.render().starts_with("SSH keys managed by PubliKey agent v0.4.0 — last sync 2024-05-02 10:31 UTC\n"));
+
+        assert_eq!(format_utc(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn test_plain_file_and_update_motd_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("run/pkagent/motd-snippet");
+        write(&plain, &summary()).unwrap();
+        assert_eq!(fs::read_to_string(&plain).unwrap(), summary().render());
+        assert_eq!(fs::metadata(&plain).unwrap().permissions().mode() & 0o777, 0o644);
+
+        let script = dir.path().join("update-motd.d/80-pkagent");
+        write(&script, &summary()).unwrap();
+        let content = fs::read_to_string(&script).unwrap();
+        assert!(content.starts_with("#!/bin/sh\n"));
+        assert!(content.contains(&summary().render()));
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    /// Fails every rename, the step that puts a new snippet in place
+    struct FailingRename;
+
+    impl FileOps for FailingRename {
+        fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+            RealFs.write_new(path, bytes, mode)
+        }
+        fn sync_file(&self, path: &Path) -> io::Result<()> {
+            RealFs.sync_file(path)
+        }
+        fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+            Err(io::Error::other("injected failure"))
+        }
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            RealFs.sync_dir(dir)
+        }
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            RealFs.remove(path)
+        }
+    }
+
+    #[test]
+    fn test_failed_write_keeps_previous_snippet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("motd-snippet");
+        write(&path, &summary()).unwrap();
+
+        let later = MotdSummary { synced_at: 1_714_700_000, errors: 3, ..summary() };
+        assert!(write_with(&FailingRename, &path, &later).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), summary().render());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("motd-snippet");
+        write(&path, &summary()).unwrap();
+        assert!(remove(&path).unwrap());
+        assert!(!path.exists());
+        assert!(!remove(&path).unwrap());
+    }
+}
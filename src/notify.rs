@@ -0,0 +1,150 @@
+use std::ffi::OsString;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::output::RunSummary;
+
+/// Connection to systemd's notification socket (sd_notify(3)), present only under `Type=notify`
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+    /// Notifier for the socket systemd passed in NOTIFY_SOCKET, if any
+    pub fn from_env() -> Result<Option<Self>> {
+        Self::from_socket(std::env::var_os("NOTIFY_SOCKET"))
+    }
+
+    /// Notifier for a NOTIFY_SOCKET value: a path, or `@name` for the abstract namespace
+    fn from_socket(value: Option<OsString>) -> Result<Option<Self>> {
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            return Ok(None);
+        };
+        let address = match value.to_str().and_then(|value| value.strip_prefix('@')) {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&value),
+        }
+        .with_context(|| format!("Invalid NOTIFY_SOCKET {:?}", value))?;
+        let socket = UnixDatagram::unbound().context("Failed to create notification socket")?;
+        Ok(Some(Self { socket, address }))
+    }
+
+    fn send(&self, message: &str) -> Result<()> {
+        self.socket
+            .send_to_addr(message.as_bytes(), &self.address)
+            .context("Failed to notify systemd")?;
+        Ok(())
+    }
+
+    /// Report the outcome of a cycle: STATUS= always, READY=1 once the cycle is over, and
+    /// WATCHDOG=1 only when it succeeded so a failing agent runs into the watchdog
+    pub fn cycle_finished(&self, result: &Result<RunSummary>) -> Result<()> {
+        self.send(&cycle_message(result))
+    }
+}
+
+/// Notification sent after a cycle
+fn cycle_message(result: &Result<RunSummary>) -> String {
+    let status = match result {
+        Ok(summary) => status_line(summary),
+        Err(e) => format!("Failed: {:#}", e),
+    };
+    // STATUS is a single line; anything after a newline would be read as another assignment
+    let status = status.replace('\n', " ");
+    match result {
+        Ok(summary) if summary.errors.is_empty() => format!("READY=1\nSTATUS={}\nWATCHDOG=1\n", status),
+        _ => format!("READY=1\nSTATUS={}\n", status),
+    }
+}
+
+/// Short summary of a cycle for `systemctl status`
+fn status_line(summary: &RunSummary) -> String {
+    let mut line = summary.msg.clone();
+    if let Some(stats) = &summary.stats {
+        line.push_str(&format!(
+            ": {} keys added, {} removed, {} files updated",
+            stats.keys_added, stats.keys_removed, stats.files_updated
+        ));
+    }
+    if !summary.errors.is_empty() {
+        line.push_str(&format!(" ({} errors)", summary.errors.len()));
+    }
+    line
+}
+
+/// Watchdog interval systemd expects pings within (WATCHDOG_USEC), if the watchdog is meant for
+/// this process (WATCHDOG_PID)
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_from(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_from(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::RunError;
+    use crate::ssh_keys::KeySyncStats;
+
+    #[test]
+    fn test_cycle_message() {
+        let summary = RunSummary {
+            stats: Some(KeySyncStats { keys_added: 2, files_updated: 1, ..Default::default() }),
+            ..RunSummary::message("Report completed successfully")
+        };
+        assert_eq!(
+            cycle_message(&Ok(summary)),
+            "READY=1\nSTATUS=Report completed successfully: 2 keys added, 0 removed, 1 files updated\nWATCHDOG=1\n"
+        );
+
+        // Errors withhold the watchdog ping
+        let summary = RunSummary {
+            errors: vec![RunError::from_error(&anyhow::anyhow!("sync failed"))],
+            ..RunSummary::message("Report completed successfully")
+        };
+        assert!(!cycle_message(&Ok(summary)).contains("WATCHDOG"));
+        let message = cycle_message(&Err(anyhow::anyhow!("API down\nretry later")));
+        assert_eq!(message, "READY=1\nSTATUS=Failed: API down retry later\n");
+    }
+
+    #[test]
+    fn test_notifies_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::from_socket(Some(path.into_os_string())).unwrap().unwrap();
+        notifier.cycle_finished(&Ok(RunSummary::message("done"))).unwrap();
+
+        let mut buf = [0; 256];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=done\nWATCHDOG=1\n");
+
+        assert!(Notifier::from_socket(None).unwrap().is_none());
+        assert!(Notifier::from_socket(Some(OsString::new())).unwrap().is_none());
+        assert!(Notifier::from_socket(Some("@pkagent-test".into())).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_from(Some("30000000"), None, 42), Some(Duration::from_secs(30)));
+        assert_eq!(watchdog_from(Some("30000000"), Some("42"), 42), Some(Duration::from_secs(30)));
+        // Meant for another process, e.g. a parent shell
+        assert_eq!(watchdog_from(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_from(Some("0"), None, 42), None);
+        assert_eq!(watchdog_from(None, None, 42), None);
+    }
+}
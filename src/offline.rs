@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+use crate::api::{KeyAssignment, KeyAssignmentsResponse};
+
+/// Read assignments exported from the server: a key assignments response, or a plain array of
+/// assignments
+pub fn load(path: &Path) -> Result<KeyAssignmentsResponse> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read assignments file {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| anyhow!("Invalid assignments file {}: {}", path.display(), e))
+}
+
+fn parse(text: &str) -> Result<KeyAssignmentsResponse> {
+    let value: Value = serde_json::from_str(text).map_err(|e| anyhow!("not valid JSON: {}", e))?;
+    let (mut response, entries) = match value {
+        Value::Array(entries) => (empty_response(), entries),
+        Value::Object(mut object) => {
+            let entries = match object.remove("assignments") {
+                Some(Value::Array(entries)) => entries,
+                Some(Value::Null) | None => Vec::new(),
+                Some(_) => return Err(anyhow!("assignments: expected an array")),
+            };
+            let response: KeyAssignmentsResponse =
+                serde_path_to_error::deserialize(Value::Object(object)).map_err(|e| anyhow!("{}", e))?;
+            if !response.success {
+                return Err(anyhow!(
+                    "exported from a failed response: {}",
+                    response.error.as_deref().unwrap_or("no error given")
+                ));
+            }
+            (response, entries)
+        }
+        _ => return Err(anyhow!("expected a key assignments response or an array of assignments")),
+    };
+
+    let mut assignments = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.into_iter().enumerate() {
+        // Name the entry by its ID when it has one, so it can be found in a large export
+        let name = match entry.get("assignmentId").and_then(Value::as_str) {
+            Some(id) => format!("assignment {} ({})", index, id),
+            None => format!("assignment {}", index),
+        };
+        let assignment: KeyAssignment = serde_path_to_error::deserialize(entry).map_err(|e| anyhow!("{}: {}", name, e))?;
+        assignments.push(assignment);
+    }
+    response.assignments = Some(assignments);
+    Ok(response)
+}
+
+fn empty_response() -> KeyAssignmentsResponse {
+    KeyAssignmentsResponse {
+        success: true,
+        host_id: None,
+        hostname: None,
+        assignments: None,
+        managed_users: None,
+        excluded_users: None,
+        rollout_percent: None,
+        timestamp: None,
+        error: None,
+        extra: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    fn assignment(id: &str) -> Value {
+        serde_json::json!({
+            "username": "alice",
+            "fingerprint": "SHA256:abc",
+            "publicKey": KEY,
+            "keyType": "ssh-ed25519",
+            "assignmentId": id,
+        })
+    }
+
+    #[test]
+    fn test_parse_response_and_array() {
+        let response = serde_json::json!({
+            "success": true,
+            "hostId": "h1",
+            "assignments": [assignment("a1"), assignment("a2")],
+            "managedUsers": ["alice"],
+        });
+        let parsed = parse(&response.to_string()).unwrap();
+        assert_eq!(parsed.assignments.as_ref().unwrap().len(), 2);
+        assert_eq!(parsed.managed_users, Some(vec!["alice".to_string()]));
+
+        let parsed = parse(&serde_json::json!([assignment("a1")]).to_string()).unwrap();
+        assert_eq!(parsed.assignments.unwrap()[0].assignment_id, "a1");
+        assert_eq!(parsed.managed_users, None);
+    }
+
+    #[test]
+    fn test_errors_name_the_entry() {
+        let mut broken = assignment("a2");
+        broken.as_object_mut().unwrap().remove("publicKey");
+        let err = parse(&serde_json::json!([assignment("a1"), broken]).to_string()).unwrap_err().to_string();
+        assert!(err.starts_with("assignment 1 (a2):") && err.contains("publicKey"), "{}", err);
+
+        let mut broken = assignment("a1");
+        broken["uid"] = serde_json::json!("1000");
+        let err = parse(&serde_json::json!({"success": true, "assignments": [broken]}).to_string()).unwrap_err().to_string();
+        assert!(err.starts_with("assignment 0 (a1): uid:"), "{}", err);
+
+        let err = parse(r#"{"success": false, "error": "host not found"}"#).unwrap_err().to_string();
+        assert!(err.contains("host not found"), "{}", err);
+        assert!(parse("{\"success\": true,").unwrap_err().to_string().starts_with("not valid JSON"));
+        assert!(parse("\"assignments\"").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use tracing::info;
+
+use crate::users::UserInfo;
+
+/// Marker file, relative to the home directory, that takes a user's keys out of the agent's hands
+pub const OPT_OUT_FILE: &str = ".ssh/.publikey-ignore";
+
+/// Longest opt-out honoured unless --max-opt-out-days says otherwise
+pub const DEFAULT_MAX_OPT_OUT_DAYS: u64 = 30;
+
+/// State file remembering when the agent first saw each user's marker. The marker's own mtime
+/// would let a user extend an opt-out forever with `touch`.
+pub const SEEN_FILE: &str = "opt-outs.json";
+
+/// Bytes of the marker file read; it holds at most a date and a comment
+const MARKER_READ_LIMIT: u64 = 1024;
+
+/// An opt-out in effect, as reported with the user
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserOptOut {
+    /// When the agent first saw the marker, seconds since the epoch
+    pub since: u64,
+    /// When management resumes: the expiry in the marker, capped at the maximum duration
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+}
+
+/// Standing of a marker that is present
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OptOut {
+    Active(UserOptOut),
+    /// The expiry written in the marker has passed
+    Expired,
+    /// The marker has been in place longer than the maximum opt-out duration
+    Exceeded { since: u64 },
+}
+
+/// Read the opt-out marker under `home`: `None` without one, otherwise the expiry written in it
+fn read_marker(home: &Path) -> Result<Option<Option<u64>>> {
+    let path = home.join(OPT_OUT_FILE);
+    // A symlink or FIFO planted by the user must not make root read elsewhere or block
+    let Ok(metadata) = fs::symlink_metadata(&path) else {
+        return Ok(None);
+    };
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let mut content = String::new();
+    fs::File::open(&path)?.take(MARKER_READ_LIMIT).read_to_string(&mut content)?;
+    let written = parse_expiry(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    Ok(Some(written))
+}
+
+/// State of a marker first seen at `since` with expiry `written`, at time `now`
+fn evaluate(since: u64, written: Option<u64>, now: u64, max_duration: Duration) -> OptOut {
+    let cap = since.saturating_add(max_duration.as_secs());
+    if written.is_some_and(|expiry| expiry <= now) {
+        OptOut::Expired
+    } else if cap <= now {
+        OptOut::Exceeded { since }
+    } else {
+        OptOut::Active(UserOptOut { since, expires_at: written.map_or(cap, |expiry| expiry.min(cap)) })
+    }
+}
+
+/// Set `opt_out` on users whose marker is in effect. `seen` maps usernames to when their marker
+/// was first seen and is updated in place. Returns warnings for opt-outs that ran past the maximum
+/// duration or whose marker could not be read; those users are managed as usual.
+pub fn mark_users(users: &mut [UserInfo], seen: &mut BTreeMap<String, u64>, now: u64, max_duration: Duration) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for user in users {
+        let Some(home) = &user.home_dir else {
+            continue;
+        };
+        let written = match read_marker(Path::new(home)) {
+            Ok(Some(written)) => written,
+            Ok(None) => {
+                seen.remove(&user.username);
+                continue;
+            }
+            Err(e) => {
+                warnings.push(format!("ignoring unreadable opt-out marker of user {}: {}", user.username, e));
+                continue;
+            }
+        };
+        let since = *seen.entry(user.username.clone()).or_insert(now);
+        match evaluate(since, written, now, max_duration) {
+            OptOut::Active(opt_out) => {
+                info!("User {} opted out of key management until {}", user.username, opt_out.expires_at);
+                user.opt_out = Some(opt_out);
+            }
+            OptOut::Expired => info!("Opt-out of user {} has expired, managing their keys again", user.username),
+            OptOut::Exceeded { since } => warnings.push(format!(
+                "opt-out of user {} has been in place since {} and exceeds the maximum of {} days; managing their keys again",
+                user.username,
+                since,
+                max_duration.as_secs() / (24 * 60 * 60)
+            )),
+        }
+    }
+    warnings
+}
+
+/// Expiry written in a marker: the first line that is neither blank nor a comment, either a
+/// `YYYY-MM-DD` date (the opt-out lasts through that day, UTC) or seconds since the epoch
+fn parse_expiry(content: &str) -> Result<Option<u64>> {
+    let Some(line) = content.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#')) else {
+        return Ok(None);
+    };
+    if let Ok(seconds) = line.parse::<u64>() {
+        return Ok(Some(seconds));
+    }
+    let parts: Vec<&str> = line.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return Err(anyhow!("expected an expiry date as YYYY-MM-DD, got {:?}", line));
+    };
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse::<i64>(), month.parse::<u32>(), day.parse::<u32>()) else {
+        return Err(anyhow!("expected an expiry date as YYYY-MM-DD, got {:?}", line));
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return Err(anyhow!("invalid expiry date {:?}", line));
+    }
+    Ok(Some((days_from_civil(year, month, day) as u64 + 1) * 24 * 60 * 60))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+    const MAX: Duration = Duration::from_secs(30 * DAY);
+
+    fn home_with_marker(content: &str) -> tempfile::TempDir {
+        let home = tempfile::tempdir().unwrap();
+        let path = home.path().join(OPT_OUT_FILE);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        home
+    }
+
+    fn user(home: &Path) -> UserInfo {
+        UserInfo {
+            username: "alice".to_string(),
+            uid: 1000,
+            shell: None,
+            home_dir: Some(home.to_string_lossy().to_string()),
+            disabled: None,
+            home_encryption: None,
+            home_mounted: None,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        }
+    }
+
+    #[test]
+    fn test_absent_marker() {
+        let home = tempfile::tempdir().unwrap();
+        let mut seen = BTreeMap::from([("alice".to_string(), 5)]);
+        let mut users = vec![user(home.path())];
+        assert!(mark_users(&mut users, &mut seen, 1_000_000, MAX).is_empty());
+        assert_eq!(users[0].opt_out, None);
+        // A removed marker starts the clock over next time
+        assert!(seen.is_empty());
+
+        // Only regular files count
+        fs::create_dir_all(home.path().join(OPT_OUT_FILE)).unwrap();
+        assert_eq!(read_marker(home.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_present_marker_lasts_the_maximum() {
+        let home = home_with_marker("");
+        let mut seen = BTreeMap::new();
+        let mut users = vec![user(home.path())];
+        let since = 1_700_000_000;
+        mark_users(&mut users, &mut seen, since, MAX);
+        assert_eq!(users[0].opt_out, Some(UserOptOut { since, expires_at: since + 30 * DAY }));
+        assert_eq!(seen["alice"], since);
+
+        // Counted from when the agent first saw it, not from the file's mtime
+        let mut users = vec![user(home.path())];
+        let warnings = mark_users(&mut users, &mut seen, since + 30 * DAY, MAX);
+        assert_eq!(users[0].opt_out, None);
+        assert!(warnings[0].contains("exceeds the maximum of 30 days"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn test_expired_marker() {
+        // 2023-11-14 22:13:20 UTC
+        let since = 1_700_000_000;
+        let end_of_day = 1_700_524_800; // 2023-11-21 00:00:00 UTC
+        let written = parse_expiry("# hardware token migration\n2023-11-20\n").unwrap();
+        assert_eq!(
+            evaluate(since, written, since + DAY, MAX),
+            OptOut::Active(UserOptOut { since, expires_at: end_of_day })
+        );
+        assert_eq!(evaluate(since, written, end_of_day, MAX), OptOut::Expired);
+
+        let home = home_with_marker("2023-11-20");
+        let mut users = vec![user(home.path())];
+        assert!(mark_users(&mut users, &mut BTreeMap::new(), end_of_day, MAX).is_empty());
+        assert_eq!(users[0].opt_out, None);
+
+        // An expiry beyond the maximum is capped
+        assert_eq!(
+            evaluate(since, parse_expiry("2030-01-01").unwrap(), since + DAY, MAX),
+            OptOut::Active(UserOptOut { since, expires_at: since + 30 * DAY })
+        );
+    }
+
+    #[test]
+    fn test_unreadable_marker_is_ignored() {
+        let home = home_with_marker("next tuesday");
+        let mut users = vec![user(home.path())];
+        let warnings = mark_users(&mut users, &mut BTreeMap::new(), 1_700_000_000, MAX);
+        assert_eq!(users[0].opt_out, None);
+        assert!(warnings[0].contains("YYYY-MM-DD"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(parse_expiry("\n# comment only\n").unwrap(), None);
+        assert_eq!(parse_expiry("1700000000").unwrap(), Some(1_700_000_000));
+        assert_eq!(parse_expiry("1970-01-01").unwrap(), Some(DAY));
+        assert_eq!(parse_expiry("2024-02-29").unwrap(), Some(19_783 * DAY));
+        assert!(parse_expiry("next tuesday").is_err());
+        assert!(parse_expiry("2024-13-01").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::sync::OnceLock;
+use serde::Serialize;
+
+use crate::api::{self, ApiError};
+use crate::check::FileDrift;
+use crate::interrupt;
+use crate::ssh_keys::KeySyncStats;
+use crate::system::SystemInfo;
+use crate::token;
+use crate::users::UserInfo;
+
+/// How the agent reports the outcome of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable progress on stdout
+    #[default]
+    Text,
+    /// A single JSON object with changed/failed/msg, as Ansible modules emit
+    Ansible,
+    /// A single JSON document with the report, sync stats and errors
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Select the output format for this process
+pub fn set_format(format: OutputFormat) {
+    FORMAT.set(format).ok();
+}
+
+pub fn format() -> OutputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Print a progress line: stdout in text mode, stderr when stdout carries machine-readable output
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::format() == $crate::output::OutputFormat::Text {
+            println!($($arg)*);
+        } else {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// What the run reported about this host
+#[derive(Debug, Serialize)]
+pub struct ReportedHost {
+    pub hostname: String,
+    #[serde(rename = "systemInfo")]
+    pub system_info: SystemInfo,
+    pub users: Vec<UserInfo>,
+    /// Host ID the server answered the report with
+    #[serde(rename = "hostId", skip_serializing_if = "Option::is_none")]
+    pub host_id: Option<String>,
+}
+
+/// An error for machine-readable output, categorised like the exit code
+#[derive(Debug, Serialize)]
+pub struct RunError {
+    pub category: ExitCode,
+    pub message: String,
+}
+
+impl RunError {
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        Self {
+            category: error_category(error),
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+/// Outcome of a successful run
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub changed: bool,
+    pub msg: String,
+    #[serde(flatten)]
+    pub host: Option<ReportedHost>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<KeySyncStats>,
+    /// Failures that did not abort the run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<RunError>,
+    /// `check-update` found a newer release
+    #[serde(skip)]
+    pub update_available: bool,
+    /// ID sent with every API request of the run
+    #[serde(rename = "runId", skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// The health check the run started with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthSummary>,
+    /// `--check`: the files that differ from the key assignments, empty when all match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<Vec<FileDrift>>,
+}
+
+/// How the health check at the start of a run went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthStatus {
+    Passed,
+    /// The server answered, but not with a healthy JSON response
+    Unhealthy,
+    /// No usable answer; the run went on regardless
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthSummary {
+    pub status: HealthStatus,
+    /// Round trip of the health check, when the server answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+impl HealthSummary {
+    pub fn of(result: &Result<api::Health, ApiError>) -> Self {
+        match result {
+            Ok(health) => Self {
+                status: if health.healthy { HealthStatus::Passed } else { HealthStatus::Unhealthy },
+                latency_ms: Some(health.latency.as_millis() as u64),
+            },
+            Err(_) => Self { status: HealthStatus::Unreachable, latency_ms: None },
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn message(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether a sync (or dry-run simulation) changed anything
+pub fn stats_changed(stats: &KeySyncStats) -> bool {
+    stats.files_updated > 0
+        || stats.keys_added > 0
+        || stats.keys_removed > 0
+        || stats.keys_updated > 0
+        || stats.permissions_fixed > 0
+}
+
+/// Ansible module result for a run
+pub fn ansible_result(result: &anyhow::Result<RunSummary>) -> serde_json::Value {
+    match result {
+        Ok(summary) => serde_json::json!({
+            "changed": summary.changed,
+            "failed": false,
+            "msg": summary.msg,
+        }),
+        Err(e) => serde_json::json!({
+            "changed": false,
+            "failed": true,
+            "msg": e.to_string(),
+        }),
+    }
+}
+
+/// JSON document for `--output json`
+pub fn json_result(result: &anyhow::Result<RunSummary>) -> serde_json::Value {
+    match result {
+        Ok(summary) => {
+            let mut value = serde_json::to_value(summary).unwrap_or_default();
+            value["failed"] = false.into();
+            value
+        }
+        Err(e) => serde_json::json!({
+            "changed": false,
+            "failed": true,
+            "msg": e.to_string(),
+            "errors": [RunError::from_error(e)],
+        }),
+    }
+}
+
+/// Process exit codes. Scripts and monitoring depend on these values; never renumber them.
+/// Serialized names are the error categories of `--output json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitCode {
+    Success = 0,
+    /// Any failure without a more specific code
+    Failure = 1,
+    /// The run completed, but some keys or files could not be synced
+    PartialSync = 2,
+    /// The API server could not be reached
+    ApiUnreachable = 3,
+    /// The server refused the token (401/403), or --token-command could not provide one
+    AuthFailure = 4,
+    /// The server requires a newer agent (426)
+    VersionTooOld = 5,
+    /// `check-update` found a newer release
+    UpdateAvailable = 6,
+    /// SIGTERM or SIGINT stopped the run
+    Interrupted = 7,
+    /// Key assignments were unsigned or failed `--assignments-pubkey` verification
+    BadSignature = 8,
+    /// `--check` found authorized_keys files that differ from the key assignments
+    Drift = 9,
+}
+
+/// Exit code for a run. `changed_exit_code` distinguishes success-with-changes from
+/// success-no-changes; partial sync errors take precedence over it.
+pub fn exit_code(result: &anyhow::Result<RunSummary>, changed_exit_code: Option<u8>) -> i32 {
+    let code = match result {
+        Ok(summary) if summary.update_available => ExitCode::UpdateAvailable,
+        Ok(summary) if summary.stats.as_ref().is_some_and(|stats| stats.interrupted) => ExitCode::Interrupted,
+        Ok(summary) if let Some(error) = summary.errors.first() => error.category,
+        Ok(summary) if summary.stats.as_ref().is_some_and(|stats| stats.errors > 0) => ExitCode::PartialSync,
+        Ok(summary) if summary.check.as_ref().is_some_and(|drift| !drift.is_empty()) => ExitCode::Drift,
+        Ok(summary) if summary.changed => return changed_exit_code.map(i32::from).unwrap_or(0),
+        Ok(_) => ExitCode::Success,
+        Err(e) => error_category(e),
+    };
+    code as i32
+}
+
+/// Exit code an error maps to
+pub fn error_category(error: &anyhow::Error) -> ExitCode {
+    if interrupt::is_interrupted(error) {
+        return ExitCode::Interrupted;
+    }
+    if error.chain().any(|cause| cause.is::<token::TokenCommandError>()) {
+        return ExitCode::AuthFailure;
+    }
+    match api::api_error(error) {
+        Some(ApiError::Network { .. }) => ExitCode::ApiUnreachable,
+        Some(ApiError::Unauthorized { .. }) => ExitCode::AuthFailure,
+        Some(ApiError::VersionTooOld { .. }) => ExitCode::VersionTooOld,
+        Some(ApiError::BadSignature(_)) => ExitCode::BadSignature,
+        Some(ApiError::Http { .. } | ApiError::Decode { .. } | ApiError::Protocol(_)) | None => ExitCode::Failure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiClient;
+    use crate::test_support::{MockResponse, mock_server};
+
+    fn changed() -> anyhow::Result<RunSummary> {
+        let stats = KeySyncStats { keys_added: 2, files_updated: 1, ..Default::default() };
+        Ok(RunSummary {
+            changed: stats_changed(&stats),
+            msg: "Report completed successfully".to_string(),
+            stats: Some(stats),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let result = Ok(RunSummary::message("Report completed successfully"));
+        assert_eq!(exit_code(&result, Some(90)), 0);
+        assert_eq!(
+            ansible_result(&result),
+            serde_json::json!({"changed": false, "failed": false, "msg": "Report completed successfully"})
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let result = changed();
+        assert_eq!(exit_code(&result, Some(90)), 90);
+        assert_eq!(exit_code(&result, None), 0);
+        assert_eq!(
+            ansible_result(&result),
+            serde_json::json!({"changed": true, "failed": false, "msg": "Report completed successfully"})
+        );
+    }
+
+    #[test]
+    fn test_failure() {
+        let result: anyhow::Result<RunSummary> = Err(anyhow::anyhow!("HTTP error (500)"));
+        assert_eq!(exit_code(&result, Some(90)), 1);
+        assert_eq!(
+            ansible_result(&result),
+            serde_json::json!({"changed": false, "failed": true, "msg": "HTTP error (500)"})
+        );
+    }
+
+    #[test]
+    fn test_partial_sync() {
+        let stats = KeySyncStats { keys_added: 1, errors: 1, ..Default::default() };
+        let result = Ok(RunSummary { changed: true, stats: Some(stats), ..Default::default() });
+        assert_eq!(exit_code(&result, Some(90)), 2);
+    }
+
+    #[test]
+    fn test_check_drift() {
+        let compliant = Ok(RunSummary { check: Some(Vec::new()), ..RunSummary::message("Compliance check passed") });
+        assert_eq!(exit_code(&compliant, Some(90)), 0);
+
+        let stats = KeySyncStats {
+            changes: vec![crate::ssh_keys::FileKeyChanges {
+                username: "alice".to_string(),
+                path: "/home/alice/.ssh/authorized_keys".into(),
+                added: vec!["SHA256:assigned".to_string()],
+                removed: Vec::new(),
+                updated: Vec::new(),
+            }],
+            ..Default::default()
+        };
+        let drift = crate::check::compare(&stats);
+        let result = Ok(RunSummary { check: Some(drift), stats: Some(stats), ..Default::default() });
+        assert_eq!(exit_code(&result, None), 9);
+        assert_eq!(json_result(&result)["check"][0]["missing"], serde_json::json!(["SHA256:assigned"]));
+    }
+
+    #[test]
+    fn test_update_available() {
+        let result = Ok(RunSummary { update_available: true, ..RunSummary::message("Update check completed") });
+        assert_eq!(exit_code(&result, None), 6);
+    }
+
+    async fn assignments_error(responses: Vec<MockResponse>) -> anyhow::Error {
+        let (server, _) = mock_server(responses).await;
+        let client = ApiClient::new(server, "pk_test".into()).unwrap();
+        client.get_key_assignments().await.unwrap_err().into()
+    }
+
+    #[tokio::test]
+    async fn test_api_failure_exit_codes() {
+        let code = |error: anyhow::Error| exit_code(&Err(error), Some(90));
+
+        assert_eq!(code(assignments_error(vec![MockResponse::new(401, r#"{"error":"invalid token"}"#)]).await), 4);
+        assert_eq!(code(assignments_error(vec![MockResponse::new(403, "forbidden")]).await), 4);
+        assert_eq!(code(assignments_error(vec![MockResponse::new(500, "boom")]).await), 1);
+
+        let (server, _) = mock_server(vec![MockResponse::new(
+            426,
+            r#"{"error":"outdated","message":"too old","minimumVersion":"9.0.0","currentVersion":"0.1.0"}"#,
+        )])
+        .await;
+        let client = ApiClient::new(server, "pk_test".into()).unwrap();
+        let report_err = client.report_spooled(&serde_json::json!({}), "key").await.unwrap_err().into();
+        assert_eq!(code(report_err), 5);
+
+        // Nothing listens on a port the OS just handed out and released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let client = ApiClient::new(format!("http://127.0.0.1:{}", port), "pk_test".into()).unwrap();
+        assert_eq!(code(client.get_key_assignments().await.unwrap_err().into()), 3);
+
+        let (server, _) = mock_server(vec![MockResponse::new(200, r#"{"success":true,"assignments":[]}"#)]).await;
+        let signer = crate::test_support::AssignmentsSigner::generate();
+        let client = ApiClient::new(server, "pk_test".into()).unwrap().with_assignments_pubkey(Some(signer.public_key()));
+        assert_eq!(code(client.get_key_assignments().await.unwrap_err().into()), 8);
+    }
+
+    #[test]
+    fn test_json_result() {
+        let summary = RunSummary {
+            host: Some(ReportedHost {
+                hostname: "web-1".to_string(),
+                system_info: crate::system::SystemInfo {
+                    os: "Linux".to_string(),
+                    arch: "x86_64".to_string(),
+                    platform: "linux".to_string(),
+                    kernel: "6.1.0".to_string(),
+                    distribution: "Debian".to_string(),
+                    version: "12".to_string(),
+                    immutable_os: false,
+                },
+                users: Vec::new(),
+                host_id: Some("h-1".to_string()),
+            }),
+            errors: vec![RunError { category: ExitCode::ApiUnreachable, message: "Failed to fetch key assignments".to_string() }],
+            ..changed().unwrap()
+        };
+        let value = json_result(&Ok(summary));
+        assert_eq!(value["failed"], false);
+        assert_eq!(value["changed"], true);
+        assert_eq!(value["hostname"], "web-1");
+        assert_eq!(value["hostId"], "h-1");
+        assert_eq!(value["systemInfo"]["distribution"], "Debian");
+        assert_eq!(value["stats"]["keys_added"], 2);
+        assert_eq!(value["errors"][0]["category"], "api-unreachable");
+        assert!(value.get("health").is_none());
+
+        let health = Ok(api::Health { healthy: true, latency: std::time::Duration::from_millis(42) });
+        let summary = RunSummary { health: Some(HealthSummary::of(&health)), ..changed().unwrap() };
+        assert_eq!(json_result(&Ok(summary))["health"], serde_json::json!({"status": "passed", "latencyMs": 42}));
+        let unreachable = HealthSummary::of(&Err(ApiError::Protocol("TLS handshake failed".to_string())));
+        assert_eq!(serde_json::to_value(unreachable).unwrap(), serde_json::json!({"status": "unreachable"}));
+
+        let failed = json_result(&Err(anyhow::anyhow!("HTTP error (500)")));
+        assert_eq!(failed["failed"], true);
+        assert_eq!(failed["errors"], serde_json::json!([{"category": "failure", "message": "HTTP error (500)"}]));
+    }
+
+    #[test]
+    fn test_stats_changed() {
+        assert!(!stats_changed(&KeySyncStats::default()));
+        assert!(stats_changed(&KeySyncStats { keys_removed: 1, ..Default::default() }));
+        assert!(stats_changed(&KeySyncStats { files_updated: 1, ..Default::default() }));
+    }
+}
@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use clap::ValueEnum;
+use secrecy::SecretString;
+
+use crate::config;
+use crate::service::{self, ServiceUnits};
+use crate::setup::{self, SetupAnswers};
+
+/// Where distribution packages install the binary
+const PACKAGED_BINARY: &str = "/usr/bin/pkagent";
+
+/// Package flavour to emit assets for
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PackageFormat {
+    Deb,
+    Rpm,
+}
+
+/// One generated file, relative to the output directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asset {
+    pub path: PathBuf,
+    pub content: String,
+    pub mode: u32,
+}
+
+/// Answers describing a packaged system install: empty endpoint and token for the admin to fill in
+fn packaged_answers() -> SetupAnswers {
+    SetupAnswers {
+        endpoint: String::new(),
+        token: SecretString::from(String::new()),
+        user_mode: false,
+        schedule: setup::DEFAULT_SCHEDULE.to_string(),
+        config_path: PathBuf::from(setup::SYSTEM_CONFIG_PATH),
+        install_service: true,
+    }
+}
+
+/// Shell snippet run after install: reload units and enable the timer on first configuration
+fn enable_snippet(condition: &str) -> String {
+    format!(
+        "if {} && [ -d /run/systemd/system ]; then\n    \
+         systemctl daemon-reload >/dev/null || true\n    \
+         systemctl {} >/dev/null || true\n\
+         fi\n",
+        condition,
+        service::ENABLE_TIMER.join(" ")
+    )
+}
+
+/// Shell snippet run before removal: stop and disable the timer
+fn disable_snippet(condition: &str) -> String {
+    format!(
+        "if {} && [ -d /run/systemd/system ]; then\n    \
+         systemctl disable --now {} >/dev/null || true\n\
+         fi\n",
+        condition,
+        service::TIMER_UNIT
+    )
+}
+
+/// Every file for `format`, rendered from the same templates `pkagent install-service` installs.
+///
+/// The output depends only on `format`, never on the build host or the time, so packages built
+/// from it are reproducible.
+pub fn assets(format: PackageFormat) -> Vec<Asset> {
+    let answers = packaged_answers();
+    let schedule = service::Schedule::Calendar(answers.schedule.clone());
+    let (service, timer) = ServiceUnits::system(Path::new(PACKAGED_BINARY), &answers.config_path, schedule).render();
+    let env_file = setup::render_config(&answers, "package-assets");
+    let relative = |path: &str| PathBuf::from(path.trim_start_matches('/'));
+
+    let (unit_dir, scripts) = match format {
+        PackageFormat::Deb => (
+            "lib/systemd/system",
+            vec![
+                ("DEBIAN/postinst", enable_snippet("[ \"$1\" = \"configure\" ]")),
+                ("DEBIAN/prerm", disable_snippet("[ \"$1\" = \"remove\" ]")),
+            ],
+        ),
+        PackageFormat::Rpm => (
+            "usr/lib/systemd/system",
+            vec![
+                // Scriptlet bodies for %post and %preun; $1 counts installed versions
+                ("scriptlets/post", enable_snippet("[ \"$1\" -eq 1 ]")),
+                ("scriptlets/preun", disable_snippet("[ \"$1\" -eq 0 ]")),
+            ],
+        ),
+    };
+
+    let mut assets = vec![
+        Asset { path: Path::new(unit_dir).join(service::SERVICE_UNIT), content: service, mode: 0o644 },
+        Asset { path: Path::new(unit_dir).join(service::TIMER_UNIT), content: timer, mode: 0o644 },
+        Asset { path: relative(setup::SYSTEM_CONFIG_PATH), content: env_file, mode: 0o600 },
+        // 0600 like the environment file, since a token may be uncommented into it
+        Asset { path: relative(config::DEFAULT_CONFIG_PATH), content: config::TEMPLATE.to_string(), mode: 0o600 },
+    ];
+    for (path, body) in scripts {
+        assets.push(Asset { path: PathBuf::from(path), content: format!("#!/bin/sh\nset -e\n{}", body), mode: 0o755 });
+    }
+    if format == PackageFormat::Deb {
+        // Keep local edits to both config files across upgrades
+        assets.push(Asset {
+            path: PathBuf::from("DEBIAN/conffiles"),
+            content: format!("{}\n{}\n", setup::SYSTEM_CONFIG_PATH, config::DEFAULT_CONFIG_PATH),
+            mode: 0o644,
+        });
+    }
+    assets
+}
+
+/// Write the assets for `format` under `out`; returns the paths written
+pub fn write_assets(format: PackageFormat, out: &Path) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for asset in assets(format) {
+        let path = out.join(&asset.path);
+        setup::write_atomically(&path, &asset.content, asset.mode)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TEMPLATE;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Every asset as one string, so a change to any template shows up as a snapshot diff
+    fn snapshot(format: PackageFormat) -> String {
+        assets(format)
+            .iter()
+            .map(|asset| format!("=== {} ({:o})\n{}", asset.path.display(), asset.mode, asset.content))
+            .collect()
+    }
+
+    const UNITS: &str = "\
+[Unit]
+Description=PubliKey Agent
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=oneshot
+EnvironmentFile=/etc/publikey/agent.env
+ExecStart=/usr/bin/pkagent run
+ProtectSystem=strict
+ReadWritePaths=/home /root -/var/lib/publikey
+PrivateTmp=true
+NoNewPrivileges=true
+ProtectKernelTunables=true
+ProtectKernelModules=true
+ProtectControlGroups=true
+RestrictSUIDSGID=true
+";
+
+    const TIMER: &str = "\
+[Unit]
+Description=Run PubliKey Agent periodically
+Requires=publikey-agent.service
+
+[Timer]
+OnCalendar=*:*:00
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+";
+
+    const CONFIG: &str = "\
+# PubliKey Agent configuration (generated by pkagent package-assets)
+PUBLIKEY_ENDPOINT=
+PUBLIKEY_TOKEN=
+";
+
+    #[test]
+    fn test_deb_snapshot() {
+        let expected = format!(
+            "=== lib/systemd/system/publikey-agent.service (644)\n{UNITS}\
+             === lib/systemd/system/publikey-agent.timer (644)\n{TIMER}\
+             === etc/publikey/agent.env (600)\n{CONFIG}\
+             === etc/publikey/agent.toml (600)\n{TEMPLATE}\
+             === DEBIAN/postinst (755)\n\
+             #!/bin/sh\n\
+             set -e\n\
+             if [ \"$1\" = \"configure\" ] && [ -d /run/systemd/system ]; then\n    \
+             systemctl daemon-reload >/dev/null || true\n    \
+             systemctl enable --now publikey-agent.timer >/dev/null || true\n\
+             fi\n\
+             === DEBIAN/prerm (755)\n\
+             #!/bin/sh\n\
+             set -e\n\
+             if [ \"$1\" = \"remove\" ] && [ -d /run/systemd/system ]; then\n    \
+             systemctl disable --now publikey-agent.timer >/dev/null || true\n\
+             fi\n\
+             === DEBIAN/conffiles (644)\n\
+             /etc/publikey/agent.env\n\
+             /etc/publikey/agent.toml\n"
+        );
+        assert_eq!(snapshot(PackageFormat::Deb), expected);
+    }
+
+    #[test]
+    fn test_rpm_snapshot() {
+        let expected = format!(
+            "=== usr/lib/systemd/system/publikey-agent.service (644)\n{UNITS}\
+             === usr/lib/systemd/system/publikey-agent.timer (644)\n{TIMER}\
+             === etc/publikey/agent.env (600)\n{CONFIG}\
+             === etc/publikey/agent.toml (600)\n{TEMPLATE}\
+             === scriptlets/post (755)\n\
+             #!/bin/sh\n\
+             set -e\n\
+             if [ \"$1\" -eq 1 ] && [ -d /run/systemd/system ]; then\n    \
+             systemctl daemon-reload >/dev/null || true\n    \
+             systemctl enable --now publikey-agent.timer >/dev/null || true\n\
+             fi\n\
+             === scriptlets/preun (755)\n\
+             #!/bin/sh\n\
+             set -e\n\
+             if [ \"$1\" -eq 0 ] && [ -d /run/systemd/system ]; then\n    \
+             systemctl disable --now publikey-agent.timer >/dev/null || true\n\
+             fi\n"
+        );
+        assert_eq!(snapshot(PackageFormat::Rpm), expected);
+    }
+
+    #[test]
+    fn test_write_is_deterministic() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        let written = write_assets(PackageFormat::Deb, first.path()).unwrap();
+        write_assets(PackageFormat::Deb, second.path()).unwrap();
+
+        for path in &written {
+            let relative = path.strip_prefix(first.path()).unwrap();
+            assert_eq!(fs::read(path).unwrap(), fs::read(second.path().join(relative)).unwrap());
+        }
+        let postinst = first.path().join("DEBIAN/postinst");
+        assert_eq!(fs::metadata(&postinst).unwrap().permissions().mode() & 0o777, 0o755);
+        let config = first.path().join("etc/publikey/agent.env");
+        assert_eq!(fs::metadata(&config).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
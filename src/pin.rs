@@ -0,0 +1,308 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::{Result, Context, anyhow};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::client_cert::ClientIdentity;
+use crate::preflight;
+use crate::state::StateDir;
+
+/// File in the state directory holding the pinned endpoint certificate
+pub const PIN_FILE: &str = "cert_pin.json";
+
+/// Error reported when the endpoint presents a certificate other than the pinned one
+pub const CERT_CHANGED: &str =
+    "certificate changed: the endpoint presented a certificate that does not match the pinned one; run `pkagent reset-pin` if this change is expected";
+
+/// Pinned SPKI for an endpoint host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredPin {
+    pub host: String,
+    #[serde(rename = "spkiSha256")]
+    pub spki_sha256: String,
+}
+
+/// Trust-on-first-use certificate pin for the configured endpoint
+pub struct CertPin {
+    state: StateDir,
+    host: String,
+    verifier: Arc<PinningVerifier>,
+}
+
+impl CertPin {
+    /// Load the pin for `endpoint`; a pin recorded for a different host is ignored
+    pub fn load(state: &StateDir, endpoint: &str, roots: RootCertStore) -> Result<Self> {
+        let (host, _) = preflight::endpoint_address(endpoint)?;
+
+        let expected = match state.load::<StoredPin>(PIN_FILE) {
+            Some(stored) if stored.host == host => Some(
+                decode_hex(&stored.spki_sha256)
+                    .ok_or_else(|| anyhow!("Corrupt certificate pin in {}", state.file(PIN_FILE).display()))?,
+            ),
+            Some(stored) => {
+                info!("Ignoring certificate pin for previous endpoint host {}", stored.host);
+                None
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            state: state.clone(),
+            host,
+            verifier: Arc::new(PinningVerifier::new(roots, expected)),
+        })
+    }
+
+    /// TLS configuration enforcing the pin, presenting the `--client-cert` identity if any
+    pub fn client_config(&self, identity: Option<&ClientIdentity>) -> ClientConfig {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(self.verifier.clone())
+            .with_no_client_auth();
+        if let Some(identity) = identity {
+            identity.apply(&mut config);
+        }
+        config
+    }
+
+    /// Fail if any connection was rejected because the certificate changed
+    pub fn ensure_unchanged(&self) -> Result<()> {
+        if self.verifier.mismatch.load(Ordering::SeqCst) {
+            return Err(anyhow!("{}", CERT_CHANGED));
+        }
+        Ok(())
+    }
+
+    /// Persist the certificate seen on the first successful connection
+    pub fn persist_first_use(&self) -> Result<()> {
+        if self.verifier.expected.is_some() {
+            return Ok(());
+        }
+        let Some(spki) = *self.verifier.observed.lock().unwrap() else {
+            return Ok(());
+        };
+
+        let pin = StoredPin {
+            host: self.host.clone(),
+            spki_sha256: encode_hex(&spki),
+        };
+        self.state.store(PIN_FILE, &pin).context("Failed to write certificate pin")?;
+        info!("Pinned certificate for {} (SPKI sha256 {})", pin.host, pin.spki_sha256);
+        Ok(())
+    }
+}
+
+/// Remove the stored pin so the next run pins whatever certificate it sees; returns whether one existed
+pub fn reset_pin(state: &StateDir) -> Result<bool> {
+    state.remove(PIN_FILE)
+}
+
+/// Certificate verifier that checks the presented chain against a pinned SPKI before regular validation
+pub struct PinningVerifier {
+    inner: WebPkiVerifier,
+    expected: Option<[u8; 32]>,
+    observed: Mutex<Option<[u8; 32]>>,
+    mismatch: AtomicBool,
+}
+
+impl PinningVerifier {
+    pub fn new(roots: RootCertStore, expected: Option<[u8; 32]>) -> Self {
+        Self {
+            inner: WebPkiVerifier::new(roots, None),
+            expected,
+            observed: Mutex::new(None),
+            mismatch: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let leaf = spki_sha256(&end_entity.0).map_err(|e| rustls::Error::General(e.to_string()))?;
+
+        if let Some(expected) = self.expected {
+            let pinned = std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(|cert| spki_sha256(&cert.0).ok())
+                .any(|spki| spki == expected);
+            if !pinned {
+                self.mismatch.store(true, Ordering::SeqCst);
+                return Err(rustls::Error::General(CERT_CHANGED.to_string()));
+            }
+        }
+
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        *self.observed.lock().unwrap() = Some(leaf);
+        Ok(verified)
+    }
+}
+
+/// SHA-256 of a certificate's DER-encoded SubjectPublicKeyInfo
+pub fn spki_sha256(cert_der: &[u8]) -> Result<[u8; 32]> {
+    let (_, certificate, _) = read_der(cert_der)?;
+    let (_, mut tbs, _) = read_der(certificate)?;
+
+    // Skip the optional [0] version, then serial, signature algorithm, issuer, validity and subject
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_der(tbs)?.2;
+    }
+    for _ in 0..5 {
+        tbs = read_der(tbs)?.2;
+    }
+
+    let (tag, _, rest) = read_der(tbs)?;
+    if tag != 0x30 {
+        return Err(anyhow!("Unexpected tag {:#04x} for SubjectPublicKeyInfo", tag));
+    }
+    let encoded = &tbs[..tbs.len() - rest.len()];
+    Ok(Sha256::digest(encoded).into())
+}
+
+/// Split one DER TLV off the front of `data`: (tag, contents, remainder)
+fn read_der(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let truncated = || anyhow!("Truncated DER structure");
+    let tag = *data.first().ok_or_else(truncated)?;
+    let first = *data.get(1).ok_or_else(truncated)?;
+
+    let (len, header) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err(anyhow!("Unsupported DER length encoding"));
+        }
+        let bytes = data.get(2..2 + count).ok_or_else(truncated)?;
+        (bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize), 2 + count)
+    };
+
+    let contents = data.get(header..header + len).ok_or_else(truncated)?;
+    Ok((tag, contents, &data[header + len..]))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiClient;
+    use crate::test_support::{tls_mock_server, SelfSignedCert};
+
+    fn roots_for(certs: &[&SelfSignedCert]) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        for cert in certs {
+            roots.add(&Certificate(cert.der.clone())).unwrap();
+        }
+        roots
+    }
+
+    async fn connect(state: &StateDir, endpoint: &str, roots: RootCertStore) -> (CertPin, bool) {
+        let pin = CertPin::load(state, endpoint, roots).unwrap();
+        let client = ApiClient::with_tls_config(endpoint.to_string(), "token".into(), pin.client_config(None)).unwrap();
+        let healthy = client.health_check().await.is_ok_and(|health| health.healthy);
+        (pin, healthy)
+    }
+
+    #[tokio::test]
+    async fn test_pin_on_first_use_then_reject_changed_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let first = SelfSignedCert::generate();
+        let second = SelfSignedCert::generate();
+        let roots = || roots_for(&[&first, &second]);
+        let first_url = tls_mock_server(&first).await;
+        let second_url = tls_mock_server(&second).await;
+
+        // First use pins the certificate
+        let (pin, healthy) = connect(&state, &first_url, roots()).await;
+        assert!(healthy);
+        pin.ensure_unchanged().unwrap();
+        pin.persist_first_use().unwrap();
+        let stored = state.load::<StoredPin>(PIN_FILE).unwrap();
+        assert_eq!(stored.host, "localhost");
+        assert_eq!(stored.spki_sha256, encode_hex(&spki_sha256(&first.der).unwrap()));
+
+        // Same certificate keeps working
+        let (pin, healthy) = connect(&state, &first_url, roots()).await;
+        assert!(healthy);
+        pin.ensure_unchanged().unwrap();
+
+        // A different certificate on the same host fails closed
+        let (pin, healthy) = connect(&state, &second_url, roots()).await;
+        assert!(!healthy);
+        let err = pin.ensure_unchanged().unwrap_err();
+        assert!(err.to_string().contains("certificate changed"));
+        assert!(err.to_string().contains("pkagent reset-pin"));
+
+        // reset-pin lets the new certificate be pinned
+        assert!(reset_pin(&state).unwrap());
+        assert!(!reset_pin(&state).unwrap());
+        let (pin, healthy) = connect(&state, &second_url, roots()).await;
+        assert!(healthy);
+        pin.persist_first_use().unwrap();
+        let stored = state.load::<StoredPin>(PIN_FILE).unwrap();
+        assert_eq!(stored.spki_sha256, encode_hex(&spki_sha256(&second.der).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_pin_ignored_when_host_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let cert = SelfSignedCert::generate();
+        let url = tls_mock_server(&cert).await;
+        state
+            .store(PIN_FILE, &StoredPin {
+                host: "old.example.com".to_string(),
+                spki_sha256: encode_hex(&[0u8; 32]),
+            })
+            .unwrap();
+
+        let (pin, healthy) = connect(&state, &url, roots_for(&[&cert])).await;
+        assert!(healthy);
+        pin.ensure_unchanged().unwrap();
+        pin.persist_first_use().unwrap();
+        assert_eq!(state.load::<StoredPin>(PIN_FILE).unwrap().host, "localhost");
+    }
+
+    #[test]
+    fn test_spki_differs_between_certificates() {
+        let a = SelfSignedCert::generate();
+        let b = SelfSignedCert::generate();
+        assert_ne!(spki_sha256(&a.der).unwrap(), spki_sha256(&b.der).unwrap());
+        assert!(spki_sha256(&a.der[..20]).is_err());
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let bytes = [0xabu8; 32];
+        assert_eq!(decode_hex(&encode_hex(&bytes)), Some(bytes));
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Result, anyhow};
+
+use crate::ssh_keys::{KNOWN_KEY_TYPES, SshKey};
+
+/// Local key acceptance policy, enforced independently of server policy
+#[derive(Debug, Clone, Default)]
+pub struct KeyPolicy {
+    /// Key types that may be written; `None` allows every known type
+    pub allowed_key_types: Option<Vec<String>>,
+    /// Minimum modulus size for ssh-rsa keys and certificates
+    pub min_rsa_bits: Option<u32>,
+}
+
+impl KeyPolicy {
+    /// Build a policy, validating the allowed key types against the known key type list
+    pub fn new(allowed_key_types: &[String], min_rsa_bits: Option<u32>) -> Result<Self> {
+        for key_type in allowed_key_types {
+            if !KNOWN_KEY_TYPES.contains(&key_type.as_str()) {
+                return Err(anyhow!(
+                    "Unknown key type in --allowed-key-types: {} (known types: {})",
+                    key_type,
+                    KNOWN_KEY_TYPES.join(", ")
+                ));
+            }
+        }
+
+        let allowed_key_types = if allowed_key_types.is_empty() {
+            None
+        } else {
+            Some(allowed_key_types.to_vec())
+        };
+
+        Ok(Self {
+            allowed_key_types,
+            min_rsa_bits,
+        })
+    }
+
+    /// Check a key against the policy
+    pub fn check(&self, key: &SshKey) -> Result<()> {
+        if let Some(allowed) = &self.allowed_key_types
+            && !allowed.iter().any(|t| t == &key.key_type)
+        {
+            return Err(anyhow!("Key type {} is not allowed on this host", key.key_type));
+        }
+
+        if let Some(min_bits) = self.min_rsa_bits
+            && matches!(key.key_type.as_str(), "ssh-rsa" | "ssh-rsa-cert-v01@openssh.com")
+        {
+            let bits = rsa_modulus_bits(&key.key_data)
+                .ok_or_else(|| anyhow!("Could not determine RSA key size"))?;
+            if bits < min_bits {
+                return Err(anyhow!("RSA key has {} bits, minimum is {}", bits, min_bits));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Determine the modulus size of an ssh-rsa public key or certificate blob
+fn rsa_modulus_bits(key_data: &str) -> Option<u32> {
+    use base64::Engine;
+
+    let blob = base64::engine::general_purpose::STANDARD.decode(key_data).ok()?;
+    let mut rest = blob.as_slice();
+
+    // Wire format: string "ssh-rsa", mpint e, mpint n; certificates put a nonce before e
+    let key_type = read_ssh_string(&mut rest)?;
+    if key_type.ends_with(b"-cert-v01@openssh.com") {
+        let _nonce = read_ssh_string(&mut rest)?;
+    }
+    let _exponent = read_ssh_string(&mut rest)?;
+    let modulus = read_ssh_string(&mut rest)?;
+
+    let modulus: Vec<u8> = modulus.iter().copied().skip_while(|b| *b == 0).collect();
+    let first = *modulus.first()?;
+    Some((modulus.len() as u32 - 1) * 8 + (8 - first.leading_zeros()))
+}
+
+/// Read a length-prefixed SSH wire string
+fn read_ssh_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len_bytes: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    let value = data.get(4..4 + len)?;
+    *data = &data[4 + len..];
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_2048: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj test@example.com";
+    const ED25519: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e";
+
+    const RSA_3072_CERT: &str = "ssh-rsa-cert-v01@openssh.com AAAAHHNzaC1yc2EtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgQTzA9pNS5iXW8uBEf36p1ZCrIf8nmZT11c+uu0bxSy8AAAADAQABAAABgQCspqL2KpDs8JAlLUp8cl2Jn7jsmyGuA3ctS8yTFjTq5RFSFzNc+PYgvPvzARiKSECEB5EoIGLuuBJk27bp2l/8//QApuAZfdznyKaGBZH9eTi8Ya9DhDgqIVx/vqFTJTlHUdYn/mrSJ3L6usQjnNVr1RrjMTRw7er8YQSvyOluWEVb0VN5ppVTqYkd8CRWSM362Dx2dYiiSffNNCwEugokVy8W+oVsbHwyykR0ya75p7m1goJ0IySSOEOUH9Z6OE4Nwp2VbA3wvIh0knPM2idYA8fU3I3wPx1T5Ifv7aqpwEepV2QWiIJlTkFVgJUHUce17rY4zbQJsoNYV/V7OU0RQhHw7HGqpAys8cvoOh+OC6QN22T+MclmwzXWm08jkTaUBu3rnaHV69nKgSXsEziiK/muWKueySXW3uEr/mvzMtJ81HOBLJhIG/PZ89isP9RjvK5pYIV050EMyzNIUuJx3sGKQD6qza3yDvvtEDuMo5QmWt6qIB3PgRO+Jt0E1BMAAAAAAAAAAQAAAAEAAAAGaWQtcnNhAAAACQAAAAVhbGljZQAAAABeC+EAAAAAAPSFBYAAAAAAAAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACA1aew8YvFYUwtZeV4eq4N/gSPoCDwkgjNI2DJ4jrFyqwAAAFMAAAALc3NoLWVkMjU1MTkAAABA3hJcSL3Uh4TiFyRvsMvIZEl7Po6mVo8KftvVnT2h0zqiAM3/oNOTyF/xkNUlT/4PuCAUuuqyEDklCj+q0Vw/CQ==";
+
+    fn types(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rsa_modulus_bits() {
+        let key = SshKey::parse(RSA_2048).unwrap();
+        assert_eq!(rsa_modulus_bits(&key.key_data), Some(2048));
+
+        let cert = SshKey::parse(RSA_3072_CERT).unwrap();
+        assert_eq!(rsa_modulus_bits(&cert.key_data), Some(3072));
+        let policy = KeyPolicy::new(&[], Some(4096)).unwrap();
+        assert!(policy.check(&cert).is_err());
+    }
+
+    #[test]
+    fn test_unknown_allowed_key_type_rejected() {
+        assert!(KeyPolicy::new(&types(&["ssh-ed25519", "ssh-foo"]), None).is_err());
+        assert!(KeyPolicy::new(&types(&["ssh-ed25519", "ecdsa-sha2-nistp256"]), None).is_ok());
+    }
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        let policy = KeyPolicy::new(&[], None).unwrap();
+        assert!(policy.check(&SshKey::parse(RSA_2048).unwrap()).is_ok());
+        assert!(policy.check(&SshKey::parse(ED25519).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_allowed_key_types() {
+        let policy = KeyPolicy::new(&types(&["ssh-ed25519"]), None).unwrap();
+        assert!(policy.check(&SshKey::parse(RSA_2048).unwrap()).is_err());
+        assert!(policy.check(&SshKey::parse(ED25519).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_combined_policy() {
+        let rsa = SshKey::parse(RSA_2048).unwrap();
+        let ed25519 = SshKey::parse(ED25519).unwrap();
+
+        // RSA allowed by type but too small
+        let policy = KeyPolicy::new(&types(&["ssh-rsa", "ssh-ed25519"]), Some(3072)).unwrap();
+        assert!(policy.check(&rsa).is_err());
+        assert!(policy.check(&ed25519).is_ok());
+
+        // RSA large enough and allowed
+        let policy = KeyPolicy::new(&types(&["ssh-rsa"]), Some(2048)).unwrap();
+        assert!(policy.check(&rsa).is_ok());
+        assert!(policy.check(&ed25519).is_err());
+
+        // Size limit alone does not affect other key types
+        let policy = KeyPolicy::new(&[], Some(4096)).unwrap();
+        assert!(policy.check(&rsa).is_err());
+        assert!(policy.check(&ed25519).is_ok());
+    }
+}
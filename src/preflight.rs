@@ -0,0 +1,133 @@
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use tracing::{info, warn};
+
+use crate::state::{self, StateDir};
+
+/// Timeout for the pre-flight TCP connect
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to do after the pre-flight reachability probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreflightOutcome {
+    /// Endpoint reachable (or unreachable without --offline-ok): continue with the run
+    Proceed,
+    /// Endpoint unreachable and --offline-ok set: exit quietly
+    SkipOffline,
+}
+
+/// Host and port the endpoint URL points at
+pub fn endpoint_address(endpoint: &str) -> Result<(String, u16)> {
+    let url = reqwest::Url::parse(endpoint).map_err(|e| anyhow!("Invalid endpoint URL {}: {}", endpoint, e))?;
+    let host = url.host_str().ok_or_else(|| anyhow!("Endpoint URL has no host: {}", endpoint))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Endpoint URL has no port: {}", endpoint))?;
+    Ok((host.trim_matches(['[', ']']).to_string(), port))
+}
+
+/// Cheap TCP connect probe to the endpoint host
+pub async fn endpoint_reachable(endpoint: &str, timeout: Duration) -> bool {
+    let Ok((host, port)) = endpoint_address(endpoint) else {
+        return false;
+    };
+
+    let target = format!("{}:{}", host, port);
+    connect_within(&target, tokio::net::TcpStream::connect((host.as_str(), port)), timeout).await
+}
+
+/// Await a connect attempt, treating errors and timeouts as unreachable
+async fn connect_within<T>(
+    target: &str,
+    connect: impl std::future::Future<Output = std::io::Result<T>>,
+    timeout: Duration,
+) -> bool {
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            info!("Endpoint {} unreachable: {}", target, e);
+            false
+        }
+        Err(_) => {
+            info!("Endpoint {} did not answer within {:?}", target, timeout);
+            false
+        }
+    }
+}
+
+/// Probe the endpoints and decide whether the run should continue; one reachable endpoint is enough
+pub async fn preflight(endpoints: &[String], offline_ok: bool, timeout: Duration, state: &StateDir) -> PreflightOutcome {
+    for endpoint in endpoints {
+        if endpoint_reachable(endpoint, timeout).await {
+            return PreflightOutcome::Proceed;
+        }
+    }
+
+    if offline_ok {
+        if let Err(e) = state::record_last_run(state, "skipped: offline") {
+            warn!("Failed to record last run state: {}", e);
+        }
+        PreflightOutcome::SkipOffline
+    } else {
+        warn!("{} is not reachable, continuing anyway", endpoints.join(", "));
+        PreflightOutcome::Proceed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An endpoint on a port nothing is listening on
+    async fn closed_endpoint() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_endpoint_address() {
+        assert_eq!(endpoint_address("https://pk.example.com").unwrap(), ("pk.example.com".to_string(), 443));
+        assert_eq!(endpoint_address("http://localhost:3000/").unwrap(), ("localhost".to_string(), 3000));
+        assert_eq!(endpoint_address("http://[::1]:8080").unwrap(), ("::1".to_string(), 8080));
+        assert!(endpoint_address("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_reachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        assert!(endpoint_reachable(&endpoint, PROBE_TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn test_probe_timeout() {
+        // A connect that never completes is cut off at the timeout
+        let start = std::time::Instant::now();
+        let hanging = std::future::pending::<std::io::Result<()>>();
+        assert!(!connect_within("blackhole:81", hanging, Duration::from_millis(200)).await);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < PROBE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_offline_ok_skips_quietly_and_records_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        let endpoint = closed_endpoint().await;
+
+        let outcome = preflight(std::slice::from_ref(&endpoint), true, PROBE_TIMEOUT, &state).await;
+        assert_eq!(outcome, PreflightOutcome::SkipOffline);
+        let last_run = state::read_last_run(&state).unwrap();
+        assert_eq!(last_run.status, "skipped: offline");
+
+        let outcome = preflight(std::slice::from_ref(&endpoint), false, PROBE_TIMEOUT, &state).await;
+        assert_eq!(outcome, PreflightOutcome::Proceed);
+
+        // A reachable fallback is enough
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback = format!("http://{}", listener.local_addr().unwrap());
+        assert_eq!(preflight(&[endpoint, fallback], true, PROBE_TIMEOUT, &state).await, PreflightOutcome::Proceed);
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "metrics")]
+use sysinfo::{Disks, Networks};
+
+use crate::api::AgentReport;
+use crate::login::LoginBlocker;
+use crate::ssh_dir_scan::SshDirReport;
+use crate::state::LastReport;
+
+/// Version of the report payload layout; bump on any breaking change
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Default for --max-report-interval
+pub const DEFAULT_MAX_REPORT_INTERVAL: &str = "6h";
+
+/// Optional report sections this agent build can produce
+#[cfg(feature = "metrics")]
+pub const REPORT_CAPABILITIES: &[&str] = &["network", "ssh", "storage", "timings", "keyInventory", "sshDirs"];
+#[cfg(not(feature = "metrics"))]
+pub const REPORT_CAPABILITIES: &[&str] = &["ssh", "timings", "keyInventory", "sshDirs"];
+
+/// Optional report data, grouped by section instead of flat fields
+#[derive(Serialize, Debug, Default)]
+pub struct ReportSections {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkSection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshSection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageSection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<TimingsSection>,
+    #[serde(rename = "keyInventory", skip_serializing_if = "Option::is_none")]
+    pub key_inventory: Option<Vec<KeyInventoryEntry>>,
+    /// Per-user ~/.ssh risk indicators (only with --scan-ssh-dirs)
+    #[serde(rename = "sshDirs", skip_serializing_if = "Option::is_none")]
+    pub ssh_dirs: Option<Vec<SshDirReport>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NetworkSection {
+    pub interfaces: Vec<NetworkInterface>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NetworkInterface {
+    pub name: String,
+    #[serde(rename = "macAddress")]
+    pub mac_address: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SshSection {
+    #[serde(rename = "authorizedKeysPatterns")]
+    pub authorized_keys_patterns: Vec<String>,
+    #[serde(rename = "permitRootLogin")]
+    pub permit_root_login: String,
+    /// sshd does not let root log in with the keys the agent deploys
+    #[serde(rename = "rootLoginDisabled")]
+    pub root_login_disabled: bool,
+    /// Host-wide login blockers (nologin, blanket pam_access deny)
+    #[serde(rename = "loginBlockers", skip_serializing_if = "Vec::is_empty")]
+    pub login_blockers: Vec<LoginBlocker>,
+    /// The agent's effective umask
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StorageSection {
+    pub mounts: Vec<StorageMount>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StorageMount {
+    #[serde(rename = "mountPoint")]
+    pub mount_point: String,
+    #[serde(rename = "totalBytes")]
+    pub total_bytes: u64,
+    #[serde(rename = "availableBytes")]
+    pub available_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimingsSection {
+    #[serde(rename = "collectionMs")]
+    pub collection_ms: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyInventoryEntry {
+    pub username: String,
+    pub path: String,
+    pub fingerprints: Vec<String>,
+    /// Subset of `fingerprints` written by other tools such as cloud-init
+    #[serde(rename = "externallyManaged", skip_serializing_if = "Vec::is_empty")]
+    pub externally_managed: Vec<String>,
+    /// Subset of `fingerprints` that are OpenSSH certificates
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub certificates: Vec<String>,
+}
+
+/// Collect network interface names and hardware addresses
+#[cfg(feature = "metrics")]
+pub fn collect_network() -> NetworkSection {
+    let networks = Networks::new_with_refreshed_list();
+    let mut interfaces: Vec<NetworkInterface> = networks
+        .iter()
+        .map(|(name, data)| NetworkInterface {
+            name: name.clone(),
+            mac_address: data.mac_address().to_string(),
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    NetworkSection { interfaces }
+}
+
+/// Collect capacity of mounted filesystems
+#[cfg(feature = "metrics")]
+pub fn collect_storage() -> StorageSection {
+    let disks = Disks::new_with_refreshed_list();
+    let mounts = disks
+        .iter()
+        .map(|disk| StorageMount {
+            mount_point: disk.mount_point().to_string_lossy().to_string(),
+            total_bytes: disk.total_space(),
+            available_bytes: disk.available_space(),
+        })
+        .collect();
+    StorageSection { mounts }
+}
+
+/// Stable hash of a report as sent to `url`, leaving out what changes on every run without
+/// anything worth reporting happening: the idempotency key, collection timings and free disk space
+pub fn fingerprint(report: &AgentReport, url: &str) -> String {
+    let mut value = serde_json::to_value(report).unwrap_or_default();
+    if let Some(object) = value.as_object_mut() {
+        object.remove("idempotencyKey");
+        object.remove("timings");
+    }
+    if let Some(mounts) = value.pointer_mut("/storage/mounts").and_then(|mounts| mounts.as_array_mut()) {
+        for mount in mounts.iter_mut().filter_map(|mount| mount.as_object_mut()) {
+            mount.remove("availableBytes");
+        }
+    }
+    // serde_json keeps object keys sorted, so equal reports serialize equally
+    let digest = Sha256::digest(format!("{}\n{}", url, value).as_bytes());
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// How long ago the last delivered report with this fingerprint was sent, unless that is longer
+/// than `max_interval` (or there is none) and the report has to go out anyway
+pub fn unchanged_for(last: Option<&LastReport>, fingerprint: &str, now: u64, max_interval: Duration) -> Option<Duration> {
+    let last = last.filter(|last| last.fingerprint == fingerprint)?;
+    Some(Duration::from_secs(now.saturating_sub(last.sent_at))).filter(|age| *age < max_interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(idempotency_key: &str, collection_ms: u64, available_bytes: u64) -> AgentReport {
+        AgentReport {
+            schema_version: REPORT_SCHEMA_VERSION,
+            capabilities: Vec::new(),
+            hostname: "web-1".to_string(),
+            system_info: crate::system::SystemInfo {
+                os: "Linux".to_string(),
+                arch: "x86_64".to_string(),
+                platform: "linux".to_string(),
+                kernel: "6.1.0".to_string(),
+                distribution: "Debian".to_string(),
+                version: "12".to_string(),
+                immutable_os: false,
+            },
+            agent_version: "0.4.0".to_string(),
+            users: Vec::new(),
+            idempotency_key: idempotency_key.to_string(),
+            users_partial: false,
+            sections: ReportSections {
+                storage: Some(StorageSection {
+                    mounts: vec![StorageMount { mount_point: "/".to_string(), total_bytes: 100, available_bytes }],
+                }),
+                timings: Some(TimingsSection { collection_ms }),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_volatile_fields() {
+        let url = "https://pk.example.com/api/agent/report";
+        let first = fingerprint(&report("k1", 12, 50), url);
+        assert_eq!(first, fingerprint(&report("k2", 40, 45), url));
+
+        let mut renamed = report("k1", 12, 50);
+        renamed.hostname = "web-2".to_string();
+        assert_ne!(first, fingerprint(&renamed, url));
+        // Another server has not seen the report
+        assert_ne!(first, fingerprint(&report("k1", 12, 50), "https://other.example.com/api/agent/report"));
+    }
+
+    #[test]
+    fn test_unchanged_for_until_max_interval() {
+        let last = LastReport { fingerprint: "abc".to_string(), sent_at: 1_000 };
+        let max_interval = Duration::from_secs(3600);
+        assert_eq!(unchanged_for(Some(&last), "abc", 1_600, max_interval), Some(Duration::from_secs(600)));
+        assert_eq!(unchanged_for(Some(&last), "abc", 4_600, max_interval), None);
+        assert_eq!(unchanged_for(Some(&last), "def", 1_600, max_interval), None);
+        assert_eq!(unchanged_for(None, "abc", 1_600, max_interval), None);
+    }
+}
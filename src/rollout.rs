@@ -0,0 +1,118 @@
+use std::fs;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+/// Files holding the stable machine identifier, in order of preference
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// Stable identifier for this host: the systemd machine-id, falling back to the hostname
+pub fn machine_id() -> String {
+    for path in MACHINE_ID_PATHS {
+        if let Ok(id) = fs::read_to_string(path) {
+            let id = id.trim();
+            if !id.is_empty() {
+                return id.to_string();
+            }
+        }
+    }
+    warn!("No machine-id found, using hostname for rollout bucketing");
+    crate::system::collect_hostname().unwrap_or_default()
+}
+
+/// Deterministic rollout bucket (0-99) for a machine identifier
+pub fn rollout_bucket(machine_id: &str) -> u8 {
+    let digest = Sha256::digest(machine_id.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % 100) as u8
+}
+
+/// Startup delay in [0, `splay`) for a machine identifier. Stable across runs so each host keeps
+/// its slot, and hashed apart from the rollout bucket so canary hosts are not all early.
+pub fn splay_delay(machine_id: &str, splay: Duration) -> Duration {
+    let window = splay.as_millis() as u64;
+    if window == 0 {
+        return Duration::ZERO;
+    }
+    let digest = Sha256::digest(format!("splay:{}", machine_id).as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    Duration::from_millis(u64::from_be_bytes(prefix) % window)
+}
+
+/// Whether a host in `bucket` is part of a rollout covering `percent` of the fleet
+pub fn in_canary(bucket: u8, percent: u8) -> bool {
+    bucket < percent
+}
+
+/// Whether removals must be deferred for this run.
+///
+/// Only applies when the server sent a rollout percentage and `--ignore-rollout` is not set.
+pub fn defer_removals(rollout_percent: Option<u8>, bucket: u8, ignore_rollout: bool) -> bool {
+    let Some(percent) = rollout_percent else {
+        return false;
+    };
+    if ignore_rollout {
+        info!("Ignoring server rollout percentage {}% (--ignore-rollout)", percent);
+        return false;
+    }
+    if in_canary(bucket, percent) {
+        info!("Host is inside the {}% rollout canary (bucket {})", percent, bucket);
+        false
+    } else {
+        info!("Host is outside the {}% rollout canary (bucket {}): deferring key removals", percent, bucket);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splay_delay() {
+        let splay = Duration::from_secs(600);
+        let delay = splay_delay("4c4c4544-0042", splay);
+        assert_eq!(delay, splay_delay("4c4c4544-0042", splay));
+        assert!(delay < splay);
+        assert_ne!(delay, splay_delay("4c4c4544-0043", splay));
+        assert_eq!(splay_delay("4c4c4544-0042", Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rollout_bucket_is_deterministic() {
+        let id = "4c4c4544004b4a1080523c4f4f4b4b31";
+        assert_eq!(rollout_bucket(id), rollout_bucket(id));
+        assert_eq!(rollout_bucket(id), 21);
+        assert_eq!(rollout_bucket("0123456789abcdef0123456789abcdef"), 22);
+        assert!((0..1000).all(|i| rollout_bucket(&format!("host-{}", i)) < 100));
+    }
+
+    #[test]
+    fn test_rollout_buckets_spread_evenly() {
+        let inside = (0..10_000)
+            .filter(|i| in_canary(rollout_bucket(&format!("machine-{}", i)), 25))
+            .count();
+        assert!((2_200..2_800).contains(&inside), "{} of 10000 hosts inside a 25% canary", inside);
+    }
+
+    #[test]
+    fn test_in_canary_bounds() {
+        assert!(!in_canary(0, 0));
+        assert!(in_canary(0, 1));
+        assert!(!in_canary(1, 1));
+        assert!(in_canary(99, 100));
+        assert!(in_canary(99, 255));
+    }
+
+    #[test]
+    fn test_defer_removals() {
+        assert!(!defer_removals(None, 80, false));
+        assert!(!defer_removals(Some(90), 80, false));
+        assert!(defer_removals(Some(50), 80, false));
+        assert!(!defer_removals(Some(50), 80, true));
+        assert!(defer_removals(Some(0), 0, false));
+        assert!(!defer_removals(Some(100), 99, false));
+    }
+}
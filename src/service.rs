@@ -0,0 +1,203 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use secrecy::SecretString;
+
+use crate::setup::{self, SetupAnswers};
+use crate::state;
+
+/// The units every install path writes: `install-service`, `setup`, the install script and the
+/// packages. One name, so no host ends up with two timers.
+pub const SERVICE_UNIT: &str = "publikey-agent.service";
+pub const TIMER_UNIT: &str = "publikey-agent.timer";
+
+/// systemctl arguments that enable the timer, shared with the install script and package scripts
+pub const ENABLE_TIMER: &[&str] = &["enable", "--now", TIMER_UNIT];
+
+/// Where system units go
+pub const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Exists while systemd is PID 1 (see sd_booted(3))
+const SYSTEMD_RUNTIME_DIR: &str = "/run/systemd/system";
+
+/// When the timer fires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Wall-clock schedule (`OnCalendar=`)
+    Calendar(String),
+    /// Fixed interval after the previous run (`OnUnitActiveSec=`)
+    Interval(Duration),
+}
+
+/// Everything the units are rendered from
+#[derive(Debug, Clone)]
+pub struct ServiceUnits {
+    pub binary: PathBuf,
+    /// Environment file with the endpoint and token
+    pub env_file: PathBuf,
+    /// TOML config passed to `pkagent run --config`, if one was given
+    pub config: Option<PathBuf>,
+    pub schedule: Schedule,
+    /// Paths the agent writes besides /home and /root (state directory, MOTD snippet)
+    pub writable_paths: Vec<PathBuf>,
+}
+
+impl ServiceUnits {
+    /// Units for a system install of `binary` reading `env_file`, keeping state in the system
+    /// state directory
+    pub fn system(binary: &Path, env_file: &Path, schedule: Schedule) -> Self {
+        ServiceUnits {
+            binary: binary.to_path_buf(),
+            env_file: env_file.to_path_buf(),
+            config: None,
+            schedule,
+            writable_paths: vec![PathBuf::from(state::SYSTEM_STATE_DIR)],
+        }
+    }
+
+    /// Render the service and timer units
+    pub fn render(&self) -> (String, String) {
+        let mut exec_start = format!("{} run", self.binary.display());
+        if let Some(config) = &self.config {
+            exec_start.push_str(&format!(" --config {}", config.display()));
+        }
+        // A leading '-' lets the unit start before the path exists
+        let writable: String = self.writable_paths.iter().map(|path| format!(" -{}", path.display())).collect();
+        let service = format!(
+            "[Unit]\n\
+             Description=PubliKey Agent\n\
+             After=network-online.target\n\
+             Wants=network-online.target\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             EnvironmentFile={}\n\
+             ExecStart={}\n\
+             ProtectSystem=strict\n\
+             ReadWritePaths=/home /root{}\n\
+             PrivateTmp=true\n\
+             NoNewPrivileges=true\n\
+             ProtectKernelTunables=true\n\
+             ProtectKernelModules=true\n\
+             ProtectControlGroups=true\n\
+             RestrictSUIDSGID=true\n",
+            self.env_file.display(),
+            exec_start,
+            writable
+        );
+        let trigger = match &self.schedule {
+            Schedule::Calendar(spec) => format!("OnCalendar={}\nPersistent=true\n", spec),
+            Schedule::Interval(interval) => format!("OnBootSec=1min\nOnUnitActiveSec={}s\n", interval.as_secs()),
+        };
+        let timer = format!(
+            "[Unit]\n\
+             Description=Run PubliKey Agent periodically\n\
+             Requires={SERVICE_UNIT}\n\
+             \n\
+             [Timer]\n\
+             {}\
+             \n\
+             [Install]\n\
+             WantedBy=timers.target\n",
+            trigger
+        );
+        (service, timer)
+    }
+}
+
+/// Whether systemd is the running init, judged by its runtime directory under `root`
+pub fn systemd_running(root: &Path) -> bool {
+    root.join(SYSTEMD_RUNTIME_DIR.trim_start_matches('/')).is_dir()
+}
+
+/// Write the units and the environment file (mode 0600, it holds the token)
+pub fn install(units: &ServiceUnits, unit_dir: &Path, endpoint: &str, token: &SecretString) -> Result<()> {
+    let answers = SetupAnswers {
+        endpoint: endpoint.to_string(),
+        token: token.clone(),
+        user_mode: false,
+        schedule: String::new(),
+        config_path: units.env_file.clone(),
+        install_service: true,
+    };
+    setup::write_atomically(&units.env_file, &setup::render_config(&answers, "install-service"), 0o600)?;
+    write_units(units, unit_dir)
+}
+
+/// Write the service and timer units to `unit_dir`
+pub fn write_units(units: &ServiceUnits, unit_dir: &Path) -> Result<()> {
+    let (service, timer) = units.render();
+    setup::write_atomically(&unit_dir.join(SERVICE_UNIT), &service, 0o644)?;
+    setup::write_atomically(&unit_dir.join(TIMER_UNIT), &timer, 0o644)?;
+    Ok(())
+}
+
+/// Reload systemd and start the timer now and on every boot; `user` talks to the user's manager
+pub fn enable(user: bool) -> Result<()> {
+    for args in [&["daemon-reload"][..], ENABLE_TIMER] {
+        let mut command = std::process::Command::new("systemctl");
+        if user {
+            command.arg("--user");
+        }
+        let status = command.args(args).status().context("Failed to run systemctl")?;
+        if !status.success() {
+            return Err(anyhow!("systemctl {} failed: {}", args.join(" "), status));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn units(schedule: Schedule) -> ServiceUnits {
+        ServiceUnits::system(Path::new("/usr/local/bin/pkagent"), Path::new("/etc/publikey/agent.env"), schedule)
+    }
+
+    #[test]
+    fn test_render_hardened_service() {
+        let (service, timer) = units(Schedule::Calendar("hourly".to_string())).render();
+        assert!(service.contains("EnvironmentFile=/etc/publikey/agent.env\n"));
+        assert!(service.contains("ExecStart=/usr/local/bin/pkagent run\n"));
+        assert!(service.contains("ProtectSystem=strict\n"));
+        assert!(service.contains("ReadWritePaths=/home /root -/var/lib/publikey\n"));
+        assert!(!service.contains("ProtectHome"), "{}", service);
+        assert!(timer.contains("Requires=publikey-agent.service\n"));
+        assert!(timer.contains("OnCalendar=hourly\nPersistent=true\n"));
+
+        let mut with_config = units(Schedule::Interval(Duration::from_secs(900)));
+        with_config.config = Some(PathBuf::from("/etc/publikey/agent.toml"));
+        let (service, timer) = with_config.render();
+        assert!(service.contains("ExecStart=/usr/local/bin/pkagent run --config /etc/publikey/agent.toml\n"));
+        assert!(timer.contains("OnBootSec=1min\nOnUnitActiveSec=900s\n"));
+        assert!(!timer.contains("OnCalendar"));
+    }
+
+    #[test]
+    fn test_systemd_detection() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!systemd_running(root.path()));
+        fs::create_dir_all(root.path().join("run/systemd/system")).unwrap();
+        assert!(systemd_running(root.path()));
+    }
+
+    #[test]
+    fn test_install_writes_units_and_private_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut units = units(Schedule::Calendar(setup::DEFAULT_SCHEDULE.to_string()));
+        units.env_file = dir.path().join("publikey/agent.env");
+        let unit_dir = dir.path().join("systemd");
+        install(&units, &unit_dir, "https://publikey.example.com", &SecretString::from("pk_test")).unwrap();
+
+        let env = fs::read_to_string(&units.env_file).unwrap();
+        assert!(env.contains("PUBLIKEY_TOKEN=pk_test\n"), "{}", env);
+        assert_eq!(fs::metadata(&units.env_file).unwrap().permissions().mode() & 0o777, 0o600);
+        let service = fs::read_to_string(unit_dir.join(SERVICE_UNIT)).unwrap();
+        assert_eq!(service, units.render().0);
+        assert_eq!(fs::read_to_string(unit_dir.join(TIMER_UNIT)).unwrap(), units.render().1);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, anyhow};
+use secrecy::{ExposeSecret, SecretString};
+use tracing::info;
+
+use crate::api::ApiClient;
+use crate::fsutil;
+use crate::service::{self, Schedule, ServiceUnits};
+use crate::state;
+use crate::trust;
+use crate::cli::SetupArgs;
+
+/// Default systemd OnCalendar schedule (every minute, matching install.sh)
+pub(crate) const DEFAULT_SCHEDULE: &str = "*:*:00";
+
+/// Default system-wide configuration file, read by the service unit
+pub(crate) const SYSTEM_CONFIG_PATH: &str = "/etc/publikey/agent.env";
+
+/// Answers collected by the setup wizard
+#[derive(Debug, Clone)]
+pub struct SetupAnswers {
+    pub endpoint: String,
+    pub token: SecretString,
+    pub user_mode: bool,
+    pub schedule: String,
+    pub config_path: PathBuf,
+    pub install_service: bool,
+}
+
+/// Resolve wizard answers from flags, prompting for anything missing unless non-interactive
+fn resolve_answers(
+    args: &SetupArgs,
+    prompt: &mut dyn FnMut(&str, Option<&str>) -> Result<String>,
+) -> Result<SetupAnswers> {
+    let mut ask = |question: &str, value: &Option<String>, default: Option<&str>| -> Result<String> {
+        match (value, args.non_interactive) {
+            (Some(value), _) => Ok(value.clone()),
+            (None, true) => default
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("{} is required in non-interactive mode", question)),
+            (None, false) => {
+                let answer = prompt(question, default)?;
+                if answer.is_empty() {
+                    default.map(str::to_string).ok_or_else(|| anyhow!("{} is required", question))
+                } else {
+                    Ok(answer)
+                }
+            }
+        }
+    };
+
+    let endpoint = ask("Server endpoint", &args.endpoint, None)?;
+    let token = match &args.token {
+        Some(token) => token.clone(),
+        None => SecretString::from(ask("API token", &None, None)?),
+    };
+    let user_mode = if args.user_mode || args.non_interactive {
+        args.user_mode
+    } else {
+        parse_yes_no(&ask("Run in user mode (only manage your own keys)? [y/N]", &None, Some("n"))?)?
+    };
+    let schedule = ask("Run schedule (systemd OnCalendar)", &args.schedule, Some(DEFAULT_SCHEDULE))?;
+    let config_path = match &args.config_path {
+        Some(path) => path.clone(),
+        None => default_config_path(user_mode)?,
+    };
+    let install_service = if args.install_service || args.non_interactive {
+        args.install_service
+    } else {
+        parse_yes_no(&ask("Install and enable systemd units? [y/N]", &None, Some("n"))?)?
+    };
+
+    Ok(SetupAnswers {
+        endpoint,
+        token,
+        user_mode,
+        schedule,
+        config_path,
+        install_service,
+    })
+}
+
+fn parse_yes_no(answer: &str) -> Result<bool> {
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(true),
+        "n" | "no" => Ok(false),
+        other => Err(anyhow!("Expected yes or no, got: {}", other)),
+    }
+}
+
+/// Read an answer from stdin
+fn prompt_stdin(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(anyhow!("Setup cancelled"));
+    }
+    Ok(line.trim().to_string())
+}
+
+/// Default config location for system or user installs
+fn default_config_path(user_mode: bool) -> Result<PathBuf> {
+    if user_mode {
+        let home = std::env::var("HOME").context("HOME is not set")?;
+        Ok(PathBuf::from(home).join(".config/publikey/agent.env"))
+    } else {
+        Ok(PathBuf::from(SYSTEM_CONFIG_PATH))
+    }
+}
+
+/// Render the environment file read by the agent and its systemd unit; `generated_by` names the
+/// pkagent subcommand in the header
+pub(crate) fn render_config(answers: &SetupAnswers, generated_by: &str) -> String {
+    let mut content = String::new();
+    content.push_str(&format!("# PubliKey Agent configuration (generated by pkagent {})\n", generated_by));
+    content.push_str(&format!("PUBLIKEY_ENDPOINT={}\n", answers.endpoint));
+    content.push_str(&format!("PUBLIKEY_TOKEN={}\n", answers.token.expose_secret()));
+    if answers.user_mode {
+        content.push_str("PUBLIKEY_USER_MODE=true\n");
+    }
+    content
+}
+
+/// Write a file atomically with the given mode, creating parent directories
+pub(crate) fn write_atomically(path: &Path, content: &str, mode: u32) -> Result<()> {
+    let parent = path.parent().ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    fsutil::atomic_write(path, content.as_bytes(), mode).context(format!("Failed to write {}", path.display()))
+}
+
+/// The units for these answers: the same hardened template `install-service` writes
+fn service_units(answers: &SetupAnswers, binary: PathBuf) -> ServiceUnits {
+    ServiceUnits {
+        binary,
+        env_file: answers.config_path.clone(),
+        config: None,
+        schedule: Schedule::Calendar(answers.schedule.clone()),
+        writable_paths: vec![state::default_state_dir(answers.user_mode)],
+    }
+}
+
+/// Write systemd units and enable the timer
+fn install_units(answers: &SetupAnswers) -> Result<()> {
+    let binary = std::env::current_exe().context("Failed to get current executable path")?;
+    let units = service_units(answers, binary);
+
+    let unit_dir = if answers.user_mode {
+        let home = std::env::var("HOME").context("HOME is not set")?;
+        PathBuf::from(home).join(".config/systemd/user")
+    } else {
+        PathBuf::from(service::SYSTEM_UNIT_DIR)
+    };
+    service::write_units(&units, &unit_dir)?;
+    println!("Installed systemd units in {}", unit_dir.display());
+    service::enable(answers.user_mode)
+}
+
+/// Run the setup wizard
+pub async fn run_setup(args: &SetupArgs) -> Result<()> {
+    let answers = resolve_answers(args, &mut prompt_stdin)?;
+
+    // Validate endpoint reachability and token before writing anything
+    let trust_roots = trust::load(None)?;
+    let api_client = ApiClient::with_tls_config(answers.endpoint.clone(), answers.token.clone(), trust_roots.client_config())?;
+    println!("Checking endpoint {}...", answers.endpoint);
+    if !api_client.health_check().await?.healthy {
+        return Err(anyhow!("Endpoint {} is not healthy", answers.endpoint));
+    }
+    println!("Checking token...");
+    api_client
+        .get_key_assignments()
+        .await
+        .map_err(|e| anyhow!("Token check failed: {}", e))?;
+
+    write_atomically(&answers.config_path, &render_config(&answers, "setup"), 0o600)?;
+    println!("Wrote configuration to {}", answers.config_path.display());
+    info!("Setup wrote configuration to {}", answers.config_path.display());
+
+    if answers.install_service {
+        install_units(&answers)?;
+    }
+
+    if !args.no_preview {
+        println!("Previewing first sync (dry run)...");
+        let binary = std::env::current_exe().context("Failed to get current executable path")?;
+        let mut command = std::process::Command::new(binary);
+        command
+            .arg("--dry-run")
+            .env("PUBLIKEY_ENDPOINT", &answers.endpoint)
+            .env("PUBLIKEY_TOKEN", answers.token.expose_secret());
+        if answers.user_mode {
+            command.arg("--user-mode");
+        }
+        command.status().context("Failed to run dry-run preview")?;
+    }
+
+    println!("Setup complete.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn non_interactive_args(config_path: PathBuf) -> SetupArgs {
+        SetupArgs {
+            endpoint: Some("https://publikey.example.com".to_string()),
+            token: Some("pk_test".into()),
+            user_mode: false,
+            schedule: None,
+            config_path: Some(config_path),
+            install_service: false,
+            non_interactive: true,
+            no_preview: true,
+        }
+    }
+
+    #[test]
+    fn test_non_interactive_answers_never_prompt() {
+        let args = non_interactive_args(PathBuf::from("/tmp/agent.env"));
+        let answers = resolve_answers(&args, &mut |q, _| panic!("unexpected prompt: {}", q)).unwrap();
+
+        assert_eq!(answers.endpoint, "https://publikey.example.com");
+        assert_eq!(answers.token.expose_secret(), "pk_test");
+        assert!(!answers.user_mode);
+        assert_eq!(answers.schedule, DEFAULT_SCHEDULE);
+        assert!(!answers.install_service);
+    }
+
+    #[test]
+    fn test_non_interactive_requires_endpoint_and_token() {
+        let mut args = non_interactive_args(PathBuf::from("/tmp/agent.env"));
+        args.token = None;
+        assert!(resolve_answers(&args, &mut |q, _| panic!("unexpected prompt: {}", q)).is_err());
+    }
+
+    #[test]
+    fn test_interactive_prompts_for_missing_answers() {
+        let mut args = non_interactive_args(PathBuf::from("/tmp/agent.env"));
+        args.non_interactive = false;
+        args.token = None;
+
+        let mut asked = Vec::new();
+        let answers = resolve_answers(&args, &mut |q, _| {
+            asked.push(q.to_string());
+            Ok(if q == "API token" { "pk_prompted".to_string() } else { String::new() })
+        })
+        .unwrap();
+
+        assert_eq!(answers.token.expose_secret(), "pk_prompted");
+        assert!(!answers.user_mode);
+        assert_eq!(answers.schedule, DEFAULT_SCHEDULE);
+        assert!(asked.contains(&"API token".to_string()));
+    }
+
+    #[test]
+    fn test_config_file_written_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("publikey/agent.env");
+        let mut args = non_interactive_args(config_path.clone());
+        args.user_mode = true;
+        let answers = resolve_answers(&args, &mut |q, _| panic!("unexpected prompt: {}", q)).unwrap();
+
+        write_atomically(&answers.config_path, &render_config(&answers, "setup"), 0o600).unwrap();
+
+        let content = fs::read_to_string(&config_path).unwrap();
+        assert!(content.contains("PUBLIKEY_ENDPOINT=https://publikey.example.com\n"));
+        assert!(content.contains("PUBLIKEY_TOKEN=pk_test\n"));
+        assert!(content.contains("PUBLIKEY_USER_MODE=true\n"));
+        assert_eq!(fs::metadata(&config_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!config_path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_service_units() {
+        let args = non_interactive_args(PathBuf::from("/etc/publikey/agent.env"));
+        let answers = resolve_answers(&args, &mut |q, _| panic!("unexpected prompt: {}", q)).unwrap();
+        let (service, timer) = service_units(&answers, PathBuf::from("/usr/local/bin/pkagent")).render();
+
+        assert!(service.contains("EnvironmentFile=/etc/publikey/agent.env\n"));
+        assert!(service.contains("ExecStart=/usr/local/bin/pkagent run\n"));
+        assert!(service.contains("ProtectSystem=strict\n"));
+        assert!(timer.contains("OnCalendar=*:*:00\n"));
+        assert!(timer.contains("Requires=publikey-agent.service\n"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::fsutil;
+
+/// Maximum number of spooled reports kept on disk
+const MAX_SPOOL_ENTRIES: usize = 100;
+/// Maximum total size of spooled reports in bytes
+const MAX_SPOOL_BYTES: u64 = 4 * 1024 * 1024;
+/// Clock skew tolerated before a spooled timestamp is considered suspect
+const CLOCK_JUMP_THRESHOLD_SECS: u64 = 300;
+
+/// Source of wall-clock time, injectable for tests
+pub trait Clock {
+    fn now_secs(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A report that could not be delivered, stored for a later run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpooledReport {
+    pub sequence: u64,
+    #[serde(rename = "spooledAt")]
+    pub spooled_at: u64,
+    /// Idempotency key of the original submission, reused when flushing
+    #[serde(rename = "idempotencyKey", default)]
+    pub idempotency_key: String,
+    pub report: serde_json::Value,
+}
+
+/// A spooled report ready to be flushed
+#[derive(Debug, Clone)]
+pub struct FlushEntry {
+    pub path: PathBuf,
+    pub entry: SpooledReport,
+    pub timestamp_suspect: bool,
+}
+
+/// On-disk spool of undelivered reports
+pub struct Spool<C: Clock = SystemClock> {
+    dir: PathBuf,
+    clock: C,
+}
+
+impl Spool<SystemClock> {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, clock: SystemClock }
+    }
+}
+
+impl<C: Clock> Spool<C> {
+    #[cfg(test)]
+    pub fn with_clock(dir: PathBuf, clock: C) -> Self {
+        Self { dir, clock }
+    }
+
+    /// Store a report for later delivery
+    pub fn push(&self, report: serde_json::Value, idempotency_key: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)
+            .context(format!("Failed to create spool directory {}", self.dir.display()))?;
+
+        let sequence = self.entries()?.iter().map(|(_, e)| e.sequence).max().map_or(1, |s| s + 1);
+        let entry = SpooledReport {
+            sequence,
+            spooled_at: self.clock.now_secs(),
+            idempotency_key: idempotency_key.to_string(),
+            report,
+        };
+
+        let path = self.dir.join(format!("report-{:010}.json", sequence));
+        fsutil::atomic_write(&path, &serde_json::to_vec(&entry)?, 0o600).context("Failed to write spooled report")?;
+        info!("Spooled report #{} to {}", sequence, path.display());
+
+        self.enforce_limits()?;
+        Ok(path)
+    }
+
+    /// Spooled reports in sequence order, with clock-jump detection applied
+    pub fn pending(&self) -> Result<Vec<FlushEntry>> {
+        let now = self.clock.now_secs();
+        let mut previous_spooled_at: Option<u64> = None;
+        let mut flush = Vec::new();
+
+        for (path, entry) in self.entries()? {
+            let mtime = file_mtime_secs(&path);
+            let suspect = is_timestamp_suspect(&entry, mtime, previous_spooled_at, now);
+            if suspect {
+                warn!("Spooled report #{} has a suspect timestamp (clock jump detected)", entry.sequence);
+            }
+            previous_spooled_at = Some(entry.spooled_at);
+            flush.push(FlushEntry {
+                path,
+                entry,
+                timestamp_suspect: suspect,
+            });
+        }
+
+        Ok(flush)
+    }
+
+    /// Remove a delivered report from the spool
+    pub fn remove(&self, entry: &FlushEntry) -> Result<()> {
+        fs::remove_file(&entry.path).context(format!("Failed to remove {}", entry.path.display()))
+    }
+
+    /// All readable spool entries sorted by sequence number
+    fn entries(&self) -> Result<Vec<(PathBuf, SpooledReport)>> {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return Ok(Vec::new());
+        };
+
+        let mut entries = Vec::new();
+        for item in dir.filter_map(|e| e.ok()) {
+            let path = item.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read(&path).ok().and_then(|data| serde_json::from_slice::<SpooledReport>(&data).ok()) {
+                Some(entry) => entries.push((path, entry)),
+                None => warn!("Ignoring unreadable spool file {}", path.display()),
+            }
+        }
+        entries.sort_by_key(|(_, e)| e.sequence);
+        Ok(entries)
+    }
+
+    /// Drop the oldest reports until both count and byte limits are met
+    fn enforce_limits(&self) -> Result<()> {
+        let mut entries: Vec<(PathBuf, u64)> = self
+            .entries()?
+            .into_iter()
+            .map(|(path, _)| {
+                let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                (path, size)
+            })
+            .collect();
+
+        let mut total: u64 = entries.iter().map(|(_, size)| size).sum();
+        while !entries.is_empty() && (entries.len() > MAX_SPOOL_ENTRIES || total > MAX_SPOOL_BYTES) {
+            let (path, size) = entries.remove(0);
+            warn!("Spool limit reached, dropping oldest report {}", path.display());
+            fs::remove_file(&path).ok();
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a spooled report's wall-clock stamp can't be trusted.
+///
+/// Suspect when the stamp disagrees with the file mtime, lies in the future, or goes
+/// backwards relative to the previous (lower sequence) report, each beyond the threshold.
+fn is_timestamp_suspect(entry: &SpooledReport, mtime: Option<u64>, previous_spooled_at: Option<u64>, now: u64) -> bool {
+    let mtime_mismatch = mtime.is_some_and(|m| m.abs_diff(entry.spooled_at) > CLOCK_JUMP_THRESHOLD_SECS);
+    let in_future = entry.spooled_at > now + CLOCK_JUMP_THRESHOLD_SECS;
+    let went_backwards = previous_spooled_at.is_some_and(|p| p > entry.spooled_at + CLOCK_JUMP_THRESHOLD_SECS);
+    mtime_mismatch || in_future || went_backwards
+}
+
+fn file_mtime_secs(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakeClock(Cell<u64>);
+
+    impl Clock for &FakeClock {
+        fn now_secs(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn real_now() -> u64 {
+        SystemClock.now_secs()
+    }
+
+    #[test]
+    fn test_sequence_numbers_increase() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = FakeClock(Cell::new(real_now()));
+        let spool = Spool::with_clock(dir.path().to_path_buf(), &clock);
+
+        spool.push(serde_json::json!({"n": 1}), "key").unwrap();
+        spool.push(serde_json::json!({"n": 2}), "key").unwrap();
+
+        let pending = spool.pending().unwrap();
+        let sequences: Vec<_> = pending.iter().map(|e| e.entry.sequence).collect();
+        assert_eq!(sequences, vec![1, 2]);
+        assert!(pending.iter().all(|e| e.entry.idempotency_key == "key"));
+        assert!(pending.iter().all(|e| !e.timestamp_suspect));
+
+        spool.remove(&pending[0]).unwrap();
+        assert_eq!(spool.pending().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_clock_jump_backwards_marks_suspect() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = FakeClock(Cell::new(real_now()));
+        let spool = Spool::with_clock(dir.path().to_path_buf(), &clock);
+
+        spool.push(serde_json::json!({"n": 1}), "key").unwrap();
+        // Clock jumps back a day before the next report is spooled
+        clock.0.set(real_now() - 86_400);
+        spool.push(serde_json::json!({"n": 2}), "key").unwrap();
+        clock.0.set(real_now());
+
+        let pending = spool.pending().unwrap();
+        assert!(!pending[0].timestamp_suspect);
+        assert!(pending[1].timestamp_suspect);
+        assert_eq!(pending.len(), 2);
+    }
+
+    #[test]
+    fn test_clock_jump_forwards_marks_suspect() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = FakeClock(Cell::new(real_now() + 86_400));
+        let spool = Spool::with_clock(dir.path().to_path_buf(), &clock);
+
+        spool.push(serde_json::json!({"n": 1}), "key").unwrap();
+        clock.0.set(real_now());
+
+        let pending = spool.pending().unwrap();
+        assert!(pending[0].timestamp_suspect);
+    }
+
+    #[test]
+    fn test_spool_byte_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = FakeClock(Cell::new(real_now()));
+        let spool = Spool::with_clock(dir.path().to_path_buf(), &clock);
+
+        let big = "x".repeat((MAX_SPOOL_BYTES / 3) as usize);
+        for n in 0..5 {
+            spool.push(serde_json::json!({"n": n, "data": big}), "key").unwrap();
+        }
+
+        let pending = spool.pending().unwrap();
+        assert!(pending.len() < 5);
+        assert_eq!(pending.last().unwrap().entry.sequence, 5);
+        let total: u64 = pending.iter().map(|e| fs::metadata(&e.path).unwrap().len()).sum();
+        assert!(total <= MAX_SPOOL_BYTES);
+    }
+}
@@ -0,0 +1,87 @@
+/// Event types that only keep the connection alive
+const KEEPALIVE_EVENTS: &[&str] = &["ping", "keepalive"];
+
+/// A dispatched Server-Sent Events message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// `event:` field, `message` when absent
+    pub event: String,
+    pub data: String,
+}
+
+impl Event {
+    /// Whether the server is saying key assignments changed. The payload is not interpreted: any
+    /// event but a keepalive means "refetch".
+    pub fn is_change(&self) -> bool {
+        !KEEPALIVE_EVENTS.contains(&self.event.as_str())
+    }
+}
+
+/// Incremental `text/event-stream` parser: feed bytes as they arrive, get back complete events
+#[derive(Debug, Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl Parser {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buf.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buf.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&raw[..end]);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                // A blank line ends the event; one without data is dropped, as the spec says
+                let event = self.event.take();
+                if !self.data.is_empty() {
+                    events.push(Event {
+                        event: event.unwrap_or_else(|| "message".to_string()),
+                        data: std::mem::take(&mut self.data).join("\n"),
+                    });
+                }
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => self.data.push(value.to_string()),
+                // id and retry are not needed to decide when to refetch
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events_split_across_chunks() {
+        let mut parser = Parser::default();
+        assert!(parser.push(b": connected\n\nevent: keys-chan").is_empty());
+        let events = parser.push(b"ged\r\ndata: {\"hostId\":\"h1\"}\r\n\r\ndata: a\ndata: b\n\n");
+        assert_eq!(
+            events,
+            vec![
+                Event { event: "keys-changed".to_string(), data: "{\"hostId\":\"h1\"}".to_string() },
+                Event { event: "message".to_string(), data: "a\nb".to_string() },
+            ]
+        );
+        assert!(events.iter().all(Event::is_change));
+
+        // No data, no event
+        assert!(parser.push(b"event: keys-changed\n\n").is_empty());
+        let ping = parser.push(b"event: ping\ndata:\n\n");
+        assert_eq!(ping, vec![Event { event: "ping".to_string(), data: String::new() }]);
+        assert!(!ping[0].is_change());
+    }
+}
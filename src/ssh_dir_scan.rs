@@ -0,0 +1,265 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::glob::path_allowed;
+use crate::users::UserInfo;
+
+/// Deepest directory level below ~/.ssh that is walked
+const MAX_DEPTH: usize = 3;
+
+/// Entries examined per user before the scan stops
+const MAX_ENTRIES: usize = 1000;
+
+/// known_hosts files larger than this are flagged
+pub const KNOWN_HOSTS_WARN_BYTES: u64 = 1024 * 1024;
+
+/// Private key file names ssh-keygen uses by default
+const DEFAULT_KEY_NAMES: &[&str] = &[
+    "id_rsa", "id_dsa", "id_ecdsa", "id_ecdsa_sk", "id_ed25519", "id_ed25519_sk", "id_xmss", "identity",
+];
+
+/// Risk indicators found in one user's ~/.ssh
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct SshDirReport {
+    pub username: String,
+    pub path: PathBuf,
+    #[serde(rename = "hasAuthorizedKeys2", skip_serializing_if = "std::ops::Not::not")]
+    pub has_authorized_keys2: bool,
+    /// sshd's AuthorizedKeysFile still lists authorized_keys2
+    #[serde(rename = "authorizedKeys2Honored", skip_serializing_if = "std::ops::Not::not")]
+    pub authorized_keys2_honored: bool,
+    #[serde(rename = "permissivePrivateKeys", skip_serializing_if = "Vec::is_empty")]
+    pub permissive_private_keys: Vec<PermissiveKey>,
+    #[serde(rename = "totalBytes")]
+    pub total_bytes: u64,
+    #[serde(rename = "knownHostsBytes")]
+    pub known_hosts_bytes: u64,
+    /// The depth or entry limit was hit, so sizes are lower bounds
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// A private key readable or writable by group or others
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PermissiveKey {
+    pub path: PathBuf,
+    /// Octal permission bits, e.g. "644"
+    pub mode: String,
+}
+
+impl SshDirReport {
+    /// Human-readable warnings for the run output
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.has_authorized_keys2 && self.authorized_keys2_honored {
+            warnings.push(format!("{}: {}/authorized_keys2 exists and sshd still reads it", self.username, self.path.display()));
+        }
+        for key in &self.permissive_private_keys {
+            warnings.push(format!("{}: private key {} has mode {}", self.username, key.path.display(), key.mode));
+        }
+        if self.known_hosts_bytes > KNOWN_HOSTS_WARN_BYTES {
+            warnings.push(format!("{}: known_hosts is {} bytes", self.username, self.known_hosts_bytes));
+        }
+        warnings
+    }
+}
+
+/// Scan each user's ~/.ssh, honouring path excludes; contents are never read, only metadata
+pub fn scan_users(users: &[UserInfo], exclude_paths: &[String], authorized_keys2_honored: bool) -> Vec<SshDirReport> {
+    users
+        .iter()
+        .filter_map(|user| {
+            let home = user.home_dir.as_deref().filter(|home| !home.is_empty())?;
+            scan_ssh_dir(&user.username, Path::new(home), exclude_paths, authorized_keys2_honored)
+        })
+        .collect()
+}
+
+/// Scan `home/.ssh`; `None` when it is missing, excluded or leaves the home through a symlink
+fn scan_ssh_dir(username: &str, home: &Path, exclude_paths: &[String], authorized_keys2_honored: bool) -> Option<SshDirReport> {
+    let ssh_dir = home.join(".ssh");
+    if !path_allowed(&ssh_dir.to_string_lossy(), &[], exclude_paths) {
+        debug!("Skipping excluded {}", ssh_dir.display());
+        return None;
+    }
+    let metadata = fs::symlink_metadata(&ssh_dir).ok()?;
+    if metadata.file_type().is_symlink() {
+        let inside_home = match (fs::canonicalize(&ssh_dir), fs::canonicalize(home)) {
+            (Ok(target), Ok(home)) => target.starts_with(home),
+            _ => false,
+        };
+        if !inside_home {
+            warn!("Not scanning {}: symlink points outside the home of {}", ssh_dir.display(), username);
+            return None;
+        }
+    } else if !metadata.is_dir() {
+        return None;
+    }
+
+    let mut report = SshDirReport {
+        username: username.to_string(),
+        path: ssh_dir.clone(),
+        ..Default::default()
+    };
+    let mut entries = 0;
+    walk(&ssh_dir, 0, exclude_paths, &mut entries, &mut report);
+    report.authorized_keys2_honored = report.has_authorized_keys2 && authorized_keys2_honored;
+    Some(report)
+}
+
+fn walk(dir: &Path, depth: usize, exclude_paths: &[String], entries: &mut usize, report: &mut SshDirReport) {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut children: Vec<_> = read_dir.filter_map(|entry| entry.ok()).collect();
+    children.sort_by_key(|entry| entry.file_name());
+    let names: Vec<String> = children.iter().map(|entry| entry.file_name().to_string_lossy().to_string()).collect();
+
+    for entry in children {
+        if *entries >= MAX_ENTRIES {
+            report.truncated = true;
+            return;
+        }
+        *entries += 1;
+
+        let path = entry.path();
+        if !path_allowed(&path.to_string_lossy(), &[], exclude_paths) {
+            continue;
+        }
+        // symlink_metadata: links are never followed, so nothing outside the home is touched
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if metadata.is_dir() {
+            if depth + 1 < MAX_DEPTH {
+                walk(&path, depth + 1, exclude_paths, entries, report);
+            } else {
+                report.truncated = true;
+            }
+            continue;
+        }
+        if !metadata.is_file() {
+            continue;
+        }
+
+        report.total_bytes += metadata.len();
+        if depth == 0 && name == "authorized_keys2" {
+            report.has_authorized_keys2 = true;
+        }
+        if name == "known_hosts" {
+            report.known_hosts_bytes += metadata.len();
+        }
+        let mode = metadata.mode() & 0o777;
+        if mode & 0o077 != 0 && is_private_key_name(&name, &names) {
+            report.permissive_private_keys.push(PermissiveKey { path, mode: format!("{:o}", mode) });
+        }
+    }
+}
+
+/// Private keys by name alone: ssh-keygen defaults, or any file with a `.pub` sibling
+fn is_private_key_name(name: &str, siblings: &[String]) -> bool {
+    if name.ends_with(".pub") {
+        return false;
+    }
+    DEFAULT_KEY_NAMES.contains(&name) || siblings.iter().any(|sibling| *sibling == format!("{}.pub", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    fn write(path: &Path, content: &[u8], mode: u32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_authorized_keys2() {
+        let home = tempfile::tempdir().unwrap();
+        write(&home.path().join(".ssh/authorized_keys2"), b"ssh-ed25519 AAAA", 0o600);
+
+        let report = scan_ssh_dir("alice", home.path(), &[], true).unwrap();
+        assert!(report.has_authorized_keys2 && report.authorized_keys2_honored);
+        assert_eq!(report.warnings().len(), 1);
+
+        // Present but ignored by sshd is reported without a warning
+        let report = scan_ssh_dir("alice", home.path(), &[], false).unwrap();
+        assert!(report.has_authorized_keys2 && !report.authorized_keys2_honored);
+        assert!(report.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_permissive_private_keys() {
+        let home = tempfile::tempdir().unwrap();
+        let ssh = home.path().join(".ssh");
+        write(&ssh.join("id_ed25519"), b"secret", 0o644);
+        write(&ssh.join("id_ed25519.pub"), b"ssh-ed25519 AAAA", 0o644);
+        write(&ssh.join("deploy"), b"secret", 0o640);
+        write(&ssh.join("deploy.pub"), b"ssh-ed25519 AAAA", 0o644);
+        write(&ssh.join("id_rsa"), b"secret", 0o600);
+        write(&ssh.join("config"), b"Host *", 0o644);
+
+        let report = scan_ssh_dir("alice", home.path(), &[], true).unwrap();
+        let keys: Vec<(String, &str)> = report
+            .permissive_private_keys
+            .iter()
+            .map(|key| (key.path.file_name().unwrap().to_string_lossy().to_string(), key.mode.as_str()))
+            .collect();
+        assert_eq!(keys, vec![("deploy".to_string(), "640"), ("id_ed25519".to_string(), "644")]);
+    }
+
+    #[test]
+    fn test_sizes_and_known_hosts() {
+        let home = tempfile::tempdir().unwrap();
+        let ssh = home.path().join(".ssh");
+        write(&ssh.join("known_hosts"), &vec![b'x'; KNOWN_HOSTS_WARN_BYTES as usize + 1], 0o644);
+        write(&ssh.join("config"), b"Host *\n", 0o644);
+
+        let report = scan_ssh_dir("alice", home.path(), &[], true).unwrap();
+        assert_eq!(report.known_hosts_bytes, KNOWN_HOSTS_WARN_BYTES + 1);
+        assert_eq!(report.total_bytes, KNOWN_HOSTS_WARN_BYTES + 8);
+        assert!(report.warnings()[0].contains("known_hosts"));
+    }
+
+    #[test]
+    fn test_bounds_excludes_and_symlinks() {
+        let home = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let ssh = home.path().join(".ssh");
+        write(&ssh.join("a/b/c/d/deep"), b"12345", 0o600);
+        write(&outside.path().join("id_rsa"), b"secret", 0o644);
+        symlink(outside.path(), ssh.join("linked")).unwrap();
+        symlink(outside.path().join("id_rsa"), ssh.join("id_rsa")).unwrap();
+        write(&ssh.join("excluded/id_dsa"), b"secret", 0o644);
+
+        let exclude = vec![format!("{}/excluded/**", ssh.display())];
+        let report = scan_ssh_dir("alice", home.path(), &exclude, true).unwrap();
+        assert!(report.truncated);
+        assert_eq!(report.total_bytes, 0);
+        assert!(report.permissive_private_keys.is_empty());
+
+        // A .ssh symlinked out of the home is not scanned at all
+        let other = tempfile::tempdir().unwrap();
+        symlink(outside.path(), other.path().join(".ssh")).unwrap();
+        assert!(scan_ssh_dir("bob", other.path(), &[], true).is_none());
+        assert!(scan_ssh_dir("bob", other.path().join("missing").as_path(), &[], true).is_none());
+    }
+
+    #[test]
+    fn test_entry_limit() {
+        let home = tempfile::tempdir().unwrap();
+        for i in 0..MAX_ENTRIES + 5 {
+            write(&home.path().join(format!(".ssh/f{:04}", i)), b"x", 0o600);
+        }
+        let report = scan_ssh_dir("alice", home.path(), &[], true).unwrap();
+        assert!(report.truncated);
+        assert_eq!(report.total_bytes, MAX_ENTRIES as u64);
+    }
+}
@@ -1,22 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, Permissions};
-use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use anyhow::{Result, Context, anyhow};
 use tracing::{info, warn, error, debug, instrument};
 use serde::Serialize;
 
-use crate::api::KeyAssignment;
-use crate::users::UserInfo;
+use crate::fsutil::{self, FileOps, RealFs};
+use crate::keylock;
+use crate::interrupt::Interrupt;
+use crate::api::{AssignmentAck, KeyAssignment};
+use crate::glob::path_allowed;
+use crate::policy::KeyPolicy;
+use crate::report::KeyInventoryEntry;
+use crate::sshd::{PermitRootLogin, SSHD_CONFIG_PATHS};
+use crate::users::{UserFilter, UserInfo, filter_users, should_skip_unmounted_home};
 
 /// Represents a parsed SSH public key
 #[derive(Debug, Clone, PartialEq)]
 pub struct SshKey {
+    /// Options field in front of the key type (`from="10.0.0.0/8",no-pty`), as written
+    pub options: Option<String>,
     pub key_type: String,
     pub key_data: String,
     pub comment: Option<String>,
     pub fingerprint: String,
+    /// Legacy colon-separated MD5 fingerprint, for assignments imported from older systems
+    pub md5_fingerprint: String,
+    /// Original line as read from disk, kept verbatim for keys the agent does not manage
+    pub raw: Option<String>,
+}
+
+/// A line of an authorized_keys file
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthorizedKeysEntry {
+    /// A parsed public key
+    Key(SshKey),
+    /// Comments, blank lines and anything else, carried through untouched
+    Opaque(String),
+}
+
+/// SSH key types the agent understands
+pub const KNOWN_KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss", 
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384", 
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+    "ssh-rsa-cert-v01@openssh.com",
+    "ssh-dss-cert-v01@openssh.com",
+    "ssh-ed25519-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp384-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp521-cert-v01@openssh.com",
+    "sk-ssh-ed25519-cert-v01@openssh.com",
+    "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com",
+];
+
+/// Suffix shared by the OpenSSH certificate key types
+const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+/// An authorized_keys file split around the part the agent manages
+struct ManagedSplit {
+    /// Lines above the managed block, left as they are
+    before: Vec<AuthorizedKeysEntry>,
+    /// The managed block's contents, markers excluded, or the whole file when the agent owns it
+    managed: Vec<AuthorizedKeysEntry>,
+    /// Lines below the managed block
+    after: Vec<AuthorizedKeysEntry>,
+    has_block: bool,
+}
+
+/// What a rewrite puts inside the managed part of a file
+struct ManagedSection {
+    preserved: Vec<String>,
+    external: Vec<ExternalKey>,
+    adopted: Vec<SshKey>,
+    keys: Vec<SshKey>,
 }
 
 /// Information about an authorized_keys file
@@ -28,25 +95,426 @@ pub struct AuthorizedKeysFile {
     pub exists: bool,
 }
 
+/// Result of authorized_keys discovery
+#[derive(Debug, Default)]
+pub struct DiscoveredFiles {
+    pub files: Vec<AuthorizedKeysFile>,
+    /// Users skipped because their encrypted home is not mounted
+    pub unmounted: Vec<String>,
+    /// Files skipped by --include-paths/--exclude-paths
+    pub excluded: u32,
+    /// Files refused because they resolve to a denylisted directory
+    pub denied: Vec<PathBuf>,
+}
+
+/// Directories an authorized_keys file (or its .ssh directory) must never live in
+pub const DEFAULT_DENIED_KEY_DIRS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/proc", "/sbin", "/sys", "/tmp", "/usr", "/usr/bin",
+    "/usr/sbin", "/var",
+];
+
+/// Comment markers of keys written by other tools (cloud-init, Salt, Puppet), matched case-insensitively
+pub const DEFAULT_EXTERNAL_KEY_MARKERS: &[&str] = &[
+    "added by cloud-init",
+    "managed by salt",
+    "managed by puppet",
+    "HEADER: This file was autogenerated",
+];
+
+/// Comment lines the agent writes under the managed marker, dropped on read so they are not preserved twice
+const MANAGED_HEADER: &[&str] = &["# This file is managed by PubliKey Agent", "# Manual changes will be overwritten"];
+
+/// Lines delimiting the keys the agent manages; everything outside them is left alone
+pub(crate) const BLOCK_BEGIN: &str = "# --- BEGIN PubliKey managed ---";
+pub(crate) const BLOCK_END: &str = "# --- END PubliKey managed ---";
+
+/// Comment line written above keys adopted from the unmanaged part of a file (--adopt-existing-keys);
+/// the line below it is managed like any other key, options and comment included
+const ADOPTED_MARKER: &str = "# Adopted by PubliKey Agent";
+
+/// Comment line written above assigned keys carrying server-provided options, telling them apart
+/// from keys restricted by hand, which are preserved as written
+const OPTIONS_MARKER: &str = "# Options set by PubliKey Agent";
+
+/// Directories holding user homes; drives the fallback home and the outside-root warning
+pub const DEFAULT_HOME_ROOTS: &[&str] = &["/home"];
+
+/// File name suffixes of backup, temp and editor artifacts that are never authorized_keys targets
+pub const DEFAULT_ARTIFACT_SUFFIXES: &[&str] = &[
+    "~", ".bak", ".old", ".orig", ".tmp", ".new", ".swp", ".swo", ".save", ".rej", ".dpkg-old", ".dpkg-dist",
+    ".rpmsave", ".rpmnew",
+];
+
+/// Reasons in `KeySyncStats::users_skipped_reasons`
+const SKIP_USER_FILTER: &str = "user-filter";
+const SKIP_ROOT_LOGIN_DISABLED: &str = "root-login-disabled";
+const SKIP_PUBKEY_AUTH_DISABLED: &str = "pubkey-auth-disabled";
+const SKIP_HOME_NOT_MOUNTED: &str = "home-not-mounted";
+const SKIP_LOCK_TIMEOUT: &str = "lock-timeout";
+/// The user has an opt-out marker in effect
+const SKIP_USER_OPT_OUT: &str = "user-opt-out";
+/// No authorized_keys file survived path filters and the directory denylist
+const SKIP_NO_FILES_IN_SCOPE: &str = "no-files-in-scope";
+/// SIGTERM or SIGINT arrived before the user's turn
+const SKIP_INTERRUPTED: &str = "interrupted";
+
 /// Statistics about SSH key operations
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct KeySyncStats {
+    /// Users handed to the sync; each ends up processed or skipped
+    pub users_considered: u32,
+    /// Users with at least one authorized_keys file evaluated (older agents counted files here)
     pub users_processed: u32,
+    /// Users with no file evaluated
+    pub users_skipped: u32,
+    /// Why users were skipped, with a count per reason
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users_skipped_reasons: BTreeMap<String, u32>,
     pub keys_added: u32,
     pub keys_removed: u32,
+    /// Keys rewritten because their assigned options changed
+    pub keys_updated: u32,
+    /// authorized_keys files read and planned
+    pub files_examined: u32,
+    /// Files rewritten (or that would be, in a dry run)
     pub files_updated: u32,
+    pub files_excluded: u32,
     pub errors: u32,
+    pub assignments_rejected: u32,
+    pub rejected_assignment_ids: Vec<String>,
+    /// Keys kept because removals are deferred outside the rollout canary
+    pub removals_deferred: u32,
+    /// Root's keys were not synced because sshd does not allow root to log in with them
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub root_login_disabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_filter: Option<UserFilter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub orphaned_files: Vec<OrphanedFile>,
+    /// Per-file fingerprints added and removed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<FileKeyChanges>,
+    /// Outcome of every incoming assignment, keyed by assignment ID
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub assignment_statuses: BTreeMap<String, AssignmentStatus>,
+    /// Keys in files in scope that no assignment accounts for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unmanaged_keys: Vec<UnmanagedKeys>,
+    /// Ownership or mode problems on authorized_keys files and their directories
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub permission_warnings: Vec<PermissionWarning>,
+    /// Modes and owners restored on unchanged managed files (or that would be, in a dry run)
+    pub permissions_fixed: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub permission_fixes: Vec<PermissionWarning>,
+    /// Keys written by other tools, kept unless --remove-external-keys is given
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub external_keys: Vec<UnmanagedKeys>,
+    /// Assigned keys found only outside the managed keys (other tools' keys, option-prefixed lines),
+    /// left there instead of adding a duplicate
+    pub keys_already_present_unmanaged: u32,
+    /// Such keys moved into the managed keys by --adopt-existing-keys
+    pub keys_adopted: u32,
+    /// Lines carried through rewrites untouched (option-restricted keys, unknown-type keys, comments)
+    pub preserved_lines: u32,
+    /// Users skipped because another tool held their authorized_keys lock
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lock_timeouts: Vec<LockTimeout>,
+    /// Existing authorized_keys files with neither the managed marker nor a managed block yet
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unmarked_files: Vec<UnmarkedFile>,
+    /// Planned contents of each file a dry run would rewrite, for --diff
+    #[serde(skip)]
+    pub previews: Vec<FilePreview>,
+    /// Part of the plan shown and counted above, when a dry run is limited to one kind of change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run_scope: Option<DryRunScope>,
+    /// Counts of the full plan, when `dry_run_scope` hides part of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan_totals: Option<PlanTotals>,
+    /// Users with assignments whose logins are blocked outside authorized_keys
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub login_warnings: Vec<LoginWarning>,
+    /// The sync stopped early on SIGTERM or SIGINT; users after the last file written were left alone
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+}
+
+impl KeySyncStats {
+    /// Limit the plan to one kind of change; the full counts move to `plan_totals`
+    pub fn limit_to(&mut self, scope: DryRunScope) {
+        if scope == DryRunScope::All {
+            return;
+        }
+        self.plan_totals = Some(PlanTotals {
+            keys_added: self.keys_added,
+            keys_removed: self.keys_removed,
+            files_updated: self.files_updated,
+        });
+        self.dry_run_scope = Some(scope);
+
+        for change in &mut self.changes {
+            // An options change rewrites the key's line, so it shows with the additions
+            match scope {
+                DryRunScope::Additions => change.removed.clear(),
+                DryRunScope::Removals => {
+                    change.added.clear();
+                    change.updated.clear();
+                }
+                DryRunScope::All => {}
+            }
+        }
+        self.changes.retain(|change| !change.added.is_empty() || !change.removed.is_empty() || !change.updated.is_empty());
+        match scope {
+            DryRunScope::Additions => self.keys_removed = 0,
+            DryRunScope::Removals => {
+                self.keys_added = 0;
+                self.keys_updated = 0;
+            }
+            DryRunScope::All => {}
+        }
+        self.files_updated = self.changes.len() as u32;
+    }
+
+    /// One acknowledgement per incoming assignment, in assignment ID order
+    pub fn acknowledgements(&self) -> Vec<AssignmentAck> {
+        self.assignment_statuses
+            .iter()
+            .map(|(assignment_id, status)| AssignmentAck {
+                assignment_id: assignment_id.clone(),
+                outcome: status.status.outcome(),
+                status: status.status,
+                detail: status.detail.clone(),
+            })
+            .collect()
+    }
+
+    /// Record an assignment's outcome; across several files of one user the most significant outcome wins
+    fn record_status(&mut self, assignment_id: &str, state: AssignmentState, detail: Option<String>) {
+        if let Some(existing) = self.assignment_statuses.get(assignment_id)
+            && existing.status.rank() >= state.rank()
+        {
+            return;
+        }
+        self.assignment_statuses
+            .insert(assignment_id.to_string(), AssignmentStatus { status: state, detail });
+    }
+}
+
+/// How an assignment was handled on this host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AssignmentState {
+    /// Key written to the user's authorized_keys
+    AppliedNew,
+    /// Key would be written (dry run)
+    Pending,
+    /// Key was already present
+    AlreadyPresent,
+    /// Public key could not be parsed
+    FailedParse,
+    /// Writing the authorized_keys file failed
+    FailedApply,
+    /// No (unambiguous) local account matches the assignment
+    UserNotFound,
+    /// Target user or their authorized_keys files are filtered out of the sync
+    ExcludedByFilter,
+    /// sshd has public key authentication turned off for the target user
+    PubkeyAuthDisabled,
+    /// Another tool held the authorized_keys lock past the lock timeout
+    LockTimeout,
+    /// Assignment has expired, so its key is removed
+    RemovedExpired,
+    /// Key violates the local key policy
+    SuppressedByPolicy,
+    /// The target user has an opt-out marker in effect
+    UserOptOut,
+    /// The run was interrupted before the target user's files were synced
+    Interrupted,
+}
+
+/// Coarse result of an assignment, as acknowledged to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AckOutcome {
+    Applied,
+    Skipped,
+    Failed,
+}
+
+impl AssignmentState {
+    pub fn outcome(self) -> AckOutcome {
+        match self {
+            AssignmentState::AppliedNew | AssignmentState::AlreadyPresent | AssignmentState::RemovedExpired => AckOutcome::Applied,
+            AssignmentState::FailedParse | AssignmentState::FailedApply | AssignmentState::LockTimeout => AckOutcome::Failed,
+            AssignmentState::Pending
+            | AssignmentState::UserNotFound
+            | AssignmentState::ExcludedByFilter
+            | AssignmentState::PubkeyAuthDisabled
+            | AssignmentState::SuppressedByPolicy
+            | AssignmentState::UserOptOut
+            | AssignmentState::Interrupted => AckOutcome::Skipped,
+        }
+    }
+
+    /// Precedence when one assignment touches several files
+    fn rank(self) -> u8 {
+        match self {
+            AssignmentState::FailedApply | AssignmentState::LockTimeout => 3,
+            AssignmentState::AppliedNew | AssignmentState::Pending => 2,
+            AssignmentState::AlreadyPresent => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Outcome of a single assignment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AssignmentStatus {
+    pub status: AssignmentState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Fingerprints changed in a single authorized_keys file
+#[derive(Debug, Clone, Serialize)]
+pub struct FileKeyChanges {
+    pub username: String,
+    pub path: PathBuf,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Keys kept but rewritten with different options
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub updated: Vec<String>,
+}
+
+/// Keys present in one authorized_keys file without a matching assignment
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmanagedKeys {
+    pub username: String,
+    pub path: PathBuf,
+    pub fingerprints: Vec<String>,
+}
+
+/// A key another tool wrote, with the separate marker comment line above it, if any
+#[derive(Debug, Clone)]
+struct ExternalKey {
+    key: SshKey,
+    marker_line: Option<String>,
+}
+
+/// Ownership or mode problem on an authorized_keys file or its directory
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionWarning {
+    pub username: String,
+    pub path: PathBuf,
+    pub detail: String,
+}
+
+/// An authorized_keys file left alone because its lock stayed held
+#[derive(Debug, Clone, Serialize)]
+pub struct LockTimeout {
+    pub username: String,
+    pub path: PathBuf,
+}
+
+/// An authorized_keys file the agent has not put its marker in yet
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmarkedFile {
+    pub username: String,
+    pub path: PathBuf,
+}
+
+/// An authorized_keys file as it is and as a dry run would write it
+#[derive(Debug, Clone)]
+pub struct FilePreview {
+    pub path: PathBuf,
+    /// `None` when the file does not exist yet
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// Something that refuses a user's logins even with the right keys in place
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginWarning {
+    pub username: String,
+    pub detail: String,
+}
+
+/// Which part of the sync plan a dry run shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DryRunScope {
+    /// Additions and removals
+    #[default]
+    All,
+    /// Only keys that would be added
+    Additions,
+    /// Only keys that would be removed
+    Removals,
+}
+
+/// Counts of the full sync plan
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PlanTotals {
+    pub keys_added: u32,
+    pub keys_removed: u32,
+    pub files_updated: u32,
+}
+
+/// What to do with managed files whose owner no longer exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrphanMode {
+    /// Only report orphaned files
+    Report,
+    /// Report and delete orphaned files older than the grace period; files that also hold lines
+    /// outside the managed block only lose the block
+    Delete,
+}
+
+/// A PubliKey managed authorized_keys file whose owner is no longer a known user
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanedFile {
+    pub path: PathBuf,
+    pub username: String,
+    /// Last modification time as seconds since the Unix epoch
+    pub last_modified: Option<u64>,
+    pub key_count: usize,
+    pub deleted: bool,
+    /// The managed block was cut out of a file that also holds other lines, which were kept
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub block_removed: bool,
 }
 
 /// SSH key validation and parsing
 impl SshKey {
     /// Parse an SSH public key line
     pub fn parse(line: &str) -> Result<Self> {
+        Self::parse_with(line, None)
+    }
+
+    /// Parse an SSH public key line, reusing fingerprints from `cache` when given
+    pub fn parse_with(line: &str, cache: Option<&FingerprintCache>) -> Result<Self> {
+        let raw = line.trim_end_matches(['\n', '\r']).to_string();
         let line = line.trim();
         if line.is_empty() || line.starts_with('#') {
             return Err(anyhow!("Empty or comment line"));
         }
 
+        // Anything but a known key type up front is an options field, ending at the first whitespace
+        // outside double quotes
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if !KNOWN_KEY_TYPES.contains(&first)
+            && let Some(options_end) = options_end(line)
+            && let Ok(key) = Self::parse_without_options(line[options_end..].trim_start(), raw.clone(), cache)
+        {
+            return Ok(SshKey { options: Some(line[..options_end].to_string()), ..key });
+        }
+        Self::parse_without_options(line, raw, cache)
+    }
+
+    /// Parse `<type> <base64> [comment]`, already trimmed
+    fn parse_without_options(line: &str, raw: String, cache: Option<&FingerprintCache>) -> Result<Self> {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() < 2 {
             return Err(anyhow!("Invalid SSH key format: too few parts"));
@@ -63,126 +531,438 @@ impl SshKey {
         // Validate key type
         Self::validate_key_type(&key_type)?;
         
-        // Validate key data (base64)
-        Self::validate_key_data(&key_data)?;
-
-        // Generate fingerprint
-        let fingerprint = Self::calculate_fingerprint(&key_type, &key_data)?;
+        // Validate key data (base64) and fingerprint it from a single decode
+        let fingerprints = match cache {
+            Some(cache) => cache.fingerprints(&key_data)?,
+            None => Fingerprints::of(&decode_key_data(&key_data)?),
+        };
 
         Ok(SshKey {
+            options: None,
             key_type,
             key_data,
             comment,
-            fingerprint,
+            fingerprint: fingerprints.sha256,
+            md5_fingerprint: fingerprints.md5,
+            raw: Some(raw),
         })
     }
 
+    /// Whether this is an OpenSSH certificate rather than a plain public key. Its fingerprint is
+    /// that of the whole certificate blob, so a reissued certificate counts as a new key.
+    pub fn is_certificate(&self) -> bool {
+        self.key_type.ends_with(CERT_SUFFIX)
+    }
+
+    /// Line to write back: the original text if known, canonical form otherwise
+    pub fn to_line(&self) -> String {
+        self.raw.clone().unwrap_or_else(|| self.to_string())
+    }
+
     /// Validate SSH key type
     fn validate_key_type(key_type: &str) -> Result<()> {
-        const ALLOWED_KEY_TYPES: &[&str] = &[
-            "ssh-rsa",
-            "ssh-dss", 
-            "ssh-ed25519",
-            "ecdsa-sha2-nistp256",
-            "ecdsa-sha2-nistp384", 
-            "ecdsa-sha2-nistp521",
-            "sk-ssh-ed25519@openssh.com",
-            "sk-ecdsa-sha2-nistp256@openssh.com",
-        ];
-
-        if ALLOWED_KEY_TYPES.contains(&key_type) {
+        if KNOWN_KEY_TYPES.contains(&key_type) {
             Ok(())
         } else {
             Err(anyhow!("Unsupported SSH key type: {}", key_type))
         }
     }
 
-    /// Validate base64 key data
-    fn validate_key_data(key_data: &str) -> Result<()> {
-        use base64::Engine;
-        let engine = base64::engine::general_purpose::STANDARD;
-        
-        engine.decode(key_data)
-            .context("Invalid base64 in SSH key data")?;
-        
-        Ok(())
+    /// Whether `fingerprint` names this key, in whichever format the server stored it
+    pub fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+        match FingerprintFormat::detect(fingerprint) {
+            // Older agents reported padded fingerprints and the server may still store them
+            Some(FingerprintFormat::Sha256) => self.fingerprint == fingerprint.trim_end_matches('='),
+            Some(FingerprintFormat::Md5) => {
+                let md5 = fingerprint.strip_prefix("MD5:").unwrap_or(fingerprint);
+                self.md5_fingerprint.eq_ignore_ascii_case(md5)
+            }
+            None => false,
+        }
     }
+}
 
-    /// Calculate SHA256 fingerprint
-    fn calculate_fingerprint(_key_type: &str, key_data: &str) -> Result<String> {
-        use sha2::{Sha256, Digest};
-        use base64::Engine;
-        
-        let engine = base64::engine::general_purpose::STANDARD;
-        let key_bytes = engine.decode(key_data)
-            .context("Failed to decode key data for fingerprint")?;
-        
-        let mut hasher = Sha256::new();
-        hasher.update(&key_bytes);
-        let hash = hasher.finalize();
-        
-        // Format as SSH fingerprint
-        let fingerprint = engine.encode(&hash);
-        Ok(format!("SHA256:{}", fingerprint))
+/// Compare the fingerprint the server stored for an assignment with the key it sent; the key is
+/// what gets written either way, so a mismatch or a legacy MD5 fingerprint is only logged
+fn check_assignment_fingerprint(assignment: &KeyAssignment, key: &SshKey) {
+    match FingerprintFormat::detect(&assignment.fingerprint) {
+        Some(_) if !key.matches_fingerprint(&assignment.fingerprint) => warn!(
+            "Key assignment {} has fingerprint {} but its public key is {}; using the public key",
+            assignment.assignment_id, assignment.fingerprint, key.fingerprint
+        ),
+        Some(FingerprintFormat::Md5) => info!(
+            "Key assignment {} matched by legacy MD5 fingerprint; the server should migrate it to SHA256",
+            assignment.assignment_id
+        ),
+        _ => {}
     }
+}
 
-    /// Convert back to SSH public key format
-    pub fn to_string(&self) -> String {
+/// Convert back to SSH public key format
+impl fmt::Display for SshKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(options) = &self.options {
+            write!(f, "{} ", options)?;
+        }
         match &self.comment {
-            Some(comment) => format!("{} {} {}", self.key_type, self.key_data, comment),
-            None => format!("{} {}", self.key_type, self.key_data),
+            Some(comment) => write!(f, "{} {} {}", self.key_type, self.key_data, comment),
+            None => write!(f, "{} {}", self.key_type, self.key_data),
         }
     }
+}
 
-    /// Check if this key matches a PubliKey assignment
-    pub fn matches_assignment(&self, assignment: &KeyAssignment) -> bool {
-        // Primary match: fingerprint
-        if self.fingerprint == assignment.fingerprint {
-            return true;
+/// Decode base64 key data, validating it
+fn decode_key_data(key_data: &str) -> Result<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(key_data)
+        .context("Invalid base64 in SSH key data")
+}
+
+/// Format of a fingerprint string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintFormat {
+    /// `SHA256:<base64>`
+    Sha256,
+    /// Sixteen colon-separated hex bytes, optionally prefixed with `MD5:`
+    Md5,
+}
+
+impl FingerprintFormat {
+    pub fn detect(fingerprint: &str) -> Option<Self> {
+        if fingerprint.starts_with("SHA256:") {
+            return Some(FingerprintFormat::Sha256);
+        }
+        let hex = fingerprint.strip_prefix("MD5:").unwrap_or(fingerprint);
+        let bytes: Vec<&str> = hex.split(':').collect();
+        let is_md5 = bytes.len() == 16
+            && bytes.iter().all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()));
+        is_md5.then_some(FingerprintFormat::Md5)
+    }
+}
+
+/// Fingerprints of one key in every supported format
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprints {
+    sha256: String,
+    md5: String,
+}
+
+impl Fingerprints {
+    fn of(key_bytes: &[u8]) -> Self {
+        Self {
+            sha256: fingerprint_of(key_bytes),
+            md5: md5_fingerprint_of(key_bytes),
+        }
+    }
+}
+
+/// OpenSSH-style SHA256 fingerprint of decoded key bytes. Like `ssh-keygen -l`,
+/// the base64 is unpadded.
+fn fingerprint_of(key_bytes: &[u8]) -> String {
+    use sha2::{Sha256, Digest};
+    use base64::Engine;
+
+    let hash = Sha256::digest(key_bytes);
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash))
+}
+
+/// Legacy OpenSSH MD5 fingerprint (`aa:bb:...`) of decoded key bytes
+fn md5_fingerprint_of(key_bytes: &[u8]) -> String {
+    use md5::{Md5, Digest};
+
+    Md5::digest(key_bytes).iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// Entries kept before the fingerprint cache is reset
+const FINGERPRINT_CACHE_CAPACITY: usize = 65_536;
+
+/// Within-run cache of fingerprints keyed by base64 key data.
+///
+/// The same key usually shows up both in existing authorized_keys files and in assignments,
+/// and files are read more than once per run.
+#[derive(Debug, Default)]
+pub struct FingerprintCache {
+    entries: Mutex<HashMap<String, Fingerprints>>,
+    keys_parsed: AtomicU64,
+    cache_hits: AtomicU64,
+    decodes: AtomicU64,
+}
+
+/// Counters for the key parsing hot path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseCounters {
+    pub keys_parsed: u64,
+    pub cache_hits: u64,
+    pub decodes: u64,
+}
+
+impl FingerprintCache {
+    fn fingerprints(&self, key_data: &str) -> Result<Fingerprints> {
+        self.keys_parsed.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(fingerprints) = entries.get(key_data) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(fingerprints.clone());
+        }
+
+        self.decodes.fetch_add(1, Ordering::Relaxed);
+        let fingerprints = Fingerprints::of(&decode_key_data(key_data)?);
+        if entries.len() >= FINGERPRINT_CACHE_CAPACITY {
+            entries.clear();
+        }
+        entries.insert(key_data.to_string(), fingerprints.clone());
+        Ok(fingerprints)
+    }
+
+    pub fn counters(&self) -> ParseCounters {
+        ParseCounters {
+            keys_parsed: self.keys_parsed.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            decodes: self.decodes.load(Ordering::Relaxed),
         }
-        
-        // Secondary match: key type and data
-        self.key_type == assignment.key_type && 
-        self.key_data == assignment.public_key.split_whitespace().nth(1).unwrap_or("")
     }
 }
 
 /// SSH key file management
 pub struct SshKeyManager {
     managed_marker: String,
+    attempt_unmounted_homes: bool,
+    key_policy: KeyPolicy,
+    include_paths: Vec<String>,
+    exclude_paths: Vec<String>,
+    denied_key_dirs: Vec<PathBuf>,
+    artifact_suffixes: Vec<String>,
+    home_roots: Vec<PathBuf>,
+    external_markers: Vec<String>,
+    remove_external_keys: bool,
+    adopt_existing_keys: bool,
+    exclusive: bool,
+    lock_timeout: Duration,
+    user_filter: Option<UserFilter>,
+    defer_removals: bool,
+    root_login: Option<PermitRootLogin>,
+    pubkey_auth_disabled: HashSet<String>,
+    preview_content: bool,
+    fingerprints: FingerprintCache,
+    interrupt: Interrupt,
+    file_ops: Box<dyn FileOps + Send + Sync>,
 }
 
 impl SshKeyManager {
     pub fn new() -> Self {
         Self {
             managed_marker: "# PubliKey managed - do not edit manually".to_string(),
+            attempt_unmounted_homes: false,
+            key_policy: KeyPolicy::default(),
+            include_paths: Vec::new(),
+            exclude_paths: Vec::new(),
+            denied_key_dirs: DEFAULT_DENIED_KEY_DIRS.iter().map(PathBuf::from).collect(),
+            artifact_suffixes: DEFAULT_ARTIFACT_SUFFIXES.iter().map(|s| s.to_string()).collect(),
+            home_roots: DEFAULT_HOME_ROOTS.iter().map(PathBuf::from).collect(),
+            external_markers: DEFAULT_EXTERNAL_KEY_MARKERS.iter().map(|s| s.to_string()).collect(),
+            remove_external_keys: false,
+            adopt_existing_keys: false,
+            exclusive: false,
+            lock_timeout: keylock::DEFAULT_LOCK_TIMEOUT,
+            user_filter: None,
+            defer_removals: false,
+            root_login: None,
+            pubkey_auth_disabled: HashSet::new(),
+            preview_content: false,
+            fingerprints: FingerprintCache::default(),
+            interrupt: Interrupt::default(),
+            file_ops: Box::new(RealFs),
+        }
+    }
+
+    /// Write authorized_keys files through `ops` instead of the real filesystem
+    #[cfg(test)]
+    pub fn with_file_ops(mut self, ops: Box<dyn FileOps + Send + Sync>) -> Self {
+        self.file_ops = ops;
+        self
+    }
+
+    /// How long to wait for another tool holding an authorized_keys lock before skipping the user
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Stop before the next user's files once `interrupt` is triggered
+    pub fn with_interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    /// Add keys but keep keys that would be removed (host outside the rollout canary)
+    pub fn with_defer_removals(mut self, defer: bool) -> Self {
+        self.defer_removals = defer;
+        self
+    }
+
+    /// Skip root's keys when sshd's PermitRootLogin rules out key logins for root
+    pub fn with_root_login(mut self, permit_root_login: PermitRootLogin) -> Self {
+        self.root_login = Some(permit_root_login);
+        self
+    }
+
+    /// Skip users for whom sshd has public key authentication turned off
+    pub fn with_pubkey_auth_disabled(mut self, usernames: HashSet<String>) -> Self {
+        self.pubkey_auth_disabled = usernames;
+        self
+    }
+
+    /// Key parsing counters for this manager
+    pub fn parse_counters(&self) -> ParseCounters {
+        self.fingerprints.counters()
+    }
+
+    /// Only sync users passing this include/exclude filter; assignments for other users are reported as excluded
+    pub fn with_user_filter(mut self, filter: UserFilter) -> Self {
+        self.user_filter = Some(filter);
+        self
+    }
+
+    /// Replace the list of directories authorized_keys files may not be written to
+    pub fn with_denied_key_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.denied_key_dirs = dirs;
+        self
+    }
+
+    /// Replace the list of file name suffixes treated as backup/temp artifacts rather than key files
+    pub fn with_artifact_suffixes(mut self, suffixes: Vec<String>) -> Self {
+        self.artifact_suffixes = suffixes;
+        self
+    }
+
+    /// Replace the directories user homes are expected under
+    pub fn with_home_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.home_roots = roots;
+        self
+    }
+
+    /// Replace the comment markers identifying keys written by other tools
+    pub fn with_external_key_markers(mut self, markers: Vec<String>) -> Self {
+        self.external_markers = markers;
+        self
+    }
+
+    /// Treat keys written by other tools like any other unassigned key
+    pub fn with_remove_external_keys(mut self, remove: bool) -> Self {
+        self.remove_external_keys = remove;
+        self
+    }
+
+    /// Move assigned keys found outside the managed keys into them instead of leaving them where they are
+    pub fn with_adopt_existing_keys(mut self, adopt: bool) -> Self {
+        self.adopt_existing_keys = adopt;
+        self
+    }
+
+    /// Own whole authorized_keys files instead of a delimited block, removing every unassigned key
+    pub fn with_exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Whether `path` is a real authorized_keys target rather than a backup, temp or editor artifact
+    pub fn is_key_file_target(&self, path: &Path) -> bool {
+        if is_file_artifact(path, &self.artifact_suffixes) {
+            debug!("Skipping backup/temp artifact {}", path.display());
+            return false;
         }
+        true
+    }
+
+    /// Restrict sync to authorized_keys paths matching `include` and never touch paths matching `exclude`
+    pub fn with_path_filters(mut self, include: Vec<String>, exclude: Vec<String>) -> Self {
+        self.include_paths = include;
+        self.exclude_paths = exclude;
+        self
+    }
+
+    /// Enforce a local key policy on assignments and existing keys
+    pub fn with_key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.key_policy = policy;
+        self
+    }
+
+    /// In a dry run, also render each file that would be rewritten (`KeySyncStats::previews`)
+    pub fn with_content_preview(mut self, preview: bool) -> Self {
+        self.preview_content = preview;
+        self
+    }
+
+    /// Also write keys for users whose encrypted home is not currently mounted
+    pub fn with_attempt_unmounted_homes(mut self, attempt: bool) -> Self {
+        self.attempt_unmounted_homes = attempt;
+        self
     }
 
     /// Discover all authorized_keys files for given users
-    pub fn discover_authorized_keys_files(&self, users: &[UserInfo]) -> Result<Vec<AuthorizedKeysFile>> {
-        let mut files = Vec::new();
-        
+    pub fn discover_authorized_keys_files(&self, users: &[UserInfo]) -> Result<DiscoveredFiles> {
         // Get authorized_keys file patterns from sshd_config
         let auth_keys_patterns = self.get_authorized_keys_patterns()?;
         info!("Found {} AuthorizedKeysFile patterns in sshd_config", auth_keys_patterns.len());
         
+        Ok(self.expand_authorized_keys_files(users, &auth_keys_patterns))
+    }
+
+    /// Expand patterns for every user, applying include/exclude path filters and the directory denylist
+    fn expand_authorized_keys_files(&self, users: &[UserInfo], auth_keys_patterns: &[String]) -> DiscoveredFiles {
+        let mut discovered = DiscoveredFiles::default();
+        
         for user in users {
-            let user_home = if user.uid == 0 {
-                PathBuf::from("/root")
-            } else {
-                match &user.home_dir {
-                    Some(home) => PathBuf::from(home),
-                    None => PathBuf::from("/home").join(&user.username),
-                }
+            if should_skip_unmounted_home(user, self.attempt_unmounted_homes) {
+                warn!(
+                    "Skipping user {}: encrypted home ({:?}) is not mounted",
+                    user.username,
+                    user.home_encryption
+                );
+                discovered.unmounted.push(user.username.clone());
+                continue;
+            }
+            
+            let user_home = match &user.home_dir {
+                Some(home) if !home.is_empty() => PathBuf::from(home),
+                _ if user.uid == 0 => PathBuf::from("/root"),
+                _ => fallback_home(&user.username, &self.home_roots),
             };
+            if user.uid != 0 && is_outside_home_roots(&user_home, &self.home_roots) {
+                warn!(
+                    "Home {} of user {} is outside the configured home roots; check passwd for a typo or tampering",
+                    user_home.display(),
+                    user.username
+                );
+            }
             
             // Expand each pattern for this user
-            for pattern in &auth_keys_patterns {
+            for pattern in auth_keys_patterns {
                 if let Some(expanded_path) = self.expand_authorized_keys_pattern(pattern, &user.username, &user_home) {
+                    if !self.is_key_file_target(&expanded_path) {
+                        continue;
+                    }
+
+                    if !path_allowed(&expanded_path.to_string_lossy(), &self.include_paths, &self.exclude_paths) {
+                        debug!("Skipping excluded authorized_keys path {}", expanded_path.display());
+                        discovered.excluded += 1;
+                        continue;
+                    }
+                    
+                    if is_denied_key_path(&expanded_path, &self.denied_key_dirs) {
+                        error!(
+                            "Refusing to manage {} for user {}: location is on the directory denylist",
+                            expanded_path.display(),
+                            user.username
+                        );
+                        discovered.denied.push(expanded_path);
+                        continue;
+                    }
+                    
                     let exists = expanded_path.exists();
                     
-                    files.push(AuthorizedKeysFile {
+                    discovered.files.push(AuthorizedKeysFile {
                         path: expanded_path,
                         username: user.username.clone(),
                         uid: user.uid,
@@ -192,8 +972,38 @@ impl SshKeyManager {
             }
         }
         
-        info!("Discovered {} authorized_keys files across all patterns", files.len());
-        Ok(files)
+        info!(
+            "Discovered {} authorized_keys files across all patterns ({} excluded, {} denied)",
+            discovered.files.len(),
+            discovered.excluded,
+            discovered.denied.len()
+        );
+        discovered
+    }
+
+    /// AuthorizedKeysFile patterns in effect on this host
+    pub fn authorized_keys_patterns(&self) -> Result<Vec<String>> {
+        self.get_authorized_keys_patterns()
+    }
+
+    /// Fingerprints of the keys currently present in each user's authorized_keys files
+    pub fn key_inventory(&self, users: &[UserInfo]) -> Result<Vec<KeyInventoryEntry>> {
+        let discovered = self.discover_authorized_keys_files(users)?;
+        Ok(discovered
+            .files
+            .iter()
+            .filter(|file| file.exists)
+            .map(|file| {
+                let entries = self.read_entries(file).unwrap_or_default();
+                KeyInventoryEntry {
+                    username: file.username.clone(),
+                    path: file.path.display().to_string(),
+                    fingerprints: keys_of(&entries).map(|k| k.fingerprint.clone()).collect(),
+                    externally_managed: self.external_keys(&entries).into_iter().map(|e| e.key.fingerprint).collect(),
+                    certificates: keys_of(&entries).filter(|k| k.is_certificate()).map(|k| k.fingerprint.clone()).collect(),
+                }
+            })
+            .collect())
     }
 
     /// Parse sshd_config to find AuthorizedKeysFile directives
@@ -203,15 +1013,8 @@ impl SshKeyManager {
         // Default pattern if no sshd_config found
         let default_patterns = vec![".ssh/authorized_keys".to_string()];
         
-        // Common sshd_config locations
-        let sshd_config_paths = [
-            "/etc/ssh/sshd_config",
-            "/etc/sshd_config",
-            "/usr/local/etc/ssh/sshd_config",
-        ];
-        
         let mut found_config = false;
-        for config_path in &sshd_config_paths {
+        for config_path in SSHD_CONFIG_PATHS {
             if let Ok(content) = fs::read_to_string(config_path) {
                 info!("Reading SSH configuration from: {}", config_path);
                 found_config = true;
@@ -253,7 +1056,7 @@ impl SshKeyManager {
     }
 
     /// Expand SSH authorized_keys file pattern with user-specific values
-    fn expand_authorized_keys_pattern(&self, pattern: &str, username: &str, home_dir: &PathBuf) -> Option<PathBuf> {
+    fn expand_authorized_keys_pattern(&self, pattern: &str, username: &str, home_dir: &Path) -> Option<PathBuf> {
         let mut expanded = pattern.to_string();
         
         // Replace SSH configuration tokens
@@ -272,8 +1075,8 @@ impl SshKeyManager {
         Some(path)
     }
 
-    /// Read and parse authorized_keys file
-    pub fn read_authorized_keys(&self, file: &AuthorizedKeysFile) -> Result<Vec<SshKey>> {
+    /// Read and parse authorized_keys file, keeping comments and unparsable lines; a missing file has none
+    fn read_entries(&self, file: &AuthorizedKeysFile) -> Result<Vec<AuthorizedKeysEntry>> {
         if !file.exists {
             return Ok(Vec::new());
         }
@@ -281,22 +1084,145 @@ impl SshKeyManager {
         let content = fs::read_to_string(&file.path)
             .context(format!("Failed to read {}", file.path.display()))?;
 
-        let mut keys = Vec::new();
-        for (line_num, line) in content.lines().enumerate() {
-            match SshKey::parse(line) {
-                Ok(key) => {
-                    debug!("Parsed SSH key on line {}: {}", line_num + 1, key.fingerprint);
-                    keys.push(key);
+        let entries = parse_authorized_keys(&content, Some(&self.fingerprints));
+        for (line_num, entry) in entries.iter().enumerate() {
+            match entry {
+                AuthorizedKeysEntry::Key(key) => debug!("Parsed SSH key on line {}: {}", line_num + 1, key.fingerprint),
+                // Comments, empty lines, malformed keys
+                AuthorizedKeysEntry::Opaque(_) => debug!("Skipped line {} in {}", line_num + 1, file.path.display()),
+            }
+        }
+
+        info!("Read {} valid SSH keys from {}", keys_of(&entries).count(), file.path.display());
+        Ok(entries)
+    }
+
+    /// Keys written by other tools: the key's own comment carries a marker, or a marker comment
+    /// line sits above it with no blank line in between
+    fn external_keys(&self, entries: &[AuthorizedKeysEntry]) -> Vec<ExternalKey> {
+        let has_marker = |text: &str| self.has_external_marker(text);
+
+        let mut external = Vec::new();
+        let mut block_marker: Option<String> = None;
+        let mut after_key_marker = false;
+        for entry in entries {
+            if std::mem::take(&mut after_key_marker) {
+                continue;
+            }
+            match entry {
+                AuthorizedKeysEntry::Opaque(line) if is_key_marker(line) => after_key_marker = true,
+                AuthorizedKeysEntry::Opaque(line) if line.trim().is_empty() => block_marker = None,
+                AuthorizedKeysEntry::Opaque(line) => {
+                    if line.trim_start().starts_with('#') && has_marker(line) {
+                        block_marker = Some(line.clone());
+                    }
                 }
-                Err(_) => {
-                    // Skip invalid lines (comments, empty lines, malformed keys)
-                    debug!("Skipped line {} in {}", line_num + 1, file.path.display());
+                AuthorizedKeysEntry::Key(key) if key.options.is_none() => {
+                    if key.comment.as_deref().is_some_and(has_marker) {
+                        external.push(ExternalKey { key: key.clone(), marker_line: None });
+                    } else if let Some(marker_line) = &block_marker {
+                        external.push(ExternalKey { key: key.clone(), marker_line: Some(marker_line.clone()) });
+                    }
+                }
+                // Option-restricted keys are carried through as preserved lines
+                AuthorizedKeysEntry::Key(_) => {}
+            }
+        }
+        external
+    }
+
+    fn has_external_marker(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.external_markers.iter().any(|marker| !marker.is_empty() && text.contains(&marker.to_lowercase()))
+    }
+
+    /// Keys on the lines below `marker`, each keeping its full line as raw text
+    fn marked_keys(&self, entries: &[AuthorizedKeysEntry], marker: &str) -> Vec<SshKey> {
+        entries
+            .windows(2)
+            .filter(|pair| matches!(&pair[0], AuthorizedKeysEntry::Opaque(line) if line.trim() == marker))
+            .filter_map(|pair| match &pair[1] {
+                AuthorizedKeysEntry::Key(key) => Some(key.clone()),
+                AuthorizedKeysEntry::Opaque(_) => None,
+            })
+            .collect()
+    }
+
+    /// Lines to carry through a rewrite as written: everything that is not a plain key, a blank
+    /// line, the agent's own header, an external-key marker (re-emitted with its keys) or a key the
+    /// agent adopted or wrote with options
+    fn preserved_lines(&self, entries: &[AuthorizedKeysEntry]) -> Vec<String> {
+        let mut preserved = Vec::new();
+        let mut after_key_marker = false;
+        for entry in entries {
+            let line = match entry {
+                AuthorizedKeysEntry::Opaque(line) => line.clone(),
+                AuthorizedKeysEntry::Key(key) if key.options.is_some() => key.to_line(),
+                AuthorizedKeysEntry::Key(_) => {
+                    after_key_marker = false;
+                    continue;
                 }
+            };
+            if std::mem::take(&mut after_key_marker) {
+                continue;
+            }
+            let trimmed = line.trim();
+            let is_external_marker = trimmed.starts_with('#') && self.has_external_marker(trimmed);
+            if is_key_marker(trimmed) {
+                after_key_marker = true;
+            } else if !trimmed.is_empty()
+                && line != self.managed_marker
+                && !MANAGED_HEADER.contains(&trimmed)
+                && trimmed != BLOCK_BEGIN
+                && trimmed != BLOCK_END
+                && !is_external_marker
+            {
+                preserved.push(line);
             }
         }
+        preserved
+    }
+
+    /// Whether an authorized_keys file carries the PubliKey managed marker or a managed block
+    fn is_managed_file(&self, file: &AuthorizedKeysFile) -> bool {
+        file.exists
+            && fs::read_to_string(&file.path)
+                .map(|content| content.lines().any(|line| line == self.managed_marker || line.trim() == BLOCK_BEGIN))
+                .unwrap_or(false)
+    }
+
+    /// Split a file around the managed block. With --exclusive, and for files written whole by older
+    /// agents (managed marker, no block), the agent owns the entire file; a file without either gets
+    /// a new block appended.
+    fn split_managed(&self, file: &AuthorizedKeysFile, entries: Vec<AuthorizedKeysEntry>) -> Result<ManagedSplit> {
+        let is_line = |entry: &AuthorizedKeysEntry, marker: &str| matches!(entry, AuthorizedKeysEntry::Opaque(line) if line.trim() == marker);
+        let begin = entries.iter().position(|entry| is_line(entry, BLOCK_BEGIN));
+        let end = entries.iter().position(|entry| is_line(entry, BLOCK_END));
 
-        info!("Read {} valid SSH keys from {}", keys.len(), file.path.display());
-        Ok(keys)
+        let legacy = !entries.is_empty()
+            && begin.is_none()
+            && entries.iter().any(|entry| matches!(entry, AuthorizedKeysEntry::Opaque(line) if *line == self.managed_marker));
+        if self.exclusive || legacy {
+            return Ok(ManagedSplit { before: Vec::new(), managed: entries, after: Vec::new(), has_block: false });
+        }
+
+        match (begin, end) {
+            (None, None) => Ok(ManagedSplit { before: entries, managed: Vec::new(), after: Vec::new(), has_block: false }),
+            (Some(begin), Some(end)) if begin < end => {
+                let mut before = entries;
+                let after = before.split_off(end + 1);
+                before.pop();
+                let managed = before.split_off(begin + 1);
+                before.pop();
+                Ok(ManagedSplit { before, managed, after, has_block: true })
+            }
+            _ => Err(anyhow!(
+                "{} has an unbalanced PubliKey managed block ({} / {}); fix it by hand",
+                file.path.display(),
+                BLOCK_BEGIN,
+                BLOCK_END
+            )),
+        }
     }
 
     /// Sync SSH keys for all users based on PubliKey assignments
@@ -308,47 +1234,286 @@ impl SshKeyManager {
         dry_run: bool,
         user_mode: bool,
     ) -> Result<KeySyncStats> {
-        let mut stats = KeySyncStats {
-            users_processed: 0,
-            keys_added: 0,
-            keys_removed: 0,
-            files_updated: 0,
-            errors: 0,
-        };
+        let auth_keys_patterns = self.get_authorized_keys_patterns()?;
+        info!("Found {} AuthorizedKeysFile patterns in sshd_config", auth_keys_patterns.len());
+        self.sync_with_patterns(users, assignments, &auth_keys_patterns, dry_run)
+    }
 
-        // Group assignments by username
-        let mut assignments_by_user: HashMap<String, Vec<&KeyAssignment>> = HashMap::new();
-        for assignment in assignments {
-            assignments_by_user
-                .entry(assignment.username.clone())
-                .or_default()
+    fn sync_with_patterns(
+        &self,
+        users: &[UserInfo],
+        assignments: &[KeyAssignment],
+        auth_keys_patterns: &[String],
+        dry_run: bool,
+    ) -> Result<KeySyncStats> {
+        let mut stats = KeySyncStats {
+            users_considered: users.len() as u32,
+            ..Default::default()
+        };
+        // Users left out of the sync, with the first reason that applied
+        let mut skipped: BTreeMap<String, &str> = BTreeMap::new();
+
+        let mut sync_users = users.to_vec();
+        if let Some(filter) = &self.user_filter {
+            filter_users(&mut sync_users, &filter.include, &filter.exclude);
+            stats.user_filter = Some(filter.clone());
+            for user in users.iter().filter(|user| !sync_users.iter().any(|kept| kept.username == user.username)) {
+                skipped.entry(user.username.clone()).or_insert(SKIP_USER_FILTER);
+            }
+        }
+
+        // Under forced-commands-only root's keys are usable only when every one of its assignments forces a command
+        let root_names: Vec<&str> = sync_users.iter().filter(|user| user.uid == 0).map(|user| user.username.as_str()).collect();
+        let root_forced_commands = assignments
+            .iter()
+            .filter(|assignment| {
+                matches!(resolve_assignment_user(users, assignment), AssignmentTarget::User(target) if root_names.contains(&target.as_str()))
+            })
+            .all(|assignment| assignment.options.as_deref().is_some_and(has_forced_command));
+        if let Some(permit_root_login) = self.root_login
+            && !permit_root_login.allows_key_login(root_forced_commands)
+            && !root_names.is_empty()
+        {
+            warn!("Skipping root's keys: sshd has PermitRootLogin {}", permit_root_login);
+            for user in sync_users.iter().filter(|user| user.uid == 0) {
+                skipped.entry(user.username.clone()).or_insert(SKIP_ROOT_LOGIN_DISABLED);
+            }
+            sync_users.retain(|user| user.uid != 0);
+            stats.root_login_disabled = true;
+        }
+
+        // Keys would be dead weight for users sshd never offers pubkey auth to
+        for user in sync_users.iter().filter(|user| self.pubkey_auth_disabled.contains(&user.username)) {
+            skipped.entry(user.username.clone()).or_insert(SKIP_PUBKEY_AUTH_DISABLED);
+        }
+        sync_users.retain(|user| !self.pubkey_auth_disabled.contains(&user.username));
+
+        // Users who opted out keep their authorized_keys exactly as they are
+        for user in sync_users.iter().filter(|user| user.opt_out.is_some()) {
+            info!("Skipping user {}: opted out of key management", user.username);
+            skipped.entry(user.username.clone()).or_insert(SKIP_USER_OPT_OUT);
+        }
+        sync_users.retain(|user| user.opt_out.is_none());
+
+        // Resolve each assignment to a local account before any path work
+        let (resolved, rejected) = resolve_assignments(users, assignments);
+        for (assignment, target) in &rejected {
+            let detail = match target {
+                AssignmentTarget::Unresolved => {
+                    warn!(
+                        "Rejecting key assignment {} for invalid or unknown username {:?}",
+                        assignment.assignment_id, assignment.username
+                    );
+                    Some(format!("no local account {:?} on this host", assignment.username))
+                }
+                AssignmentTarget::Ambiguous(reason) | AssignmentTarget::Conflict(reason) => {
+                    warn!(
+                        "Skipping key assignment {} for {:?}: {}",
+                        assignment.assignment_id, assignment.username, reason
+                    );
+                    Some(reason.clone())
+                }
+                AssignmentTarget::User(_) => None,
+            };
+            stats.assignments_rejected += 1;
+            stats.rejected_assignment_ids.push(assignment.assignment_id.clone());
+            stats.record_status(&assignment.assignment_id, AssignmentState::UserNotFound, detail);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        // Drop filtered, expired, unparseable and policy-violating assignments
+        let accepted: Vec<ResolvedAssignment> = resolved
+            .into_iter()
+            .filter(|(username, assignment)| {
+                let id = &assignment.assignment_id;
+                if self.pubkey_auth_disabled.contains(username) {
+                    info!("Skipping key assignment {} for {}: pubkey auth disabled by sshd", id, username);
+                    stats.record_status(id, AssignmentState::PubkeyAuthDisabled, Some("pubkey auth disabled for user".to_string()));
+                    return false;
+                }
+                if let Some(opt_out) = users.iter().find(|user| &user.username == username).and_then(|user| user.opt_out.as_ref()) {
+                    info!("Skipping key assignment {} for {}: user opted out", id, username);
+                    stats.record_status(id, AssignmentState::UserOptOut, Some(format!("user opt-out until {}", opt_out.expires_at)));
+                    return false;
+                }
+                if !sync_users.iter().any(|user| &user.username == username) {
+                    debug!("Key assignment {} for {} is outside the user filter", id, username);
+                    let detail = if stats.root_login_disabled && users.iter().any(|u| u.uid == 0 && &u.username == username) {
+                        "root login disabled by sshd"
+                    } else {
+                        "user filtered out"
+                    };
+                    stats.record_status(id, AssignmentState::ExcludedByFilter, Some(detail.to_string()));
+                    return false;
+                }
+                if assignment.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    info!("Key assignment {} for {} has expired", id, username);
+                    stats.record_status(id, AssignmentState::RemovedExpired, None);
+                    return false;
+                }
+                let key = match SshKey::parse_with(&assignment.public_key, Some(&self.fingerprints)) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        warn!("Rejecting key assignment {} for {}: {}", id, username, e);
+                        stats.assignments_rejected += 1;
+                        stats.rejected_assignment_ids.push(id.clone());
+                        stats.record_status(id, AssignmentState::FailedParse, Some(e.to_string()));
+                        return false;
+                    }
+                };
+                if key.options.is_some() {
+                    let detail = "public key carries authorized_keys options; the server must send them in the options field";
+                    warn!("Rejecting key assignment {} for {}: {}", id, username, detail);
+                    stats.assignments_rejected += 1;
+                    stats.rejected_assignment_ids.push(id.clone());
+                    stats.record_status(id, AssignmentState::FailedParse, Some(detail.to_string()));
+                    return false;
+                }
+                match self.key_policy.check(&key) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Rejecting key assignment {} for {}: {}", id, username, e);
+                        stats.assignments_rejected += 1;
+                        stats.rejected_assignment_ids.push(id.clone());
+                        stats.record_status(id, AssignmentState::SuppressedByPolicy, Some(e.to_string()));
+                        false
+                    }
+                }
+            })
+            .collect();
+
+        // Group assignments by resolved local username
+        let mut assignments_by_user: HashMap<String, Vec<&KeyAssignment>> = HashMap::with_capacity(accepted.len());
+        for (username, assignment) in &accepted {
+            assignments_by_user
+                .entry(username.clone())
+                .or_default()
                 .push(assignment);
         }
 
         // Discover all authorized_keys files
-        let auth_files = self.discover_authorized_keys_files(users)?;
+        let discovered = self.expand_authorized_keys_files(&sync_users, auth_keys_patterns);
+        let auth_files = &discovered.files;
+        stats.files_excluded = discovered.excluded;
+        stats.errors += discovered.denied.len() as u32;
 
-        for file in &auth_files {
-            stats.users_processed += 1;
-            
-            match self.sync_user_keys(file, assignments_by_user.get(&file.username).unwrap_or(&vec![]), dry_run) {
+        let mut processed: HashSet<&str> = HashSet::new();
+        for file in auth_files {
+            if self.interrupt.is_set() {
+                warn!("Interrupted; leaving the remaining users' keys unchanged");
+                stats.interrupted = true;
+                break;
+            }
+            let user_assignments = assignments_by_user.get(&file.username).map(Vec::as_slice).unwrap_or(&[]);
+
+            // Hold the file's lock from read to write so cooperating tools don't interleave; dry runs only read
+            let _lock = if dry_run {
+                None
+            } else {
+                match keylock::lock(&file.path, self.lock_timeout) {
+                    Ok(lock) => lock,
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                        warn!("Skipping user {}: {}", file.username, e);
+                        stats.lock_timeouts.push(LockTimeout { username: file.username.clone(), path: file.path.clone() });
+                        for assignment in user_assignments {
+                            stats.record_status(&assignment.assignment_id, AssignmentState::LockTimeout, Some(e.to_string()));
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Could not lock {}, syncing without the lock: {}", keylock::lock_path(&file.path).display(), e);
+                        None
+                    }
+                }
+            };
+            stats.files_examined += 1;
+            processed.insert(&file.username);
+            match self.sync_user_keys(file, user_assignments, dry_run) {
                 Ok(user_stats) => {
                     stats.keys_added += user_stats.keys_added;
                     stats.keys_removed += user_stats.keys_removed;
+                    stats.keys_updated += user_stats.keys_updated;
+                    stats.removals_deferred += user_stats.removals_deferred;
+                    stats.errors += user_stats.errors;
+                    stats.changes.extend(user_stats.changes);
+                    stats.unmanaged_keys.extend(user_stats.unmanaged_keys);
+                    stats.external_keys.extend(user_stats.external_keys);
+                    stats.unmarked_files.extend(user_stats.unmarked_files);
+                    stats.previews.extend(user_stats.previews);
+                    stats.preserved_lines += user_stats.preserved_lines;
+                    stats.keys_already_present_unmanaged += user_stats.keys_already_present_unmanaged;
+                    stats.keys_adopted += user_stats.keys_adopted;
+                    stats.permission_warnings.extend(user_stats.permission_warnings);
+                    stats.permissions_fixed += user_stats.permissions_fixed;
+                    stats.permission_fixes.extend(user_stats.permission_fixes);
                     if user_stats.files_updated > 0 {
                         stats.files_updated += 1;
                     }
+                    for (id, status) in user_stats.assignment_statuses {
+                        stats.record_status(&id, status.status, status.detail);
+                    }
                 }
                 Err(e) => {
                     error!("Failed to sync keys for user {}: {}", file.username, e);
                     stats.errors += 1;
+                    for assignment in user_assignments {
+                        stats.record_status(&assignment.assignment_id, AssignmentState::FailedApply, Some(e.to_string()));
+                    }
                 }
             }
         }
 
+        for user in &sync_users {
+            if processed.contains(user.username.as_str()) {
+                continue;
+            }
+            let reason = if discovered.unmounted.contains(&user.username) {
+                SKIP_HOME_NOT_MOUNTED
+            } else if stats.interrupted && auth_files.iter().any(|file| file.username == user.username) {
+                SKIP_INTERRUPTED
+            } else if stats.lock_timeouts.iter().any(|locked| locked.username == user.username) {
+                SKIP_LOCK_TIMEOUT
+            } else {
+                SKIP_NO_FILES_IN_SCOPE
+            };
+            skipped.entry(user.username.clone()).or_insert(reason);
+        }
+        stats.users_processed = processed.len() as u32;
+        stats.users_skipped = skipped.len() as u32;
+        for reason in skipped.values() {
+            *stats.users_skipped_reasons.entry(reason.to_string()).or_default() += 1;
+        }
+
+        // Assignments whose user had no authorized_keys file in scope (path filters, denylist, unmounted home)
+        for (username, assignment) in &accepted {
+            if stats.assignment_statuses.contains_key(&assignment.assignment_id) {
+                continue;
+            }
+            if stats.interrupted && !processed.contains(username.as_str()) && auth_files.iter().any(|file| &file.username == username) {
+                stats.record_status(&assignment.assignment_id, AssignmentState::Interrupted, Some("run interrupted by signal".to_string()));
+            } else {
+                stats.record_status(
+                    &assignment.assignment_id,
+                    AssignmentState::ExcludedByFilter,
+                    Some(format!("no authorized_keys file in scope for {}", username)),
+                );
+            }
+        }
+
+        let counters = self.parse_counters();
+        debug!(
+            "Key parsing: {} keys parsed, {} fingerprint cache hits, {} base64 decodes",
+            counters.keys_parsed, counters.cache_hits, counters.decodes
+        );
+
         info!(
-            "SSH key sync completed: {} users, {} keys added, {} keys removed, {} files updated, {} errors",
-            stats.users_processed, stats.keys_added, stats.keys_removed, stats.files_updated, stats.errors
+            "SSH key sync completed: {} users processed, {} skipped, {} keys added, {} keys removed, {} files updated, {} errors, {} assignments rejected",
+            stats.users_processed, stats.users_skipped, stats.keys_added, stats.keys_removed, stats.files_updated, stats.errors,
+            stats.assignments_rejected
         );
 
         Ok(stats)
@@ -362,44 +1527,257 @@ impl SshKeyManager {
         dry_run: bool,
     ) -> Result<KeySyncStats> {
         let mut stats = KeySyncStats {
-            users_processed: 1,
-            keys_added: 0,
-            keys_removed: 0,
-            files_updated: 0,
-            errors: 0,
+            files_examined: 1,
+            ..Default::default()
         };
 
-        // Read existing keys
-        let existing_keys = self.read_authorized_keys(file)?;
+        // Read existing keys; only those in the managed part can be removed. Keys restricted with
+        // options are left as written unless adopted
+        let ManagedSplit { mut before, managed: entries, mut after, has_block } = self.split_managed(file, self.read_entries(file)?)?;
+        let outside_keys: Vec<SshKey> = keys_of(&before).chain(keys_of(&after)).cloned().collect();
+        let mut adopted = self.marked_keys(&entries, ADOPTED_MARKER);
+        let with_options = self.marked_keys(&entries, OPTIONS_MARKER);
+        let previously_adopted: HashSet<String> = adopted.iter().map(|key| key.fingerprint.clone()).collect();
+        let marked_lines: HashSet<Option<&str>> = adopted.iter().chain(&with_options).map(|key| key.raw.as_deref()).collect();
+        let mut existing_keys: Vec<SshKey> = keys_of(&entries)
+            .filter(|key| key.options.is_none() && !marked_lines.contains(&key.raw.as_deref()))
+            .cloned()
+            .collect();
+        existing_keys.extend(adopted.iter().cloned());
+        existing_keys.extend(with_options);
+        let mut preserved = self.preserved_lines(&entries);
+        
+        for detail in permission_problems(file) {
+            warn!("Permission problem for user {}: {}", file.username, detail);
+            stats.permission_warnings.push(PermissionWarning {
+                username: file.username.clone(),
+                path: file.path.clone(),
+                detail,
+            });
+        }
+
+        // Keys in files we don't manage yet only get a warning on policy violations
+        if !self.is_managed_file(file) {
+            if file.exists {
+                stats.unmarked_files.push(UnmarkedFile { username: file.username.clone(), path: file.path.clone() });
+            }
+            for key in existing_keys.iter().chain(&outside_keys) {
+                if let Err(e) = self.key_policy.check(key) {
+                    warn!("Unmanaged key {} in {} violates key policy: {}", key.fingerprint, file.path.display(), e);
+                }
+            }
+        }
         
-        // Convert assignments to SSH keys
-        let mut target_keys = Vec::new();
+        // Convert assignments to SSH keys, remembering which assignment each key came from
+        // Each key is written once: when several assignments carry it, the first one's options win
+        let mut planned: Vec<(&KeyAssignment, SshKey)> = Vec::new();
+        let mut planned_by_fingerprint: HashMap<String, usize> = HashMap::new();
+        let mut duplicates: Vec<(&KeyAssignment, usize)> = Vec::new();
         for assignment in assignments {
-            match self.assignment_to_ssh_key(assignment) {
-                Ok(key) => target_keys.push(key),
+            match self.assignment_to_ssh_key(assignment).inspect(|key| check_assignment_fingerprint(assignment, key)) {
+                Ok(key) => match planned_by_fingerprint.get(&key.fingerprint) {
+                    Some(&first) => {
+                        if planned[first].1.options != key.options {
+                            warn!(
+                                "Key assignments {} and {} give key {} different options; using those of {}",
+                                planned[first].0.assignment_id, assignment.assignment_id, key.fingerprint, planned[first].0.assignment_id
+                            );
+                        }
+                        duplicates.push((assignment, first));
+                    }
+                    None => {
+                        planned_by_fingerprint.insert(key.fingerprint.clone(), planned.len());
+                        planned.push((assignment, key));
+                    }
+                },
                 Err(e) => {
                     warn!("Invalid key assignment for {}: {}", file.username, e);
                     stats.errors += 1;
+                    stats.record_status(&assignment.assignment_id, AssignmentState::FailedParse, Some(e.to_string()));
                 }
             }
         }
+        let target_keys: Vec<SshKey> = planned.iter().map(|(_, key)| key.clone()).collect();
+        // Plan against fingerprint sets so hosts with thousands of keys per user stay linear
+        let target_fingerprints: HashSet<&str> = target_keys.iter().map(|key| key.fingerprint.as_str()).collect();
+        let is_assigned = |key: &SshKey| target_fingerprints.contains(key.fingerprint.as_str());
+
+        // Assigned keys that exist only outside the managed keys (outside the block, other tools' keys,
+        // option-prefixed lines) are left in place, or moved into the managed keys with --adopt-existing-keys
+        let external = self.external_keys(&entries);
+        let external_lines: HashSet<Option<&str>> = external.iter().map(|e| e.key.raw.as_deref()).collect();
+        let managed_fingerprints: HashSet<&str> = existing_keys
+            .iter()
+            .filter(|key| !external_lines.contains(&key.raw.as_deref()))
+            .map(|key| key.fingerprint.as_str())
+            .collect();
+        let mut unmanaged_copies: Vec<SshKey> = Vec::new();
+        let mut copy_fingerprints: HashSet<String> = HashSet::new();
+        let candidates = external
+            .iter()
+            .map(|e| e.key.clone())
+            .chain(preserved.iter().filter_map(|line| SshKey::parse_with(line, Some(&self.fingerprints)).ok()))
+            .chain(outside_keys.iter().cloned());
+        for key in candidates {
+            if is_assigned(&key)
+                && !managed_fingerprints.contains(key.fingerprint.as_str())
+                && copy_fingerprints.insert(key.fingerprint.clone())
+            {
+                unmanaged_copies.push(key);
+            }
+        }
+        let mut adopted_copies: HashSet<String> = HashSet::new();
+        for copy in &unmanaged_copies {
+            if self.adopt_existing_keys {
+                let action = if dry_run { "Would adopt" } else { "Adopting" };
+                info!("{} existing key {} for user {} into the managed keys", action, copy.fingerprint, file.username);
+                adopted_copies.extend(copy.raw.clone());
+                adopted.push(copy.clone());
+                stats.keys_adopted += 1;
+            } else {
+                info!(
+                    "Key {} for user {} is already present outside the managed keys; not adding a duplicate \
+                     (--adopt-existing-keys moves it into the managed keys)",
+                    copy.fingerprint, file.username
+                );
+                stats.keys_already_present_unmanaged += 1;
+            }
+        }
+        if !adopted_copies.is_empty() {
+            let is_copy_line = |entry: &AuthorizedKeysEntry| match entry {
+                AuthorizedKeysEntry::Key(key) => key.raw.as_ref().is_some_and(|raw| adopted_copies.contains(raw)),
+                AuthorizedKeysEntry::Opaque(line) => adopted_copies.contains(line),
+            };
+            preserved.retain(|line| !adopted_copies.contains(line));
+            before.retain(|entry| !is_copy_line(entry));
+            after.retain(|entry| !is_copy_line(entry));
+        }
+        let is_copy = |key: &SshKey| copy_fingerprints.contains(&key.fingerprint);
+        let existing_by_fingerprint: HashMap<&str, &SshKey> =
+            existing_keys.iter().map(|key| (key.fingerprint.as_str(), key)).collect();
+        // Keys the agent wrote follow their assignment's options exactly; adopted keys keep their own
+        // unless the server sets some
+        let options_changed = |target: &SshKey| match existing_by_fingerprint.get(target.fingerprint.as_str()) {
+            Some(existing) if previously_adopted.contains(&target.fingerprint) => {
+                target.options.is_some() && existing.options != target.options
+            }
+            Some(existing) => existing.options != target.options,
+            None => false,
+        };
+        stats.preserved_lines = preserved.len() as u32;
+
+        for (assignment, key) in &planned {
+            let (state, detail) = if is_copy(key) && self.adopt_existing_keys {
+                (AssignmentState::AlreadyPresent, Some("adopted from outside the managed keys".to_string()))
+            } else if is_copy(key) {
+                (AssignmentState::AlreadyPresent, Some("present outside the managed keys".to_string()))
+            } else if options_changed(key) && dry_run {
+                (AssignmentState::Pending, Some("options changed".to_string()))
+            } else if options_changed(key) {
+                (AssignmentState::AppliedNew, Some("options changed".to_string()))
+            } else if existing_by_fingerprint.contains_key(key.fingerprint.as_str()) {
+                (AssignmentState::AlreadyPresent, None)
+            } else if dry_run {
+                (AssignmentState::Pending, None)
+            } else {
+                (AssignmentState::AppliedNew, None)
+            };
+            stats.record_status(&assignment.assignment_id, state, detail);
+        }
+        for (assignment, first) in duplicates {
+            if let Some(status) = stats.assignment_statuses.get(&planned[first].0.assignment_id).cloned() {
+                stats.record_status(&assignment.assignment_id, status.status, status.detail);
+            }
+        }
+
+        // Keys other tools wrote stay, unless told otherwise
+        let unassigned_external: Vec<String> =
+            external.iter().filter(|e| !is_assigned(&e.key)).map(|e| e.key.fingerprint.clone()).collect();
+        if !unassigned_external.is_empty() {
+            stats.external_keys.push(UnmanagedKeys {
+                username: file.username.clone(),
+                path: file.path.clone(),
+                fingerprints: unassigned_external,
+            });
+        }
+        let kept_external: Vec<ExternalKey> = external
+            .iter()
+            .filter(|e| if is_copy(&e.key) { !self.adopt_existing_keys } else { !is_assigned(&e.key) && !self.remove_external_keys })
+            .cloned()
+            .collect();
+        let kept_external_fingerprints: HashSet<&str> = kept_external.iter().map(|e| e.key.fingerprint.as_str()).collect();
+        let is_kept_external = |key: &SshKey| kept_external_fingerprints.contains(key.fingerprint.as_str());
+
+        // Unassigned keys outside the block are reported but never removed
+        let unassigned: Vec<String> = existing_keys
+            .iter()
+            .filter(|existing| !is_assigned(existing) && !is_kept_external(existing))
+            .chain(outside_keys.iter().filter(|outside| !is_assigned(outside)))
+            .map(|existing| existing.fingerprint.clone())
+            .collect();
+        if !unassigned.is_empty() {
+            stats.unmanaged_keys.push(UnmanagedKeys {
+                username: file.username.clone(),
+                path: file.path.clone(),
+                fingerprints: unassigned,
+            });
+        }
 
         // Determine what changed
         let keys_to_add: Vec<_> = target_keys.iter()
-            .filter(|target_key| !existing_keys.iter().any(|existing| existing.fingerprint == target_key.fingerprint))
+            .filter(|target_key| !existing_by_fingerprint.contains_key(target_key.fingerprint.as_str()))
+            .filter(|target_key| !is_copy(target_key))
             .collect();
+        let keys_to_update: Vec<_> = target_keys.iter().filter(|target_key| options_changed(target_key)).collect();
 
-        let keys_to_remove: Vec<_> = existing_keys.iter()
-            .filter(|existing_key| !target_keys.iter().any(|target| target.fingerprint == existing_key.fingerprint))
+        let mut keys_to_remove: Vec<_> = existing_keys.iter()
+            .filter(|existing_key| !is_assigned(existing_key) && !is_kept_external(existing_key))
             .collect();
 
+        // Adopted keys are written as found unless the server sets other options, including those
+        // adopted this run; copies left outside the managed keys are not written twice
+        let adopted_by_fingerprint: HashMap<&str, &SshKey> =
+            adopted.iter().map(|key| (key.fingerprint.as_str(), key)).collect();
+        let mut write_keys: Vec<SshKey> = target_keys
+            .iter()
+            .filter_map(|target| match adopted_by_fingerprint.get(target.fingerprint.as_str()) {
+                Some(adopted_key) if target.options.is_none() || adopted_key.options == target.options => {
+                    Some((*adopted_key).clone())
+                }
+                None if is_copy(target) => None,
+                _ => Some(target.clone()),
+            })
+            .collect();
+
+        // Outside the rollout canary, keys slated for removal stay until a later run
+        if self.defer_removals && !keys_to_remove.is_empty() {
+            info!(
+                "Deferring removal of {} keys for user {}: host is outside the rollout canary",
+                keys_to_remove.len(),
+                file.username
+            );
+            stats.removals_deferred = keys_to_remove.len() as u32;
+            write_keys.extend(keys_to_remove.drain(..).cloned());
+        }
+
         // Update statistics
         stats.keys_added = keys_to_add.len() as u32;
         stats.keys_removed = keys_to_remove.len() as u32;
+        stats.keys_updated = keys_to_update.len() as u32;
+        if !keys_to_add.is_empty() || !keys_to_remove.is_empty() || !keys_to_update.is_empty() {
+            stats.changes.push(FileKeyChanges {
+                username: file.username.clone(),
+                path: file.path.clone(),
+                added: keys_to_add.iter().map(|k| k.fingerprint.clone()).collect(),
+                removed: keys_to_remove.iter().map(|k| k.fingerprint.clone()).collect(),
+                updated: keys_to_update.iter().map(|k| k.fingerprint.clone()).collect(),
+            });
+        }
 
         // If no changes needed, skip file update
-        if keys_to_add.is_empty() && keys_to_remove.is_empty() {
+        if keys_to_add.is_empty() && keys_to_remove.is_empty() && keys_to_update.is_empty() && stats.keys_adopted == 0 {
             info!("No changes needed for user {}", file.username);
+            self.reassert_permissions(file, dry_run, &mut stats);
             return Ok(stats);
         }
 
@@ -420,9 +1798,20 @@ impl SshKeyManager {
             }
         }
 
+        if !keys_to_update.is_empty() {
+            let action = if dry_run { "Would update" } else { "Updating" };
+            info!("{} options of {} keys for user {}", action, keys_to_update.len(), file.username);
+            for key in &keys_to_update {
+                info!("  ~ {}", key.fingerprint);
+            }
+        }
+
         // Write updated authorized_keys file (unless dry run)
         if !dry_run {
-            self.write_authorized_keys_file(file, &target_keys)?;
+            let split = ManagedSplit { before, managed: Vec::new(), after, has_block };
+            let section = ManagedSection { preserved, external: kept_external, adopted, keys: write_keys };
+            self.write_authorized_keys_file(file, &self.render_key_file(&split, &section))?;
+            info!("Updated authorized_keys file: {} ({} managed keys)", file.path.display(), section.keys.len());
             stats.files_updated = 1;
         } else {
             info!("DRY RUN: Would update {}", file.path.display());
@@ -432,6 +1821,15 @@ impl SshKeyManager {
             } else if file.uid != nix::unistd::getuid().as_raw() {
                 info!("DRY RUN: Would warn about ownership (not running as root)");
             }
+            if self.preview_content {
+                let split = ManagedSplit { before, managed: Vec::new(), after, has_block };
+                let section = ManagedSection { preserved, external: kept_external, adopted, keys: write_keys };
+                stats.previews.push(FilePreview {
+                    path: file.path.clone(),
+                    old: file.exists.then(|| fs::read_to_string(&file.path).unwrap_or_default()),
+                    new: self.render_key_file(&split, &section),
+                });
+            }
             // In dry run, we count it as "would be updated"
             stats.files_updated = 1;
         }
@@ -439,17 +1837,181 @@ impl SshKeyManager {
         Ok(stats)
     }
 
+    /// Lines sshd's AuthorizedKeysCommand should print for `username`: the user's assignments a sync
+    /// would accept, once per key, in authorized_keys format. Reads and writes no files.
+    pub fn authorized_keys_for(&self, users: &[UserInfo], username: &str, assignments: &[KeyAssignment], now: u64) -> Vec<String> {
+        let mut candidates: Vec<UserInfo> = users.iter().filter(|user| user.username == username).cloned().collect();
+        if let Some(filter) = &self.user_filter {
+            filter_users(&mut candidates, &filter.include, &filter.exclude);
+        }
+        if candidates.is_empty() {
+            debug!("User {} is unknown or filtered out", username);
+            return Vec::new();
+        }
+
+        let mut seen = HashSet::new();
+        let mut lines = Vec::new();
+        for assignment in assignments {
+            if !matches!(resolve_assignment_user(users, assignment), AssignmentTarget::User(target) if target == username) {
+                continue;
+            }
+            if assignment.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                debug!("Key assignment {} for {} has expired", assignment.assignment_id, username);
+                continue;
+            }
+            let key = match SshKey::parse_with(&assignment.public_key, Some(&self.fingerprints))
+                .and_then(|key| match key.options {
+                    Some(_) => Err(anyhow!("public key carries authorized_keys options")),
+                    None => Ok(key),
+                })
+                .and_then(|key| self.key_policy.check(&key))
+                .and_then(|()| self.assignment_to_ssh_key(assignment))
+            {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!("Skipping key assignment {} for {}: {}", assignment.assignment_id, username, e);
+                    continue;
+                }
+            };
+            if seen.insert(key.fingerprint.clone()) {
+                lines.push(key.to_string());
+            }
+        }
+        lines
+    }
+
     /// Convert PubliKey assignment to SSH key
     fn assignment_to_ssh_key(&self, assignment: &KeyAssignment) -> Result<SshKey> {
-        SshKey::parse(&assignment.public_key)
+        // Keys the agent manages are always written in canonical form
+        let mut key = SshKey::parse_with(&assignment.public_key, Some(&self.fingerprints))?;
+        key.raw = None;
+        if let Some(options) = assignment.options.as_deref().map(str::trim).filter(|options| !options.is_empty()) {
+            validate_options(options)?;
+            key.options = Some(options.to_string());
+        }
+        Ok(key)
+    }
+
+    /// File content after a rewrite: the untouched lines around the block with the block rebuilt
+    /// (appended when the file has none yet), or the whole file under the managed header with --exclusive
+    fn render_key_file(&self, split: &ManagedSplit, section: &ManagedSection) -> String {
+        let mut managed = Vec::new();
+        let adopted: HashSet<&str> = section.adopted.iter().map(|key| key.fingerprint.as_str()).collect();
+        // Option-restricted keys and lines the agent cannot parse may still be keys sshd accepts; never drop them
+        if !section.preserved.is_empty() {
+            managed.extend(section.preserved.iter().cloned().map(AuthorizedKeysEntry::Opaque));
+            managed.push(AuthorizedKeysEntry::Opaque(String::new()));
+        }
+        // Other tools' keys go first, as written, each under its marker line so they are recognised again
+        for external_key in &section.external {
+            if let Some(marker_line) = &external_key.marker_line {
+                managed.push(AuthorizedKeysEntry::Opaque(marker_line.clone()));
+            }
+            managed.push(AuthorizedKeysEntry::Key(external_key.key.clone()));
+            managed.push(AuthorizedKeysEntry::Opaque(String::new()));
+        }
+        for key in &section.keys {
+            if adopted.contains(key.fingerprint.as_str()) {
+                managed.push(AuthorizedKeysEntry::Opaque(ADOPTED_MARKER.to_string()));
+            } else if key.options.is_some() {
+                managed.push(AuthorizedKeysEntry::Opaque(OPTIONS_MARKER.to_string()));
+            }
+            managed.push(AuthorizedKeysEntry::Key(key.clone()));
+        }
+
+        let mut entries = Vec::new();
+        if self.exclusive {
+            entries.push(AuthorizedKeysEntry::Opaque(self.managed_marker.clone()));
+            entries.extend(MANAGED_HEADER.iter().map(|line| AuthorizedKeysEntry::Opaque(line.to_string())));
+            entries.push(AuthorizedKeysEntry::Opaque(String::new()));
+            entries.extend(managed);
+        } else {
+            let is_blank = |entry: &AuthorizedKeysEntry| matches!(entry, AuthorizedKeysEntry::Opaque(line) if line.trim().is_empty());
+            while managed.last().is_some_and(is_blank) {
+                managed.pop();
+            }
+            entries.extend(split.before.iter().cloned());
+            // Keep an appended block visually apart from the user's own lines
+            if !split.has_block && split.before.last().is_some_and(|entry| !is_blank(entry)) {
+                entries.push(AuthorizedKeysEntry::Opaque(String::new()));
+            }
+            entries.push(AuthorizedKeysEntry::Opaque(BLOCK_BEGIN.to_string()));
+            entries.extend(managed);
+            entries.push(AuthorizedKeysEntry::Opaque(BLOCK_END.to_string()));
+            entries.extend(split.after.iter().cloned());
+        }
+        render_authorized_keys(&entries, true)
+    }
+
+    /// Restore mode 600 on a managed file, 700 on its .ssh directory and the user's ownership of both
+    /// (as root) without rewriting the file, so a chmod between syncs does not outlive an unchanged
+    /// key set. Symlinks and directories other than .ssh are left alone.
+    fn reassert_permissions(&self, file: &AuthorizedKeysFile, dry_run: bool, stats: &mut KeySyncStats) {
+        use std::os::unix::fs::MetadataExt;
+
+        if !self.is_managed_file(file) {
+            return;
+        }
+        let is_root = nix::unistd::getuid().is_root();
+        let uid = nix::unistd::Uid::from_raw(file.uid);
+        let gid = self.get_user_primary_gid(file.uid).unwrap_or(nix::unistd::Gid::from_raw(file.uid));
+        let ssh_dir = file.path.parent().filter(|dir| dir.file_name().is_some_and(|name| name == ".ssh"));
+
+        for (path, wanted_mode) in [(Some(file.path.as_path()), 0o600), (ssh_dir, 0o700)] {
+            let Some(path) = path else {
+                continue;
+            };
+            let Ok(metadata) = fs::symlink_metadata(path) else {
+                continue;
+            };
+            if metadata.file_type().is_symlink() {
+                continue;
+            }
+            let mode = metadata.mode() & 0o777;
+            let wrong_mode = mode != wanted_mode;
+            let wrong_owner = is_root && (metadata.uid() != uid.as_raw() || metadata.gid() != gid.as_raw());
+            if !wrong_mode && !wrong_owner {
+                continue;
+            }
+
+            let mut fixes = Vec::new();
+            if wrong_mode {
+                fixes.push(format!("mode {:o} -> {:o}", mode, wanted_mode));
+            }
+            if wrong_owner {
+                fixes.push(format!("owner {}:{} -> {}:{}", metadata.uid(), metadata.gid(), uid, gid));
+            }
+            let detail = format!("{}: {}", path.display(), fixes.join(", "));
+
+            if dry_run {
+                info!("Would fix permissions of {}", detail);
+            } else {
+                let fixed = (|| -> Result<()> {
+                    if wrong_owner {
+                        nix::unistd::chown(path, Some(uid), Some(gid))?;
+                    }
+                    if wrong_mode {
+                        fs::set_permissions(path, Permissions::from_mode(wanted_mode))?;
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = fixed {
+                    warn!("Failed to fix permissions of {}: {}", path.display(), e);
+                    continue;
+                }
+                info!("Fixed permissions of {}", detail);
+            }
+            stats.permissions_fixed += 1;
+            stats.permission_fixes.push(PermissionWarning {
+                username: file.username.clone(),
+                path: path.to_path_buf(),
+                detail,
+            });
+        }
     }
 
     /// Write authorized_keys file with proper permissions
-    fn write_authorized_keys_file(
-        &self,
-        file: &AuthorizedKeysFile,
-        keys: &[SshKey],
-    ) -> Result<()> {
+    fn write_authorized_keys_file(&self, file: &AuthorizedKeysFile, content: &str) -> Result<()> {
         let ssh_dir = file.path.parent().ok_or_else(|| anyhow!("Invalid authorized_keys path"))?;
         
         // Ensure .ssh directory exists with proper permissions
@@ -463,35 +2025,9 @@ impl SshKeyManager {
         fs::set_permissions(ssh_dir, Permissions::from_mode(0o700))
             .context("Failed to set .ssh directory permissions")?;
 
-        // Create file content
-        let mut content = String::new();
-        content.push_str(&format!("{}\n", self.managed_marker));
-        content.push_str("# This file is managed by PubliKey Agent\n");
-        content.push_str("# Manual changes will be overwritten\n\n");
-
-        for key in keys {
-            content.push_str(&key.to_string());
-            content.push('\n');
-        }
-
-        // Write atomically using temporary file
-        let temp_path = file.path.with_extension("tmp");
-        
-        {
-            let mut temp_file = fs::File::create(&temp_path)
-                .context("Failed to create temporary authorized_keys file")?;
-            
-            temp_file.write_all(content.as_bytes())
-                .context("Failed to write to temporary authorized_keys file")?;
-            
-            // Set file permissions before moving (600)
-            temp_file.set_permissions(Permissions::from_mode(0o600))
-                .context("Failed to set temporary file permissions")?;
-        }
-
-        // Atomic move
-        fs::rename(&temp_path, &file.path)
-            .context("Failed to move temporary file to authorized_keys")?;
+        // Write atomically: fsynced temp file renamed over the original
+        fsutil::atomic_write_with(self.file_ops.as_ref(), &file.path, content.as_bytes(), 0o600)
+            .context("Failed to write authorized_keys")?;
 
         // Set proper ownership if running as root
         if nix::unistd::getuid().is_root() {
@@ -518,7 +2054,169 @@ impl SshKeyManager {
             warn!("File will be owned by current user ({})", nix::unistd::getuid());
         }
 
-        info!("Updated authorized_keys file: {} ({} keys)", file.path.display(), keys.len());
+        Ok(())
+    }
+
+    /// Find managed authorized_keys files left behind by users that no longer exist
+    pub fn find_orphaned_files(&self, known_users: &[UserInfo]) -> Result<Vec<OrphanedFile>> {
+        let patterns = self.get_authorized_keys_patterns()?;
+        Ok(self.find_orphaned_files_in(&patterns, &self.home_roots, known_users))
+    }
+
+    /// Scan absolute pattern locations and home roots for orphaned managed files
+    fn find_orphaned_files_in(
+        &self,
+        patterns: &[String],
+        home_roots: &[PathBuf],
+        known_users: &[UserInfo],
+    ) -> Vec<OrphanedFile> {
+        let is_known = |name: &str| known_users.iter().any(|user| user.username == name);
+        let mut candidates: Vec<(String, PathBuf)> = Vec::new();
+
+        for pattern in patterns {
+            if pattern.starts_with('/') {
+                // Absolute patterns: enumerate the directory holding the %u component
+                let Some(user_index) = pattern.split('/').position(|c| c.contains("%u")) else {
+                    continue;
+                };
+                let parent: PathBuf = pattern.split('/').take(user_index).collect::<Vec<_>>().join("/").into();
+                for name in list_dir_names(&parent) {
+                    if let Some(path) = self.expand_authorized_keys_pattern(pattern, &name, Path::new("/nonexistent")) {
+                        candidates.push((name, path));
+                    }
+                }
+            } else {
+                // Home-relative and %h patterns: look into every home directory
+                for root in home_roots {
+                    for name in list_dir_names(root) {
+                        if let Some(path) = self.expand_authorized_keys_pattern(pattern, &name, &root.join(&name)) {
+                            candidates.push((name, path));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut orphans = Vec::new();
+        for (username, path) in candidates {
+            if is_known(&username) || !path.is_file() || !self.is_key_file_target(&path) {
+                continue;
+            }
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            // Files with a managed block, or written whole by older agents (managed marker, no block)
+            let lines: Vec<&str> = content.lines().collect();
+            let block = managed_block(&lines);
+            let legacy = !lines.iter().any(|line| line.trim() == BLOCK_BEGIN) && lines.contains(&self.managed_marker.as_str());
+            if block.is_none() && !legacy {
+                continue;
+            }
+
+            let last_modified = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            let managed = match block {
+                Some(block) => &lines[block],
+                None => &lines[..],
+            };
+            let key_count = managed.iter().filter(|line| SshKey::parse(line).is_ok()).count();
+
+            warn!("Found orphaned managed file {} (user {} no longer exists, {} keys)", path.display(), username, key_count);
+            orphans.push(OrphanedFile {
+                path,
+                username,
+                last_modified,
+                key_count,
+                deleted: false,
+                block_removed: false,
+            });
+        }
+
+        orphans
+    }
+
+    /// Delete orphaned files that have not been modified within the grace period
+    pub fn delete_orphaned_files(&self, orphans: &mut [OrphanedFile], grace: std::time::Duration, dry_run: bool) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        for orphan in orphans.iter_mut() {
+            let age = orphan.last_modified.map(|modified| now.saturating_sub(modified)).unwrap_or(0);
+            if age < grace.as_secs() {
+                info!("Keeping orphaned file {} until grace period expires", orphan.path.display());
+                continue;
+            }
+
+            let content = match fs::read_to_string(&orphan.path) {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!("Failed to read orphaned file {}: {}", orphan.path.display(), e);
+                    continue;
+                }
+            };
+            // Lines outside the managed block are someone else's; only the block goes
+            let lines: Vec<&str> = content.lines().collect();
+            let remainder = managed_block(&lines).and_then(|block| {
+                // With the blank line a sync puts above the block
+                let start = match block.start().checked_sub(1) {
+                    Some(above) if lines[above].trim().is_empty() => above,
+                    _ => *block.start(),
+                };
+                let block = start..=*block.end();
+                let kept: String = content
+                    .split_inclusive('\n')
+                    .enumerate()
+                    .filter(|(index, _)| !block.contains(index))
+                    .map(|(_, line)| line)
+                    .collect();
+                (!kept.trim().is_empty()).then_some(kept)
+            });
+
+            let Some(remainder) = remainder else {
+                if dry_run {
+                    info!("DRY RUN: Would delete orphaned file {}", orphan.path.display());
+                    continue;
+                }
+                match fs::remove_file(&orphan.path) {
+                    Ok(()) => {
+                        info!("Deleted orphaned file {}", orphan.path.display());
+                        orphan.deleted = true;
+                    }
+                    Err(e) => warn!("Failed to delete orphaned file {}: {}", orphan.path.display(), e),
+                }
+                continue;
+            };
+            if dry_run {
+                info!("DRY RUN: Would remove the managed block from orphaned file {}", orphan.path.display());
+                continue;
+            }
+            match self.strip_orphaned_block(&orphan.path, &remainder) {
+                Ok(()) => {
+                    info!("Removed the managed block from orphaned file {}", orphan.path.display());
+                    orphan.block_removed = true;
+                }
+                Err(e) => warn!("Failed to remove the managed block from orphaned file {}: {:#}", orphan.path.display(), e),
+            }
+        }
+    }
+
+    /// Rewrite an orphaned file without its managed block, keeping its mode and owner
+    fn strip_orphaned_block(&self, path: &Path, remainder: &str) -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path)?;
+        fsutil::atomic_write(path, remainder.as_bytes(), metadata.mode() & 0o777)?;
+        if nix::unistd::getuid().is_root() {
+            nix::unistd::chown(
+                path,
+                Some(nix::unistd::Uid::from_raw(metadata.uid())),
+                Some(nix::unistd::Gid::from_raw(metadata.gid())),
+            )?;
+        }
         Ok(())
     }
 
@@ -535,14 +2233,11 @@ impl SshKeyManager {
                     }
                     
                     let parts: Vec<&str> = line.split(':').collect();
-                    if parts.len() >= 4 {
-                        if let Ok(line_uid) = parts[2].parse::<u32>() {
-                            if line_uid == uid {
-                                if let Ok(gid) = parts[3].parse::<u32>() {
-                                    return Some(nix::unistd::Gid::from_raw(gid));
-                                }
-                            }
-                        }
+                    if parts.len() >= 4
+                        && parts[2].parse::<u32>() == Ok(uid)
+                        && let Ok(gid) = parts[3].parse::<u32>()
+                    {
+                        return Some(nix::unistd::Gid::from_raw(gid));
                     }
                 }
             }
@@ -552,54 +2247,337 @@ impl SshKeyManager {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_parse_valid_ssh_key() {
-        let key_line = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj test@example.com";
-        let result = SshKey::parse(key_line);
-        assert!(result.is_ok());
-        
-        let key = result.unwrap();
-        assert_eq!(key.key_type, "ssh-rsa");
-        assert_eq!(key.key_data, "AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj");
-        assert_eq!(key.comment, Some("test@example.com".to_string()));
+/// Whether an authorized_keys path resolves to a denylisted directory.
+///
+/// The file's directory is checked, or the directory holding `.ssh` for the usual layout,
+/// so a home of `/` is refused just like a file placed directly in `/etc`.
+fn is_denied_key_path(path: &Path, denied_dirs: &[PathBuf]) -> bool {
+    let Some(mut base) = path.parent() else {
+        return true;
+    };
+    if base.file_name().is_some_and(|name| name == ".ssh") {
+        base = base.parent().unwrap_or(Path::new("/"));
     }
+    let base = base.components().collect::<PathBuf>();
+    denied_dirs.iter().any(|dir| dir.components().collect::<PathBuf>() == base)
+}
 
-    #[test]
-    fn test_parse_key_without_comment() {
-        let key_line = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e";
-        let result = SshKey::parse(key_line);
-        assert!(result.is_ok());
-        
-        let key = result.unwrap();
-        assert_eq!(key.key_type, "ssh-ed25519");
-        assert_eq!(key.comment, None);
-    }
+/// Lines of a balanced managed block, markers included
+fn managed_block(lines: &[&str]) -> Option<std::ops::RangeInclusive<usize>> {
+    let begin = lines.iter().position(|line| line.trim() == BLOCK_BEGIN)?;
+    let end = lines.iter().position(|line| line.trim() == BLOCK_END)?;
+    (begin < end).then_some(begin..=end)
+}
 
-    #[test]
-    fn test_parse_invalid_key() {
-        let invalid_key = "not-a-valid-ssh-key";
-        let result = SshKey::parse(invalid_key);
-        assert!(result.is_err());
-    }
+/// Home for a user without one in passwd: the first root already holding their directory, else the first root
+fn fallback_home(username: &str, home_roots: &[PathBuf]) -> PathBuf {
+    home_roots
+        .iter()
+        .map(|root| root.join(username))
+        .find(|home| home.is_dir())
+        .or_else(|| home_roots.first().map(|root| root.join(username)))
+        .unwrap_or_else(|| PathBuf::from("/home").join(username))
+}
 
-    #[test]
-    fn test_ssh_key_to_string() {
-        let key = SshKey {
-            key_type: "ssh-rsa".to_string(),
-            key_data: "AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj".to_string(),
-            comment: Some("test@example.com".to_string()),
-            fingerprint: "SHA256:test".to_string(),
-        };
-        
-        assert_eq!(key.to_string(), "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj test@example.com");
-    }
+/// Whether `home` lies outside every home root (`..` components count as outside)
+fn is_outside_home_roots(home: &Path, home_roots: &[PathBuf]) -> bool {
+    home.components().any(|c| c == std::path::Component::ParentDir)
+        || !home_roots.iter().any(|root| home.starts_with(root) && home != root.as_path())
+}
 
-    #[test]
-    fn test_expand_authorized_keys_pattern() {
+/// Ownership and mode problems sshd's StrictModes would reject, for an existing file and its directory
+fn permission_problems(file: &AuthorizedKeysFile) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    if !file.exists {
+        return Vec::new();
+    }
+    let mut problems = Vec::new();
+    let paths = [Some(file.path.as_path()), file.path.parent()];
+    for path in paths.into_iter().flatten() {
+        let Ok(metadata) = fs::metadata(path) else {
+            continue;
+        };
+        if metadata.uid() != file.uid && metadata.uid() != 0 {
+            problems.push(format!("{} is owned by uid {}, expected {}", path.display(), metadata.uid(), file.uid));
+        }
+        let mode = metadata.mode() & 0o777;
+        if mode & 0o022 != 0 {
+            problems.push(format!("{} is group or world writable (mode {:o})", path.display(), mode));
+        }
+    }
+    problems
+}
+
+/// Split authorized_keys content into parsed keys and opaque lines, reusing fingerprints from `cache` when given
+pub fn parse_authorized_keys(content: &str, cache: Option<&FingerprintCache>) -> Vec<AuthorizedKeysEntry> {
+    content
+        .split_inclusive('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            match SshKey::parse_with(line, cache) {
+                Ok(key) => AuthorizedKeysEntry::Key(key),
+                Err(_) => AuthorizedKeysEntry::Opaque(line.to_string()),
+            }
+        })
+        .collect()
+}
+
+/// Byte offset where an authorized_keys options field ends: the first whitespace outside double quotes
+fn options_end(line: &str) -> Option<usize> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    line.find(|c: char| {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => return true,
+            _ => {}
+        }
+        false
+    })
+}
+
+/// Check an options field before writing it: quotes must balance and no whitespace may sit outside them
+fn validate_options(options: &str) -> Result<()> {
+    if options.contains(['\n', '\r']) {
+        return Err(anyhow!("Invalid key options {:?}: line breaks are not allowed", options));
+    }
+    if options_end(options).is_some() {
+        return Err(anyhow!("Invalid key options {:?}: whitespace outside quotes", options));
+    }
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in options.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            _ => {}
+        }
+    }
+    if in_quotes {
+        return Err(anyhow!("Invalid key options {:?}: unbalanced quotes", options));
+    }
+    Ok(())
+}
+
+/// Whether an options field holds a `command=` option, which sshd's forced-commands-only accepts
+fn has_forced_command(options: &str) -> bool {
+    let mut option_start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (index, c) in options.char_indices().chain([(options.len(), ',')]) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                let option = options[option_start..index].trim();
+                if option.get(..8).is_some_and(|name| name.eq_ignore_ascii_case("command=")) {
+                    return true;
+                }
+                option_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether `line` is a marker the agent writes above a key it manages with the key's own options
+fn is_key_marker(line: &str) -> bool {
+    matches!(line.trim(), ADOPTED_MARKER | OPTIONS_MARKER)
+}
+
+fn keys_of(entries: &[AuthorizedKeysEntry]) -> impl Iterator<Item = &SshKey> {
+    entries.iter().filter_map(|entry| match entry {
+        AuthorizedKeysEntry::Key(key) => Some(key),
+        AuthorizedKeysEntry::Opaque(_) => None,
+    })
+}
+
+/// Render entries back to file content, preserving original text where known
+pub fn render_authorized_keys(entries: &[AuthorizedKeysEntry], trailing_newline: bool) -> String {
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|entry| match entry {
+            AuthorizedKeysEntry::Key(key) => key.to_line(),
+            AuthorizedKeysEntry::Opaque(line) => line.clone(),
+        })
+        .collect();
+    let mut content = lines.join("\n");
+    if trailing_newline && !content.is_empty() {
+        content.push('\n');
+    }
+    content
+}
+
+/// List the names of entries in a directory, ignoring errors
+fn list_dir_names(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Backup, temp and editor artifacts: configured suffixes plus emacs `#name#` autosaves and `.#name` locks
+fn is_file_artifact(path: &Path, suffixes: &[String]) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+        || suffixes.iter().any(|suffix| !suffix.is_empty() && name.ends_with(suffix.as_str()))
+}
+
+/// Check that a username is safe to use in path expansion
+pub fn is_safe_username(username: &str) -> bool {
+    !username.is_empty()
+        && username != "."
+        && username != ".."
+        && !username.chars().any(|c| c == '/' || c == '\\' || c == '\0' || c.is_whitespace())
+}
+
+/// Local account a key assignment resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssignmentTarget {
+    /// Deploy to this local username
+    User(String),
+    /// No local account matches the assignment's uid, username or email
+    Unresolved,
+    /// One identifier matches several local accounts
+    Ambiguous(String),
+    /// Identifiers match different local accounts
+    Conflict(String),
+}
+
+/// Resolve the local account for an assignment by uid, then exact username, then email.
+///
+/// Identifiers that match nobody are ignored; those that match must all agree on the same account.
+pub fn resolve_assignment_user(users: &[UserInfo], assignment: &KeyAssignment) -> AssignmentTarget {
+    let by_uid = assignment
+        .uid
+        .map(|uid| users.iter().filter(|user| user.uid == uid).collect::<Vec<_>>());
+    let by_username = Some(
+        users
+            .iter()
+            .filter(|user| user.username == assignment.username)
+            .collect::<Vec<_>>(),
+    );
+    let by_email = assignment.user_email.as_deref().map(|email| {
+        users
+            .iter()
+            .filter(|user| user.email.as_deref().is_some_and(|e| e.eq_ignore_ascii_case(email)))
+            .collect::<Vec<_>>()
+    });
+
+    let mut resolved: Option<(&str, &str)> = None;
+    for (via, matches) in [("uid", by_uid), ("username", by_username), ("email", by_email)] {
+        let Some(matches) = matches else {
+            continue;
+        };
+        match matches.as_slice() {
+            [] => {}
+            [user] => match resolved {
+                None => resolved = Some((&user.username, via)),
+                Some((username, first)) if username != user.username => {
+                    return AssignmentTarget::Conflict(format!(
+                        "{} matches {} but {} matches {}",
+                        first, username, via, user.username
+                    ));
+                }
+                Some(_) => {}
+            },
+            _ => {
+                return AssignmentTarget::Ambiguous(format!("{} matches {} local users", via, matches.len()));
+            }
+        }
+    }
+
+    match resolved {
+        Some((username, _)) if is_safe_username(username) => AssignmentTarget::User(username.to_string()),
+        _ => AssignmentTarget::Unresolved,
+    }
+}
+
+/// Assignment paired with the local username it resolved to
+type ResolvedAssignment<'a> = (String, &'a KeyAssignment);
+
+/// Split assignments into those resolved to a safe local username and those to reject
+fn resolve_assignments<'a>(
+    users: &[UserInfo],
+    assignments: &'a [KeyAssignment],
+) -> (Vec<ResolvedAssignment<'a>>, Vec<(&'a KeyAssignment, AssignmentTarget)>) {
+    let mut resolved = Vec::new();
+    let mut rejected = Vec::new();
+    for assignment in assignments {
+        match resolve_assignment_user(users, assignment) {
+            AssignmentTarget::User(username) => resolved.push((username, assignment)),
+            target => rejected.push((assignment, target)),
+        }
+    }
+    (resolved, rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::users::FilterSource;
+
+    #[test]
+    fn test_parse_valid_ssh_key() {
+        let key_line = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj test@example.com";
+        let result = SshKey::parse(key_line);
+        assert!(result.is_ok());
+        
+        let key = result.unwrap();
+        assert_eq!(key.key_type, "ssh-rsa");
+        assert_eq!(key.key_data, "AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj");
+        assert_eq!(key.comment, Some("test@example.com".to_string()));
+    }
+
+    #[test]
+    fn test_parse_key_without_comment() {
+        let key_line = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e";
+        let result = SshKey::parse(key_line);
+        assert!(result.is_ok());
+        
+        let key = result.unwrap();
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.comment, None);
+    }
+
+    #[test]
+    fn test_parse_invalid_key() {
+        let invalid_key = "not-a-valid-ssh-key";
+        let result = SshKey::parse(invalid_key);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_ssh_key_to_string() {
+        let key = SshKey {
+            options: None,
+            key_type: "ssh-rsa".to_string(),
+            key_data: "AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj".to_string(),
+            comment: Some("test@example.com".to_string()),
+            fingerprint: "SHA256:test".to_string(),
+            md5_fingerprint: String::new(),
+            raw: None,
+        };
+        
+        assert_eq!(key.to_string(), "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj test@example.com");
+    }
+
+    #[test]
+    fn test_expand_authorized_keys_pattern() {
         let manager = SshKeyManager::new();
         let username = "testuser";
         let home_dir = PathBuf::from("/home/testuser");
@@ -624,4 +2602,1469 @@ mod tests {
         let result = manager.expand_authorized_keys_pattern("/path/with%%percent/%u", username, &home_dir);
         assert_eq!(result, Some(PathBuf::from("/path/with%percent/testuser")));
     }
-}
\ No newline at end of file
+
+    fn test_user(username: &str, uid: u32) -> UserInfo {
+        UserInfo {
+            username: username.to_string(),
+            uid,
+            shell: Some("/bin/bash".to_string()),
+            home_dir: Some(format!("/home/{}", username)),
+            disabled: Some(false),
+            home_encryption: None,
+            home_mounted: None,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        }
+    }
+
+    fn test_assignment(username: &str, assignment_id: &str) -> KeyAssignment {
+        KeyAssignment {
+            username: username.to_string(),
+            fingerprint: "SHA256:test".to_string(),
+            public_key: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e".to_string(),
+            key_type: "ssh-ed25519".to_string(),
+            comment: None,
+            use_primary_key: None,
+            assignment_id: assignment_id.to_string(),
+            uid: None,
+            user_email: None,
+            expires_at: None,
+            options: None,
+            extra: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_safe_username() {
+        assert!(is_safe_username("alice"));
+        assert!(is_safe_username("svc-deploy.01"));
+        assert!(!is_safe_username(""));
+        assert!(!is_safe_username(".."));
+        assert!(!is_safe_username("../../root"));
+        assert!(!is_safe_username("alice/evil"));
+        assert!(!is_safe_username("alice\\evil"));
+        assert!(!is_safe_username("alice\0"));
+        assert!(!is_safe_username("alice bob"));
+        assert!(!is_safe_username("alice\n"));
+    }
+
+    #[test]
+    fn test_resolve_assignments_rejects_traversal() {
+        let manager = SshKeyManager::new();
+        let users = vec![test_user("alice", 1000), test_user("root", 0)];
+        let assignments = vec![
+            test_assignment("alice", "a1"),
+            test_assignment("../../root", "a2"),
+            test_assignment("alice/../../etc", "a3"),
+            test_assignment("mallory", "a4"),
+        ];
+
+        // Unvalidated, a traversal name escapes an absolute pattern's directory
+        let escaped = manager.expand_authorized_keys_pattern("/etc/ssh/keys/%u", "../../root", Path::new("/home/x"));
+        assert_eq!(escaped, Some(PathBuf::from("/etc/ssh/keys/../../root")));
+
+        let (accepted, rejected) = resolve_assignments(&users, &assignments);
+        let accepted_ids: Vec<_> = accepted.iter().map(|(_, a)| a.assignment_id.as_str()).collect();
+        let rejected_ids: Vec<_> = rejected.iter().map(|(a, _)| a.assignment_id.as_str()).collect();
+        assert_eq!(accepted_ids, vec!["a1"]);
+        assert_eq!(rejected_ids, vec!["a2", "a3", "a4"]);
+
+        // Every accepted username expands inside the pattern's directory
+        for (username, _) in accepted {
+            let path = manager
+                .expand_authorized_keys_pattern("/etc/ssh/keys/%u", &username, Path::new("/home/x"))
+                .unwrap();
+            assert_eq!(path.parent(), Some(Path::new("/etc/ssh/keys")));
+        }
+    }
+
+    fn user_with_email(username: &str, uid: u32, email: &str) -> UserInfo {
+        UserInfo {
+            email: Some(email.to_string()),
+            ..test_user(username, uid)
+        }
+    }
+
+    fn keyed_assignment(username: &str, uid: Option<u32>, email: Option<&str>) -> KeyAssignment {
+        KeyAssignment {
+            uid,
+            user_email: email.map(str::to_string),
+            ..test_assignment(username, "a1")
+        }
+    }
+
+    #[test]
+    fn test_resolve_assignment_user_matrix() {
+        let users = vec![
+            user_with_email("alice", 1000, "alice@example.com"),
+            user_with_email("bob", 1001, "bob@example.com"),
+            test_user("carol", 1002),
+        ];
+        let user = |name: &str| AssignmentTarget::User(name.to_string());
+        let cases = [
+            // Username only
+            (keyed_assignment("alice", None, None), user("alice")),
+            (keyed_assignment("mallory", None, None), AssignmentTarget::Unresolved),
+            // Uid wins during a rename window when the old username no longer exists
+            (keyed_assignment("alice.old", Some(1000), None), user("alice")),
+            (keyed_assignment("alice", Some(1000), None), user("alice")),
+            (keyed_assignment("mallory", Some(4242), None), AssignmentTarget::Unresolved),
+            // Email as a fallback, case-insensitive
+            (keyed_assignment("renamed", None, Some("Bob@Example.com")), user("bob")),
+            (keyed_assignment("bob", None, Some("bob@example.com")), user("bob")),
+            (keyed_assignment("carol", None, Some("nobody@example.com")), user("carol")),
+            (keyed_assignment("renamed", Some(1001), Some("bob@example.com")), user("bob")),
+            // Identifiers disagreeing are conflicts
+            (
+                keyed_assignment("bob", Some(1000), None),
+                AssignmentTarget::Conflict("uid matches alice but username matches bob".to_string()),
+            ),
+            (
+                keyed_assignment("alice", None, Some("bob@example.com")),
+                AssignmentTarget::Conflict("username matches alice but email matches bob".to_string()),
+            ),
+            (
+                keyed_assignment("renamed", Some(1002), Some("alice@example.com")),
+                AssignmentTarget::Conflict("uid matches carol but email matches alice".to_string()),
+            ),
+        ];
+
+        for (assignment, expected) in cases {
+            assert_eq!(resolve_assignment_user(&users, &assignment), expected, "{:?}", assignment);
+        }
+    }
+
+    #[test]
+    fn test_resolve_assignment_user_ambiguous() {
+        let users = vec![
+            user_with_email("alice", 1000, "shared@example.com"),
+            user_with_email("alice2", 1000, "shared@example.com"),
+            test_user("bob", 1001),
+        ];
+
+        assert_eq!(
+            resolve_assignment_user(&users, &keyed_assignment("x", Some(1000), None)),
+            AssignmentTarget::Ambiguous("uid matches 2 local users".to_string())
+        );
+        assert_eq!(
+            resolve_assignment_user(&users, &keyed_assignment("x", None, Some("shared@example.com"))),
+            AssignmentTarget::Ambiguous("email matches 2 local users".to_string())
+        );
+        // A unique username does not rescue an ambiguous uid
+        assert_eq!(
+            resolve_assignment_user(&users, &keyed_assignment("alice", Some(1000), None)),
+            AssignmentTarget::Ambiguous("uid matches 2 local users".to_string())
+        );
+    }
+
+    const RSA_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDO5XOnOPRhZ/6vQSXnd1QN2i0Swq9FvM3Nwwx5GcBTP9ydZiYqHA00wYRmWoEQpUdrosGE8UaanvdNxCm79oX0AJdiBMm7L73G3J5svovX5jY5ysOB9BnWrMrl+a180L8bWiQ3G/4zMk8dGgkf4NMa6X6KqdfjL0NKKam6q8SJ21CBDaJ5QlBZUEOWsX3qEhs/yswTNT+M7eU+NnaQTzGTfR52sW9ks+lKAF1y4lBiS3L/jeu3eO+XFVVmvbbT6ees+hMnWa0Os8AZx/k9aKao+4GSW1QlQZWuUxcG1r54djP8jiiFrrNsqJ5zEq0R8DkgfOYhxzAfyjAeCaZ6PQuj";
+
+    fn user_with_home(root: &Path, username: &str, uid: u32) -> UserInfo {
+        let home = root.join(username);
+        fs::create_dir_all(&home).unwrap();
+        UserInfo {
+            home_dir: Some(home.to_string_lossy().to_string()),
+            ..test_user(username, uid)
+        }
+    }
+
+    #[test]
+    fn test_authorized_keys_for() {
+        const OTHER_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+        let users = vec![test_user("alice", 1000), test_user("bob", 1001)];
+        let assignment = |username: &str, id: &str, public_key: &str| KeyAssignment {
+            public_key: public_key.to_string(),
+            ..test_assignment(username, id)
+        };
+        let assignments = vec![
+            KeyAssignment {
+                options: Some("no-pty".to_string()),
+                ..assignment("alice", "a1", ED25519_KEY)
+            },
+            assignment("alice", "duplicate", ED25519_KEY),
+            KeyAssignment {
+                uid: Some(1000),
+                ..assignment("alice-renamed", "by-uid", OTHER_KEY)
+            },
+            KeyAssignment {
+                expires_at: Some(1),
+                ..assignment("alice", "expired", OTHER_KEY)
+            },
+            assignment("alice", "policy", RSA_KEY),
+            assignment("alice", "inline-options", &format!("no-pty {}", OTHER_KEY)),
+            assignment("bob", "b1", OTHER_KEY),
+        ];
+        let manager = SshKeyManager::new().with_key_policy(KeyPolicy::new(&["ssh-ed25519".to_string()], None).unwrap());
+
+        assert_eq!(
+            manager.authorized_keys_for(&users, "alice", &assignments, 1_000),
+            vec![format!("no-pty {}", ED25519_KEY), OTHER_KEY.to_string()]
+        );
+        assert_eq!(manager.authorized_keys_for(&users, "bob", &assignments, 1_000), vec![OTHER_KEY.to_string()]);
+        assert!(manager.authorized_keys_for(&users, "mallory", &assignments, 1_000).is_empty());
+
+        let manager = manager.with_user_filter(UserFilter {
+            include: Vec::new(),
+            exclude: vec!["bob".to_string()],
+            source: FilterSource::Local,
+        });
+        assert!(manager.authorized_keys_for(&users, "bob", &assignments, 1_000).is_empty());
+    }
+
+    fn status_of(stats: &KeySyncStats, assignment_id: &str) -> AssignmentState {
+        stats.assignment_statuses[assignment_id].status
+    }
+
+    #[test]
+    fn test_assignment_statuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let carol = user_with_home(dir.path(), "carol", 1002);
+        let dave = user_with_home(dir.path(), "dave", 1003);
+        // bob's home is a regular file, so his authorized_keys cannot be written
+        let mut bob = test_user("bob", 1001);
+        let bob_home = dir.path().join("bob");
+        fs::write(&bob_home, "").unwrap();
+        bob.home_dir = Some(bob_home.to_string_lossy().to_string());
+
+        let dave_keys = dir.path().join("dave/.ssh/authorized_keys");
+        fs::create_dir_all(dave_keys.parent().unwrap()).unwrap();
+        fs::write(&dave_keys, format!("{}\n", ED25519_KEY)).unwrap();
+
+        let assignment = |username: &str, id: &str, public_key: &str| KeyAssignment {
+            public_key: public_key.to_string(),
+            ..test_assignment(username, id)
+        };
+        let assignments = vec![
+            assignment("alice", "applied-new", ED25519_KEY),
+            assignment("dave", "already-present", ED25519_KEY),
+            assignment("alice", "failed-parse", "ssh-ed25519 not-base64!"),
+            assignment("bob", "failed-apply", ED25519_KEY),
+            assignment("mallory", "user-not-found", ED25519_KEY),
+            assignment("carol", "excluded-by-filter", ED25519_KEY),
+            KeyAssignment {
+                expires_at: Some(1),
+                ..assignment("alice", "removed-expired", ED25519_KEY)
+            },
+            assignment("alice", "suppressed-by-policy", RSA_KEY),
+        ];
+
+        let manager = SshKeyManager::new()
+            .with_key_policy(KeyPolicy::new(&["ssh-ed25519".to_string()], None).unwrap())
+            .with_user_filter(UserFilter {
+                include: Vec::new(),
+                exclude: vec!["carol".to_string()],
+                source: FilterSource::Local,
+            });
+        let stats = manager
+            .sync_ssh_keys(&[alice, bob, carol, dave], &assignments, false, false)
+            .unwrap();
+
+        assert_eq!(stats.assignment_statuses.len(), assignments.len());
+        assert_eq!(status_of(&stats, "applied-new"), AssignmentState::AppliedNew);
+        assert_eq!(status_of(&stats, "already-present"), AssignmentState::AlreadyPresent);
+        assert_eq!(status_of(&stats, "failed-parse"), AssignmentState::FailedParse);
+        assert_eq!(status_of(&stats, "failed-apply"), AssignmentState::FailedApply);
+        assert_eq!(status_of(&stats, "user-not-found"), AssignmentState::UserNotFound);
+        assert_eq!(status_of(&stats, "excluded-by-filter"), AssignmentState::ExcludedByFilter);
+        assert_eq!(status_of(&stats, "removed-expired"), AssignmentState::RemovedExpired);
+        assert_eq!(status_of(&stats, "suppressed-by-policy"), AssignmentState::SuppressedByPolicy);
+        assert!(stats.assignment_statuses["failed-apply"].detail.is_some());
+        assert!(fs::read_to_string(dir.path().join("alice/.ssh/authorized_keys")).unwrap().contains(ED25519_KEY));
+
+        let outcomes: Vec<_> = stats.acknowledgements().iter().map(|ack| (ack.assignment_id.clone(), ack.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("already-present", AckOutcome::Applied),
+                ("applied-new", AckOutcome::Applied),
+                ("excluded-by-filter", AckOutcome::Skipped),
+                ("failed-apply", AckOutcome::Failed),
+                ("failed-parse", AckOutcome::Failed),
+                ("removed-expired", AckOutcome::Applied),
+                ("suppressed-by-policy", AckOutcome::Skipped),
+                ("user-not-found", AckOutcome::Skipped),
+            ]
+            .map(|(id, outcome)| (id.to_string(), outcome))
+        );
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["assignment_statuses"]["applied-new"]["status"], "applied-new");
+        assert_eq!(json["assignment_statuses"]["suppressed-by-policy"]["status"], "suppressed-by-policy");
+    }
+
+    #[test]
+    fn test_root_keys_follow_permit_root_login() {
+        for (permit_root_login, synced) in [
+            (PermitRootLogin::Yes, true),
+            (PermitRootLogin::ProhibitPassword, true),
+            (PermitRootLogin::ForcedCommandsOnly, false),
+            (PermitRootLogin::No, false),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let root = user_with_home(dir.path(), "root", 0);
+            let alice = user_with_home(dir.path(), "alice", 1000);
+            let assignments = vec![test_assignment("root", "r1"), test_assignment("alice", "a1")];
+
+            let manager = SshKeyManager::new().with_root_login(permit_root_login);
+            let stats = manager.sync_ssh_keys(&[root, alice], &assignments, false, false).unwrap();
+
+            assert_eq!(dir.path().join("root/.ssh/authorized_keys").exists(), synced, "{}", permit_root_login);
+            assert_eq!(stats.root_login_disabled, !synced, "{}", permit_root_login);
+            assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+            if !synced {
+                assert_eq!(status_of(&stats, "r1"), AssignmentState::ExcludedByFilter);
+                assert_eq!(stats.assignment_statuses["r1"].detail.as_deref(), Some("root login disabled by sshd"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_root_forced_command_assignments_under_forced_commands_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = user_with_home(dir.path(), "root", 0);
+        let mut forced = test_assignment("root", "r1");
+        forced.options = Some("command=\"/usr/local/bin/backup\",no-pty".to_string());
+        let manager = SshKeyManager::new().with_root_login(PermitRootLogin::ForcedCommandsOnly);
+
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&root), std::slice::from_ref(&forced), false, false).unwrap();
+        assert!(!stats.root_login_disabled);
+        assert_eq!(status_of(&stats, "r1"), AssignmentState::AppliedNew);
+        let written = fs::read_to_string(dir.path().join("root/.ssh/authorized_keys")).unwrap();
+        assert!(written.contains("command=\"/usr/local/bin/backup\",no-pty ssh-ed25519"), "{}", written);
+
+        // One key without a forced command would be unusable, so root is still skipped
+        let mut plain = test_assignment("root", "r2");
+        plain.public_key = CLOUD_KEY.to_string();
+        let stats = manager.sync_ssh_keys(&[root], &[forced, plain], false, false).unwrap();
+        assert!(stats.root_login_disabled);
+
+        assert!(has_forced_command("no-pty,COMMAND=\"ls\""));
+        assert!(!has_forced_command("from=\"a,command=x\",no-pty"));
+        assert!(!has_forced_command("no-port-forwarding"));
+    }
+
+    #[test]
+    fn test_pubkey_auth_disabled_users_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let contractor = user_with_home(dir.path(), "contractor-bob", 1001);
+        let assignments = vec![test_assignment("alice", "a1"), test_assignment("contractor-bob", "c1")];
+
+        let disabled = HashSet::from(["contractor-bob".to_string()]);
+        let manager = SshKeyManager::new().with_pubkey_auth_disabled(disabled);
+        let stats = manager.sync_ssh_keys(&[alice, contractor], &assignments, false, false).unwrap();
+
+        assert!(!dir.path().join("contractor-bob/.ssh/authorized_keys").exists());
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+        assert_eq!(status_of(&stats, "c1"), AssignmentState::PubkeyAuthDisabled);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["assignment_statuses"]["c1"]["status"], "pubkey-auth-disabled");
+    }
+
+    const ECDSA_KEY: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBF9DAcTGYilGpsHMDOO/3a9+gYqz53dyygspQuLqrC2xuu2XaoFfyhbQ5snOjyve3m32w0cBm3vkMSwBsZMJC+Q=";
+
+    // Signed with `ssh-keygen -s ca -I id-<type> -n alice -V 20200101:20991231 -z 1`
+    const ED25519_CERT: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIKlGUGw7dVccBpnlaAuuy8QCJklFf1ANyjzcVbgVpQyrAAAAIFoyVqtbXNgGE3ZvHwC3bgDukdPTaWamfUx/30AqhC5MAAAAAAAAAAEAAAABAAAACmlkLWVkMjU1MTkAAAAJAAAABWFsaWNlAAAAAF4L4QAAAAAA9IUFgAAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAIDVp7Dxi8VhTC1l5Xh6rg3+BI+gIPCSCM0jYMniOsXKrAAAAUwAAAAtzc2gtZWQyNTUxOQAAAEDg3S/MDLp408z7RxAOvAaI4BYSCwkeZqwDkONm5V7gTqdC8WiEVT+pilKk/ei0TBInlUlb3k0+7ya6mwCuZFUB";
+    const RSA_CERT: &str = "ssh-rsa-cert-v01@openssh.com AAAAHHNzaC1yc2EtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgQTzA9pNS5iXW8uBEf36p1ZCrIf8nmZT11c+uu0bxSy8AAAADAQABAAABgQCspqL2KpDs8JAlLUp8cl2Jn7jsmyGuA3ctS8yTFjTq5RFSFzNc+PYgvPvzARiKSECEB5EoIGLuuBJk27bp2l/8//QApuAZfdznyKaGBZH9eTi8Ya9DhDgqIVx/vqFTJTlHUdYn/mrSJ3L6usQjnNVr1RrjMTRw7er8YQSvyOluWEVb0VN5ppVTqYkd8CRWSM362Dx2dYiiSffNNCwEugokVy8W+oVsbHwyykR0ya75p7m1goJ0IySSOEOUH9Z6OE4Nwp2VbA3wvIh0knPM2idYA8fU3I3wPx1T5Ifv7aqpwEepV2QWiIJlTkFVgJUHUce17rY4zbQJsoNYV/V7OU0RQhHw7HGqpAys8cvoOh+OC6QN22T+MclmwzXWm08jkTaUBu3rnaHV69nKgSXsEziiK/muWKueySXW3uEr/mvzMtJ81HOBLJhIG/PZ89isP9RjvK5pYIV050EMyzNIUuJx3sGKQD6qza3yDvvtEDuMo5QmWt6qIB3PgRO+Jt0E1BMAAAAAAAAAAQAAAAEAAAAGaWQtcnNhAAAACQAAAAVhbGljZQAAAABeC+EAAAAAAPSFBYAAAAAAAAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACA1aew8YvFYUwtZeV4eq4N/gSPoCDwkgjNI2DJ4jrFyqwAAAFMAAAALc3NoLWVkMjU1MTkAAABA3hJcSL3Uh4TiFyRvsMvIZEl7Po6mVo8KftvVnT2h0zqiAM3/oNOTyF/xkNUlT/4PuCAUuuqyEDklCj+q0Vw/CQ==";
+    const ECDSA_CERT: &str = "ecdsa-sha2-nistp256-cert-v01@openssh.com AAAAKGVjZHNhLXNoYTItbmlzdHAyNTYtY2VydC12MDFAb3BlbnNzaC5jb20AAAAgiDecUTKY+hw2ZUW+7FBKz9YEjl8VsKHCahDjh4+2YKwAAAAIbmlzdHAyNTYAAABBBGbO0TYZCHH2AxE0ND9uiZ0u4ttsGJz1lAGkms/0H/sNpSCeWuYfL1HdnpdmoC/jc5sdX49A/bpFLZ+n411h33UAAAAAAAAAAQAAAAEAAAAIaWQtZWNkc2EAAAAJAAAABWFsaWNlAAAAAF4L4QAAAAAA9IUFgAAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAIDVp7Dxi8VhTC1l5Xh6rg3+BI+gIPCSCM0jYMniOsXKrAAAAUwAAAAtzc2gtZWQyNTUxOQAAAEBEc28i5vV3rkHFvUSiufguXuCM8rxS22LEiloJt4IVtHCa/jX01/rwKR7h0JtqO4qfKIRr/mIlvCYTDQwxIPUN";
+
+    #[test]
+    fn test_parse_certificates() {
+        // Fingerprints are SHA256 over the certificate blob, not the certified key `ssh-keygen -l` shows
+        for (line, key_type, fingerprint) in [
+            (ED25519_CERT, "ssh-ed25519-cert-v01@openssh.com", "SHA256:JNmEgtqw/JmSJp3KvTyT6vJdsmnULOgSl/qd1VzhHnM"),
+            (RSA_CERT, "ssh-rsa-cert-v01@openssh.com", "SHA256:K1qrMv5/A/xurVl8YV16vGktEhzfPJSL1767ylOJnwU"),
+            (ECDSA_CERT, "ecdsa-sha2-nistp256-cert-v01@openssh.com", "SHA256:qZ6Nrl6M1/PxGB9UfSOYp/j6OD9xvAne7AVeHp+XS9g"),
+        ] {
+            let key = SshKey::parse(&format!("{} alice@laptop", line)).unwrap();
+            assert_eq!(key.key_type, key_type);
+            assert_eq!(key.fingerprint, fingerprint);
+            assert_eq!(key.comment.as_deref(), Some("alice@laptop"));
+            assert!(key.is_certificate());
+
+            let restricted = SshKey::parse(&format!("no-pty {}", line)).unwrap();
+            assert_eq!(restricted.options.as_deref(), Some("no-pty"));
+            assert_eq!(restricted.fingerprint, fingerprint);
+        }
+        assert!(!SshKey::parse(ED25519_KEY).unwrap().is_certificate());
+    }
+
+    #[test]
+    fn test_certificates_survive_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n", RSA_CERT)).unwrap();
+
+        let assignment = KeyAssignment {
+            fingerprint: SshKey::parse(ED25519_CERT).unwrap().fingerprint,
+            public_key: ED25519_CERT.to_string(),
+            key_type: "ssh-ed25519-cert-v01@openssh.com".to_string(),
+            ..test_assignment("alice", "a1")
+        };
+        let manager = SshKeyManager::new();
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        assert_eq!((stats.errors, stats.keys_added), (0, 1));
+
+        let content = fs::read_to_string(&keys_path).unwrap();
+        assert!(content.contains(RSA_CERT), "{}", content);
+        assert!(content.contains(ED25519_CERT), "{}", content);
+
+        let inventory = manager.key_inventory(&[alice]).unwrap();
+        assert_eq!(inventory[0].certificates.len(), 2);
+    }
+
+    #[test]
+    fn test_fingerprint_vectors() {
+        // Expected values from `ssh-keygen -l -E sha256|md5`
+        let ed25519 = SshKey::parse(ED25519_KEY).unwrap();
+        assert_eq!(ed25519.fingerprint, "SHA256:SeN3AUxp8YpJHIJx9k5QSxGL4X9lFpicdgS6BbKsPbU");
+        assert_eq!(ed25519.md5_fingerprint, "b5:a8:5f:32:5d:08:18:69:e4:b4:63:04:d0:42:89:96");
+
+        let rsa = SshKey::parse(RSA_KEY).unwrap();
+        assert_eq!(rsa.fingerprint, "SHA256:ANnkdnpitKwiRM59vOyy+2xzht5pirEfwzd/6dMC5JY");
+        assert_eq!(rsa.md5_fingerprint, "35:4e:d8:f7:59:36:4c:7c:a9:41:eb:db:f0:fc:a1:d3");
+
+        let ecdsa = SshKey::parse(ECDSA_KEY).unwrap();
+        assert_eq!(ecdsa.fingerprint, "SHA256:+F2f74PBaMFsn39i0v2n1vUBiMLbUY/AD1J8uiZLhWE");
+    }
+
+    #[test]
+    fn test_fingerprint_format_detection() {
+        assert_eq!(FingerprintFormat::detect("SHA256:SeN3AUxp8YpJHIJx9k5QSxGL4X9lFpicdgS6BbKsPbU"), Some(FingerprintFormat::Sha256));
+        assert_eq!(FingerprintFormat::detect("b5:a8:5f:32:5d:08:18:69:e4:b4:63:04:d0:42:89:96"), Some(FingerprintFormat::Md5));
+        assert_eq!(FingerprintFormat::detect("MD5:B5:A8:5F:32:5D:08:18:69:E4:B4:63:04:D0:42:89:96"), Some(FingerprintFormat::Md5));
+        assert_eq!(FingerprintFormat::detect("b5:a8:5f"), None);
+        assert_eq!(FingerprintFormat::detect("zz:a8:5f:32:5d:08:18:69:e4:b4:63:04:d0:42:89:96"), None);
+    }
+
+    #[test]
+    fn test_matches_fingerprint_in_either_format() {
+        let key = SshKey::parse(ED25519_KEY).unwrap();
+        for fingerprint in [
+            "SHA256:SeN3AUxp8YpJHIJx9k5QSxGL4X9lFpicdgS6BbKsPbU",
+            "SHA256:SeN3AUxp8YpJHIJx9k5QSxGL4X9lFpicdgS6BbKsPbU=",
+            "b5:a8:5f:32:5d:08:18:69:e4:b4:63:04:d0:42:89:96",
+            "MD5:B5:A8:5F:32:5D:08:18:69:E4:B4:63:04:D0:42:89:96",
+        ] {
+            assert!(key.matches_fingerprint(fingerprint), "{}", fingerprint);
+        }
+
+        let rsa_md5 = "35:4e:d8:f7:59:36:4c:7c:a9:41:eb:db:f0:fc:a1:d3";
+        assert!(!key.matches_fingerprint(rsa_md5));
+        assert!(SshKey::parse(RSA_KEY).unwrap().matches_fingerprint(rsa_md5));
+        assert!(!key.matches_fingerprint("SHA256:test"));
+    }
+
+    #[test]
+    fn test_sync_applies_legacy_and_mismatched_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        // The stored fingerprint is only checked and logged; the key sent is what gets written
+        let assignments = vec![
+            KeyAssignment { fingerprint: "MD5:B5:A8:5F:32:5D:08:18:69:E4:B4:63:04:D0:42:89:96".to_string(), ..test_assignment("alice", "a1") },
+            KeyAssignment {
+                fingerprint: "SHA256:SeN3AUxp8YpJHIJx9k5QSxGL4X9lFpicdgS6BbKsPbU".to_string(),
+                public_key: RSA_KEY.to_string(),
+                key_type: "ssh-rsa".to_string(),
+                ..test_assignment("alice", "a2")
+            },
+        ];
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &assignments, false, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+        assert_eq!(status_of(&stats, "a2"), AssignmentState::AppliedNew);
+        let written = fs::read_to_string(dir.path().join("alice/.ssh/authorized_keys")).unwrap();
+        assert!(written.contains(ED25519_KEY) && written.contains(RSA_KEY), "{}", written);
+    }
+
+    #[test]
+    fn test_assignment_status_pending_in_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let assignments = vec![test_assignment("alice", "a1")];
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &assignments, true, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::Pending);
+        assert!(!dir.path().join("alice/.ssh/authorized_keys").exists());
+    }
+
+    #[test]
+    fn test_plan_reports_unmanaged_keys_and_permission_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n{}\n", ED25519_KEY, RSA_KEY)).unwrap();
+        fs::set_permissions(&keys_path, Permissions::from_mode(0o666)).unwrap();
+        let assignments = vec![test_assignment("alice", "a1")];
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &assignments, true, false).unwrap();
+
+        assert_eq!(stats.unmanaged_keys.len(), 1);
+        assert_eq!(stats.unmanaged_keys[0].fingerprints, vec![SshKey::parse(RSA_KEY).unwrap().fingerprint]);
+        assert!(stats.permission_warnings.iter().any(|w| w.username == "alice" && w.detail.contains("writable")));
+        assert_eq!(fs::metadata(&keys_path).unwrap().permissions().mode() & 0o777, 0o666);
+        // No marker yet: --check reports the file as unmanaged
+        assert_eq!(stats.unmarked_files.len(), 1);
+        assert_eq!(stats.unmarked_files[0].path, keys_path);
+    }
+
+    #[test]
+    fn test_permissions_reasserted_without_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", nix::unistd::getuid().as_raw());
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let assignments = vec![test_assignment("alice", "a1")];
+        let manager = SshKeyManager::new();
+        manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        let content = fs::read(&keys_path).unwrap();
+
+        // An admin loosens the modes; the key set stays the same
+        fs::set_permissions(&keys_path, Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(keys_path.parent().unwrap(), Permissions::from_mode(0o755)).unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options().write(true).open(&keys_path).unwrap().set_modified(old).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, true, false).unwrap();
+        assert_eq!((stats.files_updated, stats.permissions_fixed), (0, 2));
+        assert!(stats.permission_fixes[0].detail.ends_with("mode 644 -> 600"), "{:?}", stats.permission_fixes);
+        assert_eq!(mode(&keys_path), 0o644);
+
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        assert_eq!((stats.files_updated, stats.permissions_fixed), (0, 2));
+        assert_eq!(mode(&keys_path), 0o600);
+        assert_eq!(mode(keys_path.parent().unwrap()), 0o700);
+        assert_eq!(fs::read(&keys_path).unwrap(), content);
+        assert_eq!(fs::metadata(&keys_path).unwrap().modified().unwrap(), old);
+
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        assert_eq!(stats.permissions_fixed, 0);
+    }
+
+    #[test]
+    fn test_opted_out_user_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = UserInfo {
+            opt_out: Some(crate::optout::UserOptOut { since: 1, expires_at: 2_000_000_000 }),
+            ..user_with_home(dir.path(), "alice", 1000)
+        };
+        let bob = user_with_home(dir.path(), "bob", 1001);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n", RSA_KEY)).unwrap();
+        let assignments = vec![test_assignment("alice", "a1"), test_assignment("bob", "b1")];
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice, bob], &assignments, false, false).unwrap();
+        assert_eq!(stats.users_skipped_reasons.get("user-opt-out"), Some(&1));
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::UserOptOut);
+        assert_eq!(stats.assignment_statuses["a1"].detail.as_deref(), Some("user opt-out until 2000000000"));
+        assert_eq!(status_of(&stats, "b1"), AssignmentState::AppliedNew);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), format!("{}\n", RSA_KEY));
+    }
+
+    const CLOUD_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJ1bsbfYW1wqPIz7Zy0eIoDPcwJcx2tS2ULtg3wdY4r0";
+
+    /// authorized_keys as left behind by cloud-init and a Puppet run, plus one hand-added key
+    fn write_cloud_init_fixture(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            path,
+            format!(
+                "# HEADER: This file was autogenerated by Puppet\n{} puppet-deploy\n\n{} # added by cloud-init\n{} hand-added\n",
+                RSA_KEY, CLOUD_KEY, ED25519_KEY
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_external_keys_preserved_across_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        write_cloud_init_fixture(&keys_path);
+        let rsa = SshKey::parse(RSA_KEY).unwrap().fingerprint;
+        let cloud = SshKey::parse(CLOUD_KEY).unwrap().fingerprint;
+
+        // The hand-added key is assigned, so only the two externally written keys remain unassigned
+        let manager = SshKeyManager::new().with_exclusive(true);
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_removed, 0);
+        assert!(stats.unmanaged_keys.is_empty());
+        assert_eq!(stats.external_keys.len(), 1);
+        assert_eq!(stats.external_keys[0].fingerprints, vec![rsa.clone(), cloud.clone()]);
+
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(written.contains(&format!("# HEADER: This file was autogenerated by Puppet\n{} puppet-deploy\n", RSA_KEY)));
+        assert!(written.contains(&format!("{} # added by cloud-init", CLOUD_KEY)));
+
+        // The rewritten file is classified the same way on the next run
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.external_keys[0].fingerprints, vec![rsa, cloud]);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), written);
+
+        // Opting in removes them like any other unassigned key
+        let manager = SshKeyManager::new().with_exclusive(true).with_remove_external_keys(true);
+        let stats = manager.sync_ssh_keys(&[alice], &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_removed, 2);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(!written.contains(RSA_KEY) && !written.contains(CLOUD_KEY));
+    }
+
+    #[test]
+    fn test_unrecognised_lines_survive_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let restricted = format!("command=\"/usr/local/bin/backup\",no-pty {} backup@nas", CLOUD_KEY);
+        let unknown_type = "ssh-xmss@openssh.com AAAAFHNzaC14bXNzQG9wZW5zc2guY29t xmss";
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("# backup key, do not remove\n{}\n{}\n{} stale\n", restricted, unknown_type, RSA_KEY)).unwrap();
+
+        let manager = SshKeyManager::new().with_exclusive(true);
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_added, 1);
+        assert_eq!(stats.keys_removed, 1);
+        assert_eq!(stats.preserved_lines, 3);
+
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(written.contains(&format!("# backup key, do not remove\n{}\n{}\n", restricted, unknown_type)));
+        assert!(written.contains(ED25519_KEY));
+        assert!(!written.contains(RSA_KEY));
+
+        // Preserved lines are not duplicated on the next rewrite
+        let stats = manager.sync_ssh_keys(&[alice], &[test_assignment("alice", "a2")], false, false).unwrap();
+        assert_eq!(stats.preserved_lines, 3);
+        let rewritten = fs::read_to_string(&keys_path).unwrap();
+        assert_eq!(rewritten.matches(&restricted).count(), 1);
+        assert_eq!(rewritten.matches("# This file is managed by PubliKey Agent").count(), 1);
+    }
+
+    #[test]
+    fn test_assigned_key_with_options_not_duplicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let restricted = format!("from=\"10.0.0.0/8\",command=\"echo \\\"hi there\\\"\" {} alice@laptop", ED25519_KEY);
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n", restricted)).unwrap();
+
+        let stats = SshKeyManager::new().sync_ssh_keys(std::slice::from_ref(&alice), &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_added, 0);
+        assert_eq!(stats.keys_already_present_unmanaged, 1);
+        assert_eq!(stats.keys_adopted, 0);
+        assert_eq!(stats.files_updated, 0);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AlreadyPresent);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), format!("{}\n", restricted));
+    }
+
+    #[test]
+    fn test_adopt_existing_key_with_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let restricted = format!("command=\"/usr/bin/rsync --server\",no-pty {} alice@laptop", ED25519_KEY);
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("# keep me\n{}\n{} # added by cloud-init\n", restricted, CLOUD_KEY)).unwrap();
+        let mut cloud_assignment = test_assignment("alice", "a2");
+        cloud_assignment.public_key = CLOUD_KEY.to_string();
+
+        let manager = SshKeyManager::new().with_adopt_existing_keys(true);
+        let assignments = [test_assignment("alice", "a1"), cloud_assignment];
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        assert_eq!(stats.keys_adopted, 2);
+        assert_eq!(stats.keys_added, 0);
+        assert_eq!(stats.files_updated, 1);
+
+        // Both lines move into the managed block, under the adoption marker, exactly as written
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(written.contains(&format!("{}\n{}\n", ADOPTED_MARKER, restricted)));
+        assert!(written.contains(&format!("{}\n{} # added by cloud-init\n", ADOPTED_MARKER, CLOUD_KEY)));
+        assert_eq!(written.matches(ED25519_KEY).count(), 1);
+        assert!(written.starts_with(&format!("# keep me\n\n{}\n", BLOCK_BEGIN)));
+
+        // Adopted keys are managed from now on: nothing to adopt again, no rewrite
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        assert_eq!(stats.keys_adopted, 0);
+        assert_eq!(stats.files_updated, 0);
+        assert!(stats.external_keys.is_empty());
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), written);
+
+        // ... and removed like any managed key once unassigned
+        let stats = manager.sync_ssh_keys(&[alice], &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_removed, 1);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(!written.contains(CLOUD_KEY));
+        assert!(written.contains(&restricted));
+    }
+
+    #[test]
+    fn test_managed_block_leaves_other_lines_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        let hand_added = format!("# my laptop\n{} alice@laptop\n", RSA_KEY);
+        fs::write(&keys_path, &hand_added).unwrap();
+        let mut cloud_assignment = test_assignment("alice", "a2");
+        cloud_assignment.public_key = CLOUD_KEY.to_string();
+
+        // First run appends the block after the user's own lines
+        let manager = SshKeyManager::new();
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_added, 1);
+        assert_eq!(stats.keys_removed, 0);
+        assert_eq!(stats.unmanaged_keys[0].fingerprints, vec![SshKey::parse(RSA_KEY).unwrap().fingerprint]);
+        assert_eq!(
+            fs::read_to_string(&keys_path).unwrap(),
+            format!("{}\n{}\n{}\n{}\n", hand_added, BLOCK_BEGIN, ED25519_KEY, BLOCK_END)
+        );
+
+        // Later runs rewrite only the block; lines added by hand on either side stay
+        let mut content = fs::read_to_string(&keys_path).unwrap();
+        content.push_str("# added after the block\n");
+        fs::write(&keys_path, &content).unwrap();
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&cloud_assignment), false, false).unwrap();
+        assert_eq!(stats.keys_added, 1);
+        assert_eq!(stats.keys_removed, 1);
+        assert_eq!(stats.changes[0].removed, vec![SshKey::parse(ED25519_KEY).unwrap().fingerprint]);
+        assert_eq!(
+            fs::read_to_string(&keys_path).unwrap(),
+            format!("{}\n{}\n{}\n{}\n# added after the block\n", hand_added, BLOCK_BEGIN, CLOUD_KEY, BLOCK_END)
+        );
+
+        // --exclusive owns the whole file again
+        let stats = SshKeyManager::new()
+            .with_exclusive(true)
+            .sync_ssh_keys(&[alice], &[cloud_assignment], false, false)
+            .unwrap();
+        assert_eq!(stats.keys_removed, 1);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(!written.contains(RSA_KEY));
+        assert!(!written.contains(BLOCK_BEGIN));
+    }
+
+    #[test]
+    fn test_whole_file_from_older_agents_becomes_a_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let manager = SshKeyManager::new();
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n{}\n\n{}\n", manager.managed_marker, MANAGED_HEADER.join("\n"), RSA_KEY)).unwrap();
+
+        // The previously managed key is removed and the file converted
+        let stats = manager.sync_ssh_keys(&[alice], &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.keys_removed, 1);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), format!("{}\n{}\n{}\n", BLOCK_BEGIN, ED25519_KEY, BLOCK_END));
+    }
+
+    #[test]
+    fn test_unbalanced_block_is_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        let content = format!("{}\n{}\n", BLOCK_BEGIN, RSA_KEY);
+        fs::write(&keys_path, &content).unwrap();
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &[test_assignment("alice", "a1")], false, false).unwrap();
+        assert_eq!(stats.errors, 1);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::FailedApply);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_assignment_options_written_and_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let mut assignment = test_assignment("alice", "a1");
+        assignment.options = Some("command=\"/usr/bin/rrsync /srv\"".to_string());
+
+        let manager = SshKeyManager::new();
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        assert_eq!(stats.keys_added, 1);
+        let restricted = format!("command=\"/usr/bin/rrsync /srv\" {}", ED25519_KEY);
+        assert_eq!(
+            fs::read_to_string(&keys_path).unwrap(),
+            format!("{}\n{}\n{}\n{}\n", BLOCK_BEGIN, OPTIONS_MARKER, restricted, BLOCK_END)
+        );
+
+        // Same options: nothing to do
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        assert_eq!(stats.files_updated, 0);
+        assert_eq!(stats.preserved_lines, 0);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AlreadyPresent);
+
+        // Only the options change: the key is rewritten, not added twice
+        assignment.options = Some("no-pty,no-agent-forwarding".to_string());
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        assert_eq!((stats.keys_added, stats.keys_removed, stats.keys_updated), (0, 0, 1));
+        assert_eq!(stats.changes[0].updated, vec![SshKey::parse(ED25519_KEY).unwrap().fingerprint]);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(written.contains(&format!("{}\nno-pty,no-agent-forwarding {}\n", OPTIONS_MARKER, ED25519_KEY)));
+        assert_eq!(written.matches(ED25519_KEY).count(), 1);
+
+        // Dropping the options is a change too
+        assignment.options = None;
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        assert_eq!(stats.keys_updated, 1);
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), format!("{}\n{}\n{}\n", BLOCK_BEGIN, ED25519_KEY, BLOCK_END));
+
+        // Keys the agent wrote with options are removed like any other once unassigned
+        assignment.options = Some("no-pty".to_string());
+        manager.sync_ssh_keys(std::slice::from_ref(&alice), std::slice::from_ref(&assignment), false, false).unwrap();
+        let stats = manager.sync_ssh_keys(&[alice], &[], false, false).unwrap();
+        assert_eq!(stats.keys_removed, 1);
+        assert!(!fs::read_to_string(&keys_path).unwrap().contains(ED25519_KEY));
+    }
+
+    #[test]
+    fn test_duplicate_assignments_write_the_key_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let mut restricted = test_assignment("alice", "a1");
+        restricted.options = Some("no-pty".to_string());
+        let plain = test_assignment("alice", "a2");
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &[restricted, plain], false, false).unwrap();
+        assert_eq!(stats.keys_added, 1);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+        assert_eq!(status_of(&stats, "a2"), AssignmentState::AppliedNew);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert_eq!(written.matches(ED25519_KEY).count(), 1);
+        assert!(written.contains(&format!("no-pty {}", ED25519_KEY)));
+    }
+
+    #[test]
+    fn test_invalid_assignment_options_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let mut unbalanced = test_assignment("alice", "a1");
+        unbalanced.options = Some("command=\"/bin/true".to_string());
+        let mut cloud_assignment = test_assignment("alice", "a2");
+        cloud_assignment.public_key = CLOUD_KEY.to_string();
+
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &[unbalanced, cloud_assignment], false, false).unwrap();
+        assert_eq!(stats.errors, 1);
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::FailedParse);
+        assert_eq!(status_of(&stats, "a2"), AssignmentState::AppliedNew);
+        assert_eq!(stats.keys_added, 1);
+
+        assert!(validate_options("no-pty,from=\"10.0.0.0/8\"").is_ok());
+        assert!(validate_options("no-pty from=\"x\"").is_err());
+        assert!(validate_options("command=\"a\nb\"").is_err());
+    }
+
+    /// A distinct, well-formed ed25519 public key line for each `n`
+    fn generated_key(n: u32) -> String {
+        use base64::Engine;
+        let mut blob = Vec::with_capacity(51);
+        blob.extend_from_slice(&11u32.to_be_bytes());
+        blob.extend_from_slice(b"ssh-ed25519");
+        blob.extend_from_slice(&32u32.to_be_bytes());
+        blob.extend_from_slice(&[0u8; 28]);
+        blob.extend_from_slice(&n.to_be_bytes());
+        format!("ssh-ed25519 {}", base64::engine::general_purpose::STANDARD.encode(blob))
+    }
+
+    #[test]
+    fn test_sync_scales_to_ten_thousand_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        let existing: Vec<String> = (0..10_000).map(generated_key).collect();
+        fs::write(&keys_path, format!("{}\n{}\n{}\n", BLOCK_BEGIN, existing.join("\n"), BLOCK_END)).unwrap();
+
+        // Half the keys stay, half are replaced
+        let assignments: Vec<KeyAssignment> = (5_000..15_000)
+            .map(|n| KeyAssignment { public_key: generated_key(n), ..test_assignment("alice", &format!("a{}", n)) })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let stats = SshKeyManager::new().sync_ssh_keys(&[alice], &assignments, false, false).unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(stats.keys_added, 5_000);
+        assert_eq!(stats.keys_removed, 5_000);
+        assert_eq!(status_of(&stats, "a5000"), AssignmentState::AlreadyPresent);
+        assert_eq!(status_of(&stats, "a14999"), AssignmentState::AppliedNew);
+        let written = fs::read_to_string(&keys_path).unwrap();
+        assert!(!written.contains(&generated_key(4_999)));
+        assert!(written.contains(&generated_key(5_000)));
+        assert!(written.contains(&generated_key(14_999)));
+        // Linear planning takes about a second in unoptimised builds; the nested scans it replaced took several
+        assert!(elapsed < std::time::Duration::from_secs(5), "sync of 10k keys took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_parse_key_with_options() {
+        let line = format!("command=\"echo a b\",no-pty {} bob", ED25519_KEY);
+        let key = SshKey::parse(&line).unwrap();
+        assert_eq!(key.options.as_deref(), Some("command=\"echo a b\",no-pty"));
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.fingerprint, SshKey::parse(ED25519_KEY).unwrap().fingerprint);
+        assert_eq!(key.comment.as_deref(), Some("bob"));
+        assert_eq!(key.to_line(), line);
+        assert_eq!(key.to_string(), line);
+
+        // Quoted values may hold spaces, commas and escaped quotes
+        let line = format!("from=\"10.0.0.0/8,192.168.1.0/24\",command=\"echo \\\"hi, there\\\"\" {}", ED25519_KEY);
+        let key = SshKey::parse(&line).unwrap();
+        assert_eq!(key.to_string(), line);
+
+        assert!(SshKey::parse("# ssh-ed25519 commented out").is_err());
+        assert!(SshKey::parse("no-pty ssh-unknown AAAA").is_err());
+        assert!(SshKey::parse(&format!("no-pty restrict {}", ED25519_KEY)).is_err());
+        assert!(SshKey::parse(&format!("command=\"unterminated {}", ED25519_KEY)).is_err());
+    }
+
+    #[test]
+    fn test_external_keys_in_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        write_cloud_init_fixture(&dir.path().join("alice/.ssh/authorized_keys"));
+
+        let inventory = SshKeyManager::new().key_inventory(std::slice::from_ref(&alice)).unwrap();
+        assert_eq!(inventory[0].fingerprints.len(), 3);
+        assert_eq!(
+            inventory[0].externally_managed,
+            vec![SshKey::parse(RSA_KEY).unwrap().fingerprint, SshKey::parse(CLOUD_KEY).unwrap().fingerprint]
+        );
+
+        // Custom markers replace the defaults and match case-insensitively
+        let manager = SshKeyManager::new().with_external_key_markers(vec!["HAND-ADDED".to_string()]);
+        let inventory = manager.key_inventory(&[alice]).unwrap();
+        assert_eq!(inventory[0].externally_managed, vec![SshKey::parse(ED25519_KEY).unwrap().fingerprint]);
+    }
+
+    #[test]
+    fn test_user_and_file_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let bob = user_with_home(dir.path(), "bob", 1001);
+        let carol = UserInfo {
+            home_encryption: Some(crate::users::HomeEncryption::Homed),
+            home_mounted: Some(false),
+            ..user_with_home(dir.path(), "carol", 1002)
+        };
+        let dave = user_with_home(dir.path(), "dave", 1003);
+        let erin = user_with_home(dir.path(), "erin", 1004);
+        let users = [alice, bob, carol, dave, erin];
+
+        let manager = SshKeyManager::new()
+            .with_pubkey_auth_disabled(HashSet::from(["bob".to_string()]))
+            .with_path_filters(Vec::new(), vec!["**/dave/**".to_string()])
+            .with_user_filter(UserFilter {
+                include: Vec::new(),
+                exclude: vec!["erin".to_string()],
+                source: crate::users::FilterSource::Local,
+            });
+        // Two AuthorizedKeysFile patterns give alice two files, but she is still one user
+        let patterns = vec![".ssh/authorized_keys".to_string(), ".ssh/authorized_keys2".to_string()];
+        let stats = manager
+            .sync_with_patterns(&users, &[test_assignment("alice", "a1")], &patterns, true)
+            .unwrap();
+
+        assert_eq!(stats.users_considered, 5);
+        assert_eq!(stats.users_processed, 1);
+        assert_eq!(stats.users_skipped, 4);
+        assert_eq!(
+            stats.users_skipped_reasons,
+            BTreeMap::from([
+                ("home-not-mounted".to_string(), 1),
+                ("no-files-in-scope".to_string(), 1),
+                ("pubkey-auth-disabled".to_string(), 1),
+                ("user-filter".to_string(), 1),
+            ])
+        );
+        assert_eq!(stats.files_examined, 2);
+        assert_eq!(stats.files_updated, 2);
+        assert_eq!(stats.files_excluded, 2);
+    }
+
+    #[test]
+    fn test_interrupted_sync_leaves_remaining_users_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = [user_with_home(dir.path(), "alice", 1000), user_with_home(dir.path(), "bob", 1001)];
+        let interrupt = Interrupt::default();
+        interrupt.trigger();
+        let manager = SshKeyManager::new().with_interrupt(interrupt);
+        let patterns = vec![".ssh/authorized_keys".to_string()];
+        let stats = manager
+            .sync_with_patterns(&users, &[test_assignment("alice", "a1"), test_assignment("bob", "b1")], &patterns, false)
+            .unwrap();
+
+        assert!(stats.interrupted);
+        assert_eq!(stats.files_updated, 0);
+        assert_eq!(stats.users_skipped_reasons, BTreeMap::from([("interrupted".to_string(), 2)]));
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::Interrupted);
+        assert_eq!(stats.assignment_statuses["b1"].status.outcome(), AckOutcome::Skipped);
+        assert!(!dir.path().join("alice/.ssh").exists());
+    }
+
+    /// Dry-run plan adding ED25519_KEY to alice and bob and removing RSA_KEY from alice
+    fn mixed_plan() -> KeySyncStats {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let bob = user_with_home(dir.path(), "bob", 1001);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        let manager = SshKeyManager::new();
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n{}\n", manager.managed_marker, RSA_KEY)).unwrap();
+        let assignments = vec![test_assignment("alice", "a1"), test_assignment("bob", "b1")];
+        manager.sync_ssh_keys(&[alice, bob], &assignments, true, false).unwrap()
+    }
+
+    #[test]
+    fn test_dry_run_scope_all() {
+        let mut stats = mixed_plan();
+        stats.limit_to(DryRunScope::All);
+        assert_eq!((stats.keys_added, stats.keys_removed, stats.files_updated), (2, 1, 2));
+        assert_eq!(stats.changes.len(), 2);
+        assert!(stats.plan_totals.is_none() && stats.dry_run_scope.is_none());
+    }
+
+    #[test]
+    fn test_dry_run_scope_removals() {
+        let mut stats = mixed_plan();
+        stats.limit_to(DryRunScope::Removals);
+        assert_eq!((stats.keys_added, stats.keys_removed, stats.files_updated), (0, 1, 1));
+        assert_eq!(stats.changes.len(), 1);
+        assert_eq!(stats.changes[0].username, "alice");
+        assert!(stats.changes[0].added.is_empty());
+        assert_eq!(stats.plan_totals, Some(PlanTotals { keys_added: 2, keys_removed: 1, files_updated: 2 }));
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["dry_run_scope"], "removals");
+        assert_eq!(json["plan_totals"]["keys_added"], 2);
+    }
+
+    #[test]
+    fn test_dry_run_scope_additions() {
+        let mut stats = mixed_plan();
+        stats.limit_to(DryRunScope::Additions);
+        assert_eq!((stats.keys_added, stats.keys_removed, stats.files_updated), (2, 0, 2));
+        assert!(stats.changes.iter().all(|change| change.removed.is_empty()));
+        assert_eq!(stats.plan_totals.as_ref().unwrap().keys_removed, 1);
+    }
+
+    #[test]
+    fn test_user_skipped_while_another_tool_holds_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, format!("{}\n", RSA_KEY)).unwrap();
+
+        // A second writer holds the sidecar lock from another thread
+        let (taken, wait) = std::sync::mpsc::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let lock_target = keys_path.clone();
+        let holder = std::thread::spawn(move || {
+            let _lock = keylock::lock(&lock_target, Duration::ZERO).unwrap().unwrap();
+            taken.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        wait.recv().unwrap();
+
+        let manager = SshKeyManager::new().with_lock_timeout(Duration::from_millis(100));
+        let assignments = vec![test_assignment("alice", "a1")];
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, false, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::LockTimeout);
+        assert_eq!(stats.lock_timeouts.len(), 1);
+        assert_eq!((stats.keys_added, stats.errors), (0, 0));
+        assert_eq!(fs::read_to_string(&keys_path).unwrap(), format!("{}\n", RSA_KEY));
+
+        // Dry runs only read, so they don't wait for the lock
+        let stats = manager.sync_ssh_keys(std::slice::from_ref(&alice), &assignments, true, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::Pending);
+
+        release.send(()).unwrap();
+        holder.join().unwrap();
+        let stats = manager.sync_ssh_keys(&[alice], &assignments, false, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::AppliedNew);
+        assert!(stats.lock_timeouts.is_empty());
+    }
+
+    #[test]
+    fn test_assignment_status_excluded_by_path_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let assignments = vec![test_assignment("alice", "a1")];
+
+        let manager = SshKeyManager::new().with_path_filters(Vec::new(), vec!["**/alice/**".to_string()]);
+        let stats = manager.sync_ssh_keys(&[alice], &assignments, true, false).unwrap();
+        assert_eq!(status_of(&stats, "a1"), AssignmentState::ExcludedByFilter);
+    }
+
+    #[test]
+    fn test_fingerprint_cache_decodes_each_key_once() {
+        use base64::Engine;
+
+        // Thousands of distinct keys on disk, all of them also assigned
+        const KEYS: usize = 5_000;
+        let lines: Vec<String> = (0..KEYS)
+            .map(|i| {
+                let mut blob = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20".to_vec();
+                blob.extend_from_slice(&[0u8; 24]);
+                blob.extend_from_slice(&(i as u64).to_be_bytes());
+                format!("ssh-ed25519 {}", base64::engine::general_purpose::STANDARD.encode(blob))
+            })
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let user = user_with_home(dir.path(), "alice", 1000);
+        let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+        fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+        fs::write(&keys_path, lines.join("\n")).unwrap();
+
+        let assignments: Vec<KeyAssignment> = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| KeyAssignment {
+                public_key: line.clone(),
+                ..test_assignment("alice", &format!("a{}", i))
+            })
+            .collect();
+
+        let manager = SshKeyManager::new();
+        let stats = manager.sync_ssh_keys(&[user], &assignments, true, false).unwrap();
+        assert_eq!(stats.keys_added, 0);
+        assert_eq!(stats.keys_removed, 0);
+
+        // Each key is seen three times (policy check, plan, existing file) but decoded once
+        let counters = manager.parse_counters();
+        assert_eq!(counters.decodes, KEYS as u64);
+        assert_eq!(counters.keys_parsed, 3 * KEYS as u64);
+        assert_eq!(counters.cache_hits, 2 * KEYS as u64);
+    }
+
+    #[test]
+    fn test_cached_and_uncached_fingerprints_agree() {
+        let cache = FingerprintCache::default();
+        let cached = SshKey::parse_with(ED25519_KEY, Some(&cache)).unwrap();
+        let again = SshKey::parse_with(ED25519_KEY, Some(&cache)).unwrap();
+        assert_eq!(cached.fingerprint, SshKey::parse(ED25519_KEY).unwrap().fingerprint);
+        assert_eq!(again.fingerprint, cached.fingerprint);
+        assert_eq!(cache.counters(), ParseCounters { keys_parsed: 2, cache_hits: 1, decodes: 1 });
+        assert!(SshKey::parse_with("ssh-ed25519 not-base64!", Some(&cache)).is_err());
+    }
+
+    #[test]
+    fn test_deferred_removals_outside_canary() {
+        for (defer, dry_run) in [(false, false), (true, false), (false, true), (true, true)] {
+            let dir = tempfile::tempdir().unwrap();
+            let alice = user_with_home(dir.path(), "alice", 1000);
+            let keys_path = dir.path().join("alice/.ssh/authorized_keys");
+            let manager = SshKeyManager::new().with_defer_removals(defer);
+            fs::create_dir_all(keys_path.parent().unwrap()).unwrap();
+            fs::write(&keys_path, format!("{}\n{}\n", manager.managed_marker, RSA_KEY)).unwrap();
+
+            let stats = manager
+                .sync_ssh_keys(&[alice], &[test_assignment("alice", "a1")], dry_run, false)
+                .unwrap();
+
+            let case = format!("defer={} dry_run={}", defer, dry_run);
+            assert_eq!(stats.keys_added, 1, "{}", case);
+            assert_eq!(stats.keys_removed, if defer { 0 } else { 1 }, "{}", case);
+            assert_eq!(stats.removals_deferred, if defer { 1 } else { 0 }, "{}", case);
+
+            let written = fs::read_to_string(&keys_path).unwrap();
+            assert_eq!(written.contains(ED25519_KEY), !dry_run, "{}", case);
+            assert_eq!(written.contains(RSA_KEY), defer || dry_run, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_sync_deploys_by_uid_after_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("alice");
+        fs::create_dir_all(&home).unwrap();
+        let mut user = test_user("alice", 1000);
+        user.home_dir = Some(home.to_string_lossy().to_string());
+
+        let manager = SshKeyManager::new();
+        let assignments = vec![keyed_assignment("alice.old", Some(1000), None)];
+        let stats = manager.sync_ssh_keys(&[user], &assignments, true, false).unwrap();
+        assert_eq!(stats.assignments_rejected, 0);
+        assert_eq!(stats.keys_added, 1);
+    }
+
+    const ED25519_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e";
+
+    fn write_managed_file(manager: &SshKeyManager, path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{}\n{}\n", manager.managed_marker, ED25519_KEY)).unwrap();
+    }
+
+    #[test]
+    fn test_find_orphaned_files() {
+        let manager = SshKeyManager::new();
+        let dir = tempfile::tempdir().unwrap();
+        let home_root = dir.path().join("home");
+        let keys_dir = dir.path().join("keys");
+
+        // Managed files for a known and a deleted user in both locations
+        write_managed_file(&manager, &home_root.join("alice/.ssh/authorized_keys"));
+        write_managed_file(&manager, &home_root.join("olduser/.ssh/authorized_keys"));
+        write_managed_file(&manager, &keys_dir.join("olduser"));
+        // Unmanaged file for a deleted user is left alone
+        fs::create_dir_all(home_root.join("other/.ssh")).unwrap();
+        fs::write(home_root.join("other/.ssh/authorized_keys"), format!("{}\n", ED25519_KEY)).unwrap();
+
+        let patterns = vec![
+            ".ssh/authorized_keys".to_string(),
+            format!("{}/%u", keys_dir.display()),
+        ];
+        let mut orphans = manager.find_orphaned_files_in(&patterns, std::slice::from_ref(&home_root), &[test_user("alice", 1000)]);
+        orphans.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let paths: Vec<_> = orphans.iter().map(|o| o.path.clone()).collect();
+        assert_eq!(paths, vec![home_root.join("olduser/.ssh/authorized_keys"), keys_dir.join("olduser")]);
+        assert!(orphans.iter().all(|o| o.username == "olduser" && o.key_count == 1 && !o.deleted));
+        assert!(orphans.iter().all(|o| o.last_modified.is_some()));
+    }
+
+    #[test]
+    fn test_artifacts_are_not_key_files() {
+        let manager = SshKeyManager::new();
+        for decoy in [
+            "authorized_keys~", "authorized_keys.bak", ".authorized_keys.swp", "authorized_keys.tmp",
+            "#authorized_keys#", ".#authorized_keys", "authorized_keys.dpkg-old",
+        ] {
+            assert!(!manager.is_key_file_target(&Path::new("/home/alice/.ssh").join(decoy)), "{}", decoy);
+        }
+        assert!(manager.is_key_file_target(Path::new("/home/alice/.ssh/authorized_keys")));
+        assert!(manager.is_key_file_target(Path::new("/home/alice/.ssh/authorized_keys2")));
+
+        let manager = manager.with_artifact_suffixes(vec![".keep".to_string()]);
+        assert!(manager.is_key_file_target(Path::new("/home/alice/.ssh/authorized_keys.bak")));
+        assert!(!manager.is_key_file_target(Path::new("/home/alice/.ssh/authorized_keys.keep")));
+    }
+
+    #[test]
+    fn test_decoy_files_ignored_by_orphan_scan_and_discovery() {
+        let manager = SshKeyManager::new();
+        let dir = tempfile::tempdir().unwrap();
+        let keys_dir = dir.path().join("keys");
+        for name in ["olduser", "olduser~", "olduser.bak", ".olduser.swp", "olduser.tmp", "#olduser#"] {
+            write_managed_file(&manager, &keys_dir.join(name));
+        }
+
+        let patterns = vec![format!("{}/%u", keys_dir.display())];
+        let orphans = manager.find_orphaned_files_in(&patterns, &[], &[]);
+        let paths: Vec<_> = orphans.iter().map(|o| o.path.clone()).collect();
+        assert_eq!(paths, vec![keys_dir.join("olduser")]);
+
+        // A pattern pointing at a backup is never managed
+        let alice = user_with_home(dir.path(), "alice", 1000);
+        let patterns = vec![".ssh/authorized_keys".to_string(), ".ssh/authorized_keys.bak".to_string()];
+        let discovered = manager.expand_authorized_keys_files(&[alice], &patterns);
+        let paths: Vec<_> = discovered.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, vec![dir.path().join("alice/.ssh/authorized_keys")]);
+    }
+
+    #[test]
+    fn test_delete_orphaned_files() {
+        let manager = SshKeyManager::new();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("home/olduser/.ssh/authorized_keys");
+        write_managed_file(&manager, &path);
+
+        let mut orphans = manager.find_orphaned_files_in(&[".ssh/authorized_keys".to_string()], &[dir.path().join("home")], &[]);
+        assert_eq!(orphans.len(), 1);
+
+        // Within the grace period nothing is deleted
+        manager.delete_orphaned_files(&mut orphans, std::time::Duration::from_secs(3600), false);
+        assert!(path.exists());
+        assert!(!orphans[0].deleted);
+
+        // Dry run never deletes
+        manager.delete_orphaned_files(&mut orphans, std::time::Duration::ZERO, true);
+        assert!(path.exists());
+
+        manager.delete_orphaned_files(&mut orphans, std::time::Duration::ZERO, false);
+        assert!(!path.exists());
+        assert!(orphans[0].deleted);
+    }
+
+    #[test]
+    fn test_orphaned_block_files() {
+        let manager = SshKeyManager::new();
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        // Written by a sync in block mode, one file the agent created and one with the user's own lines
+        let alice = user_with_home(&home, "alice", 1000);
+        let bob = user_with_home(&home, "bob", 1001);
+        let bob_path = home.join("bob/.ssh/authorized_keys");
+        let hand_added = format!("# my laptop\n{} bob@laptop\n", RSA_KEY);
+        fs::create_dir_all(bob_path.parent().unwrap()).unwrap();
+        fs::write(&bob_path, &hand_added).unwrap();
+        let assignments = [test_assignment("alice", "a1"), test_assignment("bob", "b1")];
+        manager.sync_ssh_keys(&[alice, bob], &assignments, false, false).unwrap();
+        let alice_path = home.join("alice/.ssh/authorized_keys");
+        assert!(fs::read_to_string(&alice_path).unwrap().starts_with(BLOCK_BEGIN));
+
+        // Both users are deleted
+        let mut orphans = manager.find_orphaned_files_in(&[".ssh/authorized_keys".to_string()], std::slice::from_ref(&home), &[]);
+        orphans.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<_> = orphans.iter().map(|o| o.path.clone()).collect();
+        assert_eq!(paths, vec![alice_path.clone(), bob_path.clone()]);
+        // Only the managed keys count
+        assert!(orphans.iter().all(|o| o.key_count == 1));
+
+        manager.delete_orphaned_files(&mut orphans, std::time::Duration::ZERO, true);
+        assert!(alice_path.exists());
+        assert!(fs::read_to_string(&bob_path).unwrap().contains(BLOCK_BEGIN));
+
+        manager.delete_orphaned_files(&mut orphans, std::time::Duration::ZERO, false);
+        assert!(!alice_path.exists());
+        assert!(orphans[0].deleted);
+        // The user's own lines stay; the block goes
+        assert_eq!(fs::read_to_string(&bob_path).unwrap(), hand_added);
+        assert!(!orphans[1].deleted && orphans[1].block_removed);
+    }
+
+    #[test]
+    fn test_unmanaged_lines_round_trip_byte_for_byte() {
+        let fixture = "# added by hand\r\n\
+\n\
+ssh-ed25519   AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e\talice@laptop  # old key\n\
+\t ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e   \n\
+not a key at all\n\
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMRzhlN/DHptVc+onPgMzh73YshU9/T3BLEkip0gGx9e";
+
+        let entries = parse_authorized_keys(fixture, None);
+        let keys = entries.iter().filter(|e| matches!(e, AuthorizedKeysEntry::Key(_))).count();
+        assert_eq!(keys, 3);
+        assert_eq!(render_authorized_keys(&entries, false), fixture);
+
+        let with_newline = format!("{}\n", fixture);
+        assert_eq!(render_authorized_keys(&parse_authorized_keys(&with_newline, None), true), with_newline);
+    }
+
+    #[test]
+    fn test_managed_keys_use_canonical_form() {
+        let manager = SshKeyManager::new();
+        let mut assignment = test_assignment("alice", "a1");
+        assignment.public_key = format!("  {}\t  alice@laptop ", ED25519_KEY);
+
+        let key = manager.assignment_to_ssh_key(&assignment).unwrap();
+        assert_eq!(key.to_line(), format!("{} alice@laptop", ED25519_KEY));
+    }
+
+    #[test]
+    fn test_path_filters_during_discovery() {
+        let users = vec![test_user("alice", 1000), test_user("bob", 1001)];
+        let patterns = vec![".ssh/authorized_keys".to_string(), "/etc/ssh/keys/%u".to_string()];
+
+        let manager = SshKeyManager::new().with_path_filters(Vec::new(), vec!["/etc/ssh/keys/*".to_string()]);
+        let discovered = manager.expand_authorized_keys_files(&users, &patterns);
+        assert_eq!(discovered.excluded, 2);
+        assert!(discovered.files.iter().all(|f| f.path.ends_with(".ssh/authorized_keys")));
+
+        let manager = SshKeyManager::new().with_path_filters(vec!["/home/alice/**".to_string()], Vec::new());
+        let discovered = manager.expand_authorized_keys_files(&users, &patterns);
+        assert_eq!(discovered.excluded, 3);
+        assert_eq!(discovered.files.len(), 1);
+        assert_eq!(discovered.files[0].path, PathBuf::from("/home/alice/.ssh/authorized_keys"));
+
+        let manager = SshKeyManager::new().with_path_filters(
+            vec!["/home/**".to_string(), "/etc/ssh/keys/*".to_string()],
+            vec!["/etc/ssh/keys/bob".to_string()],
+        );
+        let discovered = manager.expand_authorized_keys_files(&users, &patterns);
+        assert_eq!(discovered.excluded, 1);
+        assert_eq!(discovered.files.len(), 3);
+    }
+
+    #[test]
+    fn test_root_home_from_passwd() {
+        let manager = SshKeyManager::new();
+        let patterns = vec![".ssh/authorized_keys".to_string()];
+
+        let mut root = test_user("root", 0);
+        root.home_dir = Some("/var/root".to_string());
+        let discovered = manager.expand_authorized_keys_files(&[root.clone()], &patterns);
+        assert_eq!(discovered.files[0].path, PathBuf::from("/var/root/.ssh/authorized_keys"));
+
+        // Empty home falls back to /root
+        root.home_dir = Some(String::new());
+        let discovered = manager.expand_authorized_keys_files(&[root], &patterns);
+        assert_eq!(discovered.files[0].path, PathBuf::from("/root/.ssh/authorized_keys"));
+    }
+
+    #[test]
+    fn test_fallback_home_selection() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = vec![dir.path().join("home"), dir.path().join("data/home"), dir.path().join("srv/homes")];
+        fs::create_dir_all(dir.path().join("srv/homes/alice")).unwrap();
+
+        // The root that already holds the user's directory wins, otherwise the first root
+        assert_eq!(fallback_home("alice", &roots), dir.path().join("srv/homes/alice"));
+        assert_eq!(fallback_home("bob", &roots), dir.path().join("home/bob"));
+        assert_eq!(fallback_home("bob", &[]), PathBuf::from("/home/bob"));
+
+        let manager = SshKeyManager::new().with_home_roots(roots);
+        let mut alice = test_user("alice", 1000);
+        alice.home_dir = None;
+        let discovered = manager.expand_authorized_keys_files(&[alice], &[".ssh/authorized_keys".to_string()]);
+        assert_eq!(discovered.files[0].path, dir.path().join("srv/homes/alice/.ssh/authorized_keys"));
+    }
+
+    #[test]
+    fn test_home_outside_roots() {
+        let roots = vec![PathBuf::from("/home"), PathBuf::from("/data/home")];
+        assert!(!is_outside_home_roots(Path::new("/home/alice"), &roots));
+        assert!(!is_outside_home_roots(Path::new("/data/home/bob"), &roots));
+        assert!(is_outside_home_roots(Path::new("/data/homes/bob"), &roots));
+        assert!(is_outside_home_roots(Path::new("/etc"), &roots));
+        assert!(is_outside_home_roots(Path::new("/home"), &roots));
+        assert!(is_outside_home_roots(Path::new("/home/../etc/alice"), &roots));
+
+        // Users outside the roots are warned about, not skipped
+        let manager = SshKeyManager::new().with_home_roots(roots);
+        let mut carol = test_user("carol", 1002);
+        carol.home_dir = Some("/opt/carol".to_string());
+        let discovered = manager.expand_authorized_keys_files(&[carol], &[".ssh/authorized_keys".to_string()]);
+        assert_eq!(discovered.files[0].path, PathBuf::from("/opt/carol/.ssh/authorized_keys"));
+    }
+
+    #[test]
+    fn test_denylisted_locations_refused() {
+        let manager = SshKeyManager::new();
+        let mut root = test_user("root", 0);
+        root.home_dir = Some("/".to_string());
+
+        // Home of / would put keys in /.ssh
+        let discovered = manager.expand_authorized_keys_files(std::slice::from_ref(&root), &[".ssh/authorized_keys".to_string()]);
+        assert!(discovered.files.is_empty());
+        assert_eq!(discovered.denied, vec![PathBuf::from("/.ssh/authorized_keys")]);
+
+        // Absolute pattern directly in /etc is refused, a subdirectory is fine
+        let discovered = manager.expand_authorized_keys_files(
+            &[test_user("alice", 1000)],
+            &["/etc/%u_keys".to_string(), "/etc/ssh/keys/%u".to_string()],
+        );
+        assert_eq!(discovered.denied, vec![PathBuf::from("/etc/alice_keys")]);
+        assert_eq!(discovered.files.len(), 1);
+
+        // The denylist is configurable
+        let manager = SshKeyManager::new().with_denied_key_dirs(vec![PathBuf::from("/etc/ssh/keys")]);
+        let discovered = manager.expand_authorized_keys_files(&[test_user("alice", 1000)], &["/etc/ssh/keys/%u".to_string()]);
+        assert_eq!(discovered.denied.len(), 1);
+        let discovered = manager.expand_authorized_keys_files(&[root], &[".ssh/authorized_keys".to_string()]);
+        assert_eq!(discovered.files.len(), 1);
+    }
+}
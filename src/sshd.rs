@@ -0,0 +1,296 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::glob::glob_match;
+
+/// Common sshd_config locations, first readable one wins
+pub const SSHD_CONFIG_PATHS: &[&str] = &[
+    "/etc/ssh/sshd_config",
+    "/etc/sshd_config",
+    "/usr/local/etc/ssh/sshd_config",
+];
+
+/// Value of sshd's PermitRootLogin setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermitRootLogin {
+    Yes,
+    No,
+    /// Also spelled `without-password`; key authentication still works
+    ProhibitPassword,
+    ForcedCommandsOnly,
+}
+
+impl PermitRootLogin {
+    /// OpenSSH default since 7.0
+    pub const DEFAULT: PermitRootLogin = PermitRootLogin::ProhibitPassword;
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "yes" => Some(PermitRootLogin::Yes),
+            "no" => Some(PermitRootLogin::No),
+            "prohibit-password" | "without-password" => Some(PermitRootLogin::ProhibitPassword),
+            "forced-commands-only" => Some(PermitRootLogin::ForcedCommandsOnly),
+            _ => None,
+        }
+    }
+
+    /// Whether root can log in with the keys the agent deploys.
+    ///
+    /// `forced_command_assignments` tells whether root's assignments carry a forced command, which
+    /// is the only way keys are usable under `forced-commands-only`.
+    pub fn allows_key_login(self, forced_command_assignments: bool) -> bool {
+        match self {
+            PermitRootLogin::Yes | PermitRootLogin::ProhibitPassword => true,
+            PermitRootLogin::ForcedCommandsOnly => forced_command_assignments,
+            PermitRootLogin::No => false,
+        }
+    }
+}
+
+impl fmt::Display for PermitRootLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PermitRootLogin::Yes => "yes",
+            PermitRootLogin::No => "no",
+            PermitRootLogin::ProhibitPassword => "prohibit-password",
+            PermitRootLogin::ForcedCommandsOnly => "forced-commands-only",
+        })
+    }
+}
+
+/// PermitRootLogin from `sshd -T` output (lowercase `keyword value` lines)
+pub fn parse_sshd_t(output: &str) -> Option<PermitRootLogin> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("permitrootlogin "))
+        .and_then(PermitRootLogin::parse)
+}
+
+/// PermitRootLogin from sshd_config content; like sshd, the first global directive wins
+pub fn parse_sshd_config(content: &str) -> Option<PermitRootLogin> {
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, |c: char| c.is_whitespace() || c == '=');
+        let keyword = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default().trim_start_matches([' ', '\t', '=']);
+        if keyword.eq_ignore_ascii_case("match") {
+            // Conditional blocks only apply to some connections
+            break;
+        }
+        if keyword.eq_ignore_ascii_case("permitrootlogin") {
+            return PermitRootLogin::parse(value);
+        }
+    }
+    None
+}
+
+/// Effective PermitRootLogin on this host: `sshd -T` when available, sshd_config otherwise
+pub fn effective_permit_root_login() -> PermitRootLogin {
+    if let Ok(output) = Command::new("sshd").arg("-T").output()
+        && output.status.success()
+        && let Some(value) = parse_sshd_t(&String::from_utf8_lossy(&output.stdout))
+    {
+        debug!("PermitRootLogin from sshd -T: {}", value);
+        return value;
+    }
+
+    for config_path in SSHD_CONFIG_PATHS {
+        if let Ok(content) = fs::read_to_string(config_path) {
+            let value = parse_sshd_config(&content).unwrap_or(PermitRootLogin::DEFAULT);
+            info!("PermitRootLogin from {}: {}", config_path, value);
+            return value;
+        }
+    }
+    PermitRootLogin::DEFAULT
+}
+
+/// Whether a username matches an sshd pattern list like `contractor*,!contractor-admin`
+fn user_matches_patterns(username: &str, patterns: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, username) => return false,
+            Some(_) => {}
+            None => matched |= glob_match(pattern, username),
+        }
+    }
+    matched
+}
+
+/// Whether a `Match` line applies to `username`; `None` when it depends on criteria we cannot evaluate
+fn match_applies(criteria: &str, username: &str) -> Option<bool> {
+    let words: Vec<&str> = criteria.split_whitespace().collect();
+    match words.as_slice() {
+        [all] if all.eq_ignore_ascii_case("all") => Some(true),
+        [user, patterns] if user.eq_ignore_ascii_case("user") => Some(user_matches_patterns(username, patterns)),
+        _ => None,
+    }
+}
+
+/// PubkeyAuthentication for `username` from sshd_config content.
+///
+/// Only `Match User` and `Match all` blocks are evaluated; blocks with other criteria are
+/// ignored, so this never reports pubkey auth as off when that depends on the connection.
+/// The first value from a matching block wins over the global one, as in sshd.
+pub fn parse_pubkey_auth_for_user(content: &str, username: &str) -> bool {
+    let mut global = None;
+    let mut in_match = false;
+    let mut block_applies = false;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, |c: char| c.is_whitespace() || c == '=');
+        let keyword = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default().trim_start_matches([' ', '\t', '=']).trim();
+        if keyword.eq_ignore_ascii_case("match") {
+            in_match = true;
+            block_applies = match_applies(value, username).unwrap_or(false);
+            continue;
+        }
+        if !keyword.eq_ignore_ascii_case("pubkeyauthentication") {
+            continue;
+        }
+
+        let enabled = !value.eq_ignore_ascii_case("no");
+        if in_match {
+            if block_applies {
+                return enabled;
+            }
+        } else if global.is_none() {
+            global = Some(enabled);
+        }
+    }
+    global.unwrap_or(true)
+}
+
+/// PubkeyAuthentication for `username` from `sshd -T -C`, if sshd can be run
+fn sshd_t_pubkey_auth(username: &str) -> Option<bool> {
+    let output = Command::new("sshd")
+        .arg("-T")
+        .arg("-C")
+        .arg(format!("user={},host=localhost,addr=127.0.0.1", username))
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("pubkeyauthentication "))
+        .map(|value| value.trim() != "no")
+}
+
+/// Users for whom sshd has public key authentication turned off
+pub fn pubkey_auth_disabled_users<'a>(usernames: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+    let config = SSHD_CONFIG_PATHS.iter().find_map(|path| fs::read_to_string(path).ok());
+    let mut sshd_available = true;
+    let mut disabled = HashSet::new();
+    for username in usernames {
+        let enabled = sshd_available
+            .then(|| sshd_t_pubkey_auth(username))
+            .flatten()
+            .or_else(|| {
+                sshd_available = false;
+                config.as_deref().map(|content| parse_pubkey_auth_for_user(content, username))
+            })
+            .unwrap_or(true);
+        if !enabled {
+            warn!("Public key authentication is disabled for user {} in sshd", username);
+            disabled.insert(username.to_string());
+        }
+    }
+    disabled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(PermitRootLogin::parse("yes"), Some(PermitRootLogin::Yes));
+        assert_eq!(PermitRootLogin::parse("No"), Some(PermitRootLogin::No));
+        assert_eq!(PermitRootLogin::parse("prohibit-password"), Some(PermitRootLogin::ProhibitPassword));
+        assert_eq!(PermitRootLogin::parse("without-password"), Some(PermitRootLogin::ProhibitPassword));
+        assert_eq!(PermitRootLogin::parse("forced-commands-only"), Some(PermitRootLogin::ForcedCommandsOnly));
+        assert_eq!(PermitRootLogin::parse("maybe"), None);
+    }
+
+    #[test]
+    fn test_key_login_decision() {
+        for forced in [false, true] {
+            assert!(PermitRootLogin::Yes.allows_key_login(forced));
+            assert!(PermitRootLogin::ProhibitPassword.allows_key_login(forced));
+            assert!(!PermitRootLogin::No.allows_key_login(forced));
+        }
+        assert!(!PermitRootLogin::ForcedCommandsOnly.allows_key_login(false));
+        assert!(PermitRootLogin::ForcedCommandsOnly.allows_key_login(true));
+    }
+
+    #[test]
+    fn test_parse_sshd_t() {
+        let output = "port 22\npermitrootlogin without-password\npubkeyauthentication yes\n";
+        assert_eq!(parse_sshd_t(output), Some(PermitRootLogin::ProhibitPassword));
+        assert_eq!(parse_sshd_t("port 22\n"), None);
+    }
+
+    const MATCH_CONFIG: &str = "\
+PubkeyAuthentication yes
+PasswordAuthentication no
+
+Match User contractor*,!contractor-admin
+    PubkeyAuthentication no
+    X11Forwarding no
+
+Match Address 10.0.0.0/8
+    PubkeyAuthentication no
+
+Match User legacy
+PubkeyAuthentication=no
+";
+
+    #[test]
+    fn test_match_user_pubkey_auth() {
+        assert!(parse_pubkey_auth_for_user(MATCH_CONFIG, "alice"));
+        assert!(!parse_pubkey_auth_for_user(MATCH_CONFIG, "contractor-bob"));
+        assert!(parse_pubkey_auth_for_user(MATCH_CONFIG, "contractor-admin"));
+        assert!(!parse_pubkey_auth_for_user(MATCH_CONFIG, "legacy"));
+
+        // A global `no` applies unless a matching block turns it back on
+        let config = "PubkeyAuthentication no\nMatch User admin\n  PubkeyAuthentication yes\n";
+        assert!(!parse_pubkey_auth_for_user(config, "alice"));
+        assert!(parse_pubkey_auth_for_user(config, "admin"));
+
+        assert!(!parse_pubkey_auth_for_user("Match all\nPubkeyAuthentication no\n", "alice"));
+        assert!(parse_pubkey_auth_for_user("Port 22\n", "alice"));
+    }
+
+    #[test]
+    fn test_user_pattern_lists() {
+        assert!(user_matches_patterns("contractor1", "contractor*"));
+        assert!(user_matches_patterns("bob", "alice, bob"));
+        assert!(!user_matches_patterns("contractor-admin", "contractor*,!contractor-admin"));
+        assert!(!user_matches_patterns("alice", "!bob"));
+        assert!(user_matches_patterns("ab", "a?"));
+    }
+
+    #[test]
+    fn test_parse_sshd_config() {
+        let config = "# PermitRootLogin yes\nPort 22\npermitrootlogin no\nPermitRootLogin yes\n";
+        assert_eq!(parse_sshd_config(config), Some(PermitRootLogin::No));
+        assert_eq!(parse_sshd_config("PermitRootLogin=forced-commands-only\n"), Some(PermitRootLogin::ForcedCommandsOnly));
+        assert_eq!(parse_sshd_config("Port 22\n"), None);
+
+        // Directives inside Match blocks are not global
+        let config = "Port 22\nMatch Address 10.0.0.0/8\n    PermitRootLogin yes\n";
+        assert_eq!(parse_sshd_config(config), None);
+    }
+}
@@ -0,0 +1,435 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, anyhow};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::fsutil;
+use crate::pin;
+
+/// Current layout of the state directory
+pub const LAYOUT_VERSION: u32 = 2;
+
+/// File holding the layout version
+const VERSION_FILE: &str = "version";
+
+/// Migration from layout `from` to `from + 1`
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(&Path) -> Result<()>,
+}
+
+/// Layout 1 is the unversioned directory written by earlier releases
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "restrict state to the agent's user (0700 directories, 0600 files)",
+    apply: restrict_permissions,
+}];
+
+/// State directory of a system install, running as root
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/publikey";
+
+/// Default directory for agent state (spool, caches, locks): the system one for root, otherwise
+/// (and always in --user-mode) the user's
+pub fn default_state_dir(user_mode: bool) -> PathBuf {
+    if nix::unistd::getuid().is_root() && !user_mode {
+        return PathBuf::from(SYSTEM_STATE_DIR);
+    }
+
+    match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("publikey"),
+        _ => match std::env::var("HOME") {
+            Ok(home) => PathBuf::from(home).join(".local/state/publikey"),
+            Err(_) => std::env::temp_dir().join("publikey"),
+        },
+    }
+}
+
+/// State that `reset-state --keep` can preserve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeepState {
+    /// The pinned endpoint certificate
+    TokenPin,
+}
+
+impl KeepState {
+    fn file_name(self) -> &'static str {
+        match self {
+            KeepState::TokenPin => pin::PIN_FILE,
+        }
+    }
+}
+
+/// The agent's state directory; all persisted state goes through here
+#[derive(Debug, Clone)]
+pub struct StateDir {
+    path: PathBuf,
+    /// The directory could not be used: nothing is read or written
+    stateless: bool,
+}
+
+impl StateDir {
+    /// Open the state directory at `path`, creating it and migrating older layouts
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let state = Self { path: path.into(), stateless: false };
+        create_private_dir(&state.path)?;
+        state.migrate()?;
+        Ok(state)
+    }
+
+    /// Like [`StateDir::open`], but a directory that cannot be created or written (e.g. on a
+    /// read-only filesystem) leaves the agent running without state instead of failing the run
+    pub fn open_or_stateless(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match Self::open(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!(
+                    "State directory {} is not usable ({:#}); running without state: no caches, report fingerprint, certificate pin or spool",
+                    path.display(),
+                    e
+                );
+                Self { path, stateless: true }
+            }
+        }
+    }
+
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Layout version on disk: fresh directories are current, unversioned ones are layout 1
+    fn layout_version(&self) -> Result<u32> {
+        match fs::read_to_string(self.path.join(VERSION_FILE)) {
+            Ok(content) => Ok(content.trim().parse().unwrap_or_else(|_| {
+                // Migrations are idempotent, so replaying them from the start is safe
+                warn!("Unreadable state layout version {:?}, migrating from layout 1", content.trim());
+                1
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let empty = fs::read_dir(&self.path)
+                    .context(format!("Failed to read {}", self.path.display()))?
+                    .next()
+                    .is_none();
+                Ok(if empty { LAYOUT_VERSION } else { 1 })
+            }
+            Err(e) => Err(anyhow!("Failed to read state layout version: {}", e)),
+        }
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let mut version = self.layout_version()?;
+        if version > LAYOUT_VERSION {
+            warn!(
+                "State directory {} has layout {} from a newer agent (this one knows {}); leaving it as is",
+                self.path.display(),
+                version,
+                LAYOUT_VERSION
+            );
+            return Ok(());
+        }
+
+        let from = version;
+        for migration in MIGRATIONS.iter().filter(|m| m.from >= from) {
+            info!("Migrating state layout {} -> {}: {}", migration.from, migration.from + 1, migration.description);
+            (migration.apply)(&self.path)
+                .context(format!("Failed to migrate state layout {} -> {}", migration.from, migration.from + 1))?;
+            version = migration.from + 1;
+        }
+        if self.read_text(VERSION_FILE) != Some(version.to_string()) {
+            self.write_text(VERSION_FILE, &format!("{}\n", version))?;
+        }
+        Ok(())
+    }
+
+    /// Path of a file or subdirectory inside the state directory
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// A private subdirectory, created if missing
+    pub fn subdir(&self, name: &str) -> Result<PathBuf> {
+        if self.stateless {
+            return Err(anyhow!("no usable state directory for {}", name));
+        }
+        let path = self.file(name);
+        create_private_dir(&path)?;
+        Ok(path)
+    }
+
+    /// Read a JSON file; missing or corrupt files read as `None`
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        if self.stateless {
+            return None;
+        }
+        let data = fs::read(self.file(name)).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Ignoring corrupt state file {}: {}", self.file(name).display(), e);
+                None
+            }
+        }
+    }
+
+    /// Atomically write a JSON file
+    pub fn store<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        self.write_bytes(name, &serde_json::to_vec(value)?)
+    }
+
+    /// Read a text file, trimmed; missing or empty files read as `None`
+    pub fn read_text(&self, name: &str) -> Option<String> {
+        if self.stateless {
+            return None;
+        }
+        let content = fs::read_to_string(self.file(name)).ok()?;
+        Some(content.trim().to_string()).filter(|content| !content.is_empty())
+    }
+
+    /// Atomically write a text file
+    pub fn write_text(&self, name: &str, content: &str) -> Result<()> {
+        self.write_bytes(name, content.as_bytes())
+    }
+
+    fn write_bytes(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        if self.stateless {
+            debug!("Not writing state file {}: running without state", name);
+            return Ok(());
+        }
+        fsutil::atomic_write(&self.file(name), bytes, 0o600).context(format!("Failed to write state file {}", name))
+    }
+
+    /// Remove a file; returns whether it existed
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        if self.stateless {
+            return Ok(false);
+        }
+        let path = self.file(name);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(anyhow!("Failed to remove {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Delete all state except `keep`; returns the names removed
+    pub fn reset(&self, keep: &[KeepState]) -> Result<Vec<String>> {
+        let mut entries: Vec<_> = fs::read_dir(&self.path)
+            .context(format!("Failed to read {}", self.path.display()))?
+            .filter_map(|entry| entry.ok())
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut removed = Vec::new();
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name == VERSION_FILE || keep.iter().any(|k| k.file_name() == name) {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .map_err(|e| anyhow!("Failed to remove {}: {}", path.display(), e))?;
+            debug!("Removed state {}", path.display());
+            removed.push(name);
+        }
+        self.write_text(VERSION_FILE, &format!("{}\n", LAYOUT_VERSION))?;
+        Ok(removed)
+    }
+}
+
+fn create_private_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path).context(format!("Failed to create {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+        .context(format!("Failed to set permissions on {}", path.display()))
+}
+
+/// Layout 1 -> 2: earlier releases wrote state world-readable
+fn restrict_permissions(dir: &Path) -> Result<()> {
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            restrict_permissions(&entry.path())?;
+        } else if file_type.is_file() {
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of the most recent run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastRun {
+    pub status: String,
+    pub timestamp: u64,
+}
+
+/// Record the outcome of this run in the state directory
+pub fn record_last_run(state: &StateDir, status: &str) -> Result<()> {
+    let last_run = LastRun {
+        status: status.to_string(),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    state.store("last_run.json", &last_run)
+}
+
+/// Fingerprint of the last report the server accepted, so an identical one can be skipped
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastReport {
+    pub fingerprint: String,
+    /// Seconds since the epoch
+    #[serde(rename = "sentAt")]
+    pub sent_at: u64,
+}
+
+/// The last report the server accepted, if any
+pub fn read_last_report(state: &StateDir) -> Option<LastReport> {
+    state.load("last_report.json")
+}
+
+/// Remember the fingerprint of a report the server accepted
+pub fn record_last_report(state: &StateDir, fingerprint: &str, now: u64) -> Result<()> {
+    state.store("last_report.json", &LastReport { fingerprint: fingerprint.to_string(), sent_at: now })
+}
+
+/// Host ID the server returned for the previous successful report
+pub fn read_host_id(state: &StateDir) -> Option<String> {
+    state.read_text("host_id")
+}
+
+/// Remember the host ID the server returned
+pub fn record_host_id(state: &StateDir, host_id: &str) -> Result<()> {
+    state.write_text("host_id", host_id)
+}
+
+/// Endpoint the last run settled on among --endpoint and its fallbacks
+pub fn read_active_endpoint(state: &StateDir) -> Option<String> {
+    state.read_text("active_endpoint")
+}
+
+/// Remember which endpoint answered, so later requests go to the same server
+pub fn record_active_endpoint(state: &StateDir, endpoint: &str) -> Result<()> {
+    state.write_text("active_endpoint", endpoint)
+}
+
+/// Number of accounts seen by the previous successful report
+pub fn read_user_count(state: &StateDir) -> Option<usize> {
+    state.read_text("last_user_count")?.parse().ok()
+}
+
+/// Remember how many accounts this report saw
+pub fn record_user_count(state: &StateDir, count: usize) -> Result<()> {
+    state.write_text("last_user_count", &count.to_string())
+}
+
+/// Read the outcome of the previous run, if any; only the tests look back at it
+#[cfg(test)]
+pub fn read_last_run(state: &StateDir) -> Option<LastRun> {
+    state.load("last_run.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_open_empty_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        let state = StateDir::open(&path).unwrap();
+        assert_eq!(state.read_text(VERSION_FILE).as_deref(), Some("2"));
+        assert_eq!(mode(&path), 0o700);
+
+        record_host_id(&state, "host-1").unwrap();
+        assert_eq!(read_host_id(&state).as_deref(), Some("host-1"));
+        assert_eq!(mode(&state.file("host_id")), 0o600);
+    }
+
+    #[test]
+    fn test_unusable_dir_runs_stateless() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocker = dir.path().join("file");
+        fs::write(&blocker, "").unwrap();
+
+        // A directory cannot be created below a file, whoever runs the test
+        let state = StateDir::open_or_stateless(blocker.join("state"));
+        record_host_id(&state, "host-1").unwrap();
+        assert_eq!(read_host_id(&state), None);
+        assert!(state.load::<LastRun>("last_run.json").is_none());
+        assert!(!state.remove("host_id").unwrap());
+        assert!(state.subdir("spool").is_err());
+        assert!(!blocker.join("state").exists());
+    }
+
+    #[test]
+    fn test_migrate_v1_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("spool")).unwrap();
+        for name in ["last_run.json", "host_id", "last_user_count", "spool/report-0000000001.json"] {
+            fs::write(dir.path().join(name), "{}").unwrap();
+            fs::set_permissions(dir.path().join(name), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        fs::set_permissions(dir.path().join("spool"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let state = StateDir::open(dir.path()).unwrap();
+        assert_eq!(state.read_text(VERSION_FILE).as_deref(), Some("2"));
+        assert_eq!(mode(dir.path()), 0o700);
+        assert_eq!(mode(&dir.path().join("spool")), 0o700);
+        assert_eq!(mode(&dir.path().join("host_id")), 0o600);
+        assert_eq!(mode(&dir.path().join("spool/report-0000000001.json")), 0o600);
+    }
+
+    #[test]
+    fn test_corrupted_version_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(VERSION_FILE), "\0\0garbage").unwrap();
+        fs::write(dir.path().join("last_run.json"), "{not json").unwrap();
+        fs::set_permissions(dir.path().join("last_run.json"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let state = StateDir::open(dir.path()).unwrap();
+        assert_eq!(state.read_text(VERSION_FILE).as_deref(), Some("2"));
+        assert_eq!(mode(&dir.path().join("last_run.json")), 0o600);
+        // Corrupt state files read as missing instead of failing the run
+        assert!(read_last_run(&state).is_none());
+
+        // A layout from a newer agent is left untouched
+        fs::write(dir.path().join(VERSION_FILE), "7\n").unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        assert_eq!(state.read_text(VERSION_FILE).as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn test_reset_keeps_requested_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = StateDir::open(dir.path()).unwrap();
+        record_host_id(&state, "host-1").unwrap();
+        record_user_count(&state, 3).unwrap();
+        state.write_text(pin::PIN_FILE, "{}").unwrap();
+        fs::write(state.subdir("spool").unwrap().join("report-0000000001.json"), "{}").unwrap();
+
+        let removed = state.reset(&[KeepState::TokenPin]).unwrap();
+        assert_eq!(removed, vec!["host_id", "last_user_count", "spool"]);
+        assert!(state.file(pin::PIN_FILE).exists());
+        assert_eq!(state.read_text(VERSION_FILE).as_deref(), Some("2"));
+
+        state.reset(&[]).unwrap();
+        assert!(!state.file(pin::PIN_FILE).exists());
+    }
+}
@@ -0,0 +1,346 @@
+//! Property tests for the key sync pipeline: random authorized_keys contents, assignment sets and
+//! write failures, checked against invariants that must hold after every run.
+//!
+//! Cases are generated from fixed seeds, so a failure names the seed that reproduces it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::api::KeyAssignment;
+use crate::fsutil::{FileOps, RealFs};
+use crate::ssh_keys::{BLOCK_BEGIN, BLOCK_END, SshKey, SshKeyManager};
+use crate::users::UserInfo;
+
+const CASES: u64 = 128;
+const USERS: &[&str] = &["alice", "bob", "carol"];
+const KEY_POOL: u32 = 10;
+
+/// xorshift64*: reproducible from a seed without extra dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+/// A distinct, well-formed ed25519 public key for each `n`
+fn pool_key(n: u32) -> String {
+    use base64::Engine;
+    let mut blob = Vec::with_capacity(51);
+    blob.extend_from_slice(&11u32.to_be_bytes());
+    blob.extend_from_slice(b"ssh-ed25519");
+    blob.extend_from_slice(&32u32.to_be_bytes());
+    blob.extend_from_slice(&[7u8; 28]);
+    blob.extend_from_slice(&n.to_be_bytes());
+    format!("ssh-ed25519 {}", base64::engine::general_purpose::STANDARD.encode(blob))
+}
+
+/// Fails every write into the given directories, as a full or read-only filesystem would
+struct FailingWrites(Vec<PathBuf>);
+
+impl FileOps for FailingWrites {
+    fn write_new(&self, path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+        if self.0.iter().any(|dir| path.parent() == Some(dir.as_path())) {
+            return Err(io::Error::other("injected failure"));
+        }
+        RealFs.write_new(path, bytes, mode)
+    }
+
+    fn sync_file(&self, path: &Path) -> io::Result<()> {
+        RealFs.sync_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        RealFs.rename(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        RealFs.sync_dir(dir)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        RealFs.remove(path)
+    }
+}
+
+/// Existing content of one authorized_keys file
+struct GeneratedFile {
+    content: String,
+    /// Lines the agent must carry through every rewrite
+    protected: Vec<String>,
+}
+
+/// Up to `max` random lines for one region of a file; each pool key is used at most once per file
+fn random_lines(rng: &mut Rng, unused_keys: &mut Vec<u32>, max: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for i in 0..rng.below(max + 1) {
+        let line = match rng.below(9) {
+            0 | 1 if !unused_keys.is_empty() => {
+                let key = pool_key(unused_keys.swap_remove(rng.below(unused_keys.len())));
+                if rng.chance(50) { format!("{} user{}@laptop", key, i) } else { key }
+            }
+            2 if !unused_keys.is_empty() => {
+                let key = pool_key(unused_keys.swap_remove(rng.below(unused_keys.len())));
+                match rng.below(3) {
+                    0 => format!("no-pty {}", key),
+                    1 => format!("from=\"10.0.0.0/8,192.168.0.0/16\",no-agent-forwarding {} backup", key),
+                    _ => format!("command=\"echo \\\"a, b\\\"\",no-pty {}", key),
+                }
+            }
+            3 => format!("# note {}", i),
+            4 => format!("not a key {}", i),
+            5 => "ssh-ed25519 !!!not-base64!!!".to_string(),
+            6 => "ssh-xmss@openssh.com AAAAFHNzaC14bXNzQG9wZW5zc2guY29t xmss".to_string(),
+            _ => String::new(),
+        };
+        lines.push(line);
+    }
+    lines
+}
+
+fn is_plain_key(line: &str) -> bool {
+    SshKey::parse(line).is_ok_and(|key| key.options.is_none())
+}
+
+fn generate_file(rng: &mut Rng, exclusive: bool) -> GeneratedFile {
+    let mut unused_keys: Vec<u32> = (0..KEY_POOL).collect();
+    let before = random_lines(rng, &mut unused_keys, 5);
+    let block = if rng.chance(35) { Some(random_lines(rng, &mut unused_keys, 4)) } else { None };
+    let after = if block.is_some() { random_lines(rng, &mut unused_keys, 2) } else { Vec::new() };
+
+    // Outside the block everything is the user's; inside it (and everywhere with --exclusive) plain
+    // keys belong to the agent
+    let owned_by_agent = |line: &String, in_block: bool| line.trim().is_empty() || ((in_block || exclusive) && is_plain_key(line));
+    let mut protected: Vec<String> = before.iter().chain(&after).filter(|line| !owned_by_agent(line, false)).cloned().collect();
+    protected.extend(block.iter().flatten().filter(|line| !owned_by_agent(line, true)).cloned());
+
+    let mut lines = before;
+    if let Some(block) = block {
+        lines.push(BLOCK_BEGIN.to_string());
+        lines.extend(block);
+        lines.push(BLOCK_END.to_string());
+        lines.extend(after);
+    }
+    let newline = if rng.chance(25) { "\r\n" } else { "\n" };
+    let mut content = lines.join(newline);
+    if !content.is_empty() && rng.chance(80) {
+        content.push_str(newline);
+    }
+    GeneratedFile { content, protected }
+}
+
+fn random_assignments(rng: &mut Rng, username: &str) -> Vec<KeyAssignment> {
+    let mut assignments = Vec::new();
+    for i in 0..rng.below(6) {
+        let public_key = if rng.chance(10) { "ssh-ed25519 !!!not-base64!!!".to_string() } else { pool_key(rng.below(KEY_POOL as usize) as u32) };
+        let options = match rng.below(6) {
+            0 => Some("no-pty".to_string()),
+            1 => Some("command=\"/usr/bin/rrsync /srv\",no-agent-forwarding".to_string()),
+            2 if rng.chance(30) => Some("command=\"unterminated".to_string()),
+            _ => None,
+        };
+        assignments.push(KeyAssignment {
+            username: username.to_string(),
+            fingerprint: String::new(),
+            public_key,
+            key_type: "ssh-ed25519".to_string(),
+            comment: None,
+            use_primary_key: None,
+            assignment_id: format!("{}-{}", username, i),
+            uid: None,
+            user_email: None,
+            expires_at: None,
+            options,
+            extra: HashMap::new(),
+        });
+    }
+    assignments
+}
+
+/// Fingerprints of assignments the agent can apply
+fn applicable<'a>(assignments: impl Iterator<Item = &'a KeyAssignment>) -> Vec<String> {
+    assignments
+        .filter(|a| a.options.as_deref().is_none_or(|options| options.matches('"').count() % 2 == 0))
+        .filter_map(|a| SshKey::parse(&a.public_key).ok())
+        .map(|key| key.fingerprint)
+        .collect()
+}
+
+struct Case {
+    _dir: tempfile::TempDir,
+    users: Vec<UserInfo>,
+    assignments: Vec<KeyAssignment>,
+    files: Vec<PathBuf>,
+    initial: Vec<Option<String>>,
+    protected: Vec<Vec<String>>,
+    failing: Vec<bool>,
+    adopt: bool,
+}
+
+fn generate_case(rng: &mut Rng, exclusive: bool, adopt: bool) -> Case {
+    let dir = tempfile::tempdir().unwrap();
+    let mut case = Case {
+        users: Vec::new(),
+        assignments: Vec::new(),
+        files: Vec::new(),
+        initial: Vec::new(),
+        protected: Vec::new(),
+        failing: Vec::new(),
+        adopt,
+        _dir: dir,
+    };
+    for (i, username) in USERS.iter().enumerate() {
+        let home = case._dir.path().join(username);
+        let ssh_dir = home.join(".ssh");
+        let path = ssh_dir.join("authorized_keys");
+        fs::create_dir_all(&home).unwrap();
+
+        let (initial, protected) = if rng.chance(85) {
+            let file = generate_file(rng, exclusive);
+            fs::create_dir_all(&ssh_dir).unwrap();
+            fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(if rng.chance(50) { 0o755 } else { 0o700 })).unwrap();
+            fs::write(&path, &file.content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(if rng.chance(50) { 0o644 } else { 0o600 })).unwrap();
+            (Some(file.content), file.protected)
+        } else {
+            (None, Vec::new())
+        };
+
+        // The atomic write of this file fails on the first run
+        let failing = rng.chance(20);
+
+        case.users.push(UserInfo {
+            username: username.to_string(),
+            uid: 1000 + i as u32,
+            shell: Some("/bin/bash".to_string()),
+            home_dir: Some(home.to_string_lossy().to_string()),
+            disabled: Some(false),
+            home_encryption: None,
+            home_mounted: None,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        });
+        case.assignments.extend(random_assignments(rng, username));
+        case.files.push(path);
+        case.initial.push(initial);
+        case.protected.push(protected);
+        case.failing.push(failing);
+    }
+    case
+}
+
+fn read(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+/// Invariants for one user's file after a run that could write it
+fn check_file(seed: u64, case: &Case, index: usize) {
+    let path = &case.files[index];
+    let username = &case.users[index].username;
+    let content = read(path).unwrap_or_default();
+    let context = || format!("seed {} user {}:\n{}", seed, username, content);
+    let lines: Vec<&str> = content.lines().map(|line| line.trim_end_matches('\r')).collect();
+
+    // No unmanaged line is lost; with --adopt-existing-keys assigned keys are the agent's to rewrite
+    let assigned = applicable(case.assignments.iter().filter(|a| &a.username == username));
+    let adopted = |line: &str| case.adopt && SshKey::parse(line).is_ok_and(|key| assigned.contains(&key.fingerprint));
+    for line in &case.protected[index] {
+        let line = line.trim_end_matches('\r');
+        if adopted(line) {
+            continue;
+        }
+        let expected = case.protected[index].iter().filter(|l| l.trim_end_matches('\r') == line).count();
+        let found = lines.iter().filter(|l| **l == line).count();
+        assert!(found >= expected, "lost {:?}; {}", line, context());
+    }
+
+    // Assigned keys are present exactly once and no key appears twice
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for line in &lines {
+        if let Ok(key) = SshKey::parse(line) {
+            *counts.entry(key.fingerprint).or_default() += 1;
+        }
+    }
+    for fingerprint in assigned {
+        assert_eq!(counts.get(&fingerprint), Some(&1), "assigned key {} not present once; {}", fingerprint, context());
+    }
+    for (fingerprint, count) in &counts {
+        assert_eq!(*count, 1, "key {} appears {} times; {}", fingerprint, count, context());
+    }
+
+    // Files the agent wrote have safe permissions
+    if read(path) != case.initial[index] {
+        assert_eq!(mode(path), 0o600, "{}", context());
+        assert_eq!(mode(path.parent().unwrap()), 0o700, "{}", context());
+    }
+}
+
+#[test]
+fn test_sync_invariants_hold_for_random_inputs() {
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let (exclusive, adopt) = (rng.chance(25), rng.chance(25));
+        let case = generate_case(&mut rng, exclusive, adopt);
+        let manager = || SshKeyManager::new().with_exclusive(exclusive).with_adopt_existing_keys(adopt);
+
+        // First run: files whose write fails stay exactly as they were
+        let failing_dirs = case.files.iter().zip(&case.failing).filter(|(_, failing)| **failing);
+        let failing_dirs = failing_dirs.map(|(path, _)| path.parent().unwrap().to_path_buf()).collect();
+        manager()
+            .with_file_ops(Box::new(FailingWrites(failing_dirs)))
+            .sync_ssh_keys(&case.users, &case.assignments, false, false)
+            .unwrap();
+        let mut after_first = Vec::new();
+        for index in 0..USERS.len() {
+            if case.failing[index] {
+                assert_eq!(read(&case.files[index]), case.initial[index], "seed {}: failed write changed the file", seed);
+            } else {
+                check_file(seed, &case, index);
+            }
+            after_first.push(read(&case.files[index]));
+        }
+
+        // Second run, failures cleared: the failed files catch up, the others stay put
+        let manager = manager();
+        manager.sync_ssh_keys(&case.users, &case.assignments, false, false).unwrap();
+        for index in 0..USERS.len() {
+            check_file(seed, &case, index);
+            if !case.failing[index] {
+                assert_eq!(read(&case.files[index]), after_first[index], "seed {}: second run changed {}", seed, USERS[index]);
+            }
+        }
+
+        // Third run: nothing left to do
+        let settled: Vec<Option<String>> = case.files.iter().map(|path| read(path)).collect();
+        let stats = manager.sync_ssh_keys(&case.users, &case.assignments, false, false).unwrap();
+        assert_eq!((stats.keys_added, stats.keys_removed, stats.keys_updated, stats.keys_adopted), (0, 0, 0, 0), "seed {}", seed);
+        assert_eq!(stats.files_updated, 0, "seed {}", seed);
+        let after_third: Vec<Option<String>> = case.files.iter().map(|path| read(path)).collect();
+        assert_eq!(after_third, settled, "seed {}", seed);
+    }
+}
@@ -1,8 +1,13 @@
+use std::env;
+use std::fs;
+use std::path::Path;
 use serde::Serialize;
-use sysinfo::System;
 use anyhow::Result;
 
-#[derive(Serialize, Debug)]
+/// Present on systems booted from an ostree deployment (Fedora CoreOS, Silverblue, RHEL for Edge)
+pub const OSTREE_BOOTED_PATH: &str = "/run/ostree-booted";
+
+#[derive(Serialize, Debug, Clone)]
 pub struct SystemInfo {
     pub os: String,
     pub arch: String,
@@ -10,67 +15,167 @@ pub struct SystemInfo {
     pub kernel: String,
     pub distribution: String,
     pub version: String,
+    /// Image-based OS whose binaries cannot be replaced in place, so `pkagent update` is unavailable
+    #[serde(rename = "immutableOs", skip_serializing_if = "std::ops::Not::not")]
+    pub immutable_os: bool,
+}
+
+/// Fields of uname(2) the report uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uname {
+    pub sysname: String,
+    pub release: String,
+    pub machine: String,
+}
+
+/// Raw inputs for `SystemInfo`, read directly instead of through sysinfo
+#[derive(Debug, Default)]
+struct SystemSources {
+    uname: Option<Uname>,
+    os_release: Option<String>,
+    lsb_release: Option<String>,
+    issue: Option<String>,
+    /// macOS /System/Library/CoreServices/SystemVersion.plist
+    system_version_plist: Option<String>,
+    immutable_os: bool,
+}
+
+impl SystemSources {
+    fn read() -> Self {
+        Self {
+            uname: uname(),
+            os_release: fs::read_to_string("/etc/os-release").ok(),
+            lsb_release: fs::read_to_string("/etc/lsb-release").ok(),
+            issue: fs::read_to_string("/etc/issue").ok(),
+            system_version_plist: cfg!(target_os = "macos")
+                .then(|| fs::read_to_string("/System/Library/CoreServices/SystemVersion.plist").ok())
+                .flatten(),
+            immutable_os: immutable_os(),
+        }
+    }
 }
 
+fn uname() -> Option<Uname> {
+    let info = nix::sys::utsname::uname().ok()?;
+    Some(Uname {
+        sysname: info.sysname().to_string_lossy().to_string(),
+        release: info.release().to_string_lossy().to_string(),
+        machine: info.machine().to_string_lossy().to_string(),
+    })
+}
 
-#[cfg(target_os = "linux")]
-fn get_linux_distribution() -> Option<String> {
-    use std::fs;
-    
+/// Platform name reported for the compile target
+fn current_platform() -> &'static str {
+    if cfg!(target_os = "linux") {
+        "linux"
+    } else if cfg!(target_os = "macos") {
+        "darwin"
+    } else if cfg!(target_os = "windows") {
+        "windows"
+    } else {
+        "unknown"
+    }
+}
+
+/// Value of the first `KEY=` line, with all double quotes removed
+fn key_value(content: &str, key: &str) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.replace('"', ""))
+}
+
+/// Linux OS name: os-release NAME, falling back to lsb-release DISTRIB_ID
+fn linux_os_name(sources: &SystemSources) -> Option<String> {
+    sources
+        .os_release
+        .as_deref()
+        .and_then(|content| key_value(content, "NAME"))
+        .or_else(|| sources.lsb_release.as_deref().and_then(|content| key_value(content, "DISTRIB_ID")))
+}
+
+/// Linux OS version: os-release VERSION_ID, falling back to lsb-release DISTRIB_RELEASE
+fn linux_os_version(sources: &SystemSources) -> Option<String> {
+    sources
+        .os_release
+        .as_deref()
+        .and_then(|content| key_value(content, "VERSION_ID"))
+        .or_else(|| sources.lsb_release.as_deref().and_then(|content| key_value(content, "DISTRIB_RELEASE")))
+}
+
+fn get_linux_distribution(sources: &SystemSources) -> Option<String> {
     // Try /etc/os-release first
-    if let Ok(content) = fs::read_to_string("/etc/os-release") {
+    if let Some(content) = &sources.os_release {
         for line in content.lines() {
-            if line.starts_with("NAME=") {
-                return Some(line[5..].trim_matches('"').to_string());
+            if let Some(name) = line.strip_prefix("NAME=") {
+                return Some(name.trim_matches('"').to_string());
             }
         }
     }
 
     // Fallback to /etc/issue
-    if let Ok(content) = fs::read_to_string("/etc/issue") {
+    if let Some(content) = &sources.issue {
         return Some(content.lines().next()?.trim().to_string());
     }
 
     None
 }
 
-pub fn collect_system_info() -> Result<SystemInfo> {
-    let os_name = System::name().unwrap_or_else(|| "Unknown".to_string());
-    let arch = System::cpu_arch().unwrap_or_else(|| "Unknown".to_string());
-    let kernel_version = System::kernel_version().unwrap_or_else(|| "Unknown".to_string());
-    let os_version = System::os_version().unwrap_or_else(|| "Unknown".to_string());
-
-    // Determine platform based on OS
-    let platform = if cfg!(target_os = "linux") {
-        "linux".to_string()
-    } else if cfg!(target_os = "macos") {
-        "darwin".to_string()
-    } else if cfg!(target_os = "windows") {
-        "windows".to_string()
-    } else {
-        "unknown".to_string()
+/// ProductVersion from macOS SystemVersion.plist
+fn macos_product_version(plist: &str) -> Option<String> {
+    let after_key = &plist[plist.find("<key>ProductVersion</key>")? + "<key>ProductVersion</key>".len()..];
+    let value = after_key.trim_start().strip_prefix("<string>")?;
+    Some(value[..value.find("</string>")?].trim().to_string())
+}
+
+fn build_system_info(sources: &SystemSources, platform: &str) -> SystemInfo {
+    let unknown = || "Unknown".to_string();
+    let (os_name, os_version) = match platform {
+        "linux" => (linux_os_name(sources), linux_os_version(sources)),
+        "darwin" => (
+            sources.uname.as_ref().map(|u| u.sysname.clone()),
+            sources.system_version_plist.as_deref().and_then(macos_product_version),
+        ),
+        _ => (sources.uname.as_ref().map(|u| u.sysname.clone()), None),
     };
+    let os_name = os_name.unwrap_or_else(unknown);
 
     // Try to get distribution info on Linux
-    let distribution = {
-        #[cfg(target_os = "linux")]
-        {
-            get_linux_distribution().unwrap_or_else(|| os_name.clone())
-        }
-        #[cfg(not(target_os = "linux"))]
-        {
-            os_name.clone()
-        }
+    let distribution = if platform == "linux" {
+        get_linux_distribution(sources).unwrap_or_else(|| os_name.clone())
+    } else {
+        os_name.clone()
     };
 
-    Ok(SystemInfo {
+    SystemInfo {
+        arch: sources.uname.as_ref().map(|u| u.machine.clone()).unwrap_or_else(unknown),
+        kernel: sources.uname.as_ref().map(|u| u.release.clone()).unwrap_or_else(unknown),
+        platform: platform.to_string(),
         os: os_name,
-        arch,
-        platform,
-        kernel: kernel_version,
         distribution,
-        version: os_version,
-    })
+        version: os_version.unwrap_or_else(unknown),
+        immutable_os: sources.immutable_os,
+    }
+}
+
+/// Whether this host runs an image-based OS where self-update cannot work: ostree-booted, or the
+/// agent binary sits on a read-only mount
+pub fn immutable_os() -> bool {
+    let exe = env::current_exe().ok();
+    detect_immutable_os(Path::new(OSTREE_BOOTED_PATH), exe.as_deref(), is_read_only_mount)
+}
+
+fn detect_immutable_os(ostree_booted: &Path, exe: Option<&Path>, read_only: impl Fn(&Path) -> bool) -> bool {
+    ostree_booted.exists() || exe.is_some_and(read_only)
+}
+
+fn is_read_only_mount(path: &Path) -> bool {
+    use nix::sys::statvfs::{FsFlags, statvfs};
+    statvfs(path).is_ok_and(|stat| stat.flags().contains(FsFlags::ST_RDONLY))
+}
+
+pub fn collect_system_info() -> Result<SystemInfo> {
+    Ok(build_system_info(&SystemSources::read(), current_platform()))
 }
 
 pub fn collect_hostname() -> Result<String> {
@@ -97,4 +202,135 @@ impl<T> Pipe<T> for T {
     {
         f(self)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBIAN_OS_RELEASE: &str = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+VERSION="12 (bookworm)"
+ID=debian
+"#;
+
+    const MACOS_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+	<key>ProductBuildVersion</key>
+	<string>23C71</string>
+	<key>ProductName</key>
+	<string>macOS</string>
+	<key>ProductVersion</key>
+	<string>14.2.1</string>
+</dict>
+</plist>
+"#;
+
+    fn uname_shim(sysname: &str, release: &str, machine: &str) -> Option<Uname> {
+        Some(Uname {
+            sysname: sysname.to_string(),
+            release: release.to_string(),
+            machine: machine.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_linux_from_os_release() {
+        let sources = SystemSources {
+            uname: uname_shim("Linux", "6.1.0-18-amd64", "x86_64"),
+            os_release: Some(DEBIAN_OS_RELEASE.to_string()),
+            ..Default::default()
+        };
+        let info = build_system_info(&sources, "linux");
+        assert_eq!(info.os, "Debian GNU/Linux");
+        assert_eq!(info.distribution, "Debian GNU/Linux");
+        assert_eq!(info.version, "12");
+        assert_eq!(info.kernel, "6.1.0-18-amd64");
+        assert_eq!(info.arch, "x86_64");
+        assert_eq!(info.platform, "linux");
+    }
+
+    #[test]
+    fn test_linux_fallbacks() {
+        // No VERSION_ID (rolling releases): lsb-release supplies the version
+        let sources = SystemSources {
+            uname: uname_shim("Linux", "6.7.4-arch1-1", "aarch64"),
+            os_release: Some("NAME=\"Arch Linux\"\nID=arch\n".to_string()),
+            lsb_release: Some("DISTRIB_ID=\"Arch\"\nDISTRIB_RELEASE=\"rolling\"\n".to_string()),
+            ..Default::default()
+        };
+        let info = build_system_info(&sources, "linux");
+        assert_eq!((info.os.as_str(), info.version.as_str()), ("Arch Linux", "rolling"));
+
+        // Without os-release: lsb-release name, /etc/issue distribution
+        let sources = SystemSources {
+            lsb_release: Some("DISTRIB_ID=Ubuntu\nDISTRIB_RELEASE=14.04\n".to_string()),
+            issue: Some("Ubuntu 14.04.6 LTS \\n \\l\n".to_string()),
+            ..Default::default()
+        };
+        let info = build_system_info(&sources, "linux");
+        assert_eq!(info.os, "Ubuntu");
+        assert_eq!(info.version, "14.04");
+        assert_eq!(info.distribution, "Ubuntu 14.04.6 LTS \\n \\l");
+        assert_eq!((info.kernel.as_str(), info.arch.as_str()), ("Unknown", "Unknown"));
+
+        let info = build_system_info(&SystemSources::default(), "linux");
+        assert_eq!((info.os.as_str(), info.distribution.as_str()), ("Unknown", "Unknown"));
+    }
+
+    #[test]
+    fn test_macos_from_uname_and_plist() {
+        let sources = SystemSources {
+            uname: uname_shim("Darwin", "23.2.0", "arm64"),
+            system_version_plist: Some(MACOS_PLIST.to_string()),
+            ..Default::default()
+        };
+        let info = build_system_info(&sources, "darwin");
+        assert_eq!(info.os, "Darwin");
+        assert_eq!(info.distribution, "Darwin");
+        assert_eq!(info.version, "14.2.1");
+        assert_eq!(info.kernel, "23.2.0");
+        assert_eq!(info.arch, "arm64");
+    }
+
+    #[test]
+    fn test_key_value_matches_whole_key() {
+        assert_eq!(key_value(DEBIAN_OS_RELEASE, "NAME").as_deref(), Some("Debian GNU/Linux"));
+        assert_eq!(key_value("PRETTY_NAME=x\n", "NAME"), None);
+    }
+
+    #[test]
+    fn test_detect_immutable_os() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ostree-booted");
+        let exe = dir.path().join("pkagent");
+
+        assert!(!detect_immutable_os(&marker, Some(&exe), |_| false));
+        assert!(!detect_immutable_os(&marker, None, |_| true));
+        // Binary on a read-only mount, e.g. /usr on an image-based OS
+        assert!(detect_immutable_os(&marker, Some(&exe), |path| path == exe));
+
+        fs::write(&marker, "").unwrap();
+        assert!(detect_immutable_os(&marker, Some(&exe), |_| false));
+    }
+
+    #[test]
+    fn test_immutable_os_reported_only_when_set() {
+        let sources = SystemSources { immutable_os: true, ..Default::default() };
+        let value = serde_json::to_value(build_system_info(&sources, "linux")).unwrap();
+        assert_eq!(value["immutableOs"], true);
+
+        let value = serde_json::to_value(build_system_info(&SystemSources::default(), "linux")).unwrap();
+        assert!(value.get("immutableOs").is_none());
+    }
+
+    #[test]
+    fn test_collect_on_this_host() {
+        let info = collect_system_info().unwrap();
+        assert_eq!(info.platform, current_platform());
+        assert_ne!(info.kernel, "Unknown");
+        assert_ne!(info.arch, "Unknown");
+    }
+}
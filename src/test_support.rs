@@ -0,0 +1,238 @@
+use std::sync::{Arc, Mutex};
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A request captured by the mock server
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// When the request was complete
+    pub received: std::time::Instant,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Everything logged on this thread, at every level, while the guard from [`CapturedLogs::start`] lives
+#[derive(Debug, Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn start() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Self::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Canned response served by the mock server
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+}
+
+/// Start a minimal HTTP/1.1 server on localhost.
+///
+/// Responses are served in order; the last one repeats once the list is exhausted.
+pub async fn mock_server(responses: Vec<MockResponse>) -> (String, Arc<Mutex<Vec<RecordedRequest>>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+
+    tokio::spawn(async move {
+        let mut served = 0;
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let Some(request) = read_request(&mut socket).await else {
+                continue;
+            };
+            recorded.lock().unwrap().push(request);
+
+            let response = responses[served.min(responses.len() - 1)].clone();
+            served += 1;
+
+            let mut raw = format!("HTTP/1.1 {} Mock\r\n", response.status);
+            for (name, value) in &response.headers {
+                raw.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            raw.push_str(&format!("content-length: {}\r\nconnection: close\r\n\r\n{}", response.body.len(), response.body));
+            socket.write_all(raw.as_bytes()).await.ok();
+            socket.shutdown().await.ok();
+        }
+    });
+
+    (format!("http://{}", addr), requests)
+}
+
+async fn read_request<S: tokio::io::AsyncRead + Unpin>(socket: &mut S) -> Option<RecordedRequest> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut data = Vec::new();
+
+    loop {
+        let n = socket.read(&mut buf).await.ok()?;
+        data.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&data).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let mut lines = text[..end].lines();
+            let request_line = lines.next()?.to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect();
+            let length = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, v)| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            if data.len() >= end + 4 + length {
+                let body = String::from_utf8_lossy(&data[end + 4..end + 4 + length]).to_string();
+                return Some(RecordedRequest {
+                    request_line,
+                    headers,
+                    body,
+                    received: std::time::Instant::now(),
+                });
+            }
+        }
+        if n == 0 {
+            return None;
+        }
+    }
+}
+
+/// Self-signed certificate for `localhost`
+pub struct SelfSignedCert {
+    pub der: Vec<u8>,
+    pub key_der: Vec<u8>,
+}
+
+impl SelfSignedCert {
+    pub fn generate() -> Self {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        Self {
+            der: cert.serialize_der().unwrap(),
+            key_der: cert.serialize_private_key_der(),
+        }
+    }
+}
+
+/// Stand-in for the server's key assignment signing key
+pub struct AssignmentsSigner(ring::signature::Ed25519KeyPair);
+
+impl AssignmentsSigner {
+    pub fn generate() -> Self {
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        Self(ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap())
+    }
+
+    pub fn public_key(&self) -> crate::assignment_signature::AssignmentsPubkey {
+        use ring::signature::KeyPair;
+        let raw = base64::engine::general_purpose::STANDARD.encode(self.0.public_key().as_ref());
+        crate::assignment_signature::AssignmentsPubkey::parse(&raw).unwrap()
+    }
+
+    /// Base64 detached signature of `body`, as the server sends it
+    pub fn sign(&self, body: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.0.sign(body.as_bytes()))
+    }
+}
+
+/// Start an HTTPS server on localhost answering every request with an empty JSON object
+pub async fn tls_mock_server(cert: &SelfSignedCert) -> String {
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![rustls::Certificate(cert.der.clone())], rustls::PrivateKey(cert.key_der.clone()))
+        .unwrap();
+    serve_tls(config).await.0
+}
+
+/// Like [`tls_mock_server`], but the handshake fails unless the client presents a certificate
+/// issued by `client_ca`
+pub async fn mtls_mock_server(cert: &SelfSignedCert, client_ca: &[u8]) -> (String, Arc<Mutex<Vec<RecordedRequest>>>) {
+    let mut client_roots = rustls::RootCertStore::empty();
+    client_roots.add(&rustls::Certificate(client_ca.to_vec())).unwrap();
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(client_roots).boxed())
+        .with_single_cert(vec![rustls::Certificate(cert.der.clone())], rustls::PrivateKey(cert.key_der.clone()))
+        .unwrap();
+    serve_tls(config).await
+}
+
+async fn serve_tls(config: rustls::ServerConfig) -> (String, Arc<Mutex<Vec<RecordedRequest>>>) {
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((socket, _)) = listener.accept().await else {
+                return;
+            };
+            let acceptor = acceptor.clone();
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let Some(request) = read_request(&mut stream).await else {
+                    return;
+                };
+                recorded.lock().unwrap().push(request);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}")
+                    .await
+                    .ok();
+                stream.shutdown().await.ok();
+            });
+        }
+    });
+
+    (format!("https://localhost:{}", addr.port()), requests)
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use secrecy::SecretString;
+
+/// How the API token was issued
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TokenType {
+    /// Long-lived host token
+    #[default]
+    Bearer,
+    /// Short-lived JWT, e.g. minted by a provisioning pipeline for a single run
+    Jwt,
+}
+
+/// The `exp` claim of a JWT (seconds since the epoch), read without verifying the signature
+pub fn jwt_expiry(token: &str) -> Result<Option<u64>> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(anyhow!("token is not a JWT (expected 3 dot-separated parts, found {})", parts.len()));
+    }
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(parts[1].trim_end_matches('='))
+        .map_err(|e| anyhow!("JWT payload is not base64url: {}", e))?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&payload).map_err(|e| anyhow!("JWT payload is not JSON: {}", e))?;
+    if !claims.is_object() {
+        return Err(anyhow!("JWT payload is not a JSON object"));
+    }
+    match claims.get("exp") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(exp) => exp
+            .as_u64()
+            .or_else(|| exp.as_f64().filter(|exp| *exp >= 0.0).map(|exp| exp as u64))
+            .map(Some)
+            .ok_or_else(|| anyhow!("JWT exp claim is not a timestamp: {}", exp)),
+    }
+}
+
+/// Warning when a token expiring at `exp` will not last `budget` from `now` (seconds since the epoch)
+pub fn expiry_warning(exp: u64, now: u64, budget: Duration) -> Option<String> {
+    if exp <= now {
+        Some(format!("the JWT expired {}s ago", now - exp))
+    } else if exp - now < budget.as_secs() {
+        Some(format!(
+            "the JWT expires in {}s, before the {}s retry budget could complete",
+            exp - now,
+            budget.as_secs()
+        ))
+    } else {
+        None
+    }
+}
+
+/// `--token-command`: a shell command printing the API token, so the token never has to be
+/// stored on the host (e.g. `vault kv get -field=token secret/pkagent`)
+#[derive(Debug, Clone)]
+pub struct TokenCommand {
+    command: String,
+    timeout: Duration,
+}
+
+impl TokenCommand {
+    pub fn new(command: String, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Run the command with `sh -c` and take its trimmed stdout as the token. The command is
+    /// killed if it outlasts the timeout.
+    pub async fn run(&self) -> Result<SecretString, TokenCommandError> {
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = match tokio::time::timeout(self.timeout, output).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return Err(TokenCommandError(format!("could not start sh: {}", e))),
+            Err(_) => return Err(TokenCommandError(format!("timed out after {:?}", self.timeout))),
+        };
+        // stdout may hold a token even on failure, so only stderr is ever shown
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(TokenCommandError(match stderr.trim() {
+                "" => format!("{}", output.status),
+                stderr => format!("{}: {}", output.status, stderr),
+            }));
+        }
+        let stdout = secrecy::zeroize::Zeroizing::new(output.stdout);
+        let token = std::str::from_utf8(&stdout).map_err(|_| TokenCommandError("printed a token that is not UTF-8".to_string()))?;
+        if token.trim().is_empty() {
+            return Err(TokenCommandError("printed no token".to_string()));
+        }
+        Ok(SecretString::from(token.trim()))
+    }
+}
+
+/// `--token-command` failed, so the agent has no token to authenticate with
+#[derive(Debug)]
+pub struct TokenCommandError(String);
+
+impl std::fmt::Display for TokenCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "--token-command failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for TokenCommandError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    /// An unsigned JWT carrying `claims`
+    fn jwt(claims: &str) -> String {
+        let encode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(part);
+        format!("{}.{}.", encode(r#"{"alg":"none","typ":"JWT"}"#), encode(claims))
+    }
+
+    #[test]
+    fn test_jwt_expiry() {
+        assert_eq!(jwt_expiry(&jwt(r#"{"sub":"ci","exp":1700000000}"#)).unwrap(), Some(1700000000));
+        assert_eq!(jwt_expiry(&jwt(r#"{"exp":1700000000.5}"#)).unwrap(), Some(1700000000));
+        assert_eq!(jwt_expiry(&jwt(r#"{"sub":"ci"}"#)).unwrap(), None);
+
+        assert!(jwt_expiry("pk_live_abc123").is_err());
+        assert!(jwt_expiry("a.!!!.c").is_err());
+        assert!(jwt_expiry(&jwt("[1]")).is_err());
+        assert!(jwt_expiry(&jwt(r#"{"exp":"tomorrow"}"#)).is_err());
+        assert!(jwt_expiry(&jwt(r#"{"exp":-5}"#)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_command() {
+        let run = |command: &str| {
+            let command = TokenCommand::new(command.to_string(), Duration::from_secs(5));
+            async move { command.run().await }
+        };
+        assert_eq!(run("echo '  pk_from_vault  '").await.unwrap().expose_secret(), "pk_from_vault");
+
+        let error = run("echo pk_leaked; echo 'permission denied' >&2; exit 3").await.unwrap_err().to_string();
+        assert!(error.contains("exit status: 3: permission denied"), "{}", error);
+        assert!(!error.contains("pk_leaked"), "{}", error);
+        assert!(run("true").await.unwrap_err().to_string().contains("printed no token"));
+
+        let hung = TokenCommand::new("sleep 30".to_string(), Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        assert!(hung.run().await.unwrap_err().to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let budget = Duration::from_secs(30);
+        assert!(expiry_warning(1000, 1060, budget).unwrap().contains("expired 60s ago"));
+        assert!(expiry_warning(1000, 1000, budget).unwrap().contains("expired"));
+        assert!(expiry_warning(1010, 1000, budget).unwrap().contains("expires in 10s"));
+        assert_eq!(expiry_warning(1030, 1000, budget), None);
+        assert_eq!(expiry_warning(5000, 1000, budget), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{Context, Result, anyhow};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tracing::{info, warn};
+
+use crate::client_cert::ClientIdentity;
+
+/// CA bundles of common distributions, tried in order when SSL_CERT_FILE is not set
+const NATIVE_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// Where the roots used to verify TLS servers came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrustSource {
+    /// Only `--ca-cert`, when neither the system bundle nor bundled roots are usable
+    CaCert(PathBuf),
+    /// The system CA bundle
+    Native(PathBuf),
+    /// Roots compiled into the binary (webpki-roots)
+    #[cfg(feature = "bundled-roots")]
+    Bundled,
+}
+
+impl fmt::Display for TrustSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrustSource::CaCert(path) => write!(f, "--ca-cert {}", path.display()),
+            TrustSource::Native(path) => write!(f, "system CA bundle {}", path.display()),
+            #[cfg(feature = "bundled-roots")]
+            TrustSource::Bundled => write!(f, "bundled webpki roots"),
+        }
+    }
+}
+
+/// Root certificates for the API and update clients
+pub struct TrustRoots {
+    pub store: RootCertStore,
+    pub source: TrustSource,
+    /// `--ca-cert` bundle trusted on top of `source`
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl TrustRoots {
+    /// rustls configuration trusting these roots
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.store.clone())
+            .with_no_client_auth()
+    }
+
+    /// rustls configuration for the API: these roots, plus the `--client-cert` identity if any
+    pub fn api_client_config(&self, identity: Option<&ClientIdentity>) -> ClientConfig {
+        let mut config = self.client_config();
+        if let Some(identity) = identity {
+            identity.apply(&mut config);
+        }
+        config
+    }
+}
+
+/// rustls configuration for `--insecure-skip-tls-verify`: any server certificate is accepted
+pub fn insecure_client_config(identity: Option<&ClientIdentity>) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert))
+        .with_no_client_auth();
+    if let Some(identity) = identity {
+        identity.apply(&mut config);
+    }
+    config
+}
+
+/// Refuse `--insecure-skip-tls-verify` for anything but a loopback endpoint, unless `yes_i_know`
+pub fn check_insecure_endpoint(endpoint: &str, yes_i_know: bool) -> Result<()> {
+    let (host, _) = crate::preflight::endpoint_address(endpoint)?;
+    let local = host.eq_ignore_ascii_case("localhost")
+        || host.to_ascii_lowercase().ends_with(".localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    if !local && !yes_i_know {
+        return Err(anyhow!(
+            "--insecure-skip-tls-verify is refused for {}, which is not localhost: anyone on the network path could impersonate the server. Add --yes-i-know to do it anyway",
+            host
+        ));
+    }
+    Ok(())
+}
+
+/// Verifier that accepts every server certificate
+struct AcceptAnyServerCert;
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Load the roots to trust: the system bundle, else (with the `bundled-roots` feature) the roots
+/// compiled into the binary, plus every certificate of `--ca-cert` for internal CAs
+pub fn load(ca_cert: Option<&Path>) -> Result<TrustRoots> {
+    let ssl_cert_file = std::env::var_os("SSL_CERT_FILE").map(PathBuf::from);
+    let roots = load_from(ca_cert, ssl_cert_file.as_deref())?;
+    match &roots.ca_cert {
+        Some(path) => info!("TLS trust source: {} plus --ca-cert {} ({} roots)", roots.source, path.display(), roots.store.len()),
+        None => info!("TLS trust source: {} ({} roots)", roots.source, roots.store.len()),
+    }
+    Ok(roots)
+}
+
+fn load_from(ca_cert: Option<&Path>, ssl_cert_file: Option<&Path>) -> Result<TrustRoots> {
+    // Read first so a broken --ca-cert fails the run before anything else happens
+    let extra = ca_cert.map(read_ca_cert).transpose().context("Failed to load --ca-cert")?;
+
+    let base = match native_roots(ssl_cert_file) {
+        Ok((store, path)) => Ok(TrustRoots { store, source: TrustSource::Native(path), ca_cert: None }),
+        Err(e) => bundled_fallback(e),
+    };
+    let (Some(path), Some(certs)) = (ca_cert, extra) else {
+        return base;
+    };
+    let mut roots = base.unwrap_or_else(|e| {
+        warn!("{:#}; trusting only --ca-cert", e);
+        TrustRoots { store: RootCertStore::empty(), source: TrustSource::CaCert(path.to_path_buf()), ca_cert: None }
+    });
+    roots.store.add_parsable_certificates(&certs);
+    roots.ca_cert = Some(path.to_path_buf());
+    Ok(roots)
+}
+
+#[cfg(feature = "bundled-roots")]
+fn bundled_fallback(native_error: anyhow::Error) -> Result<TrustRoots> {
+    warn!("System CA store unusable ({:#}), falling back to bundled webpki roots", native_error);
+    let mut store = RootCertStore::empty();
+    store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    Ok(TrustRoots { store, source: TrustSource::Bundled, ca_cert: None })
+}
+
+#[cfg(not(feature = "bundled-roots"))]
+fn bundled_fallback(native_error: anyhow::Error) -> Result<TrustRoots> {
+    warn!("System CA store unusable and this build has no bundled roots");
+    Err(native_error.context("No usable CA roots; pass --ca-cert"))
+}
+
+/// The system bundle: SSL_CERT_FILE when set, otherwise the first distribution bundle that exists
+fn native_roots(ssl_cert_file: Option<&Path>) -> Result<(RootCertStore, PathBuf)> {
+    let path = match ssl_cert_file {
+        Some(path) => path.to_path_buf(),
+        None => NATIVE_BUNDLES
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow!("no CA bundle found (tried {})", NATIVE_BUNDLES.join(", ")))?,
+    };
+    let store = read_bundle(&path)?;
+    Ok((store, path))
+}
+
+/// DER certificates of a PEM file
+fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::certs(&mut BufReader::new(file)).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Certificates of a `--ca-cert` bundle. Unlike the system bundle, which is not ours to fix, a
+/// single bad certificate in it is an error.
+fn read_ca_cert(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let certs = read_certs(path)?;
+    let (valid, invalid) = RootCertStore::empty().add_parsable_certificates(&certs);
+    if invalid > 0 {
+        return Err(anyhow!("{}: {} of {} certificates are invalid", path.display(), invalid, certs.len()));
+    }
+    if valid == 0 {
+        return Err(anyhow!("{} contains no certificates", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Parse a PEM bundle; a bundle without a single usable certificate is an error
+fn read_bundle(path: &Path) -> Result<RootCertStore> {
+    let certs = read_certs(path)?;
+    let mut store = RootCertStore::empty();
+    let (valid, invalid) = store.add_parsable_certificates(&certs);
+    if invalid > 0 {
+        warn!("Skipped {} unparsable certificates in {}", invalid, path.display());
+    }
+    if valid == 0 {
+        return Err(anyhow!("{} contains no usable certificates", path.display()));
+    }
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_ca(dir: &Path) -> PathBuf {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let path = dir.join("ca.pem");
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_native_bundle_from_ssl_cert_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = write_ca(dir.path());
+        let roots = load_from(None, Some(&bundle)).unwrap();
+        assert_eq!(roots.source, TrustSource::Native(bundle));
+        assert_eq!(roots.store.len(), 1);
+    }
+
+    #[cfg(feature = "bundled-roots")]
+    #[test]
+    fn test_empty_ssl_cert_file_falls_back_to_bundled() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let roots = load_from(None, Some(&empty)).unwrap();
+        assert_eq!(roots.source, TrustSource::Bundled);
+        assert!(roots.store.len() > 100);
+
+        let missing = dir.path().join("missing.pem");
+        assert_eq!(load_from(None, Some(&missing)).unwrap().source, TrustSource::Bundled);
+    }
+
+    #[test]
+    fn test_ca_cert_adds_to_other_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let native = write_ca(dir.path());
+        let ca = dir.path().join("internal.pem");
+        let pems: Vec<String> = (0..2)
+            .map(|_| rcgen::generate_simple_self_signed(vec!["publikey.internal".to_string()]).unwrap().serialize_pem().unwrap())
+            .collect();
+        std::fs::write(&ca, pems.concat()).unwrap();
+
+        // Every certificate of a multi-certificate bundle is trusted, next to the system roots
+        let roots = load_from(Some(&ca), Some(&native)).unwrap();
+        assert_eq!(roots.source, TrustSource::Native(native));
+        assert_eq!(roots.ca_cert, Some(ca.clone()));
+        assert_eq!(roots.store.len(), 3);
+    }
+
+    #[cfg(not(feature = "bundled-roots"))]
+    #[test]
+    fn test_ca_cert_alone_without_other_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = write_ca(dir.path());
+        let missing = dir.path().join("missing.pem");
+        let roots = load_from(Some(&ca), Some(&missing)).unwrap();
+        assert_eq!(roots.source, TrustSource::CaCert(ca));
+        assert_eq!(roots.store.len(), 1);
+    }
+
+    #[test]
+    fn test_broken_ca_cert_fails_with_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let native = write_ca(dir.path());
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let garbled = dir.path().join("garbled.pem");
+        std::fs::write(&garbled, "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydGlmaWNhdGU=\n-----END CERTIFICATE-----\n").unwrap();
+        let missing = dir.path().join("missing.pem");
+
+        // An error rather than a silent fallback to the other roots
+        for path in [&empty, &garbled, &missing] {
+            let error = format!("{:#}", load_from(Some(path), Some(&native)).err().unwrap());
+            assert!(error.contains(&path.display().to_string()), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_insecure_only_for_localhost_unless_acknowledged() {
+        for endpoint in ["https://localhost:3000", "https://127.0.0.1", "https://[::1]:8443", "https://api.localhost"] {
+            assert!(check_insecure_endpoint(endpoint, false).is_ok(), "{}", endpoint);
+        }
+        let error = check_insecure_endpoint("https://publikey.example.com", false).unwrap_err().to_string();
+        assert!(error.contains("--yes-i-know"), "{}", error);
+        assert!(check_insecure_endpoint("https://publikey.example.com", true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_insecure_config_accepts_untrusted_certificate() {
+        let cert = crate::test_support::SelfSignedCert::generate();
+        let endpoint = crate::test_support::tls_mock_server(&cert).await;
+        let roots = TrustRoots { store: RootCertStore::empty(), source: TrustSource::Bundled, ca_cert: None };
+
+        let verified = crate::api::ApiClient::with_tls_config(endpoint.clone(), "token".into(), roots.api_client_config(None)).unwrap();
+        assert!(verified.health_check().await.is_err());
+        let insecure = crate::api::ApiClient::with_tls_config(endpoint, "token".into(), insecure_client_config(None)).unwrap();
+        assert!(insecure.health_check().await.unwrap().healthy);
+    }
+}
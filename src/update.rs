@@ -4,13 +4,16 @@ use anyhow::{Result, anyhow};
 use tracing::{info, instrument};
 use std::env;
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+use crate::api;
+use crate::fsutil;
+use crate::proxy::ProxySetting;
 
 #[derive(Deserialize, Debug)]
 pub struct GitHubRelease {
     pub tag_name: String,
     pub name: String,
-    pub body: String,
     pub draft: bool,
     pub prerelease: bool,
     pub assets: Vec<GitHubAsset>,
@@ -21,27 +24,57 @@ pub struct GitHubAsset {
     pub name: String,
     pub browser_download_url: String,
     pub size: u64,
-    pub content_type: String,
+}
+
+/// GitHub repository releases are published from
+pub const RELEASES_REPO: &str = "ruohki/agent";
+
+/// Longest a release request may take; far beyond the API's `--timeout`, as it covers
+/// downloading the binary
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Result of an update check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    UpToDate,
+    /// A newer release exists but was not installed
+    Available,
+    Installed,
 }
 
 pub struct UpdateManager {
     client: Client,
+    proxy: ProxySetting,
     releases_url: String,
+    immutable_os: bool,
 }
 
 impl UpdateManager {
-    pub fn new() -> Result<Self> {
-        let client = Client::builder()
+    pub fn new(tls: rustls::ClientConfig, proxy: ProxySetting) -> Result<Self> {
+        let builder = Client::builder()
             .user_agent(format!("pkagent/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(DOWNLOAD_TIMEOUT)
+            .connect_timeout(api::DEFAULT_CONNECT_TIMEOUT)
+            .use_preconfigured_tls(tls);
+        let client = proxy
+            .apply(builder)?
             .build()
             .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
 
         Ok(Self {
             client,
-            releases_url: "https://api.github.com/repos/ruohki/agent/releases/latest".to_string(),
+            proxy,
+            releases_url: format!("https://api.github.com/repos/{}/releases/latest", RELEASES_REPO),
+            immutable_os: false,
         })
     }
 
+    /// Refuse to install updates on image-based systems, where a replaced binary does not survive
+    pub fn with_immutable_os(mut self, immutable_os: bool) -> Self {
+        self.immutable_os = immutable_os;
+        self
+    }
+
     /// Get the current platform-specific binary name
     pub fn get_current_binary_name() -> String {
         let os = if cfg!(target_os = "linux") {
@@ -64,9 +97,19 @@ impl UpdateManager {
             "unknown"
         };
 
+        Self::binary_name(os, arch)
+    }
+
+    /// Release asset name for a platform
+    pub fn binary_name(os: &str, arch: &str) -> String {
         format!("pkagent-{}-{}", os, arch)
     }
 
+    /// Base URL of the assets of the release tagged for `version`
+    pub fn release_download_url(version: &str) -> String {
+        format!("https://github.com/{}/releases/download/v{}", RELEASES_REPO, version.trim_start_matches('v'))
+    }
+
     /// Fetch the latest release information from GitHub
     #[instrument(skip(self))]
     pub async fn get_latest_release(&self) -> Result<GitHubRelease> {
@@ -77,7 +120,7 @@ impl UpdateManager {
             .header("Accept", "application/vnd.github.v3+json")
             .send()
             .await
-            .map_err(|e| anyhow!("Failed to fetch release info: {}", e))?;
+            .map_err(|e| anyhow!("Failed to fetch release info: {}", self.proxy.describe_error(&e)))?;
 
         if !response.status().is_success() {
             return Err(anyhow!("GitHub API request failed: {}", response.status()));
@@ -139,8 +182,8 @@ impl UpdateManager {
         info!("Downloading update: {} ({} bytes)", asset.name, asset.size);
         
         if dry_run {
-            println!("DRY RUN: Would download {} from {}", asset.name, asset.browser_download_url);
-            println!("DRY RUN: Would replace current binary at: {}", current_exe.display());
+            say!("DRY RUN: Would download {} from {}", asset.name, asset.browser_download_url);
+            say!("DRY RUN: Would replace current binary at: {}", current_exe.display());
             return Ok(());
         }
 
@@ -149,7 +192,7 @@ impl UpdateManager {
             .get(&asset.browser_download_url)
             .send()
             .await
-            .map_err(|e| anyhow!("Failed to download update: {}", e))?;
+            .map_err(|e| anyhow!("Failed to download update: {}", self.proxy.describe_error(&e)))?;
 
         if !response.status().is_success() {
             return Err(anyhow!("Download failed: {}", response.status()));
@@ -174,25 +217,12 @@ impl UpdateManager {
         fs::copy(&current_exe, &backup_path)
             .map_err(|e| anyhow!("Failed to create backup: {}", e))?;
 
-        // Write new binary to a temporary file first
-        let temp_path = format!("{}.new", current_exe.to_string_lossy());
-        fs::write(&temp_path, &bytes)
-            .map_err(|e| anyhow!("Failed to write new binary: {}", e))?;
-
-        // Set executable permissions
-        let metadata = fs::metadata(&temp_path)
-            .map_err(|e| anyhow!("Failed to get temp file metadata: {}", e))?;
-        let mut permissions = metadata.permissions();
-        permissions.set_mode(0o755);
-        fs::set_permissions(&temp_path, permissions)
-            .map_err(|e| anyhow!("Failed to set executable permissions: {}", e))?;
-
-        // Atomically replace the current binary
-        fs::rename(&temp_path, &current_exe)
+        // Atomically replace the current binary via an fsynced temporary file
+        fsutil::atomic_write(&current_exe, &bytes, 0o755)
             .map_err(|e| anyhow!("Failed to replace current binary: {}", e))?;
 
-        println!("Update installed successfully!");
-        println!("Backup saved to: {}", backup_path);
+        say!("Update installed successfully!");
+        say!("Backup saved to: {}", backup_path);
         info!("Update completed successfully");
 
         Ok(())
@@ -200,36 +230,67 @@ impl UpdateManager {
 
     /// Check for and optionally install updates
     #[instrument(skip(self))]
-    pub async fn check_and_update(&self, current_version: &str, dry_run: bool, install: bool) -> Result<bool> {
+    pub async fn check_and_update(&self, current_version: &str, dry_run: bool, install: bool) -> Result<UpdateOutcome> {
+        if install && self.immutable_os {
+            return Err(anyhow!(
+                "`pkagent update` is not available on an immutable OS (ostree/CoreOS or a read-only /usr): a replaced \
+                 binary would not survive the next deployment. Install updates through the package manager \
+                 or layer the package (rpm-ostree install) instead; `pkagent check-update` still reports new versions"
+            ));
+        }
+
         let release = self.get_latest_release().await?;
 
         // Skip draft and prerelease versions
         if release.draft || release.prerelease {
             info!("Skipping draft/prerelease version: {}", release.tag_name);
-            println!("Latest release is a draft or prerelease, skipping.");
-            return Ok(false);
+            say!("Latest release is a draft or prerelease, skipping.");
+            return Ok(UpdateOutcome::UpToDate);
         }
 
-        println!("Current version: {}", current_version);
-        println!("Latest version: {}", release.tag_name);
+        say!("Current version: {}", current_version);
+        say!("Latest version: {}", release.tag_name);
 
         if Self::is_newer_version(current_version, &release.tag_name) {
-            println!("Update available: {} -> {}", current_version, release.tag_name);
+            say!("Update available: {} -> {}", current_version, release.tag_name);
             
             if !install {
-                println!("Use --update to install the update");
-                return Ok(false);
+                say!("Use `pkagent update` to install the update");
+                return Ok(UpdateOutcome::Available);
             }
 
             let asset = self.find_platform_asset(&release)?;
-            println!("Found platform asset: {} ({} bytes)", asset.name, asset.size);
+            say!("Found platform asset: {} ({} bytes)", asset.name, asset.size);
 
             self.download_and_install(asset, dry_run).await?;
-            return Ok(true);
+            return Ok(UpdateOutcome::Installed);
         } else {
-            println!("You are running the latest version.");
+            say!("You are running the latest version.");
         }
 
-        Ok(false)
+        Ok(UpdateOutcome::UpToDate)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_config() -> rustls::ClientConfig {
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth()
+    }
+
+    #[tokio::test]
+    async fn test_update_refused_on_immutable_os() {
+        // Refused before any request is made, dry run or not
+        let manager = UpdateManager::new(tls_config(), ProxySetting::default()).unwrap().with_immutable_os(true);
+        for dry_run in [false, true] {
+            let err = manager.check_and_update("0.1.0", dry_run, true).await.unwrap_err().to_string();
+            assert!(err.contains("immutable OS"), "{}", err);
+            assert!(err.contains("rpm-ostree install"), "{}", err);
+        }
+    }
+}
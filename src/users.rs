@@ -1,8 +1,12 @@
 use serde::Serialize;
-use anyhow::Result;
-use tracing::{debug, instrument};
+use anyhow::{Result, anyhow};
+use tracing::{debug, instrument, warn};
+use std::collections::HashMap;
 use std::env;
 
+use crate::login::LoginBlocker;
+use crate::optout::UserOptOut;
+
 #[derive(Serialize, Debug, Clone)]
 pub struct UserInfo {
     pub username: String,
@@ -13,15 +17,44 @@ pub struct UserInfo {
     pub home_dir: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub disabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_encryption: Option<HomeEncryption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_mounted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// sshd has public key authentication turned off for this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey_auth_disabled: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub login_blockers: Vec<LoginBlocker>,
+    /// The user took their keys out of the agent's hands with an opt-out marker file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opt_out: Option<UserOptOut>,
 }
 
+/// How a user's home directory is stored
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HomeEncryption {
+    /// Regular directory, always accessible
+    Plain,
+    /// systemd-homed managed volume (LUKS, fscrypt, ...)
+    Homed,
+    /// eCryptfs stacked filesystem (~/.Private)
+    Ecryptfs,
+}
+
+/// Directory where systemd-homed keeps per-user identity records
+const HOMED_IDENTITY_DIR: &str = "/var/lib/systemd/home";
+
 #[instrument]
-pub fn collect_users(exclude_users: &[String], include_users: &[String], user_mode: bool) -> Result<Vec<UserInfo>> {
+pub fn collect_users(exclude_users: &[String], include_users: &[String], user_mode: bool, home_override: bool) -> Result<Vec<UserInfo>> {
     let mut users = Vec::new();
     
     if user_mode {
         // In user mode, only report the current user
-        let current_user = get_current_user()?;
+        let current_user = get_current_user(home_override)?;
         users.push(current_user);
         debug!("User mode: only including current user");
     } else {
@@ -39,11 +72,26 @@ pub fn collect_users(exclude_users: &[String], include_users: &[String], user_mo
                 shell: Some("/bin/bash".to_string()),
                 home_dir: Some("/root".to_string()),
                 disabled: Some(false),
+                home_encryption: None,
+                home_mounted: None,
+                email: None,
+                pubkey_auth_disabled: None,
+                login_blockers: Vec::new(),
+                opt_out: None,
             });
         }
     }
     
-    // Apply user filtering (include mode takes precedence over exclude mode)
+    filter_users(&mut users, include_users, exclude_users);
+    
+    // Sort by UID for consistent ordering
+    users.sort_by_key(|u| u.uid);
+    
+    Ok(users)
+}
+
+/// Apply include/exclude filtering to a user list (include mode takes precedence over exclude mode)
+pub fn filter_users(users: &mut Vec<UserInfo>, include_users: &[String], exclude_users: &[String]) {
     if !include_users.is_empty() {
         let initial_count = users.len();
         users.retain(|user| include_users.contains(&user.username));
@@ -60,22 +108,167 @@ pub fn collect_users(exclude_users: &[String], include_users: &[String], user_mo
             debug!("Excluded {} users: {:?}", excluded_count, exclude_users);
         }
     }
-    
-    // Sort by UID for consistent ordering
-    users.sort_by_key(|u| u.uid);
-    
-    Ok(users)
 }
 
-fn get_current_user() -> Result<UserInfo> {
+/// Where the effective user filter came from
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterSource {
+    Local,
+    Server,
+}
+
+/// User filter applied to the key sync phase
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub source: FilterSource,
+}
+
+/// Merge local include/exclude flags with server-provided managed/excluded users.
+///
+/// Server lists override the local flags as a whole whenever either one is present,
+/// unless `local_override` is set, in which case the local flags always win.
+pub fn effective_user_filter(
+    local_include: &[String],
+    local_exclude: &[String],
+    server_managed: Option<&[String]>,
+    server_excluded: Option<&[String]>,
+    local_override: bool,
+) -> UserFilter {
+    if local_override || (server_managed.is_none() && server_excluded.is_none()) {
+        return UserFilter {
+            include: local_include.to_vec(),
+            exclude: local_exclude.to_vec(),
+            source: FilterSource::Local,
+        };
+    }
+
+    UserFilter {
+        include: server_managed.unwrap_or_default().to_vec(),
+        exclude: server_excluded.unwrap_or_default().to_vec(),
+        source: FilterSource::Server,
+    }
+}
+
+/// Identity of the effective user, as passwd has it
+#[derive(Debug, Clone, PartialEq)]
+struct PasswdIdentity {
+    username: String,
+    home_dir: String,
+    shell: String,
+}
+
+/// Identity-related environment variables, which sudo and su may leave pointing at the invoking user
+#[derive(Debug, Default)]
+struct IdentityEnv {
+    user: Option<String>,
+    home: Option<String>,
+    shell: Option<String>,
+}
+
+impl IdentityEnv {
+    fn read() -> Self {
+        Self {
+            user: env::var("USER").or_else(|_| env::var("USERNAME")).ok(),
+            home: env::var("HOME").ok(),
+            shell: env::var("SHELL").ok(),
+        }
+    }
+}
+
+/// The current user's identity, with warnings where the environment disagrees with passwd
+#[derive(Debug)]
+struct ResolvedIdentity {
+    username: String,
+    home_dir: Option<String>,
+    shell: Option<String>,
+    warnings: Vec<String>,
+}
+
+/// Username, home and shell of the current user, plus warnings where the environment disagrees.
+///
+/// passwd wins; $HOME is used only with `home_override`. Without a passwd entry (e.g. an arbitrary
+/// container uid) the environment is all there is.
+fn resolve_current_user(
+    uid: u32,
+    passwd: Option<PasswdIdentity>,
+    env: &IdentityEnv,
+    home_override: bool,
+) -> Result<ResolvedIdentity> {
+    let mut warnings = Vec::new();
+    let Some(passwd) = passwd else {
+        let username = env.user.clone().ok_or_else(|| anyhow!("uid {} has no passwd entry and $USER is not set", uid))?;
+        warnings.push(format!("uid {} has no passwd entry; using $USER ({}) and $HOME from the environment", uid, username));
+        return Ok(ResolvedIdentity { username, home_dir: env.home.clone(), shell: env.shell.clone(), warnings });
+    };
+
+    if let Some(user) = &env.user
+        && *user != passwd.username
+    {
+        warnings.push(format!(
+            "$USER is {} but the effective uid {} belongs to {}; using {}",
+            user, uid, passwd.username, passwd.username
+        ));
+    }
+
+    let same_dir = |a: &str, b: &str| a.trim_end_matches('/') == b.trim_end_matches('/');
+    let home_dir = match &env.home {
+        Some(home) if home_override => {
+            if !same_dir(home, &passwd.home_dir) {
+                warnings.push(format!(
+                    "using $HOME ({}) instead of {}'s passwd home ({}) because of --home-override",
+                    home, passwd.username, passwd.home_dir
+                ));
+            }
+            home.clone()
+        }
+        Some(home) if !same_dir(home, &passwd.home_dir) => {
+            warnings.push(format!(
+                "$HOME is {} but {}'s passwd home is {}; using {} (pass --home-override to use $HOME)",
+                home, passwd.username, passwd.home_dir, passwd.home_dir
+            ));
+            passwd.home_dir.clone()
+        }
+        _ => passwd.home_dir.clone(),
+    };
+
+    Ok(ResolvedIdentity {
+        username: passwd.username,
+        home_dir: Some(home_dir).filter(|home| !home.is_empty()),
+        shell: Some(passwd.shell).filter(|shell| !shell.is_empty()),
+        warnings,
+    })
+}
+
+fn get_current_user(home_override: bool) -> Result<UserInfo> {
     #[cfg(unix)]
     {
         use nix::unistd;
         
-        let uid = unistd::getuid();
-        let username = env::var("USER").or_else(|_| env::var("USERNAME"))?;
-        let home_dir = env::var("HOME").ok();
-        let shell = env::var("SHELL").ok();
+        // The effective uid decides whose files get written, so it decides who the user is
+        let uid = unistd::geteuid();
+        let passwd = unistd::User::from_uid(uid)
+            .map_err(|e| anyhow!("Failed to look up uid {} in passwd: {}", uid, e))?
+            .map(|user| PasswdIdentity {
+                username: user.name,
+                home_dir: user.dir.to_string_lossy().to_string(),
+                shell: user.shell.to_string_lossy().to_string(),
+            });
+        let ResolvedIdentity { username, home_dir, shell, warnings } =
+            resolve_current_user(uid.as_raw(), passwd, &IdentityEnv::read(), home_override)?;
+        for warning in warnings {
+            crate::say!("Warning: {}", warning);
+            warn!("{}", warning);
+        }
+        let (home_encryption, home_mounted) = match &home_dir {
+            Some(home) => {
+                let (encryption, mounted) = probe_home_encryption(&username, home);
+                (Some(encryption), Some(mounted))
+            }
+            None => (None, None),
+        };
         
         Ok(UserInfo {
             username,
@@ -83,11 +276,18 @@ fn get_current_user() -> Result<UserInfo> {
             shell,
             home_dir,
             disabled: Some(false),
+            home_encryption,
+            home_mounted,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
         })
     }
     
     #[cfg(not(unix))]
     {
+        let _ = home_override;
         let username = env::var("USER").or_else(|_| env::var("USERNAME"))?;
         Ok(UserInfo {
             username,
@@ -95,17 +295,66 @@ fn get_current_user() -> Result<UserInfo> {
             shell: Some("/bin/bash".to_string()),
             home_dir: env::var("HOME").ok(),
             disabled: Some(false),
+            home_encryption: None,
+            home_mounted: None,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
         })
     }
 }
 
 #[cfg(unix)]
 fn parse_passwd_file() -> Result<Vec<UserInfo>> {
+    let passwd_content = read_passwd_stable("/etc/passwd")?;
+    Ok(parse_passwd_content(&passwd_content))
+}
+
+/// Read the passwd file until two consecutive reads agree and the content looks complete.
+///
+/// vipw/useradd rewrite the file in place, so a single read can observe a truncated file.
+#[cfg(unix)]
+fn read_passwd_stable(path: &str) -> Result<String> {
     use std::fs;
     
+    const ATTEMPTS: u32 = 5;
+    let read = || fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e));
+    
+    let mut previous = read()?;
+    for attempt in 1..=ATTEMPTS {
+        std::thread::sleep(std::time::Duration::from_millis(50 * attempt as u64));
+        let current = read()?;
+        if current == previous && !is_passwd_content_suspect(&current) {
+            return Ok(current);
+        }
+        warn!("{} changed or looks truncated while reading (attempt {}), retrying", path, attempt);
+        previous = current;
+    }
+    
+    Err(anyhow::anyhow!("{} did not stabilize after {} attempts; refusing to report a partial user list", path, ATTEMPTS))
+}
+
+/// Whether passwd content looks like a partial write
+fn is_passwd_content_suspect(content: &str) -> bool {
+    let has_root = content.lines().any(|line| line.starts_with("root:"));
+    content.is_empty() || !content.ends_with('\n') || !has_root
+}
+
+/// Whether the user count dropped by more than `max_drop_percent` since the last run
+pub fn user_count_dropped(previous: Option<usize>, current: usize, max_drop_percent: u8) -> bool {
+    match previous {
+        Some(previous) if previous > current => {
+            let dropped = (previous - current) * 100 / previous;
+            dropped > max_drop_percent as usize
+        }
+        _ => false,
+    }
+}
+
+/// Parse passwd-format content into the users we manage
+fn parse_passwd_content(passwd_content: &str) -> Vec<UserInfo> {
     let mut users = Vec::new();
-    let passwd_content = fs::read_to_string("/etc/passwd")
-        .map_err(|e| anyhow::anyhow!("Failed to read /etc/passwd: {}", e))?;
     
     for line in passwd_content.lines() {
         if line.trim().is_empty() || line.starts_with('#') {
@@ -119,6 +368,7 @@ fn parse_passwd_file() -> Result<Vec<UserInfo>> {
         
         let username = parts[0].to_string();
         let uid: u32 = parts[2].parse().unwrap_or_continue();
+        let email = email_from_gecos(parts[4]);
         let shell = parts[6].to_string();
         let home_dir = parts[5].to_string();
         
@@ -153,7 +403,10 @@ fn parse_passwd_file() -> Result<Vec<UserInfo>> {
         };
         
         // Check if user account is disabled
-        let disabled = is_user_disabled(&shell.as_ref().unwrap_or(&String::new()));
+        let disabled = is_user_disabled(shell.as_deref().unwrap_or(""));
+        
+        // Check whether the home lives on an encrypted volume that may not be mounted
+        let (home_encryption, home_mounted) = probe_home_encryption(&username, home_dir.as_deref().unwrap_or(""));
         
         users.push(UserInfo {
             username,
@@ -161,10 +414,61 @@ fn parse_passwd_file() -> Result<Vec<UserInfo>> {
             shell,
             home_dir,
             disabled: Some(disabled),
+            home_encryption: Some(home_encryption),
+            home_mounted: Some(home_mounted),
+            email,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
         });
     }
     
-    Ok(users)
+    users
+}
+
+/// First email address found in a GECOS field (comma-separated, possibly `Name <addr>`)
+fn email_from_gecos(gecos: &str) -> Option<String> {
+    gecos
+        .split(',')
+        .flat_map(str::split_whitespace)
+        .map(|token| token.trim_matches(|c| c == '<' || c == '>'))
+        .find(|token| is_plausible_email(token))
+        .map(str::to_string)
+}
+
+fn is_plausible_email(value: &str) -> bool {
+    match value.split_once('@') {
+        Some((local, domain)) => !local.is_empty() && domain.contains('.') && !domain.contains('@'),
+        None => false,
+    }
+}
+
+/// Parse a `--user-email-map` file: one `username email` pair per line, `#` comments allowed
+pub fn parse_email_map(content: &str) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(username), Some(email), None) if is_plausible_email(email) => {
+                map.insert(username.to_string(), email.to_string());
+            }
+            _ => return Err(anyhow!("Invalid user email map entry on line {}: {:?}", index + 1, line)),
+        }
+    }
+    Ok(map)
+}
+
+/// Set user emails from a mapping, overriding anything found in GECOS
+pub fn apply_email_map(users: &mut [UserInfo], map: &HashMap<String, String>) {
+    for user in users {
+        if let Some(email) = map.get(&user.username) {
+            user.email = Some(email.clone());
+        }
+    }
 }
 
 // Helper trait to continue on parse error
@@ -178,6 +482,55 @@ impl<T: Default> UnwrapOrContinue<T> for Result<T, std::num::ParseIntError> {
     }
 }
 
+/// Probe the local system for a user's home encryption status
+fn probe_home_encryption(username: &str, home_dir: &str) -> (HomeEncryption, bool) {
+    use std::fs;
+    use std::path::Path;
+
+    let homed_identity = Path::new(HOMED_IDENTITY_DIR)
+        .join(format!("{}.identity", username))
+        .exists();
+    let ecryptfs_private = Path::new(home_dir).join(".Private").exists()
+        || Path::new("/home/.ecryptfs").join(username).exists();
+    let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+
+    detect_home_encryption(home_dir, homed_identity, ecryptfs_private, &mounts)
+}
+
+/// Determine home encryption type and whether it is currently mounted.
+///
+/// `mounts` uses the /proc/self/mounts format. Plain homes always count as mounted.
+fn detect_home_encryption(
+    home_dir: &str,
+    homed_identity: bool,
+    ecryptfs_private: bool,
+    mounts: &str,
+) -> (HomeEncryption, bool) {
+    let home_mount_type = mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let _device = fields.next()?;
+        let mount_point = fields.next()?.replace("\\040", " ");
+        let fs_type = fields.next()?;
+        (mount_point.trim_end_matches('/') == home_dir.trim_end_matches('/')).then(|| fs_type.to_string())
+    });
+
+    if homed_identity {
+        (HomeEncryption::Homed, home_mount_type.is_some())
+    } else if home_mount_type.as_deref() == Some("ecryptfs") {
+        (HomeEncryption::Ecryptfs, true)
+    } else if ecryptfs_private {
+        (HomeEncryption::Ecryptfs, false)
+    } else {
+        (HomeEncryption::Plain, true)
+    }
+}
+
+/// Whether key sync should skip a user because their encrypted home is not mounted
+pub fn should_skip_unmounted_home(user: &UserInfo, attempt_unmounted: bool) -> bool {
+    let encrypted = matches!(user.home_encryption, Some(HomeEncryption::Homed | HomeEncryption::Ecryptfs));
+    encrypted && user.home_mounted == Some(false) && !attempt_unmounted
+}
+
 fn is_user_disabled(_shell: &str) -> bool {
     // Since we already filter out nologin shells during collection,
     // the remaining users are generally not disabled
@@ -191,7 +544,7 @@ mod tests {
 
     #[test]
     fn test_collect_users() {
-        let users = collect_users(&[], &[], false).unwrap();
+        let users = collect_users(&[], &[], false, false).unwrap();
         
         // Should have at least root user (unless root has nologin shell)
         // Check that all users have valid UIDs (0 or >= 1000)
@@ -223,4 +576,225 @@ mod tests {
         assert!(!is_user_disabled("/usr/bin/false")); // Already filtered out during collection
         assert!(!is_user_disabled("/sbin/nologin"));  // Already filtered out during collection
     }
-}
\ No newline at end of file
+
+    const MOUNTS: &str = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+/dev/mapper/home-alice /home/alice ext4 rw,nosuid,nodev 0 0
+/home/bob/.Private /home/bob ecryptfs rw,ecryptfs_sig=abc 0 0
+proc /proc proc rw,nosuid,nodev,noexec 0 0
+";
+
+    #[test]
+    fn test_email_from_gecos() {
+        assert_eq!(email_from_gecos("Alice,,,,alice@example.com"), Some("alice@example.com".to_string()));
+        assert_eq!(email_from_gecos("Alice Smith <alice@example.com>,Room 1"), Some("alice@example.com".to_string()));
+        assert_eq!(email_from_gecos("Alice,Room 1,555-1234,,"), None);
+        assert_eq!(email_from_gecos("@handle,user@localhost"), None);
+        assert_eq!(email_from_gecos(""), None);
+    }
+
+    #[test]
+    fn test_parse_passwd_content_collects_gecos_email() {
+        let users = parse_passwd_content("alice:x:1000:1000:Alice,,,,alice@example.com:/home/alice:/bin/bash\nbob:x:1001:1001:Bob:/home/bob:/bin/bash\n");
+        assert_eq!(users[0].email.as_deref(), Some("alice@example.com"));
+        assert_eq!(users[1].email, None);
+    }
+
+    #[test]
+    fn test_email_map() {
+        let map = parse_email_map("# comment\nalice  alice@corp.example\n\nbob bob@corp.example\n").unwrap();
+        assert_eq!(map.len(), 2);
+        assert!(parse_email_map("alice").is_err());
+        assert!(parse_email_map("alice not-an-email").is_err());
+        assert!(parse_email_map("alice a@b.example extra").is_err());
+
+        let mut users = parse_passwd_content("alice:x:1000:1000:Alice,,,,alice@old.example:/home/alice:/bin/bash\ncarol:x:1002:1002::/home/carol:/bin/bash\n");
+        apply_email_map(&mut users, &map);
+        assert_eq!(users[0].email.as_deref(), Some("alice@corp.example"));
+        assert_eq!(users[1].email, None);
+    }
+
+    fn encrypted_user(encryption: Option<HomeEncryption>, mounted: Option<bool>) -> UserInfo {
+        UserInfo {
+            username: "alice".to_string(),
+            uid: 1000,
+            shell: Some("/bin/bash".to_string()),
+            home_dir: Some("/home/alice".to_string()),
+            disabled: Some(false),
+            home_encryption: encryption,
+            home_mounted: mounted,
+            email: None,
+            pubkey_auth_disabled: None,
+            login_blockers: Vec::new(),
+            opt_out: None,
+        }
+    }
+
+    #[test]
+    fn test_detect_home_encryption() {
+        // Plain home is always reachable
+        assert_eq!(detect_home_encryption("/home/carol", false, false, MOUNTS), (HomeEncryption::Plain, true));
+
+        // systemd-homed, logged in and not logged in
+        assert_eq!(detect_home_encryption("/home/alice", true, false, MOUNTS), (HomeEncryption::Homed, true));
+        assert_eq!(detect_home_encryption("/home/dave", true, false, MOUNTS), (HomeEncryption::Homed, false));
+
+        // eCryptfs mounted, and ~/.Private present without the mount
+        assert_eq!(detect_home_encryption("/home/bob", false, true, MOUNTS), (HomeEncryption::Ecryptfs, true));
+        assert_eq!(detect_home_encryption("/home/bob/", false, false, MOUNTS), (HomeEncryption::Ecryptfs, true));
+        assert_eq!(detect_home_encryption("/home/erin", false, true, MOUNTS), (HomeEncryption::Ecryptfs, false));
+    }
+
+    #[test]
+    fn test_should_skip_unmounted_home() {
+        assert!(should_skip_unmounted_home(&encrypted_user(Some(HomeEncryption::Homed), Some(false)), false));
+        assert!(should_skip_unmounted_home(&encrypted_user(Some(HomeEncryption::Ecryptfs), Some(false)), false));
+        assert!(!should_skip_unmounted_home(&encrypted_user(Some(HomeEncryption::Homed), Some(false)), true));
+        assert!(!should_skip_unmounted_home(&encrypted_user(Some(HomeEncryption::Homed), Some(true)), false));
+        assert!(!should_skip_unmounted_home(&encrypted_user(Some(HomeEncryption::Plain), Some(true)), false));
+        assert!(!should_skip_unmounted_home(&encrypted_user(None, None), false));
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_effective_user_filter_without_server_lists() {
+        let filter = effective_user_filter(&names(&["alice"]), &[], None, None, false);
+        assert_eq!(filter.include, names(&["alice"]));
+        assert!(filter.exclude.is_empty());
+        assert_eq!(filter.source, FilterSource::Local);
+    }
+
+    #[test]
+    fn test_effective_user_filter_server_overrides_local() {
+        let managed = names(&["bob", "carol"]);
+        let filter = effective_user_filter(&names(&["alice"]), &names(&["dave"]), Some(&managed), None, false);
+        assert_eq!(filter.include, managed);
+        assert!(filter.exclude.is_empty());
+        assert_eq!(filter.source, FilterSource::Server);
+
+        let excluded = names(&["root"]);
+        let filter = effective_user_filter(&names(&["alice"]), &[], None, Some(&excluded), false);
+        assert!(filter.include.is_empty());
+        assert_eq!(filter.exclude, excluded);
+        assert_eq!(filter.source, FilterSource::Server);
+    }
+
+    #[test]
+    fn test_effective_user_filter_server_both_lists() {
+        let managed = names(&["bob"]);
+        let excluded = names(&["root"]);
+        let filter = effective_user_filter(&[], &[], Some(&managed), Some(&excluded), false);
+        assert_eq!(filter.include, managed);
+        assert_eq!(filter.exclude, excluded);
+        assert_eq!(filter.source, FilterSource::Server);
+
+        // Include still takes precedence when the filter is applied
+        let mut users = vec![encrypted_user(None, None)];
+        users[0].username = "bob".to_string();
+        filter_users(&mut users, &filter.include, &filter.exclude);
+        assert_eq!(users.len(), 1);
+    }
+
+    #[test]
+    fn test_effective_user_filter_local_override() {
+        let managed = names(&["bob"]);
+        let filter = effective_user_filter(&names(&["alice"]), &[], Some(&managed), None, true);
+        assert_eq!(filter.include, names(&["alice"]));
+        assert_eq!(filter.source, FilterSource::Local);
+    }
+
+    const PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/bash
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+alice:x:1000:1000:Alice:/home/alice:/bin/bash
+bob:x:1001:1001:Bob:/home/bob:/bin/zsh
+carol:x:1002:1002:Carol:/home/carol:/bin/bash
+";
+
+    #[test]
+    fn test_parse_passwd_content() {
+        let users = parse_passwd_content(PASSWD);
+        let names: Vec<_> = users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(names, vec!["root", "alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn test_truncated_passwd_is_suspect() {
+        assert!(!is_passwd_content_suspect(PASSWD));
+
+        // Cut off in the middle of a line
+        let truncated = &PASSWD[..PASSWD.find("bob:x:10").unwrap() + 8];
+        assert!(is_passwd_content_suspect(truncated));
+        // A truncated read parses to fewer users
+        assert_eq!(parse_passwd_content(truncated).len(), 2);
+
+        assert!(is_passwd_content_suspect(""));
+        assert!(is_passwd_content_suspect("alice:x:1000:1000::/home/alice:/bin/bash\n"));
+    }
+
+    #[test]
+    fn test_user_count_dropped() {
+        assert!(!user_count_dropped(None, 2, 20));
+        assert!(!user_count_dropped(Some(4), 4, 20));
+        assert!(!user_count_dropped(Some(4), 6, 20));
+        assert!(!user_count_dropped(Some(10), 8, 20));
+        assert!(user_count_dropped(Some(10), 7, 20));
+        assert!(user_count_dropped(Some(4), 2, 20));
+        assert!(user_count_dropped(Some(4), 0, 0));
+    }
+
+    fn deploy() -> PasswdIdentity {
+        PasswdIdentity {
+            username: "deploy".to_string(),
+            home_dir: "/home/deploy".to_string(),
+            shell: "/bin/bash".to_string(),
+        }
+    }
+
+    /// `sudo -u deploy pkagent --user-mode` with sudo keeping the invoking root's HOME
+    fn sudo_env() -> IdentityEnv {
+        IdentityEnv {
+            user: Some("root".to_string()),
+            home: Some("/root".to_string()),
+            shell: Some("/bin/zsh".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_user_mode_identity_under_sudo() {
+        let resolved = resolve_current_user(1001, Some(deploy()), &sudo_env(), false).unwrap();
+        assert_eq!(resolved.username, "deploy");
+        assert_eq!(resolved.home_dir.as_deref(), Some("/home/deploy"));
+        assert_eq!(resolved.shell.as_deref(), Some("/bin/bash"));
+        assert_eq!(resolved.warnings.len(), 2);
+        assert!(resolved.warnings[0].contains("$USER is root"));
+        assert!(resolved.warnings[1].contains("$HOME is /root") && resolved.warnings[1].contains("--home-override"));
+
+        // --home-override trusts $HOME but still names the passwd user
+        let resolved = resolve_current_user(1001, Some(deploy()), &sudo_env(), true).unwrap();
+        assert_eq!(resolved.username, "deploy");
+        assert_eq!(resolved.home_dir.as_deref(), Some("/root"));
+        assert!(resolved.warnings[1].contains("because of --home-override"));
+    }
+
+    #[test]
+    fn test_user_mode_identity_consistent_env() {
+        let env = IdentityEnv {
+            user: Some("deploy".to_string()),
+            home: Some("/home/deploy/".to_string()),
+            shell: None,
+        };
+        let resolved = resolve_current_user(1001, Some(deploy()), &env, false).unwrap();
+        assert_eq!((resolved.username.as_str(), resolved.home_dir.as_deref()), ("deploy", Some("/home/deploy")));
+        assert!(resolved.warnings.is_empty());
+
+        // Without a passwd entry the environment is used, with a warning
+        let resolved = resolve_current_user(4242, None, &sudo_env(), false).unwrap();
+        assert_eq!((resolved.username.as_str(), resolved.home_dir.as_deref()), ("root", Some("/root")));
+        assert_eq!(resolved.warnings.len(), 1);
+        assert!(resolve_current_user(4242, None, &IdentityEnv::default(), false).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Result, Context, anyhow};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{info, warn, instrument};
+
+use crate::ssh_keys::KeySyncStats;
+
+/// Default header carrying the payload signature
+pub const DEFAULT_SIGNATURE_HEADER: &str = "X-PubliKey-Signature";
+
+/// Compact key sync summary posted to the webhook
+#[derive(Serialize, Debug)]
+pub struct SyncSummary<'a> {
+    pub hostname: &'a str,
+    /// The run's `X-PubliKey-Run-Id`, so the summary can be matched to the API requests
+    #[serde(rename = "runId")]
+    pub run_id: &'a str,
+    pub timestamp: u64,
+    pub stats: &'a KeySyncStats,
+}
+
+/// Posts key sync summaries to an external webhook
+pub struct WebhookNotifier {
+    client: Client,
+    url: String,
+    secret: Option<Vec<u8>>,
+    signature_header: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String, secret_file: Option<&Path>, signature_header: String) -> Result<Self> {
+        let secret = match secret_file {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .context(format!("Failed to read webhook secret file {}", path.display()))?;
+                Some(content.trim().as_bytes().to_vec())
+            }
+            None => None,
+        };
+
+        let client = Client::builder()
+            .user_agent(format!("pkagent/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
+
+        Ok(Self {
+            client,
+            url,
+            secret,
+            signature_header,
+        })
+    }
+
+    /// Whether a sync result is worth notifying about
+    pub fn should_notify(stats: &KeySyncStats, dry_run: bool) -> bool {
+        !dry_run
+            && (stats.keys_added > 0 || stats.keys_removed > 0 || stats.keys_updated > 0 || stats.permissions_fixed > 0 || stats.errors > 0)
+    }
+
+    /// Send the summary with at most one retry; failures are logged and never propagated
+    #[instrument(skip(self, stats))]
+    pub async fn notify(&self, hostname: &str, run_id: &str, stats: &KeySyncStats) {
+        let summary = SyncSummary {
+            hostname,
+            run_id,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            stats,
+        };
+
+        let body = match serde_json::to_vec(&summary) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to serialize webhook payload: {}", e);
+                return;
+            }
+        };
+
+        for attempt in 1..=2 {
+            match self.send(&body).await {
+                Ok(()) => {
+                    info!("Webhook notification sent to {}", self.url);
+                    return;
+                }
+                Err(e) => warn!("Webhook attempt {} failed: {}", attempt, e),
+            }
+        }
+    }
+
+    async fn send(&self, body: &[u8]) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_vec());
+
+        if let Some(secret) = &self.secret {
+            request = request.header(self.signature_header.as_str(), sign(secret, body));
+        }
+
+        let response = request.send().await.map_err(|e| anyhow!("Webhook request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Webhook returned HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256 signature in the form `sha256=<hex>`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_keys::FileKeyChanges;
+    use crate::test_support::{MockResponse, mock_server};
+
+    fn changed_stats() -> KeySyncStats {
+        KeySyncStats {
+            users_processed: 1,
+            keys_added: 1,
+            files_updated: 1,
+            changes: vec![FileKeyChanges {
+                username: "alice".to_string(),
+                path: "/home/alice/.ssh/authorized_keys".into(),
+                added: vec!["SHA256:new".to_string()],
+                removed: Vec::new(),
+                updated: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sign_known_vector() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_should_notify() {
+        assert!(WebhookNotifier::should_notify(&changed_stats(), false));
+        assert!(!WebhookNotifier::should_notify(&changed_stats(), true));
+        assert!(!WebhookNotifier::should_notify(&KeySyncStats::default(), false));
+        let errors = KeySyncStats { errors: 1, ..Default::default() };
+        assert!(WebhookNotifier::should_notify(&errors, false));
+    }
+
+    #[tokio::test]
+    async fn test_payload_and_signature() {
+        let (url, requests) = mock_server(vec![MockResponse::new(200, "")]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let secret_path = dir.path().join("secret");
+        std::fs::write(&secret_path, "s3cret\n").unwrap();
+
+        let notifier = WebhookNotifier::new(url, Some(&secret_path), DEFAULT_SIGNATURE_HEADER.to_string()).unwrap();
+        notifier.notify("web-1", "run-1", &changed_stats()).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert!(request.request_line.starts_with("POST / "));
+        assert_eq!(request.header(DEFAULT_SIGNATURE_HEADER).unwrap(), sign(b"s3cret", request.body.as_bytes()));
+
+        let payload: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["hostname"], "web-1");
+        assert_eq!(payload["runId"], "run-1");
+        assert!(payload["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(payload["stats"]["keys_added"], 1);
+        assert_eq!(payload["stats"]["changes"][0]["username"], "alice");
+        assert_eq!(payload["stats"]["changes"][0]["added"][0], "SHA256:new");
+    }
+
+    #[tokio::test]
+    async fn test_single_retry_and_failure_is_swallowed() {
+        let (url, requests) = mock_server(vec![MockResponse::new(500, "")]).await;
+        let notifier = WebhookNotifier::new(url, None, DEFAULT_SIGNATURE_HEADER.to_string()).unwrap();
+        notifier.notify("web-1", "run-1", &changed_stats()).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].header(DEFAULT_SIGNATURE_HEADER).is_none());
+    }
+}